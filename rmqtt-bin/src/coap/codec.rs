@@ -0,0 +1,307 @@
+//! CoAP(RFC 7252) message format, with the Observe option of RFC 7641
+
+use rmqtt::bytes::{BufMut, Bytes, BytesMut};
+use rmqtt::{MqttError, Result};
+
+const VERSION: u8 = 1;
+const PAYLOAD_MARKER: u8 = 0xFF;
+
+pub const OPTION_URI_HOST: u16 = 3;
+pub const OPTION_OBSERVE: u16 = 6;
+pub const OPTION_URI_PORT: u16 = 7;
+pub const OPTION_URI_PATH: u16 = 11;
+pub const OPTION_CONTENT_FORMAT: u16 = 12;
+pub const OPTION_URI_QUERY: u16 = 15;
+pub const OPTION_ACCEPT: u16 = 17;
+
+pub const CONTENT_FORMAT_OCTET_STREAM: u32 = 42;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Confirmable,
+    NonConfirmable,
+    Acknowledgement,
+    Reset,
+}
+
+///Class and detail of the code, such as 2.05 is Code(2, 5)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Code(pub u8, pub u8);
+
+impl Code {
+    pub const EMPTY: Code = Code(0, 0);
+    pub const GET: Code = Code(0, 1);
+    pub const POST: Code = Code(0, 2);
+
+    pub const CHANGED: Code = Code(2, 4);
+    pub const CONTENT: Code = Code(2, 5);
+    pub const BAD_REQUEST: Code = Code(4, 0);
+    pub const UNAUTHORIZED: Code = Code(4, 1);
+    pub const BAD_OPTION: Code = Code(4, 2);
+    pub const FORBIDDEN: Code = Code(4, 3);
+    pub const NOT_FOUND: Code = Code(4, 4);
+    pub const METHOD_NOT_ALLOWED: Code = Code(4, 5);
+    pub const REQUEST_ENTITY_TOO_LARGE: Code = Code(4, 13);
+    pub const INTERNAL_SERVER_ERROR: Code = Code(5, 0);
+    pub const SERVICE_UNAVAILABLE: Code = Code(5, 3);
+
+    #[inline]
+    pub fn is_request(&self) -> bool {
+        self.0 == 0 && self.1 != 0
+    }
+
+    #[inline]
+    fn from_u8(v: u8) -> Self {
+        Code(v >> 5, v & 0x1F)
+    }
+
+    #[inline]
+    fn as_u8(&self) -> u8 {
+        (self.0 << 5) | (self.1 & 0x1F)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub typ: MessageType,
+    pub code: Code,
+    pub msg_id: u16,
+    pub token: Bytes,
+    ///Sorted by the option number
+    pub options: Vec<(u16, Bytes)>,
+    pub payload: Bytes,
+}
+
+impl Message {
+    #[inline]
+    pub fn new(typ: MessageType, code: Code, msg_id: u16, token: Bytes) -> Self {
+        Self { typ, code, msg_id, token, options: Vec::new(), payload: Bytes::new() }
+    }
+
+    ///Empty message, such as the ACK, RST or the CoAP ping(empty CON)
+    #[inline]
+    pub fn empty(typ: MessageType, msg_id: u16) -> Self {
+        Self::new(typ, Code::EMPTY, msg_id, Bytes::new())
+    }
+
+    #[inline]
+    pub fn add_option(&mut self, number: u16, value: Bytes) {
+        let pos = self.options.iter().position(|(n, _)| *n > number).unwrap_or(self.options.len());
+        self.options.insert(pos, (number, value));
+    }
+
+    #[inline]
+    pub fn options(&self, number: u16) -> impl Iterator<Item = &Bytes> {
+        self.options.iter().filter(move |(n, _)| *n == number).map(|(_, v)| v)
+    }
+
+    #[inline]
+    pub fn uint_option(&self, number: u16) -> Option<u32> {
+        self.options(number).next().map(|v| v.iter().take(4).fold(0u32, |acc, b| (acc << 8) | *b as u32))
+    }
+
+    #[inline]
+    pub fn add_uint_option(&mut self, number: u16, v: u32) {
+        let bytes = v.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        self.add_option(number, Bytes::copy_from_slice(&bytes[skip..]));
+    }
+
+    ///Unrecognized critical option(odd number), the request is rejected with 4.02
+    #[inline]
+    pub fn unknown_critical_option(&self) -> Option<u16> {
+        self.options.iter().map(|(n, _)| *n).find(|n| {
+            n % 2 == 1
+                && !matches!(
+                    *n,
+                    OPTION_URI_HOST | OPTION_URI_PORT | OPTION_URI_PATH | OPTION_URI_QUERY | OPTION_ACCEPT
+                )
+        })
+    }
+}
+
+#[inline]
+fn option_ext(b: &mut Bytes, v: u8) -> Result<u16> {
+    match v {
+        13 => {
+            check_len(b, 1)?;
+            Ok(b.split_to(1)[0] as u16 + 13)
+        }
+        14 => {
+            check_len(b, 2)?;
+            let ext = b.split_to(2);
+            u16::from_be_bytes([ext[0], ext[1]])
+                .checked_add(269)
+                .ok_or_else(|| MqttError::from("malformed option, too large"))
+        }
+        15 => Err(MqttError::from("malformed option, reserved value")),
+        v => Ok(v as u16),
+    }
+}
+
+#[inline]
+fn check_len(b: &Bytes, len: usize) -> Result<()> {
+    if b.len() < len {
+        Err(MqttError::from("malformed message, too short"))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn decode(mut b: Bytes) -> Result<Message> {
+    check_len(&b, 4)?;
+    let header = b.split_to(4);
+    if header[0] >> 6 != VERSION {
+        return Err(MqttError::from("unsupported version"));
+    }
+    let typ = match (header[0] >> 4) & 0x03 {
+        0 => MessageType::Confirmable,
+        1 => MessageType::NonConfirmable,
+        2 => MessageType::Acknowledgement,
+        _ => MessageType::Reset,
+    };
+    let tkl = (header[0] & 0x0F) as usize;
+    if tkl > 8 {
+        return Err(MqttError::from("malformed message, invalid token length"));
+    }
+    let code = Code::from_u8(header[1]);
+    let msg_id = u16::from_be_bytes([header[2], header[3]]);
+    check_len(&b, tkl)?;
+    let token = b.split_to(tkl);
+
+    let mut options = Vec::new();
+    let mut number = 0u16;
+    let mut payload = Bytes::new();
+    while !b.is_empty() {
+        let first = b.split_to(1)[0];
+        if first == PAYLOAD_MARKER {
+            if b.is_empty() {
+                return Err(MqttError::from("malformed message, empty payload after the marker"));
+            }
+            payload = b;
+            break;
+        }
+        let delta = option_ext(&mut b, first >> 4)?;
+        let len = option_ext(&mut b, first & 0x0F)? as usize;
+        number = number.checked_add(delta).ok_or_else(|| MqttError::from("malformed option, too large"))?;
+        check_len(&b, len)?;
+        options.push((number, b.split_to(len)));
+    }
+
+    if code == Code::EMPTY && (!token.is_empty() || !options.is_empty() || !payload.is_empty()) {
+        return Err(MqttError::from("malformed message, empty message is not empty"));
+    }
+    Ok(Message { typ, code, msg_id, token, options, payload })
+}
+
+#[inline]
+fn option_nibble(v: u16) -> (u8, Option<Vec<u8>>) {
+    if v < 13 {
+        (v as u8, None)
+    } else if v < 269 {
+        (13, Some(vec![(v - 13) as u8]))
+    } else {
+        (14, Some((v - 269).to_be_bytes().to_vec()))
+    }
+}
+
+pub fn encode(m: &Message) -> Bytes {
+    let typ = match m.typ {
+        MessageType::Confirmable => 0,
+        MessageType::NonConfirmable => 1,
+        MessageType::Acknowledgement => 2,
+        MessageType::Reset => 3,
+    };
+    let mut b = BytesMut::with_capacity(4 + m.token.len() + m.payload.len() + m.options.len() * 8 + 1);
+    b.put_u8((VERSION << 6) | (typ << 4) | (m.token.len() as u8 & 0x0F));
+    b.put_u8(m.code.as_u8());
+    b.put_u16(m.msg_id);
+    b.put_slice(&m.token);
+
+    let mut number = 0u16;
+    for (n, v) in m.options.iter() {
+        let (delta, delta_ext) = option_nibble(*n - number);
+        let (len, len_ext) = option_nibble(v.len() as u16);
+        b.put_u8((delta << 4) | len);
+        if let Some(ext) = delta_ext {
+            b.put_slice(&ext);
+        }
+        if let Some(ext) = len_ext {
+            b.put_slice(&ext);
+        }
+        b.put_slice(v);
+        number = *n;
+    }
+
+    if !m.payload.is_empty() {
+        b.put_u8(PAYLOAD_MARKER);
+        b.put_slice(&m.payload);
+    }
+    b.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let mut m = Message::new(MessageType::Confirmable, Code::GET, 0x1234, Bytes::from_static(b"tok"));
+        m.add_uint_option(OPTION_OBSERVE, 0);
+        m.add_option(OPTION_URI_QUERY, Bytes::from_static(b"c=dev1"));
+        m.add_option(OPTION_URI_PATH, Bytes::from_static(b"ps"));
+        m.add_option(OPTION_URI_PATH, Bytes::from_static(b"sensors"));
+        //The extended option deltas and lengths
+        m.add_option(300, Bytes::from(vec![b'x'; 20]));
+        m.add_option(1000, Bytes::from(vec![b'y'; 300]));
+        m.payload = Bytes::from_static(b"22.5");
+        let b = encode(&m);
+        assert_eq!(&b[..4], &[0x43, 0x01, 0x12, 0x34]);
+        let d = decode(b).unwrap();
+        assert_eq!(d, m);
+        assert_eq!(d.uint_option(OPTION_OBSERVE), Some(0));
+        assert_eq!(d.options(OPTION_URI_PATH).collect::<Vec<_>>(), vec!["ps", "sensors"]);
+
+        let ping = Message::empty(MessageType::Confirmable, 7);
+        assert_eq!(encode(&ping).as_ref(), &[0x40, 0x00, 0x00, 0x07]);
+        assert_eq!(decode(encode(&ping)).unwrap(), ping);
+    }
+
+    #[test]
+    fn options() {
+        let mut m = Message::new(MessageType::NonConfirmable, Code::CONTENT, 1, Bytes::new());
+        m.add_uint_option(OPTION_OBSERVE, 0x01_0203);
+        m.add_uint_option(OPTION_CONTENT_FORMAT, CONTENT_FORMAT_OCTET_STREAM);
+        m.add_option(OPTION_URI_PATH, Bytes::from_static(b"ps"));
+        assert_eq!(m.options.iter().map(|(n, _)| *n).collect::<Vec<_>>(), vec![6, 11, 12]);
+        assert_eq!(m.options(OPTION_OBSERVE).next().unwrap().as_ref(), &[0x01, 0x02, 0x03]);
+        assert_eq!(m.uint_option(OPTION_CONTENT_FORMAT), Some(CONTENT_FORMAT_OCTET_STREAM));
+        assert_eq!(m.uint_option(OPTION_ACCEPT), None);
+        assert_eq!(m.unknown_critical_option(), None);
+        //The elective options are ignored, the unknown critical options are not
+        m.add_option(60, Bytes::new());
+        assert_eq!(m.unknown_critical_option(), None);
+        m.add_option(9, Bytes::new());
+        assert_eq!(m.unknown_critical_option(), Some(9));
+    }
+
+    #[test]
+    fn malformed() {
+        //Too short, unsupported version and token length
+        assert!(decode(Bytes::from_static(&[0x40, 0x01, 0x00])).is_err());
+        assert!(decode(Bytes::from_static(&[0x80, 0x01, 0x00, 0x01])).is_err());
+        assert!(decode(Bytes::from_static(&[0x49, 0x01, 0x00, 0x01])).is_err());
+        assert!(decode(Bytes::from_static(&[0x42, 0x01, 0x00, 0x01, 0xaa])).is_err());
+        //Option value and extended option length are truncated, reserved option values
+        assert!(decode(Bytes::from_static(&[0x40, 0x01, 0x00, 0x01, 0xb3, b'p', b's'])).is_err());
+        assert!(decode(Bytes::from_static(&[0x40, 0x01, 0x00, 0x01, 0xbe, 0x00])).is_err());
+        assert!(decode(Bytes::from_static(&[0x40, 0x01, 0x00, 0x01, 0xf0])).is_err());
+        assert!(decode(Bytes::from_static(&[0x40, 0x01, 0x00, 0x01, 0x0f])).is_err());
+        //The option number overflows
+        let overflow = [0x40, 0x01, 0x00, 0x01, 0xe0, 0xfe, 0xf2, 0xd0, 0x00];
+        assert!(decode(Bytes::copy_from_slice(&overflow)).is_err());
+        //Payload marker without the payload, and the empty message with a token
+        assert!(decode(Bytes::from_static(&[0x40, 0x01, 0x00, 0x01, 0xff])).is_err());
+        assert!(decode(Bytes::from_static(&[0x41, 0x00, 0x00, 0x01, 0xaa])).is_err());
+    }
+}
@@ -0,0 +1,766 @@
+//! CoAP(RFC 7252) gateway over UDP, the resources are the topics under "/ps".
+//!
+//! `POST /ps/{topic}` publishes the payload without a session, `GET /ps/{topic_filter}` with the
+//! Observe option(RFC 7641) creates a session of the broker, see [`rmqtt::broker::gateway`], which
+//! subscribes to the topic filter and delivers the messages as notifications. The client id,
+//! username and password are passed by the query parameters "c", "u" and "p", such as
+//! `coap://host/ps/sensors/1?c=dev1&u=user&p=passwd&qos=1&retain=false`.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::num::NonZeroU16;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmqtt::broker::conn_limit::{ConnCountGuard, ConnCounter, ConnGuard, ConnLimiter};
+use rmqtt::broker::gateway::{self, ConnectionlessPublish, GatewayPacket, GatewaySink, GATEWAY_KEY};
+use rmqtt::broker::ip_filter::IpFilters;
+use rmqtt::bytes::Bytes;
+use rmqtt::bytestring::ByteString;
+use rmqtt::futures::channel::mpsc;
+use rmqtt::futures::StreamExt;
+use rmqtt::ntex;
+use rmqtt::ntex_mqtt::v3::codec::Publish as PublishV3;
+use rmqtt::settings::listener::{Listener, ListenerId};
+use rmqtt::tokio::{self, net::UdpSocket, time::Instant};
+use rmqtt::{
+    log, timestamp_millis, ConnectAckReasonV3, ConnectV3, DashMap, ExtraAttrs, PacketV3, Password, Publish,
+    QoS, Reason, Result, Runtime, SessionState, TopicFilter, TopicName, UserName,
+};
+
+use crate::workers::Workers;
+
+use codec::{Code, Message, MessageType};
+
+mod codec;
+
+const PROTOCOL_NAME: &str = "coap";
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const RESOURCE_PREFIX: &str = "ps";
+
+///Transmission parameters of RFC 7252
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RETRANSMIT: usize = 4;
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+const PURGE_INTERVAL: Duration = Duration::from_secs(10);
+///Maximum number of the kept responses, the confirmable requests are refused with 5.03 if it is reached
+const MAX_RESPONSES: usize = 100_000;
+
+const OBSERVE_REGISTER: u32 = 0;
+const OBSERVE_DEREGISTER: u32 = 1;
+
+type Endpoint = (SocketAddr, u16);
+
+enum Event {
+    Ack(u16),
+    Reset(u16),
+    ///Re-registration of the observation
+    Register(Message),
+    ///Deregistration of the observation
+    Cancel,
+}
+
+struct Gateway {
+    socket: Arc<UdpSocket>,
+    listen_cfg: Listener,
+    counter: Arc<ConnCounter>,
+    ///Observations by the client address and the token
+    observers: DashMap<(SocketAddr, Bytes), mpsc::UnboundedSender<Event>>,
+    ///Notifications and pings waiting for the ACK or RST
+    exchanges: DashMap<Endpoint, mpsc::UnboundedSender<Event>>,
+    ///Responses of the confirmable requests, the retransmitted requests are not processed again.
+    ///They are kept for EXCHANGE_LIFETIME, up to MAX_RESPONSES
+    responses: DashMap<Endpoint, (Instant, Option<Bytes>)>,
+    next_msg_id: AtomicU16,
+}
+
+impl Gateway {
+    ///The current configuration of the listener, it may be updated at runtime
+    #[inline]
+    fn listen_cfg(&self) -> Listener {
+        Runtime::instance()
+            .settings
+            .listeners
+            .coap(self.listen_cfg.addr.port())
+            .unwrap_or_else(|| self.listen_cfg.clone())
+    }
+
+    #[inline]
+    fn next_msg_id(&self) -> u16 {
+        self.next_msg_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    #[inline]
+    async fn send(&self, addr: SocketAddr, m: &Message) -> Bytes {
+        log::debug!("{:?} coap send: {:?}", addr, m);
+        let data = codec::encode(m);
+        self.send_bytes(addr, &data).await;
+        data
+    }
+
+    #[inline]
+    async fn send_bytes(&self, addr: SocketAddr, data: &[u8]) {
+        if let Err(e) = self.socket.send_to(data, addr).await {
+            log::debug!("{:?} coap send error, {:?}", addr, e);
+        }
+    }
+
+    ///Piggybacked response of the confirmable request, or a non-confirmable response
+    fn response(&self, req: &Message, code: Code) -> Message {
+        if matches!(req.typ, MessageType::Confirmable) {
+            Message::new(MessageType::Acknowledgement, code, req.msg_id, req.token.clone())
+        } else {
+            Message::new(MessageType::NonConfirmable, code, self.next_msg_id(), req.token.clone())
+        }
+    }
+
+    async fn reply(&self, addr: SocketAddr, req: &Message, resp: Message) {
+        let data = self.send(addr, &resp).await;
+        if matches!(req.typ, MessageType::Confirmable) {
+            self.responses.insert((addr, req.msg_id), (Instant::now(), Some(data)));
+        }
+    }
+
+    ///Keeps the endpoint of the confirmable request until its response is ready, returns false if
+    ///too many responses are kept
+    #[inline]
+    fn track(&self, endpoint: Endpoint) -> bool {
+        if self.responses.len() >= MAX_RESPONSES {
+            self.purge();
+            if self.responses.len() >= MAX_RESPONSES {
+                return false;
+            }
+        }
+        self.responses.insert(endpoint, (Instant::now(), None));
+        true
+    }
+
+    ///Removes the expired responses
+    #[inline]
+    fn purge(&self) {
+        self.responses.retain(|_, (at, _)| at.elapsed() < EXCHANGE_LIFETIME);
+    }
+
+    #[inline]
+    async fn reply_code(&self, addr: SocketAddr, req: &Message, code: Code, diagnostic: &str) {
+        let mut resp = self.response(req, code);
+        resp.payload = Bytes::copy_from_slice(diagnostic.as_bytes());
+        self.reply(addr, req, resp).await
+    }
+}
+
+pub(crate) async fn listen(name: &str, listen_cfg: &Listener) -> Result<()> {
+    let socket = Arc::new(UdpSocket::bind(listen_cfg.addr).await?);
+    let conn_limiter = ConnLimiter::new(listen_cfg);
+    let ip_filter = IpFilters::instance().register(listen_cfg);
+    let gw = Arc::new(Gateway {
+        socket,
+        listen_cfg: listen_cfg.clone(),
+        counter: ConnCounter::new(ListenerId::Port(listen_cfg.addr.port())),
+        observers: DashMap::default(),
+        exchanges: DashMap::default(),
+        responses: DashMap::default(),
+        next_msg_id: AtomicU16::new(timestamp_millis() as u16),
+    });
+    let mut workers = Workers::new(listen_cfg.workers);
+    log::info!("{} coap listener is started on {:?}", name, listen_cfg.addr);
+
+    let mut purge = tokio::time::interval(PURGE_INTERVAL);
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let (n, remote_addr) = tokio::select! {
+            res = gw.socket.recv_from(&mut buf) => match res {
+                Ok(res) => res,
+                Err(e) => {
+                    log::debug!("coap recv error, {:?}", e);
+                    continue;
+                }
+            },
+            _ = purge.tick() => {
+                gw.purge();
+                continue;
+            }
+        };
+
+        if !ip_filter.is_allowed(&remote_addr.ip()) {
+            log::debug!("{:?} coap message is denied by the ip filter", remote_addr);
+            Runtime::instance().metrics.client_connect_ip_denied_inc();
+            continue;
+        }
+        let msg = match codec::decode(Bytes::copy_from_slice(&buf[..n])) {
+            Ok(m) => m,
+            Err(e) => {
+                log::debug!("{:?} coap decode error, {:?}", remote_addr, e);
+                continue;
+            }
+        };
+        log::debug!("{:?} coap recv: {:?}", remote_addr, msg);
+
+        match msg.typ {
+            MessageType::Acknowledgement | MessageType::Reset => {
+                if let Some((_, tx)) = gw.exchanges.remove(&(remote_addr, msg.msg_id)) {
+                    let event = if matches!(msg.typ, MessageType::Reset) {
+                        Event::Reset(msg.msg_id)
+                    } else {
+                        Event::Ack(msg.msg_id)
+                    };
+                    let _ = tx.unbounded_send(event);
+                }
+            }
+            MessageType::Confirmable | MessageType::NonConfirmable if msg.code.is_request() => {
+                if matches!(msg.typ, MessageType::Confirmable) {
+                    let endpoint = (remote_addr, msg.msg_id);
+                    if let Some(resp) = gw.responses.get(&endpoint).map(|r| r.1.clone()) {
+                        //Duplicate request, the response is sent again if it is ready
+                        if let Some(resp) = resp {
+                            gw.send_bytes(remote_addr, &resp).await;
+                        }
+                        continue;
+                    }
+                    if !gw.track(endpoint) {
+                        log::debug!("{:?} coap request is refused, too many responses are kept", remote_addr);
+                        gw.send(remote_addr, &gw.response(&msg, Code::SERVICE_UNAVAILABLE)).await;
+                        continue;
+                    }
+                }
+                let gw = gw.clone();
+                let conn_limiter = conn_limiter.clone();
+                workers.exec_fn(move || {
+                    ntex::rt::spawn(async move {
+                        if let Err(e) = request(gw, conn_limiter, remote_addr, msg).await {
+                            log::debug!("{:?} coap request error, {:?}", remote_addr, e);
+                        }
+                    });
+                });
+            }
+            MessageType::Confirmable => {
+                //CoAP ping or an unexpected message
+                gw.send(remote_addr, &Message::empty(MessageType::Reset, msg.msg_id)).await;
+            }
+            MessageType::NonConfirmable => {}
+        }
+    }
+}
+
+#[inline]
+fn conn_attrs() -> ExtraAttrs {
+    let mut conn_attrs = ExtraAttrs::new();
+    conn_attrs.insert(GATEWAY_KEY.into(), PROTOCOL_NAME.to_owned());
+    conn_attrs
+}
+
+///Query parameters of the request
+#[derive(Default)]
+struct Query {
+    client_id: Option<ByteString>,
+    username: Option<UserName>,
+    password: Option<Password>,
+    qos: Option<QoS>,
+    retain: bool,
+}
+
+impl Query {
+    fn parse(req: &Message) -> std::result::Result<Self, &'static str> {
+        let mut query = Query::default();
+        for q in req.options(codec::OPTION_URI_QUERY) {
+            let q = std::str::from_utf8(q).map_err(|_| "invalid query")?;
+            let (k, v) = q.split_once('=').unwrap_or((q, ""));
+            match k {
+                "c" => query.client_id = Some(ByteString::from(v)),
+                "u" => query.username = Some(UserName::from(v)),
+                "p" => query.password = Some(Password::copy_from_slice(v.as_bytes())),
+                "qos" => {
+                    query.qos = Some(match v {
+                        "0" => QoS::AtMostOnce,
+                        "1" => QoS::AtLeastOnce,
+                        "2" => QoS::ExactlyOnce,
+                        _ => return Err("invalid qos"),
+                    })
+                }
+                "retain" => query.retain = matches!(v, "true" | "1"),
+                _ => {}
+            }
+        }
+        Ok(query)
+    }
+
+    fn connect(&self, keep_alive: u16) -> ConnectV3 {
+        ConnectV3 {
+            client_id: self.client_id.clone().unwrap_or_default(),
+            clean_session: true,
+            keep_alive,
+            username: self.username.clone(),
+            password: self.password.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+///Topic of the Uri-Path options, "/ps/a/b" is "a/b"
+fn topic(req: &Message) -> Option<ByteString> {
+    let mut path = req.options(codec::OPTION_URI_PATH);
+    if path.next().map(|p| p.as_ref()) != Some(RESOURCE_PREFIX.as_bytes()) {
+        return None;
+    }
+    let levels = path.map(|p| std::str::from_utf8(p).ok()).collect::<Option<Vec<_>>>()?;
+    if levels.is_empty() {
+        return None;
+    }
+    Some(ByteString::from(levels.join("/")))
+}
+
+async fn request(
+    gw: Arc<Gateway>,
+    conn_limiter: Option<Arc<ConnLimiter>>,
+    remote_addr: SocketAddr,
+    req: Message,
+) -> Result<()> {
+    if let Some(number) = req.unknown_critical_option() {
+        let diagnostic = format!("unrecognized option {}", number);
+        gw.reply_code(remote_addr, &req, Code::BAD_OPTION, &diagnostic).await;
+        return Ok(());
+    }
+    let topic = if let Some(topic) = topic(&req) {
+        topic
+    } else {
+        gw.reply_code(remote_addr, &req, Code::NOT_FOUND, "").await;
+        return Ok(());
+    };
+    let query = match Query::parse(&req) {
+        Ok(query) => query,
+        Err(e) => {
+            gw.reply_code(remote_addr, &req, Code::BAD_REQUEST, e).await;
+            return Ok(());
+        }
+    };
+
+    match req.code {
+        Code::POST => post(gw, remote_addr, req, topic, query).await,
+        Code::GET => match req.uint_option(codec::OPTION_OBSERVE) {
+            Some(OBSERVE_REGISTER) => observe(gw, conn_limiter, remote_addr, req, topic, query).await,
+            Some(OBSERVE_DEREGISTER) => {
+                if let Some((_, tx)) = gw.observers.remove(&(remote_addr, req.token.clone())) {
+                    let _ = tx.unbounded_send(Event::Cancel);
+                }
+                gw.reply_code(remote_addr, &req, Code::CONTENT, "").await;
+                Ok(())
+            }
+            _ => {
+                gw.reply_code(remote_addr, &req, Code::BAD_REQUEST, "observe is required").await;
+                Ok(())
+            }
+        },
+        _ => {
+            gw.reply_code(remote_addr, &req, Code::METHOD_NOT_ALLOWED, "").await;
+            Ok(())
+        }
+    }
+}
+
+async fn post(
+    gw: Arc<Gateway>,
+    remote_addr: SocketAddr,
+    req: Message,
+    topic: TopicName,
+    query: Query,
+) -> Result<()> {
+    if topic.contains(['+', '#']) {
+        gw.reply_code(remote_addr, &req, Code::BAD_REQUEST, "invalid topic").await;
+        return Ok(());
+    }
+    let listen_cfg = gw.listen_cfg();
+    if req.payload.len() > listen_cfg.max_packet_size.as_u32() as usize {
+        gw.reply_code(remote_addr, &req, Code::REQUEST_ENTITY_TOO_LARGE, "").await;
+        return Ok(());
+    }
+    let qos = query.qos.unwrap_or(QoS::AtMostOnce);
+    let publish = Publish {
+        dup: false,
+        retain: query.retain,
+        qos,
+        topic,
+        packet_id: if matches!(qos, QoS::AtMostOnce) { None } else { NonZeroU16::new(req.msg_id.max(1)) },
+        payload: req.payload.clone(),
+        properties: Default::default(),
+        delay_interval: None,
+        create_time: timestamp_millis(),
+    };
+    let local_addr = listen_cfg.addr;
+    let res = gateway::publish_connectionless(
+        listen_cfg,
+        query.connect(0),
+        remote_addr,
+        local_addr,
+        conn_attrs(),
+        publish,
+        None,
+    )
+    .await;
+    let code = match res {
+        Ok(ConnectionlessPublish::Published) => Code::CHANGED,
+        Ok(ConnectionlessPublish::NotAuthorized) => Code::UNAUTHORIZED,
+        Ok(ConnectionlessPublish::Refused) => Code::FORBIDDEN,
+        Ok(ConnectionlessPublish::RateLimited) | Ok(ConnectionlessPublish::Unavailable) => {
+            Code::SERVICE_UNAVAILABLE
+        }
+        Err(e) => {
+            log::warn!("{:?} coap publish failed, reason: {:?}", remote_addr, e);
+            Code::INTERNAL_SERVER_ERROR
+        }
+    };
+    gw.reply_code(remote_addr, &req, code, "").await;
+    Ok(())
+}
+
+async fn observe(
+    gw: Arc<Gateway>,
+    conn_limiter: Option<Arc<ConnLimiter>>,
+    remote_addr: SocketAddr,
+    req: Message,
+    topic_filter: TopicFilter,
+    query: Query,
+) -> Result<()> {
+    let key = (remote_addr, req.token.clone());
+    if let Some(tx) = gw.observers.get(&key) {
+        let _ = tx.unbounded_send(Event::Register(req));
+        return Ok(());
+    }
+
+    let count_guard = if let Some(guard) = gw.counter.acquire() {
+        guard
+    } else {
+        log::debug!("{:?} coap observation is rejected, too many connections", remote_addr);
+        gw.reply_code(remote_addr, &req, Code::SERVICE_UNAVAILABLE, "too many observations").await;
+        return Ok(());
+    };
+    let conn_guard = match conn_limiter.as_ref().map(|l| l.acquire(remote_addr.ip())).transpose() {
+        Ok(guard) => guard,
+        Err(e) => {
+            log::debug!("{:?} coap observation is rejected, {:?}", remote_addr, e);
+            gw.reply_code(remote_addr, &req, Code::SERVICE_UNAVAILABLE, "rate limited").await;
+            return Ok(());
+        }
+    };
+
+    let listen_cfg = gw.listen_cfg();
+    let keep_alive = listen_cfg.coap_keepalive.as_secs().clamp(1, u16::MAX as u64) as u16;
+    let (sink, sink_rx) = GatewaySink::new();
+    let res = gateway::connect(
+        listen_cfg.clone(),
+        query.connect(keep_alive),
+        remote_addr,
+        listen_cfg.addr,
+        conn_attrs(),
+        sink,
+    )
+    .await;
+    let state = match res {
+        Ok(Ok((state, _))) => state,
+        Ok(Err(ack_code)) => {
+            let code = match ack_code {
+                ConnectAckReasonV3::BadUserNameOrPassword | ConnectAckReasonV3::NotAuthorized => {
+                    Code::UNAUTHORIZED
+                }
+                ConnectAckReasonV3::ServiceUnavailable => Code::SERVICE_UNAVAILABLE,
+                _ => Code::BAD_REQUEST,
+            };
+            gw.reply_code(remote_addr, &req, code, "").await;
+            return Ok(());
+        }
+        Err(e) => {
+            gw.reply_code(remote_addr, &req, Code::SERVICE_UNAVAILABLE, "").await;
+            return Err(e);
+        }
+    };
+
+    let qos = query.qos.unwrap_or(QoS::AtMostOnce);
+    match gateway::subscribe(&state, &topic_filter, qos).await {
+        Ok(Some(_)) => {}
+        Ok(None) | Err(_) => {
+            gw.reply_code(remote_addr, &req, Code::FORBIDDEN, "").await;
+            gateway::disconnect(&state);
+            return Ok(());
+        }
+    }
+
+    let (tx, rx) = mpsc::unbounded();
+    if let Some(old) = gw.observers.insert(key.clone(), tx) {
+        let _ = old.unbounded_send(Event::Cancel);
+    }
+    let mut observer = Observer {
+        gw: gw.clone(),
+        listen_cfg,
+        remote_addr,
+        token: req.token.clone(),
+        state,
+        seq: 0,
+        outstanding: None,
+        last_non: None,
+        queue: VecDeque::new(),
+        _count_guard: count_guard,
+        _conn_guard: conn_guard,
+    };
+    observer.registered(&req).await;
+    observer.run(rx, sink_rx).await;
+    drop(observer);
+    gw.observers.remove_if(&key, |_, tx| tx.is_closed());
+    Ok(())
+}
+
+///Confirmable message waiting for the ACK
+struct Outstanding {
+    msg_id: u16,
+    data: Bytes,
+    retransmits: usize,
+    timeout: Duration,
+    deadline: Instant,
+    ///None for the CoAP ping
+    publish: Option<(NonZeroU16, QoS)>,
+}
+
+struct Observer {
+    gw: Arc<Gateway>,
+    listen_cfg: Listener,
+    remote_addr: SocketAddr,
+    token: Bytes,
+    state: SessionState,
+    ///Value of the Observe option of the notifications
+    seq: u32,
+    outstanding: Option<Outstanding>,
+    ///The last non-confirmable notification, the client may reject it with RST
+    last_non: Option<u16>,
+    queue: VecDeque<PublishV3>,
+    _count_guard: ConnCountGuard,
+    _conn_guard: Option<ConnGuard>,
+}
+
+impl Observer {
+    #[inline]
+    fn next_seq(&mut self) -> u32 {
+        self.seq = (self.seq + 1) & 0xFF_FFFF;
+        self.seq
+    }
+
+    async fn registered(&mut self, req: &Message) {
+        let mut resp = self.gw.response(req, Code::CONTENT);
+        let seq = self.next_seq();
+        resp.add_uint_option(codec::OPTION_OBSERVE, seq);
+        self.gw.reply(self.remote_addr, req, resp).await;
+    }
+
+    async fn run(
+        &mut self,
+        mut rx: mpsc::UnboundedReceiver<Event>,
+        mut sink_rx: mpsc::UnboundedReceiver<GatewayPacket>,
+    ) {
+        let keepalive = self.listen_cfg.coap_keepalive.max(Duration::from_secs(2));
+        let mut ping_tick = tokio::time::interval(keepalive / 2);
+        let mut last_active = Instant::now();
+        loop {
+            let deadline = self.outstanding.as_ref().map(|o| o.deadline);
+            let timeout = deadline.unwrap_or_else(Instant::now);
+            tokio::select! {
+                event = rx.next() => {
+                    match event {
+                        Some(Event::Ack(msg_id)) => {
+                            last_active = Instant::now();
+                            self.acked(msg_id).await;
+                        }
+                        Some(Event::Reset(msg_id)) => {
+                            //Reply of the CoAP ping
+                            if self.is_ping(msg_id) {
+                                last_active = Instant::now();
+                                self.acked(msg_id).await;
+                            } else {
+                                log::debug!("{:?} coap notification is rejected", self.state.id);
+                                gateway::disconnect(&self.state);
+                                break;
+                            }
+                        }
+                        Some(Event::Register(req)) => {
+                            last_active = Instant::now();
+                            gateway::keepalive(&self.state, true);
+                            self.registered(&req).await;
+                        }
+                        Some(Event::Cancel) | None => {
+                            gateway::disconnect(&self.state);
+                            break;
+                        }
+                    }
+                },
+                p = sink_rx.next() => {
+                    match p {
+                        Some(GatewayPacket::Packet(PacketV3::Publish(publish))) => {
+                            if self.outstanding.is_some() {
+                                self.enqueue(publish).await;
+                            } else {
+                                self.notify(publish).await;
+                            }
+                        }
+                        Some(GatewayPacket::Packet(PacketV3::PublishRelease { packet_id })) => {
+                            gateway::acked(&self.state, packet_id).await;
+                        }
+                        Some(GatewayPacket::Packet(p)) => {
+                            log::debug!("{:?} coap unsupported packet, {:?}", self.state.id, p);
+                        }
+                        Some(GatewayPacket::Close) | None => {
+                            //The observation is ended by the server
+                            self.closed().await;
+                            break;
+                        }
+                    }
+                },
+                _ = ping_tick.tick() => {
+                    if self.outstanding.is_none() && last_active.elapsed() >= keepalive / 2 {
+                        self.ping().await;
+                    }
+                },
+                _ = tokio::time::sleep_until(timeout), if deadline.is_some() => {
+                    if !self.retransmit().await {
+                        log::debug!("{:?} coap observer is lost", self.state.id);
+                        gateway::closed(&self.state, Reason::ConnectKeepaliveTimeout);
+                        break;
+                    }
+                },
+            }
+        }
+
+        if let Some(o) = self.outstanding.take() {
+            self.gw.exchanges.remove(&(self.remote_addr, o.msg_id));
+        }
+        if let Some(msg_id) = self.last_non.take() {
+            self.gw.exchanges.remove(&(self.remote_addr, msg_id));
+        }
+    }
+
+    #[inline]
+    fn is_ping(&self, msg_id: u16) -> bool {
+        self.outstanding.as_ref().map(|o| o.msg_id == msg_id && o.publish.is_none()).unwrap_or_default()
+    }
+
+    ///The observation is ended by the server, such as the session is kicked
+    async fn closed(&mut self) {
+        let msg_id = self.gw.next_msg_id();
+        let mut m =
+            Message::new(MessageType::NonConfirmable, Code::SERVICE_UNAVAILABLE, msg_id, self.token.clone());
+        m.payload = Bytes::from_static(b"session closed");
+        self.gw.send(self.remote_addr, &m).await;
+    }
+
+    ///Messages are delivered one by one when the confirmable notification is outstanding
+    async fn enqueue(&mut self, publish: PublishV3) {
+        if let Some(packet_id) = publish.packet_id {
+            //Redelivery of the inflight message
+            if self
+                .outstanding
+                .as_ref()
+                .and_then(|o| o.publish)
+                .map(|(id, _)| id == packet_id)
+                .unwrap_or_default()
+            {
+                return;
+            }
+            self.queue.retain(|p| p.packet_id != Some(packet_id));
+        }
+        if self.queue.len() >= self.listen_cfg.max_mqueue_len.max(1) {
+            if let Some(dropped) = self.queue.pop_front() {
+                log::debug!("{:?} coap notification queue is full, {:?}", self.state.id, dropped);
+                if let Some(packet_id) = dropped.packet_id {
+                    gateway::rejected(&self.state, packet_id, Reason::MessageQueueFull).await;
+                }
+            }
+        }
+        self.queue.push_back(publish);
+    }
+
+    async fn notify(&mut self, publish: PublishV3) {
+        let msg_id = self.gw.next_msg_id();
+        let confirmable = !matches!(publish.qos, QoS::AtMostOnce);
+        let typ = if confirmable { MessageType::Confirmable } else { MessageType::NonConfirmable };
+        let mut m = Message::new(typ, Code::CONTENT, msg_id, self.token.clone());
+        let seq = self.next_seq();
+        m.add_uint_option(codec::OPTION_OBSERVE, seq);
+        m.add_uint_option(codec::OPTION_CONTENT_FORMAT, codec::CONTENT_FORMAT_OCTET_STREAM);
+        m.payload = publish.payload;
+
+        let tx = match self.gw.observers.get(&(self.remote_addr, self.token.clone())) {
+            Some(tx) => tx.clone(),
+            None => return,
+        };
+        if let Some(last) = self.last_non.take() {
+            self.gw.exchanges.remove(&(self.remote_addr, last));
+        }
+        self.gw.exchanges.insert((self.remote_addr, msg_id), tx);
+        let data = self.gw.send(self.remote_addr, &m).await;
+        if confirmable {
+            self.outstanding = Some(Outstanding {
+                msg_id,
+                data,
+                retransmits: 0,
+                timeout: ACK_TIMEOUT,
+                deadline: Instant::now() + ACK_TIMEOUT,
+                publish: publish.packet_id.map(|id| (id, publish.qos)),
+            });
+        } else {
+            self.last_non = Some(msg_id);
+        }
+    }
+
+    async fn ping(&mut self) {
+        let tx = match self.gw.observers.get(&(self.remote_addr, self.token.clone())) {
+            Some(tx) => tx.clone(),
+            None => return,
+        };
+        let msg_id = self.gw.next_msg_id();
+        self.gw.exchanges.insert((self.remote_addr, msg_id), tx);
+        let data = self.gw.send(self.remote_addr, &Message::empty(MessageType::Confirmable, msg_id)).await;
+        self.outstanding = Some(Outstanding {
+            msg_id,
+            data,
+            retransmits: 0,
+            timeout: ACK_TIMEOUT,
+            deadline: Instant::now() + ACK_TIMEOUT,
+            publish: None,
+        });
+    }
+
+    async fn acked(&mut self, msg_id: u16) {
+        let o = match self.outstanding.take() {
+            Some(o) if o.msg_id == msg_id => o,
+            o => {
+                self.outstanding = o;
+                return;
+            }
+        };
+        gateway::keepalive(&self.state, o.publish.is_none());
+        match o.publish {
+            Some((packet_id, QoS::ExactlyOnce)) => gateway::received(&self.state, packet_id).await,
+            Some((packet_id, _)) => gateway::acked(&self.state, packet_id).await,
+            None => {}
+        }
+        while self.outstanding.is_none() {
+            match self.queue.pop_front() {
+                Some(publish) => self.notify(publish).await,
+                None => break,
+            }
+        }
+    }
+
+    ///Returns false if the retransmissions are exhausted
+    async fn retransmit(&mut self) -> bool {
+        let o = match self.outstanding.as_mut() {
+            Some(o) => o,
+            None => return true,
+        };
+        if o.retransmits >= MAX_RETRANSMIT {
+            return false;
+        }
+        o.retransmits += 1;
+        o.timeout *= 2;
+        o.deadline = Instant::now() + o.timeout;
+        let data = o.data.clone();
+        self.gw.send_bytes(self.remote_addr, &data).await;
+        true
+    }
+}
@@ -0,0 +1,224 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::{io, marker};
+
+use rmqtt::broker::conn_limit::{ConnCounter, ConnLimiter};
+use rmqtt::broker::ip_filter::IpFilter;
+use rmqtt::ntex::rt::net::TcpStream;
+#[cfg(unix)]
+use rmqtt::ntex::rt::net::UnixStream;
+use rmqtt::ntex::util::Ready;
+use rmqtt::ntex::{Service, ServiceFactory};
+use rmqtt::ntex_mqtt;
+use rmqtt::{log, MqttError, Runtime};
+
+use crate::proxy::ProxyStream;
+
+///The peer address checked by the connection limits
+pub trait PeerAddr {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl PeerAddr for ProxyStream<TcpStream> {
+    #[inline]
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        ProxyStream::peer_addr(self)
+    }
+}
+
+#[cfg(unix)]
+impl PeerAddr for ProxyStream<UnixStream> {
+    ///The peer is always on the local host
+    #[inline]
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(crate::unix::PEER_ADDR)
+    }
+}
+
+///Checks the max connections, connection rate and per IP connection limits of the listener,
+///before the TLS or MQTT handshake starts
+pub struct ConnLimitServer<S> {
+    counter: Arc<ConnCounter>,
+    limiter: Option<Arc<ConnLimiter>>,
+    io: marker::PhantomData<S>,
+}
+
+impl<S> ConnLimitServer<S> {
+    pub fn new(counter: Arc<ConnCounter>, limiter: Option<Arc<ConnLimiter>>) -> Self {
+        ConnLimitServer { counter, limiter, io: marker::PhantomData }
+    }
+}
+
+impl<S> Clone for ConnLimitServer<S> {
+    fn clone(&self) -> Self {
+        Self { counter: self.counter.clone(), limiter: self.limiter.clone(), io: marker::PhantomData }
+    }
+}
+
+impl<S: 'static> ServiceFactory for ConnLimitServer<S>
+where
+    ProxyStream<S>: PeerAddr,
+{
+    type Request = ProxyStream<S>;
+    type Response = ProxyStream<S>;
+    type Error = ntex_mqtt::MqttError<MqttError>;
+    type Config = ();
+
+    type Service = ConnLimitService<S>;
+    type InitError = ();
+    type Future = Ready<Self::Service, Self::InitError>;
+
+    fn new_service(&self, _: ()) -> Self::Future {
+        Ready::Ok(ConnLimitService {
+            counter: self.counter.clone(),
+            limiter: self.limiter.clone(),
+            io: marker::PhantomData,
+        })
+    }
+}
+
+pub struct ConnLimitService<S> {
+    counter: Arc<ConnCounter>,
+    limiter: Option<Arc<ConnLimiter>>,
+    io: marker::PhantomData<S>,
+}
+
+impl<S: 'static> Service for ConnLimitService<S>
+where
+    ProxyStream<S>: PeerAddr,
+{
+    type Request = ProxyStream<S>;
+    type Response = ProxyStream<S>;
+    type Error = ntex_mqtt::MqttError<MqttError>;
+    type Future = Ready<Self::Response, Self::Error>;
+
+    #[inline]
+    fn poll_ready(&self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn call(&self, mut req: Self::Request) -> Self::Future {
+        let peer_addr = match req.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => return Ready::Err(ntex_mqtt::MqttError::Service(MqttError::from(e))),
+        };
+        match self.counter.acquire() {
+            Some(guard) => req.set_count_guard(guard),
+            None => {
+                log::debug!("{:?} connection is rejected, too many connections", peer_addr);
+                return Ready::Err(ntex_mqtt::MqttError::Service(MqttError::from(
+                    "connection is rejected, too many connections",
+                )));
+            }
+        }
+        let limiter = if let Some(limiter) = &self.limiter {
+            limiter
+        } else {
+            return Ready::Ok(req);
+        };
+        match limiter.acquire(peer_addr.ip()) {
+            Ok(guard) => {
+                req.set_conn_guard(guard);
+                Ready::Ok(req)
+            }
+            Err(e) => {
+                log::debug!("{:?} connection is rejected, {:?}", peer_addr, e);
+                Ready::Err(ntex_mqtt::MqttError::Service(MqttError::from(format!(
+                    "connection is rejected, {:?}",
+                    e
+                ))))
+            }
+        }
+    }
+}
+
+///The source IP checked by the ip filter
+pub trait SourceIp {
+    ///Returns None if there is nothing new to check
+    fn source_ip(&self) -> Option<io::Result<IpAddr>>;
+}
+
+impl SourceIp for TcpStream {
+    #[inline]
+    fn source_ip(&self) -> Option<io::Result<IpAddr>> {
+        Some(self.peer_addr().map(|addr| addr.ip()))
+    }
+}
+
+impl SourceIp for ProxyStream<TcpStream> {
+    ///Only the address provided by the PROXY protocol, the peer address has been checked after accept
+    #[inline]
+    fn source_ip(&self) -> Option<io::Result<IpAddr>> {
+        self.proxy_info().and_then(|info| info.source_addr).map(|addr| Ok(addr.ip()))
+    }
+}
+
+///Checks the allow/deny lists of the listener, right after accept and again after
+///the PROXY protocol header is read
+pub struct IpFilterServer<T> {
+    filter: Arc<IpFilter>,
+    io: marker::PhantomData<T>,
+}
+
+impl<T> IpFilterServer<T> {
+    pub fn new(filter: Arc<IpFilter>) -> Self {
+        IpFilterServer { filter, io: marker::PhantomData }
+    }
+}
+
+impl<T> Clone for IpFilterServer<T> {
+    fn clone(&self) -> Self {
+        Self { filter: self.filter.clone(), io: marker::PhantomData }
+    }
+}
+
+impl<T: SourceIp + 'static> ServiceFactory for IpFilterServer<T> {
+    type Request = T;
+    type Response = T;
+    type Error = ntex_mqtt::MqttError<MqttError>;
+    type Config = ();
+
+    type Service = IpFilterService<T>;
+    type InitError = ();
+    type Future = Ready<Self::Service, Self::InitError>;
+
+    fn new_service(&self, _: ()) -> Self::Future {
+        Ready::Ok(IpFilterService { filter: self.filter.clone(), io: marker::PhantomData })
+    }
+}
+
+pub struct IpFilterService<T> {
+    filter: Arc<IpFilter>,
+    io: marker::PhantomData<T>,
+}
+
+impl<T: SourceIp + 'static> Service for IpFilterService<T> {
+    type Request = T;
+    type Response = T;
+    type Error = ntex_mqtt::MqttError<MqttError>;
+    type Future = Ready<Self::Response, Self::Error>;
+
+    #[inline]
+    fn poll_ready(&self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn call(&self, req: Self::Request) -> Self::Future {
+        match req.source_ip() {
+            None => Ready::Ok(req),
+            Some(Ok(ip)) if self.filter.is_allowed(&ip) => Ready::Ok(req),
+            Some(Ok(ip)) => {
+                log::debug!("{:?} connection is denied by the ip filter", ip);
+                Runtime::instance().metrics.client_connect_ip_denied_inc();
+                Ready::Err(ntex_mqtt::MqttError::Service(MqttError::from(format!(
+                    "{} connection is denied",
+                    ip
+                ))))
+            }
+            Some(Err(e)) => Ready::Err(ntex_mqtt::MqttError::Service(MqttError::from(e))),
+        }
+    }
+}
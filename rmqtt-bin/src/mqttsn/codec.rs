@@ -0,0 +1,634 @@
+//! MQTT-SN 1.2 packets, one packet per UDP datagram
+
+use rmqtt::bytes::{Buf, BufMut, Bytes, BytesMut};
+use rmqtt::bytestring::ByteString;
+use rmqtt::{MqttError, QoS, Result};
+
+const ADVERTISE: u8 = 0x00;
+const SEARCHGW: u8 = 0x01;
+const GWINFO: u8 = 0x02;
+const CONNECT: u8 = 0x04;
+const CONNACK: u8 = 0x05;
+const WILLTOPICREQ: u8 = 0x06;
+const WILLTOPIC: u8 = 0x07;
+const WILLMSGREQ: u8 = 0x08;
+const WILLMSG: u8 = 0x09;
+const REGISTER: u8 = 0x0A;
+const REGACK: u8 = 0x0B;
+const PUBLISH: u8 = 0x0C;
+const PUBACK: u8 = 0x0D;
+const PUBCOMP: u8 = 0x0E;
+const PUBREC: u8 = 0x0F;
+const PUBREL: u8 = 0x10;
+const SUBSCRIBE: u8 = 0x12;
+const SUBACK: u8 = 0x13;
+const UNSUBSCRIBE: u8 = 0x14;
+const UNSUBACK: u8 = 0x15;
+const PINGREQ: u8 = 0x16;
+const PINGRESP: u8 = 0x17;
+const DISCONNECT: u8 = 0x18;
+const WILLTOPICUPD: u8 = 0x1A;
+const WILLTOPICRESP: u8 = 0x1B;
+const WILLMSGUPD: u8 = 0x1C;
+const WILLMSGRESP: u8 = 0x1D;
+
+const PROTOCOL_ID: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReturnCode {
+    Accepted,
+    Congestion,
+    InvalidTopicId,
+    NotSupported,
+}
+
+impl ReturnCode {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0x00 => ReturnCode::Accepted,
+            0x01 => ReturnCode::Congestion,
+            0x02 => ReturnCode::InvalidTopicId,
+            _ => ReturnCode::NotSupported,
+        }
+    }
+
+    fn as_u8(&self) -> u8 {
+        match self {
+            ReturnCode::Accepted => 0x00,
+            ReturnCode::Congestion => 0x01,
+            ReturnCode::InvalidTopicId => 0x02,
+            ReturnCode::NotSupported => 0x03,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopicIdType {
+    Normal,
+    Predefined,
+    Short,
+}
+
+///DUP, QoS, Retain, Will, CleanSession and TopicIdType of the packet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags(u8);
+
+impl Flags {
+    ///`qos` is None for QoS -1
+    pub fn new(dup: bool, qos: Option<QoS>, retain: bool, topic_id_type: TopicIdType) -> Self {
+        let qos = match qos {
+            Some(QoS::AtMostOnce) => 0b00,
+            Some(QoS::AtLeastOnce) => 0b01,
+            Some(QoS::ExactlyOnce) => 0b10,
+            None => 0b11,
+        };
+        let topic_id_type = match topic_id_type {
+            TopicIdType::Normal => 0b00,
+            TopicIdType::Predefined => 0b01,
+            TopicIdType::Short => 0b10,
+        };
+        Flags((dup as u8) << 7 | qos << 5 | (retain as u8) << 4 | topic_id_type)
+    }
+
+    #[inline]
+    pub fn dup(&self) -> bool {
+        self.0 & 0x80 != 0
+    }
+
+    ///None for QoS -1
+    #[inline]
+    pub fn qos(&self) -> Option<QoS> {
+        match (self.0 >> 5) & 0b11 {
+            0b00 => Some(QoS::AtMostOnce),
+            0b01 => Some(QoS::AtLeastOnce),
+            0b10 => Some(QoS::ExactlyOnce),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn retain(&self) -> bool {
+        self.0 & 0x10 != 0
+    }
+
+    #[inline]
+    pub fn will(&self) -> bool {
+        self.0 & 0x08 != 0
+    }
+
+    #[inline]
+    pub fn clean_session(&self) -> bool {
+        self.0 & 0x04 != 0
+    }
+
+    #[inline]
+    pub fn topic_id_type(&self) -> Result<TopicIdType> {
+        match self.0 & 0b11 {
+            0b00 => Ok(TopicIdType::Normal),
+            0b01 => Ok(TopicIdType::Predefined),
+            0b10 => Ok(TopicIdType::Short),
+            _ => Err(MqttError::from("reserved topic id type")),
+        }
+    }
+}
+
+///Topic of the SUBSCRIBE and UNSUBSCRIBE packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Topic {
+    Name(ByteString),
+    Predefined(u16),
+    Short(ByteString),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Advertise {
+        gw_id: u8,
+        duration: u16,
+    },
+    SearchGw {
+        radius: u8,
+    },
+    GwInfo {
+        gw_id: u8,
+    },
+    Connect {
+        flags: Flags,
+        duration: u16,
+        client_id: ByteString,
+    },
+    ConnAck {
+        code: ReturnCode,
+    },
+    WillTopicReq,
+    ///Empty topic deletes the will
+    WillTopic {
+        flags: Flags,
+        topic: ByteString,
+    },
+    WillMsgReq,
+    WillMsg {
+        msg: Bytes,
+    },
+    Register {
+        topic_id: u16,
+        msg_id: u16,
+        topic_name: ByteString,
+    },
+    RegAck {
+        topic_id: u16,
+        msg_id: u16,
+        code: ReturnCode,
+    },
+    Publish {
+        flags: Flags,
+        topic_id: u16,
+        msg_id: u16,
+        data: Bytes,
+    },
+    PubAck {
+        topic_id: u16,
+        msg_id: u16,
+        code: ReturnCode,
+    },
+    PubRec {
+        msg_id: u16,
+    },
+    PubRel {
+        msg_id: u16,
+    },
+    PubComp {
+        msg_id: u16,
+    },
+    Subscribe {
+        flags: Flags,
+        msg_id: u16,
+        topic: Topic,
+    },
+    SubAck {
+        flags: Flags,
+        topic_id: u16,
+        msg_id: u16,
+        code: ReturnCode,
+    },
+    Unsubscribe {
+        flags: Flags,
+        msg_id: u16,
+        topic: Topic,
+    },
+    UnsubAck {
+        msg_id: u16,
+    },
+    ///The client id is present if the client is sleeping
+    PingReq {
+        client_id: Option<ByteString>,
+    },
+    PingResp,
+    ///The duration is present if the client goes to sleep
+    Disconnect {
+        duration: Option<u16>,
+    },
+    WillTopicUpd {
+        flags: Flags,
+        topic: ByteString,
+    },
+    WillTopicResp {
+        code: ReturnCode,
+    },
+    WillMsgUpd {
+        msg: Bytes,
+    },
+    WillMsgResp {
+        code: ReturnCode,
+    },
+}
+
+#[inline]
+fn string(b: Bytes) -> Result<ByteString> {
+    ByteString::try_from(b).map_err(|e| MqttError::from(format!("invalid utf8 string, {:?}", e)))
+}
+
+#[inline]
+fn check_len(b: &Bytes, len: usize) -> Result<()> {
+    if b.len() < len {
+        Err(MqttError::from("malformed packet, too short"))
+    } else {
+        Ok(())
+    }
+}
+
+#[inline]
+fn topic(flags: Flags, mut b: Bytes) -> Result<Topic> {
+    match flags.topic_id_type()? {
+        TopicIdType::Normal => Ok(Topic::Name(string(b)?)),
+        TopicIdType::Predefined => {
+            check_len(&b, 2)?;
+            Ok(Topic::Predefined(b.get_u16()))
+        }
+        TopicIdType::Short => {
+            check_len(&b, 2)?;
+            Ok(Topic::Short(string(b.split_to(2))?))
+        }
+    }
+}
+
+pub fn decode(mut b: Bytes) -> Result<Packet> {
+    check_len(&b, 2)?;
+    //The length is 3 octets if the first octet is 0x01
+    let (len, header_len) = if b[0] == 0x01 {
+        check_len(&b, 3)?;
+        (u16::from_be_bytes([b[1], b[2]]) as usize, 3)
+    } else {
+        (b[0] as usize, 1)
+    };
+    if len <= header_len || len > b.len() {
+        return Err(MqttError::from("malformed packet, invalid length"));
+    }
+    b.truncate(len);
+    b.advance(header_len);
+    let typ = b.get_u8();
+    let p = match typ {
+        ADVERTISE => {
+            check_len(&b, 3)?;
+            Packet::Advertise { gw_id: b.get_u8(), duration: b.get_u16() }
+        }
+        SEARCHGW => {
+            check_len(&b, 1)?;
+            Packet::SearchGw { radius: b.get_u8() }
+        }
+        GWINFO => {
+            check_len(&b, 1)?;
+            Packet::GwInfo { gw_id: b.get_u8() }
+        }
+        CONNECT => {
+            check_len(&b, 4)?;
+            let flags = Flags(b.get_u8());
+            if b.get_u8() != PROTOCOL_ID {
+                return Err(MqttError::from("unsupported protocol id"));
+            }
+            Packet::Connect { flags, duration: b.get_u16(), client_id: string(b)? }
+        }
+        CONNACK => {
+            check_len(&b, 1)?;
+            Packet::ConnAck { code: ReturnCode::from_u8(b.get_u8()) }
+        }
+        WILLTOPICREQ => Packet::WillTopicReq,
+        WILLTOPIC | WILLTOPICUPD => {
+            let (flags, topic) = if b.is_empty() {
+                (Flags::default(), ByteString::new())
+            } else {
+                (Flags(b.get_u8()), string(b)?)
+            };
+            if typ == WILLTOPIC {
+                Packet::WillTopic { flags, topic }
+            } else {
+                Packet::WillTopicUpd { flags, topic }
+            }
+        }
+        WILLMSGREQ => Packet::WillMsgReq,
+        WILLMSG => Packet::WillMsg { msg: b },
+        WILLMSGUPD => Packet::WillMsgUpd { msg: b },
+        REGISTER => {
+            check_len(&b, 4)?;
+            Packet::Register { topic_id: b.get_u16(), msg_id: b.get_u16(), topic_name: string(b)? }
+        }
+        REGACK => {
+            check_len(&b, 5)?;
+            Packet::RegAck {
+                topic_id: b.get_u16(),
+                msg_id: b.get_u16(),
+                code: ReturnCode::from_u8(b.get_u8()),
+            }
+        }
+        PUBLISH => {
+            check_len(&b, 5)?;
+            Packet::Publish { flags: Flags(b.get_u8()), topic_id: b.get_u16(), msg_id: b.get_u16(), data: b }
+        }
+        PUBACK => {
+            check_len(&b, 5)?;
+            Packet::PubAck {
+                topic_id: b.get_u16(),
+                msg_id: b.get_u16(),
+                code: ReturnCode::from_u8(b.get_u8()),
+            }
+        }
+        PUBREC | PUBREL | PUBCOMP => {
+            check_len(&b, 2)?;
+            let msg_id = b.get_u16();
+            match typ {
+                PUBREC => Packet::PubRec { msg_id },
+                PUBREL => Packet::PubRel { msg_id },
+                _ => Packet::PubComp { msg_id },
+            }
+        }
+        SUBSCRIBE | UNSUBSCRIBE => {
+            check_len(&b, 3)?;
+            let flags = Flags(b.get_u8());
+            let msg_id = b.get_u16();
+            let topic = topic(flags, b)?;
+            if typ == SUBSCRIBE {
+                Packet::Subscribe { flags, msg_id, topic }
+            } else {
+                Packet::Unsubscribe { flags, msg_id, topic }
+            }
+        }
+        SUBACK => {
+            check_len(&b, 6)?;
+            Packet::SubAck {
+                flags: Flags(b.get_u8()),
+                topic_id: b.get_u16(),
+                msg_id: b.get_u16(),
+                code: ReturnCode::from_u8(b.get_u8()),
+            }
+        }
+        UNSUBACK => {
+            check_len(&b, 2)?;
+            Packet::UnsubAck { msg_id: b.get_u16() }
+        }
+        PINGREQ => Packet::PingReq { client_id: if b.is_empty() { None } else { Some(string(b)?) } },
+        PINGRESP => Packet::PingResp,
+        DISCONNECT => Packet::Disconnect { duration: if b.len() >= 2 { Some(b.get_u16()) } else { None } },
+        WILLTOPICRESP | WILLMSGRESP => {
+            check_len(&b, 1)?;
+            let code = ReturnCode::from_u8(b.get_u8());
+            if typ == WILLTOPICRESP {
+                Packet::WillTopicResp { code }
+            } else {
+                Packet::WillMsgResp { code }
+            }
+        }
+        _ => return Err(MqttError::from(format!("unsupported message type, {:#04x}", typ))),
+    };
+    Ok(p)
+}
+
+pub fn encode(p: &Packet) -> Bytes {
+    let mut body = BytesMut::new();
+    let typ = match p {
+        Packet::Advertise { gw_id, duration } => {
+            body.put_u8(*gw_id);
+            body.put_u16(*duration);
+            ADVERTISE
+        }
+        Packet::SearchGw { radius } => {
+            body.put_u8(*radius);
+            SEARCHGW
+        }
+        Packet::GwInfo { gw_id } => {
+            body.put_u8(*gw_id);
+            GWINFO
+        }
+        Packet::Connect { flags, duration, client_id } => {
+            body.put_u8(flags.0);
+            body.put_u8(PROTOCOL_ID);
+            body.put_u16(*duration);
+            body.put_slice(client_id.as_bytes());
+            CONNECT
+        }
+        Packet::ConnAck { code } => {
+            body.put_u8(code.as_u8());
+            CONNACK
+        }
+        Packet::WillTopicReq => WILLTOPICREQ,
+        Packet::WillTopic { flags, topic } | Packet::WillTopicUpd { flags, topic } => {
+            if !topic.is_empty() {
+                body.put_u8(flags.0);
+                body.put_slice(topic.as_bytes());
+            }
+            if matches!(p, Packet::WillTopic { .. }) {
+                WILLTOPIC
+            } else {
+                WILLTOPICUPD
+            }
+        }
+        Packet::WillMsgReq => WILLMSGREQ,
+        Packet::WillMsg { msg } => {
+            body.put_slice(msg);
+            WILLMSG
+        }
+        Packet::WillMsgUpd { msg } => {
+            body.put_slice(msg);
+            WILLMSGUPD
+        }
+        Packet::Register { topic_id, msg_id, topic_name } => {
+            body.put_u16(*topic_id);
+            body.put_u16(*msg_id);
+            body.put_slice(topic_name.as_bytes());
+            REGISTER
+        }
+        Packet::RegAck { topic_id, msg_id, code } => {
+            body.put_u16(*topic_id);
+            body.put_u16(*msg_id);
+            body.put_u8(code.as_u8());
+            REGACK
+        }
+        Packet::Publish { flags, topic_id, msg_id, data } => {
+            body.put_u8(flags.0);
+            body.put_u16(*topic_id);
+            body.put_u16(*msg_id);
+            body.put_slice(data);
+            PUBLISH
+        }
+        Packet::PubAck { topic_id, msg_id, code } => {
+            body.put_u16(*topic_id);
+            body.put_u16(*msg_id);
+            body.put_u8(code.as_u8());
+            PUBACK
+        }
+        Packet::PubRec { msg_id } => {
+            body.put_u16(*msg_id);
+            PUBREC
+        }
+        Packet::PubRel { msg_id } => {
+            body.put_u16(*msg_id);
+            PUBREL
+        }
+        Packet::PubComp { msg_id } => {
+            body.put_u16(*msg_id);
+            PUBCOMP
+        }
+        Packet::Subscribe { flags, msg_id, topic } | Packet::Unsubscribe { flags, msg_id, topic } => {
+            body.put_u8(flags.0);
+            body.put_u16(*msg_id);
+            match topic {
+                Topic::Name(name) | Topic::Short(name) => body.put_slice(name.as_bytes()),
+                Topic::Predefined(id) => body.put_u16(*id),
+            }
+            if matches!(p, Packet::Subscribe { .. }) {
+                SUBSCRIBE
+            } else {
+                UNSUBSCRIBE
+            }
+        }
+        Packet::SubAck { flags, topic_id, msg_id, code } => {
+            body.put_u8(flags.0);
+            body.put_u16(*topic_id);
+            body.put_u16(*msg_id);
+            body.put_u8(code.as_u8());
+            SUBACK
+        }
+        Packet::UnsubAck { msg_id } => {
+            body.put_u16(*msg_id);
+            UNSUBACK
+        }
+        Packet::PingReq { client_id } => {
+            if let Some(client_id) = client_id {
+                body.put_slice(client_id.as_bytes());
+            }
+            PINGREQ
+        }
+        Packet::PingResp => PINGRESP,
+        Packet::Disconnect { duration } => {
+            if let Some(duration) = duration {
+                body.put_u16(*duration);
+            }
+            DISCONNECT
+        }
+        Packet::WillTopicResp { code } => {
+            body.put_u8(code.as_u8());
+            WILLTOPICRESP
+        }
+        Packet::WillMsgResp { code } => {
+            body.put_u8(code.as_u8());
+            WILLMSGRESP
+        }
+    };
+
+    let mut buf = BytesMut::with_capacity(body.len() + 4);
+    if body.len() + 2 > 0xff {
+        buf.put_u8(0x01);
+        buf.put_u16((body.len() + 4) as u16);
+    } else {
+        buf.put_u8((body.len() + 2) as u8);
+    }
+    buf.put_u8(typ);
+    buf.put_slice(&body);
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(p: Packet) {
+        assert_eq!(decode(encode(&p)).unwrap(), p);
+    }
+
+    #[test]
+    fn encode_decode() {
+        let flags = Flags::new(true, Some(QoS::AtLeastOnce), true, TopicIdType::Normal);
+        roundtrip(Packet::Advertise { gw_id: 1, duration: 900 });
+        roundtrip(Packet::SearchGw { radius: 0 });
+        roundtrip(Packet::GwInfo { gw_id: 1 });
+        roundtrip(Packet::Connect { flags, duration: 60, client_id: "dev1".into() });
+        roundtrip(Packet::ConnAck { code: ReturnCode::Congestion });
+        roundtrip(Packet::WillTopic { flags, topic: "will/dev1".into() });
+        roundtrip(Packet::WillTopic { flags: Flags::default(), topic: ByteString::new() });
+        roundtrip(Packet::WillMsg { msg: Bytes::from_static(b"gone") });
+        roundtrip(Packet::Register { topic_id: 0, msg_id: 1, topic_name: "sensors/1".into() });
+        roundtrip(Packet::RegAck { topic_id: 1, msg_id: 1, code: ReturnCode::Accepted });
+        roundtrip(Packet::Publish { flags, topic_id: 1, msg_id: 2, data: Bytes::from_static(b"22.5") });
+        roundtrip(Packet::PubAck { topic_id: 1, msg_id: 2, code: ReturnCode::InvalidTopicId });
+        roundtrip(Packet::PubRec { msg_id: 3 });
+        roundtrip(Packet::PubRel { msg_id: 3 });
+        roundtrip(Packet::PubComp { msg_id: 3 });
+        roundtrip(Packet::Subscribe { flags, msg_id: 4, topic: Topic::Name("sensors/+".into()) });
+        let predefined = Flags::new(false, Some(QoS::AtMostOnce), false, TopicIdType::Predefined);
+        roundtrip(Packet::Unsubscribe { flags: predefined, msg_id: 5, topic: Topic::Predefined(7) });
+        let short = Flags::new(false, None, false, TopicIdType::Short);
+        roundtrip(Packet::Subscribe { flags: short, msg_id: 6, topic: Topic::Short("ab".into()) });
+        roundtrip(Packet::SubAck { flags, topic_id: 1, msg_id: 4, code: ReturnCode::NotSupported });
+        roundtrip(Packet::UnsubAck { msg_id: 5 });
+        roundtrip(Packet::PingReq { client_id: None });
+        roundtrip(Packet::PingReq { client_id: Some("dev1".into()) });
+        roundtrip(Packet::PingResp);
+        roundtrip(Packet::Disconnect { duration: None });
+        roundtrip(Packet::Disconnect { duration: Some(300) });
+        roundtrip(Packet::WillTopicResp { code: ReturnCode::Accepted });
+        roundtrip(Packet::WillMsgResp { code: ReturnCode::Accepted });
+    }
+
+    #[test]
+    fn flags() {
+        let flags = Flags::new(true, None, true, TopicIdType::Short);
+        assert!(flags.dup() && flags.retain());
+        assert_eq!(flags.qos(), None);
+        assert_eq!(flags.topic_id_type().unwrap(), TopicIdType::Short);
+        assert_eq!(Flags::new(false, Some(QoS::ExactlyOnce), false, TopicIdType::Normal).0, 0b0100_0000);
+        assert!(Flags(0b0000_0011).topic_id_type().is_err());
+        let connect = Flags(0b0000_1100);
+        assert!(connect.will() && connect.clean_session());
+    }
+
+    #[test]
+    fn long_length() {
+        let data = Bytes::from(vec![b'x'; 300]);
+        let p = Packet::Publish { flags: Flags::default(), topic_id: 1, msg_id: 1, data };
+        let b = encode(&p);
+        assert_eq!(b[0], 0x01);
+        assert_eq!(u16::from_be_bytes([b[1], b[2]]) as usize, b.len());
+        assert_eq!(decode(b).unwrap(), p);
+    }
+
+    #[test]
+    fn malformed() {
+        //Too short, or the length is not the length of the datagram
+        assert!(decode(Bytes::from_static(&[0x02])).is_err());
+        assert!(decode(Bytes::from_static(&[0x01, 0x00])).is_err());
+        assert!(decode(Bytes::from_static(&[0x05, PUBREC, 0x00])).is_err());
+        assert!(decode(Bytes::from_static(&[0x01, PINGRESP])).is_err());
+        assert!(decode(Bytes::from_static(&[0x03, PUBREC, 0x00])).is_err());
+        assert!(decode(Bytes::from_static(&[0x04, PUBLISH, 0x00, 0x00])).is_err());
+        //The trailing bytes after the length are ignored
+        assert_eq!(
+            decode(Bytes::from_static(&[0x04, PUBREC, 0x00, 0x01, 0xff])).unwrap(),
+            Packet::PubRec { msg_id: 1 }
+        );
+        //Unsupported protocol id, message type and invalid utf8
+        assert!(decode(Bytes::from_static(&[0x07, CONNECT, 0x04, 0x02, 0x00, 0x3c, b'a'])).is_err());
+        assert!(decode(Bytes::from_static(&[0x02, 0x03])).is_err());
+        assert!(decode(Bytes::from_static(&[0x07, REGISTER, 0x00, 0x00, 0x00, 0x01, 0xff])).is_err());
+        //Reserved topic id type
+        assert!(decode(Bytes::from_static(&[0x06, SUBSCRIBE, 0x03, 0x00, 0x01, 0x00])).is_err());
+    }
+}
@@ -0,0 +1,830 @@
+//! MQTT-SN 1.2 gateway over UDP, each client is identified by its address and is mapped to a
+//! session of the broker, see [`rmqtt::broker::gateway`].
+//!
+//! The topic ids registered by REGISTER or SUBSCRIBE are kept per connection, the predefined
+//! topic ids are configured by `mqttsn_predefined_topics` of the listener. The session of a
+//! sleeping client (DISCONNECT with a duration) is kept alive by the gateway, the messages are
+//! buffered until the client wakes up with a PINGREQ. QoS -1 publishes with predefined topic ids
+//! or short topic names are accepted without connection, the authentication of their source address
+//! is cached for a while, and the uncached ones are limited by the connection limits of the listener.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::num::NonZeroU16;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmqtt::broker::conn_limit::{ConnCountGuard, ConnCounter, ConnGuard, ConnLimiter};
+use rmqtt::broker::gateway::{self, AuthCache, GatewayPacket, GatewaySink, GATEWAY_KEY};
+use rmqtt::broker::ip_filter::IpFilters;
+use rmqtt::bytes::Bytes;
+use rmqtt::bytestring::ByteString;
+use rmqtt::futures::channel::mpsc;
+use rmqtt::futures::StreamExt;
+use rmqtt::ntex;
+use rmqtt::ntex_mqtt::v3::codec::Publish as PublishV3;
+use rmqtt::settings::listener::{Listener, ListenerId};
+use rmqtt::tokio::{self, net::UdpSocket, time::Instant};
+use rmqtt::{
+    log, timestamp_millis, ConnectAckReasonV3, ConnectV3, DashMap, ExtraAttrs, LastWillV3, MqttError,
+    PacketV3, Publish, QoS, Reason, Result, Runtime, SessionState, TopicName,
+};
+
+use crate::workers::Workers;
+
+use codec::{Flags, Packet, ReturnCode, Topic, TopicIdType};
+
+mod codec;
+
+const PROTOCOL_NAME: &str = "mqttsn";
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const AUTH_CACHE_TTL: Duration = Duration::from_secs(60);
+const AUTH_CACHE_CAPACITY: usize = 10_000;
+
+type Clients = Arc<DashMap<SocketAddr, mpsc::UnboundedSender<Bytes>>>;
+
+pub(crate) async fn listen(name: &str, listen_cfg: &Listener) -> Result<()> {
+    let socket = Arc::new(UdpSocket::bind(listen_cfg.addr).await?);
+    let counter = ConnCounter::new(ListenerId::Port(listen_cfg.addr.port()));
+    let conn_limiter = ConnLimiter::new(listen_cfg);
+    let ip_filter = IpFilters::instance().register(listen_cfg);
+    let clients: Clients = Arc::new(DashMap::default());
+    let auth_cache = Arc::new(AuthCache::new(AUTH_CACHE_TTL, AUTH_CACHE_CAPACITY));
+    let mut workers = Workers::new(listen_cfg.workers);
+    log::info!("{} mqttsn listener is started on {:?}", name, listen_cfg.addr);
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let (n, remote_addr) = match socket.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(e) => {
+                log::debug!("mqttsn recv error, {:?}", e);
+                continue;
+            }
+        };
+        let data = Bytes::copy_from_slice(&buf[..n]);
+        let data = match clients.get(&remote_addr).map(|tx| tx.unbounded_send(data)) {
+            Some(Ok(())) => continue,
+            Some(Err(e)) => e.into_inner(),
+            None => data,
+        };
+
+        if !ip_filter.is_allowed(&remote_addr.ip()) {
+            log::debug!("{:?} mqttsn packet is denied by the ip filter", remote_addr);
+            Runtime::instance().metrics.client_connect_ip_denied_inc();
+            continue;
+        }
+        let packet = match codec::decode(data) {
+            Ok(p) => p,
+            Err(e) => {
+                log::debug!("{:?} mqttsn decode error, {:?}", remote_addr, e);
+                continue;
+            }
+        };
+
+        match packet {
+            Packet::SearchGw { .. } => {
+                send_to(&socket, remote_addr, &Packet::GwInfo { gw_id: listen_cfg.mqttsn_gateway_id }).await;
+            }
+            Packet::Publish { flags, topic_id, data, .. } if flags.qos().is_none() => {
+                //Each uncached authentication is limited like a connection
+                let conn_guard = if auth_cache.contains(&remote_addr) {
+                    None
+                } else {
+                    match conn_limiter.as_ref().map(|l| l.acquire(remote_addr.ip())).transpose() {
+                        Ok(guard) => guard,
+                        Err(e) => {
+                            log::debug!("{:?} mqttsn QoS -1 publish is dropped, {:?}", remote_addr, e);
+                            continue;
+                        }
+                    }
+                };
+                let listen_cfg = current(listen_cfg);
+                let auth_cache = auth_cache.clone();
+                workers.exec_fn(move || {
+                    ntex::rt::spawn(async move {
+                        if let Err(e) = publish_connectionless(
+                            listen_cfg,
+                            remote_addr,
+                            flags,
+                            topic_id,
+                            data,
+                            &auth_cache,
+                        )
+                        .await
+                        {
+                            log::debug!("{:?} mqttsn QoS -1 publish error, {:?}", remote_addr, e);
+                        }
+                        drop(conn_guard);
+                    });
+                });
+            }
+            Packet::Connect { .. } => {
+                let count_guard = if let Some(guard) = counter.acquire() {
+                    guard
+                } else {
+                    log::debug!("{:?} mqttsn connection is rejected, too many connections", remote_addr);
+                    send_to(&socket, remote_addr, &Packet::ConnAck { code: ReturnCode::Congestion }).await;
+                    continue;
+                };
+                let conn_guard = match conn_limiter.as_ref().map(|l| l.acquire(remote_addr.ip())).transpose()
+                {
+                    Ok(guard) => guard,
+                    Err(e) => {
+                        log::debug!("{:?} mqttsn connection is rejected, {:?}", remote_addr, e);
+                        send_to(&socket, remote_addr, &Packet::ConnAck { code: ReturnCode::Congestion })
+                            .await;
+                        continue;
+                    }
+                };
+
+                let (tx, rx) = mpsc::unbounded();
+                clients.insert(remote_addr, tx);
+                let socket = socket.clone();
+                let clients = clients.clone();
+                let listen_cfg = current(listen_cfg);
+                workers.exec_fn(move || {
+                    ntex::rt::spawn(async move {
+                        let mut client =
+                            Client::new(socket, remote_addr, listen_cfg, rx, count_guard, conn_guard);
+                        client.run(packet).await;
+                        drop(client);
+                        clients.remove_if(&remote_addr, |_, tx| tx.is_closed());
+                    });
+                });
+            }
+            Packet::Advertise { .. } | Packet::GwInfo { .. } => {}
+            _ => {
+                //The client is not connected, such as the gateway is restarted
+                send_to(&socket, remote_addr, &Packet::Disconnect { duration: None }).await;
+            }
+        }
+    }
+}
+
+#[inline]
+async fn send_to(socket: &UdpSocket, addr: SocketAddr, p: &Packet) {
+    log::debug!("{:?} mqttsn send: {:?}", addr, p);
+    if let Err(e) = socket.send_to(&codec::encode(p), addr).await {
+        log::debug!("{:?} mqttsn send error, {:?}", addr, e);
+    }
+}
+
+#[inline]
+fn conn_attrs() -> ExtraAttrs {
+    let mut conn_attrs = ExtraAttrs::new();
+    conn_attrs.insert(GATEWAY_KEY.into(), PROTOCOL_NAME.to_owned());
+    conn_attrs
+}
+
+#[inline]
+fn short_topic(topic_id: u16) -> Result<TopicName> {
+    ByteString::try_from(Bytes::copy_from_slice(&topic_id.to_be_bytes()))
+        .map_err(|e| MqttError::from(format!("invalid short topic name, {:?}", e)))
+}
+
+///The current configuration of the listener, it may be updated at runtime
+#[inline]
+fn current(listen_cfg: &Listener) -> Listener {
+    Runtime::instance()
+        .settings
+        .listeners
+        .mqttsn(listen_cfg.addr.port())
+        .unwrap_or_else(|| listen_cfg.clone())
+}
+
+#[inline]
+fn predefined_topic(listen_cfg: &Listener, topic_id: u16) -> Option<TopicName> {
+    listen_cfg.mqttsn_predefined_topics.get(&topic_id).map(|t| TopicName::from(t.as_str()))
+}
+
+#[inline]
+fn to_publish(flags: Flags, topic: TopicName, msg_id: u16, data: Bytes) -> Publish {
+    let qos = flags.qos().unwrap_or(QoS::AtMostOnce);
+    Publish {
+        dup: flags.dup(),
+        retain: flags.retain(),
+        qos,
+        topic,
+        packet_id: if matches!(qos, QoS::AtMostOnce) { None } else { NonZeroU16::new(msg_id) },
+        payload: data,
+        properties: Default::default(),
+        delay_interval: None,
+        create_time: timestamp_millis(),
+    }
+}
+
+async fn publish_connectionless(
+    listen_cfg: Listener,
+    remote_addr: SocketAddr,
+    flags: Flags,
+    topic_id: u16,
+    data: Bytes,
+    auth_cache: &AuthCache,
+) -> Result<()> {
+    let topic = match flags.topic_id_type()? {
+        TopicIdType::Predefined => predefined_topic(&listen_cfg, topic_id)
+            .ok_or_else(|| MqttError::from(format!("predefined topic id {} is not found", topic_id)))?,
+        TopicIdType::Short => short_topic(topic_id)?,
+        TopicIdType::Normal => return Err(MqttError::from("registered topic id is not supported")),
+    };
+    let local_addr = listen_cfg.addr;
+    let publish = to_publish(flags, topic, 0, data);
+    let connect = ConnectV3 { clean_session: true, ..Default::default() };
+    gateway::publish_connectionless(
+        listen_cfg,
+        connect,
+        remote_addr,
+        local_addr,
+        conn_attrs(),
+        publish,
+        Some(auth_cache),
+    )
+    .await?;
+    Ok(())
+}
+
+///Topic ids of the connection
+#[derive(Default)]
+struct Topics {
+    ids: HashMap<u16, TopicName>,
+    names: HashMap<TopicName, u16>,
+    ///Registered by the gateway, the REGACK is not received
+    unconfirmed: HashSet<u16>,
+    next_id: u16,
+}
+
+impl Topics {
+    fn name(&self, topic_id: u16) -> Option<&TopicName> {
+        self.ids.get(&topic_id)
+    }
+
+    fn register(&mut self, name: &TopicName, listen_cfg: &Listener) -> Option<u16> {
+        if let Some(id) = self.names.get(name) {
+            return Some(*id);
+        }
+        //0x0000 and 0xFFFF are reserved
+        for _ in 0..u16::MAX {
+            self.next_id = self.next_id.wrapping_add(1);
+            let id = self.next_id;
+            if id == 0
+                || id == u16::MAX
+                || self.ids.contains_key(&id)
+                || listen_cfg.mqttsn_predefined_topics.contains_key(&id)
+            {
+                continue;
+            }
+            self.ids.insert(id, name.clone());
+            self.names.insert(name.clone(), id);
+            return Some(id);
+        }
+        None
+    }
+
+    fn remove(&mut self, topic_id: u16) {
+        if let Some(name) = self.ids.remove(&topic_id) {
+            self.names.remove(&name);
+        }
+        self.unconfirmed.remove(&topic_id);
+    }
+
+    fn clear(&mut self) {
+        self.ids.clear();
+        self.names.clear();
+        self.unconfirmed.clear();
+    }
+}
+
+///A sleeping client is supervised until the deadline, it is awake after the PINGREQ
+///until the buffered messages are delivered
+enum Sleep {
+    Active,
+    Asleep { duration: Duration, deadline: Instant },
+    Awake { duration: Duration, deadline: Instant },
+}
+
+impl Sleep {
+    fn asleep(duration: Duration) -> Self {
+        //Tolerance of the network delay
+        Sleep::Asleep { duration, deadline: Instant::now() + duration.mul_f32(1.5) }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        match self {
+            Sleep::Active => None,
+            Sleep::Asleep { deadline, .. } | Sleep::Awake { deadline, .. } => Some(*deadline),
+        }
+    }
+}
+
+enum Next {
+    Continue,
+    ///CONNECT is received again
+    Reconnect(Packet),
+    Exit,
+}
+
+struct Client {
+    socket: Arc<UdpSocket>,
+    remote_addr: SocketAddr,
+    listen_cfg: Listener,
+    rx: mpsc::UnboundedReceiver<Bytes>,
+    _count_guard: ConnCountGuard,
+    _conn_guard: Option<ConnGuard>,
+
+    topics: Topics,
+    ///msg_id of REGISTER -> topic_id
+    registering: HashMap<u16, u16>,
+    ///Publishes waiting for the REGACK, by topic_id
+    waiting: HashMap<u16, Vec<PublishV3>>,
+    ///QoS 2 publishes of the client, waiting for the PUBREL
+    received: HashSet<u16>,
+    next_msg_id: u16,
+    sleep: Sleep,
+    buffered: VecDeque<PacketV3>,
+}
+
+impl Client {
+    fn new(
+        socket: Arc<UdpSocket>,
+        remote_addr: SocketAddr,
+        listen_cfg: Listener,
+        rx: mpsc::UnboundedReceiver<Bytes>,
+        count_guard: ConnCountGuard,
+        conn_guard: Option<ConnGuard>,
+    ) -> Self {
+        Self {
+            socket,
+            remote_addr,
+            listen_cfg,
+            rx,
+            _count_guard: count_guard,
+            _conn_guard: conn_guard,
+            topics: Topics::default(),
+            registering: HashMap::new(),
+            waiting: HashMap::new(),
+            received: HashSet::new(),
+            next_msg_id: 0,
+            sleep: Sleep::Active,
+            buffered: VecDeque::new(),
+        }
+    }
+
+    async fn run(&mut self, mut connect: Packet) {
+        loop {
+            match self.session(connect).await {
+                Ok(Some(p)) => connect = p,
+                Ok(None) => break,
+                Err(e) => {
+                    log::debug!("{:?} mqttsn connection error, {:?}", self.remote_addr, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    #[inline]
+    async fn send(&self, p: &Packet) {
+        send_to(&self.socket, self.remote_addr, p).await
+    }
+
+    async fn recv(&mut self, timeout: Duration) -> Result<Packet> {
+        match tokio::time::timeout(timeout, self.rx.next()).await {
+            Ok(Some(data)) => codec::decode(data),
+            Ok(None) => Err(MqttError::from("mqttsn listener is closed")),
+            Err(_) => Err(MqttError::from("mqttsn packet is not received within the handshake timeout")),
+        }
+    }
+
+    ///WILLTOPICREQ, WILLTOPIC, WILLMSGREQ and WILLMSG after the CONNECT with the will flag
+    async fn last_will(&mut self) -> Result<Option<LastWillV3>> {
+        let timeout = self.listen_cfg.handshake_timeout;
+        self.send(&Packet::WillTopicReq).await;
+        let (flags, topic) = match self.recv(timeout).await? {
+            Packet::WillTopic { flags, topic } => (flags, topic),
+            p => return Err(MqttError::from(format!("WILLTOPIC is expected, {:?}", p))),
+        };
+        if topic.is_empty() {
+            return Ok(None);
+        }
+        self.send(&Packet::WillMsgReq).await;
+        let message = match self.recv(timeout).await? {
+            Packet::WillMsg { msg } => msg,
+            p => return Err(MqttError::from(format!("WILLMSG is expected, {:?}", p))),
+        };
+        Ok(Some(LastWillV3 {
+            qos: flags.qos().unwrap_or(QoS::AtMostOnce),
+            retain: flags.retain(),
+            topic,
+            message,
+        }))
+    }
+
+    async fn session(&mut self, connect: Packet) -> Result<Option<Packet>> {
+        let (flags, duration, client_id) = match connect {
+            Packet::Connect { flags, duration, client_id } => (flags, duration, client_id),
+            p => return Err(MqttError::from(format!("CONNECT is expected, {:?}", p))),
+        };
+        let last_will = if flags.will() { self.last_will().await? } else { None };
+        let connect = ConnectV3 {
+            clean_session: flags.clean_session(),
+            keep_alive: duration,
+            last_will,
+            client_id,
+            ..Default::default()
+        };
+
+        self.topics.clear();
+        self.registering.clear();
+        self.waiting.clear();
+        self.received.clear();
+        self.buffered.clear();
+        self.sleep = Sleep::Active;
+
+        let (sink, mut sink_rx) = GatewaySink::new();
+        let local_addr = self.listen_cfg.addr;
+        let state = match gateway::connect(
+            self.listen_cfg.clone(),
+            connect,
+            self.remote_addr,
+            local_addr,
+            conn_attrs(),
+            sink,
+        )
+        .await
+        {
+            Ok(Ok((state, _))) => state,
+            Ok(Err(ack_code)) => {
+                let code = match ack_code {
+                    ConnectAckReasonV3::ServiceUnavailable => ReturnCode::Congestion,
+                    _ => ReturnCode::NotSupported,
+                };
+                self.send(&Packet::ConnAck { code }).await;
+                return Ok(None);
+            }
+            Err(e) => {
+                self.send(&Packet::ConnAck { code: ReturnCode::Congestion }).await;
+                return Err(e);
+            }
+        };
+        self.send(&Packet::ConnAck { code: ReturnCode::Accepted }).await;
+
+        //The session of the sleeping client is kept alive by the gateway
+        let keepalive_interval = Duration::from_secs((duration as u64 / 2).max(1));
+        let mut keepalive_tick = tokio::time::interval(keepalive_interval);
+
+        loop {
+            let deadline = self.sleep.deadline();
+            let timeout = deadline.unwrap_or_else(Instant::now);
+            let next = tokio::select! {
+                data = self.rx.next() => {
+                    match data {
+                        Some(data) => match codec::decode(data) {
+                            Ok(p) => self.handle(&state, p).await?,
+                            Err(e) => {
+                                log::debug!("{:?} mqttsn decode error, {:?}", state.id, e);
+                                Next::Continue
+                            }
+                        },
+                        None => {
+                            gateway::closed(&state, Reason::ConnectRemoteClose);
+                            Next::Exit
+                        }
+                    }
+                },
+                p = sink_rx.next() => {
+                    match p {
+                        Some(GatewayPacket::Packet(p)) => {
+                            self.deliver(&state, p).await;
+                            Next::Continue
+                        }
+                        Some(GatewayPacket::Close) | None => {
+                            self.send(&Packet::Disconnect { duration: None }).await;
+                            Next::Exit
+                        }
+                    }
+                },
+                _ = keepalive_tick.tick(), if deadline.is_some() => {
+                    gateway::keepalive(&state, false);
+                    Next::Continue
+                },
+                _ = tokio::time::sleep_until(timeout), if deadline.is_some() => {
+                    log::debug!("{:?} mqttsn sleeping client is lost", state.id);
+                    gateway::closed(&state, Reason::ConnectKeepaliveTimeout);
+                    Next::Exit
+                },
+            };
+            match next {
+                Next::Continue => {}
+                Next::Reconnect(p) => return Ok(Some(p)),
+                Next::Exit => return Ok(None),
+            }
+        }
+    }
+
+    async fn handle(&mut self, state: &SessionState, p: Packet) -> Result<Next> {
+        log::debug!("{:?} mqttsn recv: {:?}", state.id, p);
+        match p {
+            Packet::Publish { flags, topic_id, msg_id, data } => {
+                let topic = match flags.topic_id_type()? {
+                    TopicIdType::Normal => self.topics.name(topic_id).cloned(),
+                    TopicIdType::Predefined => predefined_topic(&self.listen_cfg, topic_id),
+                    TopicIdType::Short => Some(short_topic(topic_id)?),
+                };
+                let topic = if let Some(topic) = topic {
+                    topic
+                } else {
+                    self.send(&Packet::PubAck { topic_id, msg_id, code: ReturnCode::InvalidTopicId }).await;
+                    return Ok(Next::Continue);
+                };
+                let qos = flags.qos().unwrap_or(QoS::AtMostOnce);
+                if matches!(qos, QoS::ExactlyOnce) && self.received.contains(&msg_id) {
+                    self.send(&Packet::PubRec { msg_id }).await;
+                    return Ok(Next::Continue);
+                }
+                if let Err(e) = gateway::publish(state, to_publish(flags, topic, msg_id, data)).await {
+                    log::warn!("{:?} mqttsn publish failed, reason: {:?}", state.id, e);
+                    self.send(&Packet::Disconnect { duration: None }).await;
+                    gateway::closed(state, Reason::PublishFailed(e.to_string().into()));
+                    return Ok(Next::Exit);
+                }
+                match qos {
+                    QoS::AtMostOnce => {}
+                    QoS::AtLeastOnce => {
+                        self.send(&Packet::PubAck { topic_id, msg_id, code: ReturnCode::Accepted }).await
+                    }
+                    QoS::ExactlyOnce => {
+                        self.received.insert(msg_id);
+                        self.send(&Packet::PubRec { msg_id }).await;
+                    }
+                }
+            }
+            Packet::PubRel { msg_id } => {
+                gateway::keepalive(state, false);
+                self.received.remove(&msg_id);
+                self.send(&Packet::PubComp { msg_id }).await;
+            }
+            Packet::PubAck { topic_id, msg_id, code } => {
+                if let Some(packet_id) = NonZeroU16::new(msg_id) {
+                    match code {
+                        ReturnCode::Accepted => gateway::acked(state, packet_id).await,
+                        //The topic id is unknown to the client, it is registered again when redelivered
+                        ReturnCode::InvalidTopicId => self.topics.remove(topic_id),
+                        _ => gateway::rejected(state, packet_id, Reason::PublishRefused).await,
+                    }
+                }
+            }
+            Packet::PubRec { msg_id } => {
+                if let Some(packet_id) = NonZeroU16::new(msg_id) {
+                    gateway::received(state, packet_id).await;
+                }
+                self.send(&Packet::PubRel { msg_id }).await;
+            }
+            Packet::PubComp { msg_id } => {
+                if let Some(packet_id) = NonZeroU16::new(msg_id) {
+                    gateway::acked(state, packet_id).await;
+                }
+            }
+            Packet::Register { msg_id, topic_name, .. } => {
+                gateway::keepalive(state, false);
+                let (topic_id, code) = if topic_name.is_empty() || topic_name.contains(['+', '#']) {
+                    (0, ReturnCode::NotSupported)
+                } else {
+                    match self.topics.register(&topic_name, &self.listen_cfg) {
+                        Some(topic_id) => (topic_id, ReturnCode::Accepted),
+                        None => (0, ReturnCode::Congestion),
+                    }
+                };
+                self.send(&Packet::RegAck { topic_id, msg_id, code }).await;
+            }
+            Packet::RegAck { topic_id, msg_id, code } => {
+                gateway::keepalive(state, false);
+                self.registered(state, topic_id, msg_id, code).await;
+            }
+            Packet::Subscribe { flags, msg_id, topic } => {
+                let (topic_filter, topic_id) = match self.topic_filter(topic) {
+                    Some(res) => res,
+                    None => {
+                        let code = ReturnCode::InvalidTopicId;
+                        self.send(&Packet::SubAck { flags, topic_id: 0, msg_id, code }).await;
+                        return Ok(Next::Continue);
+                    }
+                };
+                let qos = flags.qos().unwrap_or(QoS::AtMostOnce);
+                let (flags, code) = match gateway::subscribe(state, &topic_filter, qos).await {
+                    Ok(Some(qos)) => {
+                        (Flags::new(false, Some(qos), false, TopicIdType::Normal), ReturnCode::Accepted)
+                    }
+                    Ok(None) | Err(_) => (flags, ReturnCode::NotSupported),
+                };
+                self.send(&Packet::SubAck { flags, topic_id, msg_id, code }).await;
+            }
+            Packet::Unsubscribe { msg_id, topic, .. } => {
+                if let Some((topic_filter, _)) = self.topic_filter(topic) {
+                    if let Err(e) = gateway::unsubscribe(state, &topic_filter).await {
+                        log::debug!("{:?} mqttsn unsubscribe error, {:?}", state.id, e);
+                    }
+                }
+                self.send(&Packet::UnsubAck { msg_id }).await;
+            }
+            Packet::PingReq { .. } => match self.sleep {
+                Sleep::Asleep { duration, deadline } => {
+                    self.sleep = Sleep::Awake { duration, deadline };
+                    gateway::keepalive(state, true);
+                    while let Some(p) = self.buffered.pop_front() {
+                        self.send_packet(state, p).await;
+                    }
+                    self.wake_done().await;
+                }
+                Sleep::Awake { .. } => {}
+                Sleep::Active => {
+                    gateway::keepalive(state, true);
+                    self.send(&Packet::PingResp).await;
+                }
+            },
+            Packet::Disconnect { duration: Some(duration) } => {
+                gateway::keepalive(state, false);
+                self.sleep = Sleep::asleep(Duration::from_secs(duration as u64));
+                self.send(&Packet::Disconnect { duration: None }).await;
+            }
+            Packet::Disconnect { duration: None } => {
+                self.send(&Packet::Disconnect { duration: None }).await;
+                gateway::disconnect(state);
+                return Ok(Next::Exit);
+            }
+            Packet::Connect { flags, ref client_id, .. } => {
+                //The sleeping client returns to the active state
+                if !matches!(self.sleep, Sleep::Active)
+                    && !flags.clean_session()
+                    && !flags.will()
+                    && *client_id == state.id.client_id
+                {
+                    gateway::keepalive(state, false);
+                    self.sleep = Sleep::Active;
+                    self.send(&Packet::ConnAck { code: ReturnCode::Accepted }).await;
+                    while let Some(p) = self.buffered.pop_front() {
+                        self.send_packet(state, p).await;
+                    }
+                    return Ok(Next::Continue);
+                }
+                //The session of the same client id is taken over by the new session
+                if *client_id != state.id.client_id {
+                    gateway::closed(state, Reason::ConnectRemoteClose);
+                }
+                return Ok(Next::Reconnect(p));
+            }
+            Packet::WillTopicUpd { .. } => {
+                self.send(&Packet::WillTopicResp { code: ReturnCode::NotSupported }).await;
+            }
+            Packet::WillMsgUpd { .. } => {
+                self.send(&Packet::WillMsgResp { code: ReturnCode::NotSupported }).await;
+            }
+            Packet::SearchGw { .. } => {
+                self.send(&Packet::GwInfo { gw_id: self.listen_cfg.mqttsn_gateway_id }).await;
+            }
+            _ => {
+                log::debug!("{:?} mqttsn unexpected packet, {:?}", state.id, p);
+            }
+        }
+        Ok(Next::Continue)
+    }
+
+    ///Returns the topic filter and the topic id for the SUBACK
+    fn topic_filter(&mut self, topic: Topic) -> Option<(TopicName, u16)> {
+        match topic {
+            Topic::Name(name) => {
+                if name.is_empty() {
+                    None
+                } else if name.contains(['+', '#']) {
+                    Some((name, 0))
+                } else {
+                    let topic_id = self.topics.register(&name, &self.listen_cfg)?;
+                    Some((name, topic_id))
+                }
+            }
+            Topic::Predefined(topic_id) => {
+                predefined_topic(&self.listen_cfg, topic_id).map(|t| (t, topic_id))
+            }
+            Topic::Short(name) => Some((name, 0)),
+        }
+    }
+
+    ///Messages from the session, they are buffered if the client is sleeping
+    async fn deliver(&mut self, state: &SessionState, p: PacketV3) {
+        if matches!(self.sleep, Sleep::Asleep { .. }) {
+            if let PacketV3::Publish(publish) = &p {
+                //Redelivery of the inflight message
+                if let Some(packet_id) = publish.packet_id {
+                    self.buffered
+                        .retain(|b| !matches!(b, PacketV3::Publish(b) if b.packet_id == Some(packet_id)));
+                }
+            }
+            if self.buffered.len() >= self.listen_cfg.max_mqueue_len.max(1) {
+                if let Some(PacketV3::Publish(dropped)) = self.buffered.pop_front() {
+                    log::debug!("{:?} mqttsn sleeping client buffer is full, {:?}", state.id, dropped);
+                    if let Some(packet_id) = dropped.packet_id {
+                        gateway::rejected(state, packet_id, Reason::MessageQueueFull).await;
+                    }
+                }
+            }
+            self.buffered.push_back(p);
+        } else {
+            self.send_packet(state, p).await;
+        }
+    }
+
+    async fn send_packet(&mut self, state: &SessionState, p: PacketV3) {
+        match p {
+            PacketV3::Publish(publish) => self.send_publish(publish).await,
+            PacketV3::PublishRelease { packet_id } => {
+                self.send(&Packet::PubRel { msg_id: packet_id.get() }).await;
+            }
+            _ => {
+                log::debug!("{:?} mqttsn unsupported packet, {:?}", state.id, p);
+            }
+        }
+    }
+
+    async fn send_publish(&mut self, publish: PublishV3) {
+        let predefined = self
+            .listen_cfg
+            .mqttsn_predefined_topics
+            .iter()
+            .find(|(_, t)| t.as_str() == publish.topic.as_ref())
+            .map(|(id, _)| *id);
+        let (topic_id_type, topic_id) = if let Some(topic_id) = predefined {
+            (TopicIdType::Predefined, topic_id)
+        } else if publish.topic.len() == 2 {
+            let b = publish.topic.as_bytes();
+            (TopicIdType::Short, u16::from_be_bytes([b[0], b[1]]))
+        } else if let Some(topic_id) = self.topics.names.get(&publish.topic).copied() {
+            if self.topics.unconfirmed.contains(&topic_id) {
+                self.wait_registered(topic_id, publish).await;
+                return;
+            }
+            (TopicIdType::Normal, topic_id)
+        } else {
+            let topic_id = if let Some(topic_id) = self.topics.register(&publish.topic, &self.listen_cfg) {
+                topic_id
+            } else {
+                log::warn!("{:?} mqttsn topic ids are exhausted, {:?}", self.remote_addr, publish.topic);
+                return;
+            };
+            self.topics.unconfirmed.insert(topic_id);
+            self.next_msg_id = self.next_msg_id.wrapping_add(1).max(1);
+            let msg_id = self.next_msg_id;
+            self.registering.insert(msg_id, topic_id);
+            self.send(&Packet::Register { topic_id, msg_id, topic_name: publish.topic.clone() }).await;
+            self.waiting.entry(topic_id).or_default().push(publish);
+            return;
+        };
+        let flags = Flags::new(publish.dup, Some(publish.qos), publish.retain, topic_id_type);
+        let msg_id = publish.packet_id.map(|id| id.get()).unwrap_or_default();
+        self.send(&Packet::Publish { flags, topic_id, msg_id, data: publish.payload }).await;
+    }
+
+    ///The topic is being registered, the REGISTER is sent again on redelivery
+    async fn wait_registered(&mut self, topic_id: u16, publish: PublishV3) {
+        if publish.dup {
+            let msg_id = self.registering.iter().find(|(_, id)| **id == topic_id).map(|(msg_id, _)| *msg_id);
+            if let (Some(msg_id), Some(topic_name)) = (msg_id, self.topics.name(topic_id).cloned()) {
+                self.send(&Packet::Register { topic_id, msg_id, topic_name }).await;
+            }
+        }
+        let waiting = self.waiting.entry(topic_id).or_default();
+        if let Some(packet_id) = publish.packet_id {
+            waiting.retain(|p| p.packet_id != Some(packet_id));
+        }
+        waiting.push(publish);
+    }
+
+    async fn registered(&mut self, state: &SessionState, topic_id: u16, msg_id: u16, code: ReturnCode) {
+        let topic_id = if let Some(id) = self.registering.remove(&msg_id) { id } else { topic_id };
+        let waiting = self.waiting.remove(&topic_id).unwrap_or_default();
+        if matches!(code, ReturnCode::Accepted) {
+            self.topics.unconfirmed.remove(&topic_id);
+            for publish in waiting {
+                self.send_publish(publish).await;
+            }
+        } else {
+            log::debug!("{:?} mqttsn topic registration is rejected, {:?}", state.id, code);
+            self.topics.remove(topic_id);
+            for publish in waiting {
+                if let Some(packet_id) = publish.packet_id {
+                    gateway::rejected(state, packet_id, Reason::PublishRefused).await;
+                }
+            }
+        }
+        self.wake_done().await;
+    }
+
+    ///The awake client goes back to sleep after the buffered messages are delivered
+    async fn wake_done(&mut self) {
+        if let Sleep::Awake { duration, .. } = self.sleep {
+            if self.registering.is_empty() {
+                self.send(&Packet::PingResp).await;
+                self.sleep = Sleep::asleep(duration);
+            }
+        }
+    }
+}
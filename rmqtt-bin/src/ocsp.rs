@@ -0,0 +1,543 @@
+//! OCSP revocation check of the client certificates of the cross_certificate listeners.
+//!
+//! The check runs after the TLS handshake, the result is kept in the connection attributes and
+//! the connection is refused with a distinct connack reason. Responses are cached until their
+//! nextUpdate, but not longer than the ocsp_refresh_interval of the listener.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::CertificateDer;
+use sha1::{Digest, Sha1};
+use x509_parser::prelude::{FromDer, GeneralName, ParsedExtension, X509Certificate};
+
+use rmqtt::anyhow::anyhow;
+use rmqtt::broker::peer_cert::{PeerCertStatus, PEER_CERT_STATUS_KEY};
+use rmqtt::chrono::NaiveDateTime;
+use rmqtt::once_cell::sync::Lazy;
+use rmqtt::reqwest::{self, header::CONTENT_TYPE};
+use rmqtt::settings::listener::Listener;
+use rmqtt::{log, timestamp_millis, DashMap, ExtraAttrs, MqttError, Result, TimestampMillis};
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const ENUMERATED: u8 = 0x0a;
+const GENERALIZED_TIME: u8 = 0x18;
+const CONTEXT_0: u8 = 0xa0;
+
+//good [0] IMPLICIT NULL, revoked [1] IMPLICIT RevokedInfo, unknown [2] IMPLICIT NULL
+const STATUS_GOOD: u8 = 0x80;
+const STATUS_REVOKED: u8 = 0xa1;
+
+//AlgorithmIdentifier of SHA-1, used in the CertID of the request
+const SHA1_ALG_ID: &[u8] = &[0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00];
+//1.3.6.1.5.5.7.48.1.1, id-pkix-ocsp-basic
+const OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+const AIA_OCSP: &str = "1.3.6.1.5.5.7.48.1";
+
+type Port = u16;
+
+static CHECKERS: Lazy<DashMap<Port, Arc<OcspChecker>>> = Lazy::new(DashMap::default);
+
+///Enable the OCSP check on the listener, `roots` are the CA certificates of the client certificates.
+///The check is disabled when the returned registration is dropped
+pub(crate) fn register(
+    listen_cfg: &Listener,
+    roots: Vec<CertificateDer<'static>>,
+    provider: Arc<CryptoProvider>,
+) -> Result<Registration> {
+    let client =
+        reqwest::Client::builder().timeout(listen_cfg.ocsp_timeout).build().map_err(|e| anyhow!(e))?;
+    let checker = OcspChecker {
+        responder_url: listen_cfg.ocsp_responder_url.clone(),
+        refresh_interval: listen_cfg.ocsp_refresh_interval,
+        fail_open: listen_cfg.ocsp_fail_open,
+        roots,
+        provider,
+        client,
+        cache: DashMap::default(),
+    };
+    let port = listen_cfg.addr.port();
+    let checker = Arc::new(checker);
+    CHECKERS.insert(port, checker.clone());
+    Ok(Registration { port, checker })
+}
+
+pub(crate) struct Registration {
+    port: Port,
+    checker: Arc<OcspChecker>,
+}
+
+impl Drop for Registration {
+    #[inline]
+    fn drop(&mut self) {
+        //the listener restarted on the same port may have registered its own checker
+        CHECKERS.remove_if(&self.port, |_, checker| Arc::ptr_eq(checker, &self.checker));
+    }
+}
+
+///Check the client certificate of the connection if OCSP is enabled on the listener,
+///a failed check is kept in the connection attributes under [`PEER_CERT_STATUS_KEY`]
+pub(crate) async fn check(
+    port: Port,
+    peer_certs: Option<&[CertificateDer<'static>]>,
+    conn_attrs: &mut ExtraAttrs,
+) {
+    let checker = if let Some(checker) = CHECKERS.get(&port) {
+        checker.value().clone()
+    } else {
+        return;
+    };
+    let chain = match peer_certs {
+        Some(chain) if !chain.is_empty() => chain.to_vec(),
+        _ => return,
+    };
+    let status = match checker.status(&chain).await {
+        Ok(CertStatus::Good) => return,
+        Ok(CertStatus::Revoked) => {
+            let subject = parse_cert(&chain[0]).map(|c| c.subject().to_string()).unwrap_or_default();
+            log::warn!("client certificate is revoked by the ocsp responder, subject: {}", subject);
+            PeerCertStatus::Revoked
+        }
+        Ok(CertStatus::Unknown) => PeerCertStatus::CheckFailed("certificate status is unknown".into()),
+        Err(e) => PeerCertStatus::CheckFailed(e.to_string()),
+    };
+    if let PeerCertStatus::CheckFailed(e) = &status {
+        if checker.fail_open {
+            log::warn!("client certificate ocsp check failed, the connection is accepted, {}", e);
+            return;
+        }
+        log::warn!("client certificate ocsp check failed, {}", e);
+    }
+    conn_attrs.insert(PEER_CERT_STATUS_KEY.into(), status);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CertStatus {
+    Good,
+    Revoked,
+    Unknown,
+}
+
+struct OcspChecker {
+    responder_url: Option<String>,
+    refresh_interval: Duration,
+    fail_open: bool,
+    roots: Vec<CertificateDer<'static>>,
+    provider: Arc<CryptoProvider>,
+    client: reqwest::Client,
+    //(issuer key hash, serial) => (status, expired at)
+    cache: DashMap<(Vec<u8>, Vec<u8>), (CertStatus, TimestampMillis)>,
+}
+
+impl OcspChecker {
+    async fn status(&self, chain: &[CertificateDer<'static>]) -> Result<CertStatus> {
+        let (cert_id, issuer, url) = self.prepare(chain)?;
+        let key = (cert_id.issuer_key_hash.clone(), cert_id.serial.clone());
+        let now = timestamp_millis();
+        if let Some(entry) = self.cache.get(&key) {
+            let (status, expired_at) = *entry.value();
+            if expired_at > now {
+                return Ok(status);
+            }
+        }
+        self.cache.retain(|_, (_, expired_at)| *expired_at > now);
+
+        let (status, next_update) = self.request(&url, &cert_id, &issuer).await?;
+        let mut expired_at = now + self.refresh_interval.as_millis() as TimestampMillis;
+        if let Some(next_update) = next_update {
+            expired_at = expired_at.min(next_update);
+        }
+        if status != CertStatus::Unknown {
+            self.cache.insert(key, (status, expired_at));
+        }
+        Ok(status)
+    }
+
+    ///Find the issuer in the presented chain or in the CA certificates, and the responder url
+    fn prepare(
+        &self,
+        chain: &[CertificateDer<'static>],
+    ) -> Result<(CertId, CertificateDer<'static>, String)> {
+        let cert = parse_cert(&chain[0])?;
+        let issuer = chain[1..]
+            .iter()
+            .chain(self.roots.iter())
+            .find(|c| {
+                parse_cert(c).map(|c| c.subject().as_raw() == cert.issuer().as_raw()).unwrap_or_default()
+            })
+            .ok_or_else(|| MqttError::from("issuer of the client certificate is not found"))?;
+        let cert_id = CertId::new(&cert, &parse_cert(issuer)?)?;
+        let url = self
+            .responder_url
+            .clone()
+            .or_else(|| responder_url(&cert))
+            .ok_or_else(|| MqttError::from("ocsp responder url is not found"))?;
+        Ok((cert_id, issuer.clone(), url))
+    }
+
+    async fn request(
+        &self,
+        url: &str,
+        cert_id: &CertId,
+        issuer: &CertificateDer<'static>,
+    ) -> Result<(CertStatus, Option<TimestampMillis>)> {
+        let resp = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/ocsp-request")
+            .body(cert_id.to_request())
+            .send()
+            .await
+            .map_err(|e| anyhow!(e))?;
+        if !resp.status().is_success() {
+            return Err(MqttError::from(format!("ocsp responder {} returned {}", url, resp.status())));
+        }
+        let body = resp.bytes().await.map_err(|e| anyhow!(e))?;
+        parse_response(&body, cert_id, &parse_cert(issuer)?, &self.provider)
+    }
+}
+
+struct CertId {
+    issuer_name_hash: Vec<u8>,
+    issuer_key_hash: Vec<u8>,
+    serial: Vec<u8>,
+}
+
+impl CertId {
+    fn new(cert: &X509Certificate<'_>, issuer: &X509Certificate<'_>) -> Result<Self> {
+        let (_, issuer_key) = spki(issuer.public_key().raw)?;
+        Ok(Self {
+            issuer_name_hash: Sha1::digest(cert.issuer().as_raw()).to_vec(),
+            issuer_key_hash: Sha1::digest(issuer_key).to_vec(),
+            serial: cert.raw_serial().to_vec(),
+        })
+    }
+
+    ///OCSPRequest with a single request and without extensions
+    fn to_request(&self) -> Vec<u8> {
+        let cert_id = [
+            SHA1_ALG_ID,
+            &der(OCTET_STRING, &self.issuer_name_hash),
+            &der(OCTET_STRING, &self.issuer_key_hash),
+            &der(INTEGER, &self.serial),
+        ]
+        .concat();
+        //OCSPRequest { TBSRequest { requestList { Request { CertID } } } }
+        der(SEQUENCE, &der(SEQUENCE, &der(SEQUENCE, &der(SEQUENCE, &der(SEQUENCE, &cert_id)))))
+    }
+}
+
+fn parse_response(
+    body: &[u8],
+    cert_id: &CertId,
+    issuer: &X509Certificate<'_>,
+    provider: &CryptoProvider,
+) -> Result<(CertStatus, Option<TimestampMillis>)> {
+    let mut resp = Der::new(Der::new(body).expect(SEQUENCE)?);
+    let resp_status = resp.expect(ENUMERATED)?;
+    if resp_status != [0] {
+        return Err(MqttError::from(format!("ocsp response status is {:?}", resp_status)));
+    }
+    let mut resp_bytes = Der::new(Der::new(resp.expect(CONTEXT_0)?).expect(SEQUENCE)?);
+    if resp_bytes.expect(OID)? != OCSP_BASIC {
+        return Err(MqttError::from("unsupported ocsp response type"));
+    }
+    let mut basic = Der::new(Der::new(resp_bytes.expect(OCTET_STRING)?).expect(SEQUENCE)?);
+    let tbs_raw = basic.expect_raw(SEQUENCE)?;
+    let sig_alg = basic.expect(SEQUENCE)?;
+    let signature = bit_string(basic.expect(BIT_STRING)?)?;
+    let certs = basic.optional(CONTEXT_0)?;
+
+    //Signed by the issuer, or by a responder certificate issued by the issuer for OCSP signing
+    if verify_signature(provider, issuer.public_key().raw, sig_alg, tbs_raw, signature).is_err() {
+        let certs = certs.ok_or_else(|| MqttError::from("ocsp response signature is invalid"))?;
+        let mut certs = Der::new(Der::new(certs).expect(SEQUENCE)?);
+        let mut verified = false;
+        while !certs.is_empty() {
+            let responder = certs.expect_raw(SEQUENCE)?;
+            if let Ok(responder) = verify_responder(provider, issuer, responder) {
+                if verify_signature(provider, responder.public_key().raw, sig_alg, tbs_raw, signature).is_ok()
+                {
+                    verified = true;
+                    break;
+                }
+            }
+        }
+        if !verified {
+            return Err(MqttError::from("ocsp response signature is invalid"));
+        }
+    }
+
+    let mut tbs = Der::new(Der::new(tbs_raw).expect(SEQUENCE)?);
+    tbs.optional(CONTEXT_0)?; //version
+    tbs.read()?; //responderID
+    tbs.expect(GENERALIZED_TIME)?; //producedAt
+    let mut responses = Der::new(tbs.expect(SEQUENCE)?);
+    while !responses.is_empty() {
+        let mut single = Der::new(responses.expect(SEQUENCE)?);
+        let mut id = Der::new(single.expect(SEQUENCE)?);
+        id.expect(SEQUENCE)?; //hashAlgorithm
+        id.expect(OCTET_STRING)?; //issuerNameHash
+        let issuer_key_hash = id.expect(OCTET_STRING)?;
+        let serial = id.expect(INTEGER)?;
+        if issuer_key_hash != cert_id.issuer_key_hash || serial != cert_id.serial {
+            continue;
+        }
+        let (tag, _, _) = single.read()?;
+        let status = match tag {
+            STATUS_GOOD => CertStatus::Good,
+            STATUS_REVOKED => CertStatus::Revoked,
+            _ => CertStatus::Unknown,
+        };
+        single.expect(GENERALIZED_TIME)?; //thisUpdate
+        let next_update = match single.optional(CONTEXT_0)? {
+            Some(next_update) => Some(generalized_time(Der::new(next_update).expect(GENERALIZED_TIME)?)?),
+            None => None,
+        };
+        if next_update.map(|t| t < timestamp_millis()).unwrap_or_default() {
+            return Err(MqttError::from("ocsp response is expired"));
+        }
+        return Ok((status, next_update));
+    }
+    Err(MqttError::from("certificate is not found in the ocsp response"))
+}
+
+///The delegated responder certificate must be issued by the issuer and have the OCSP signing usage
+fn verify_responder<'a>(
+    provider: &CryptoProvider,
+    issuer: &X509Certificate<'_>,
+    raw: &'a [u8],
+) -> Result<X509Certificate<'a>> {
+    let mut cert = Der::new(Der::new(raw).expect(SEQUENCE)?);
+    let tbs_raw = cert.expect_raw(SEQUENCE)?;
+    let sig_alg = cert.expect(SEQUENCE)?;
+    let signature = bit_string(cert.expect(BIT_STRING)?)?;
+    verify_signature(provider, issuer.public_key().raw, sig_alg, tbs_raw, signature)?;
+
+    let responder = parse_cert(raw)?;
+    let ocsp_signing = matches!(responder.extended_key_usage(), Ok(Some(eku)) if eku.value.ocsp_signing);
+    if responder.issuer().as_raw() != issuer.subject().as_raw()
+        || !ocsp_signing
+        || !responder.validity().is_valid()
+    {
+        return Err(MqttError::from("ocsp responder certificate is not authorized"));
+    }
+    Ok(responder)
+}
+
+fn verify_signature(
+    provider: &CryptoProvider,
+    spki_raw: &[u8],
+    sig_alg: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    let (key_alg, key) = spki(spki_raw)?;
+    let alg = provider
+        .signature_verification_algorithms
+        .all
+        .iter()
+        .find(|alg| *alg.public_key_alg_id() == *key_alg && *alg.signature_alg_id() == *sig_alg)
+        .ok_or_else(|| MqttError::from("unsupported signature algorithm"))?;
+    alg.verify_signature(key, message, signature).map_err(|_| MqttError::from("bad signature"))
+}
+
+///The responder of the AIA extension
+fn responder_url(cert: &X509Certificate<'_>) -> Option<String> {
+    cert.extensions().iter().find_map(|ext| match ext.parsed_extension() {
+        ParsedExtension::AuthorityInfoAccess(aia) => {
+            aia.accessdescs.iter().find_map(|desc| match &desc.access_location {
+                GeneralName::URI(uri) if desc.access_method.to_id_string() == AIA_OCSP => {
+                    Some((*uri).to_owned())
+                }
+                _ => None,
+            })
+        }
+        _ => None,
+    })
+}
+
+#[inline]
+fn parse_cert(der: &[u8]) -> Result<X509Certificate<'_>> {
+    X509Certificate::from_der(der).map(|(_, cert)| cert).map_err(|e| anyhow!(e).into())
+}
+
+///The AlgorithmIdentifier content and the key of a SubjectPublicKeyInfo
+#[inline]
+fn spki(raw: &[u8]) -> Result<(&[u8], &[u8])> {
+    let mut spki = Der::new(Der::new(raw).expect(SEQUENCE)?);
+    let alg = spki.expect(SEQUENCE)?;
+    let key = bit_string(spki.expect(BIT_STRING)?)?;
+    Ok((alg, key))
+}
+
+#[inline]
+fn bit_string(content: &[u8]) -> Result<&[u8]> {
+    match content.split_first() {
+        Some((0, bits)) => Ok(bits),
+        _ => Err(MqttError::from("unsupported bit string")),
+    }
+}
+
+///Such as "20240101120000Z", the fraction of seconds is ignored
+fn generalized_time(v: &[u8]) -> Result<TimestampMillis> {
+    let v = std::str::from_utf8(v)?;
+    let t =
+        NaiveDateTime::parse_from_str(v.get(..14).unwrap_or(v), "%Y%m%d%H%M%S").map_err(|e| anyhow!(e))?;
+    Ok(t.and_utc().timestamp_millis())
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let bytes = &bytes[bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len() - 1)..];
+        out.push(0x80 | bytes.len() as u8);
+        out.extend_from_slice(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+///Minimal DER reader, only single byte tags are supported
+struct Der<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    #[inline]
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    ///Returns the tag, the content and the whole encoding of the next element
+    fn read(&mut self) -> Result<(u8, &'a [u8], &'a [u8])> {
+        let err = || MqttError::from("malformed der encoding");
+        let (&tag, rest) = self.data.split_first().ok_or_else(err)?;
+        let (&first, rest) = rest.split_first().ok_or_else(err)?;
+        let (len, rest) = if first < 0x80 {
+            (first as usize, rest)
+        } else {
+            let n = (first & 0x7f) as usize;
+            if n == 0 || n > 4 || rest.len() < n {
+                return Err(err());
+            }
+            (rest[..n].iter().fold(0usize, |len, b| (len << 8) | *b as usize), &rest[n..])
+        };
+        if rest.len() < len {
+            return Err(err());
+        }
+        let header_len = self.data.len() - rest.len();
+        let raw = &self.data[..header_len + len];
+        self.data = &rest[len..];
+        Ok((tag, &raw[header_len..], raw))
+    }
+
+    #[inline]
+    fn read_tag(&mut self, tag: u8) -> Result<(&'a [u8], &'a [u8])> {
+        match self.read()? {
+            (t, content, raw) if t == tag => Ok((content, raw)),
+            (t, _, _) => Err(MqttError::from(format!("unexpected der tag {:#x}, expected {:#x}", t, tag))),
+        }
+    }
+
+    ///Returns the content of the next element
+    #[inline]
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8]> {
+        Ok(self.read_tag(tag)?.0)
+    }
+
+    ///Returns the whole encoding of the next element
+    #[inline]
+    fn expect_raw(&mut self, tag: u8) -> Result<&'a [u8]> {
+        Ok(self.read_tag(tag)?.1)
+    }
+
+    #[inline]
+    fn optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>> {
+        if self.data.first() == Some(&tag) {
+            Ok(Some(self.expect(tag)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(target_os = "windows"))]
+    use rustls::crypto::aws_lc_rs::default_provider;
+    #[cfg(target_os = "windows")]
+    use rustls::crypto::ring::default_provider;
+
+    use super::*;
+
+    //Recorded with tests/ocsp/gen.sh
+    const CA: &[u8] = include_bytes!("../tests/ocsp/ca.der");
+    const GOOD: &[u8] = include_bytes!("../tests/ocsp/good.der");
+    const REVOKED: &[u8] = include_bytes!("../tests/ocsp/revoked.der");
+    const GOOD_REQ: &[u8] = include_bytes!("../tests/ocsp/good.req");
+    const GOOD_RESP: &[u8] = include_bytes!("../tests/ocsp/good.resp");
+    const REVOKED_RESP: &[u8] = include_bytes!("../tests/ocsp/revoked.resp");
+    const DELEGATED_RESP: &[u8] = include_bytes!("../tests/ocsp/delegated.resp");
+    const UNAUTHORIZED_RESP: &[u8] = include_bytes!("../tests/ocsp/unauthorized.resp");
+
+    fn cert_id(cert: &[u8]) -> CertId {
+        CertId::new(&parse_cert(cert).unwrap(), &parse_cert(CA).unwrap()).unwrap()
+    }
+
+    fn parse(body: &[u8], cert: &[u8]) -> Result<(CertStatus, Option<TimestampMillis>)> {
+        parse_response(body, &cert_id(cert), &parse_cert(CA).unwrap(), &default_provider())
+    }
+
+    #[test]
+    fn request() {
+        assert_eq!(cert_id(GOOD).to_request(), GOOD_REQ);
+    }
+
+    #[test]
+    fn good_and_revoked() {
+        assert_eq!(parse(GOOD_RESP, GOOD).unwrap(), (CertStatus::Good, None));
+        assert_eq!(parse(REVOKED_RESP, REVOKED).unwrap(), (CertStatus::Revoked, None));
+        assert_eq!(parse(DELEGATED_RESP, REVOKED).unwrap(), (CertStatus::Revoked, None));
+    }
+
+    #[test]
+    fn other_certificate() {
+        assert!(parse(GOOD_RESP, REVOKED).is_err());
+        assert!(parse(REVOKED_RESP, GOOD).is_err());
+    }
+
+    #[test]
+    fn unauthorized_responder() {
+        assert!(parse(UNAUTHORIZED_RESP, GOOD).is_err());
+
+        //the responder id is signed, it is the first occurrence of the CA name
+        let mut tampered = GOOD_RESP.to_vec();
+        let pos = tampered.windows(12).position(|w| w == b"OCSP Test CA").unwrap();
+        tampered[pos] = b'X';
+        assert!(parse(&tampered, GOOD).is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(parse(&[], GOOD).is_err());
+        assert!(parse(&GOOD_RESP[..GOOD_RESP.len() - 1], GOOD).is_err());
+        assert!(parse(&GOOD_RESP[1..], GOOD).is_err());
+        //tryLater, without the response bytes
+        assert!(parse(&[0x30, 0x03, 0x0a, 0x01, 0x03], GOOD).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::{io, marker, net::SocketAddr, time::Duration};
+
+use rmqtt::broker::conn_limit::{ConnCountGuard, ConnGuard};
+use rmqtt::broker::proxy::{self, ProxyInfo, PROXY_INFO_KEY};
+use rmqtt::broker::queue::{ReadPause, READ_PAUSE_KEY};
+use rmqtt::futures::future::{FutureExt, LocalBoxFuture};
+use rmqtt::ntex::codec::ReadBuf;
+use rmqtt::ntex::codec::{AsyncRead, AsyncWrite};
+use rmqtt::ntex::rt::net::TcpStream;
+use rmqtt::ntex::util::Ready;
+use rmqtt::ntex::{Service, ServiceFactory};
+use rmqtt::ntex_mqtt;
+use rmqtt::tokio::io::AsyncReadExt;
+use rmqtt::{log, tokio, ExtraAttrs, MqttError};
+
+//v2 header(16) + max payload(65535)
+const MAX_HEADER_LEN: usize = 16 + 65535;
+
+pub struct ProxyServer<T> {
+    enable: bool,
+    timeout: Duration,
+    io: marker::PhantomData<T>,
+}
+
+impl<T: AsyncRead + AsyncWrite> ProxyServer<T> {
+    pub fn new(enable: bool, timeout: Duration) -> Self {
+        ProxyServer { enable, timeout, io: marker::PhantomData }
+    }
+}
+
+impl<T> Clone for ProxyServer<T> {
+    fn clone(&self) -> Self {
+        Self { enable: self.enable, timeout: self.timeout, io: marker::PhantomData }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + 'static> ServiceFactory for ProxyServer<T> {
+    type Request = T;
+    type Response = ProxyStream<T>;
+    type Error = ntex_mqtt::MqttError<MqttError>;
+    type Config = ();
+
+    type Service = ProxyService<T>;
+    type InitError = ();
+    type Future = Ready<Self::Service, Self::InitError>;
+
+    fn new_service(&self, _: ()) -> Self::Future {
+        Ready::Ok(ProxyService { enable: self.enable, timeout: self.timeout, io: marker::PhantomData })
+    }
+}
+
+pub struct ProxyService<T> {
+    enable: bool,
+    timeout: Duration,
+    io: marker::PhantomData<T>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + 'static> Service for ProxyService<T> {
+    type Request = T;
+    type Response = ProxyStream<T>;
+    type Error = ntex_mqtt::MqttError<MqttError>;
+    type Future = LocalBoxFuture<'static, Result<ProxyStream<T>, Self::Error>>;
+
+    #[inline]
+    fn poll_ready(&self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn call(&self, req: Self::Request) -> Self::Future {
+        if !self.enable {
+            return async move { Ok(ProxyStream::new(req, None, Vec::new())) }.boxed_local();
+        }
+        let timeout = self.timeout;
+        async move {
+            match tokio::time::timeout(timeout, read_header(req)).await {
+                Ok(Ok(s)) => Ok(s),
+                Ok(Err(e)) => {
+                    log::debug!("read PROXY protocol header error, {:?}", e);
+                    Err(ntex_mqtt::MqttError::Service(e))
+                }
+                Err(_) => Err(ntex_mqtt::MqttError::HandshakeTimeout),
+            }
+        }
+        .boxed_local()
+    }
+}
+
+#[inline]
+async fn read_header<T: AsyncRead + Unpin>(mut io: T) -> Result<ProxyStream<T>, MqttError> {
+    let mut buf = Vec::with_capacity(256);
+    let mut chunk = [0u8; 256];
+    loop {
+        let n = io.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(MqttError::from(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some((info, len)) = proxy::parse(&buf)? {
+            log::debug!("PROXY protocol header: {:?}", info);
+            let remaining = buf.split_off(len);
+            return Ok(ProxyStream::new(io, Some(info), remaining));
+        }
+        if buf.len() > MAX_HEADER_LEN {
+            return Err(MqttError::from("PROXY protocol header is too long"));
+        }
+    }
+}
+
+pub struct ProxyStream<S> {
+    s: S,
+    info: Option<ProxyInfo>,
+    cached_data: Vec<u8>,
+    idx: usize,
+    //Released when the connection is closed
+    #[allow(dead_code)]
+    conn_guard: Option<ConnGuard>,
+    #[allow(dead_code)]
+    count_guard: Option<ConnCountGuard>,
+    //Paused while the publishes of the connection are throttled
+    read_pause: ReadPause,
+}
+
+impl<S> ProxyStream<S> {
+    pub fn new(s: S, info: Option<ProxyInfo>, cached_data: Vec<u8>) -> Self {
+        Self {
+            s,
+            info,
+            cached_data,
+            idx: 0,
+            conn_guard: None,
+            count_guard: None,
+            read_pause: ReadPause::default(),
+        }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.s
+    }
+
+    #[inline]
+    pub fn set_conn_guard(&mut self, guard: ConnGuard) {
+        self.conn_guard = Some(guard);
+    }
+
+    #[inline]
+    pub fn set_count_guard(&mut self, guard: ConnCountGuard) {
+        self.count_guard = Some(guard);
+    }
+
+    #[inline]
+    pub fn proxy_info(&self) -> Option<&ProxyInfo> {
+        self.info.as_ref()
+    }
+
+    #[inline]
+    pub fn conn_attrs(&self) -> ExtraAttrs {
+        let mut attrs = ExtraAttrs::new();
+        if let Some(info) = &self.info {
+            attrs.insert(PROXY_INFO_KEY.into(), info.clone());
+        }
+        attrs.insert(READ_PAUSE_KEY.into(), self.read_pause.clone());
+        attrs
+    }
+}
+
+impl ProxyStream<TcpStream> {
+    ///The original client address if it was provided by the proxy, otherwise the peer address
+    #[inline]
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.info.as_ref().and_then(|info| info.source_addr) {
+            Some(addr) => Ok(addr),
+            None => self.s.peer_addr(),
+        }
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.s.local_addr()
+    }
+}
+
+impl<S> AsyncRead for ProxyStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.read_pause.poll_resumed(cx).is_pending() {
+            return Poll::Pending;
+        }
+        if self.idx < self.cached_data.len() {
+            let cached_buf = &self.cached_data[self.idx..];
+            let len = cached_buf.len().min(buf.remaining());
+            buf.put_slice(&cached_buf[..len]);
+            self.idx += len;
+            if self.idx >= self.cached_data.len() {
+                self.idx = 0;
+                self.cached_data = Vec::new();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.s).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for ProxyStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    #[inline]
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.s).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.s).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.s).poll_shutdown(cx)
+    }
+}
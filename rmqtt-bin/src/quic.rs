@@ -0,0 +1,257 @@
+//! MQTT over QUIC, the MQTT session of a connection runs on its first bidirectional stream.
+//!
+//! The connections are distributed to `workers` arbiters, which are stopped with the listener, each
+//! connection is handled by the same v3/v5 handshake and session machinery as the tcp listeners.
+//! The client addresses may change during the connection(migration), and resumed connections can
+//! send the CONNECT packet in the 0-RTT data if `quic_0rtt` is enabled. The 0-RTT data is only read
+//! after the handshake completes, so that a replayed 0-RTT flight is never processed.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Connecting, Connection, Endpoint, IdleTimeout, RecvStream, SendStream, TransportConfig, VarInt};
+use rustls::pki_types::CertificateDer;
+
+use rmqtt::anyhow::anyhow;
+use rmqtt::broker::conn_limit::{ConnCountGuard, ConnCounter, ConnGuard, ConnLimiter};
+use rmqtt::broker::ip_filter::IpFilters;
+use rmqtt::broker::{
+    v3::control_message as control_message_v3, v3::handshake as handshake_v3, v3::publish as publish_v3,
+    v5::control_message as control_message_v5, v5::handshake as handshake_v5, v5::publish as publish_v5,
+};
+use rmqtt::futures::future::ok;
+use rmqtt::ntex::codec::{AsyncRead, AsyncWrite, ReadBuf};
+use rmqtt::ntex::{self, fn_factory_with_config, fn_service, Service, ServiceFactory};
+use rmqtt::ntex_mqtt::{
+    v3::Handshake as HandshakeV3,
+    v5::Handshake as HandshakeV5,
+    {v3, v5, MqttServer},
+};
+use rmqtt::settings::listener::{Listener, ListenerId};
+use rmqtt::{log, tokio, ExtraAttrs, MqttError, Result, Runtime, SessionState};
+
+use crate::workers::Workers;
+use crate::{ocsp, tls};
+
+const ALPN_MQTT: &[u8] = b"mqtt";
+
+pub(crate) async fn listen(name: &str, listen_cfg: &Listener) -> Result<()> {
+    let (mut tls_config, _tls_guard) = tls::server_config(name, listen_cfg)?;
+    tls_config.alpn_protocols = vec![ALPN_MQTT.to_vec()];
+    if listen_cfg.quic_0rtt {
+        tls_config.max_early_data_size = u32::MAX;
+    }
+    let crypto = QuicServerConfig::try_from(tls_config).map_err(|e| anyhow!(e))?;
+
+    //One stream for the MQTT session. The server pings the idle connections, so that the clients with a
+    //long MQTT keepalive are kept, and the connections of the unreachable clients time out
+    let idle_timeout = listen_cfg.quic_idle_timeout.max(Duration::from_secs(1));
+    let mut transport = TransportConfig::default();
+    transport
+        .max_concurrent_bidi_streams(VarInt::from_u32(1))
+        .max_concurrent_uni_streams(VarInt::from_u32(0))
+        .max_idle_timeout(Some(IdleTimeout::try_from(idle_timeout).map_err(|e| anyhow!(e))?))
+        .keep_alive_interval(Some(idle_timeout / 3));
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    server_config.transport_config(Arc::new(transport)).migration(true);
+
+    let endpoint = Endpoint::server(server_config, listen_cfg.addr)?;
+    let conn_limiter = ConnLimiter::new(listen_cfg);
+    let ip_filter = IpFilters::instance().register(listen_cfg);
+    let counter = ConnCounter::new(ListenerId::Port(listen_cfg.addr.port()));
+    let mut workers = Workers::new(listen_cfg.workers);
+    log::info!("{} quic listener is started on {:?}", name, listen_cfg.addr);
+
+    while let Some(incoming) = endpoint.accept().await {
+        let remote_addr = incoming.remote_address();
+        if Runtime::instance().node.is_draining() {
+            incoming.refuse();
+            continue;
+        }
+        if !ip_filter.is_allowed(&remote_addr.ip()) {
+            log::debug!("{:?} connection is denied by the ip filter", remote_addr);
+            Runtime::instance().metrics.client_connect_ip_denied_inc();
+            incoming.refuse();
+            continue;
+        }
+        let count_guard = if let Some(guard) = counter.acquire() {
+            guard
+        } else {
+            log::debug!("{:?} connection is rejected, too many connections", remote_addr);
+            incoming.refuse();
+            continue;
+        };
+        let conn_guard = match conn_limiter.as_ref().map(|l| l.acquire(remote_addr.ip())).transpose() {
+            Ok(guard) => guard,
+            Err(e) => {
+                log::debug!("{:?} connection is rejected, {:?}", remote_addr, e);
+                incoming.refuse();
+                continue;
+            }
+        };
+        let connecting = match incoming.accept() {
+            Ok(connecting) => connecting,
+            Err(e) => {
+                log::debug!("{:?} accept quic connection failed, {:?}", remote_addr, e);
+                continue;
+            }
+        };
+
+        //The options updated at runtime apply to the subsequent connections
+        let listen_cfg = Runtime::instance()
+            .settings
+            .listeners
+            .quic(listen_cfg.addr.port())
+            .unwrap_or_else(|| listen_cfg.clone());
+        workers.exec_fn(move || {
+            ntex::rt::spawn(async move {
+                if let Err(e) = serve(connecting, listen_cfg, count_guard, conn_guard).await {
+                    log::debug!("{:?} quic connection error, {:?}", remote_addr, e);
+                }
+            });
+        });
+    }
+    Ok(())
+}
+
+async fn serve(
+    connecting: Connecting,
+    listen_cfg: Listener,
+    count_guard: ConnCountGuard,
+    conn_guard: Option<ConnGuard>,
+) -> Result<()> {
+    let port = listen_cfg.addr.port();
+    let handshake_timeout = listen_cfg.handshake_timeout();
+    let (conn, handshake_completed) = if listen_cfg.quic_0rtt {
+        match connecting.into_0rtt() {
+            Ok((conn, completed)) => (conn, Some(completed)),
+            Err(connecting) => (connecting.await.map_err(|e| anyhow!(e))?, None),
+        }
+    } else {
+        (connecting.await.map_err(|e| anyhow!(e))?, None)
+    };
+    let (send, recv) = tokio::time::timeout(listen_cfg.handshake_timeout, conn.accept_bi())
+        .await
+        .map_err(|_| MqttError::from("quic stream is not opened within the handshake timeout"))?
+        .map_err(|e| anyhow!(e))?;
+    //The 0-RTT data can be replayed, it is not read until the handshake completes, which a replay can not do
+    if let Some(completed) = handshake_completed {
+        tokio::time::timeout(listen_cfg.handshake_timeout, completed)
+            .await
+            .map_err(|_| MqttError::from("quic handshake is not completed within the handshake timeout"))?;
+        if let Some(reason) = conn.close_reason() {
+            return Err(anyhow!(reason).into());
+        }
+    }
+    let peer_certs = conn.peer_identity().and_then(|id| id.downcast::<Vec<CertificateDer<'static>>>().ok());
+    let stream =
+        QuicStream { conn, send, recv, peer_certs, _count_guard: count_guard, _conn_guard: conn_guard };
+
+    let max_inflight = listen_cfg.max_inflight.get() as usize;
+    let max_size = listen_cfg.max_packet_size.as_u32();
+    let srv = MqttServer::new()
+        .v3(v3::MqttServer::new(move |handshake: HandshakeV3<QuicStream>| async move {
+            let (listen_cfg, remote_addr, local_addr, conn_attrs) =
+                handshake_args(port, handshake.io()).await?;
+            handshake_v3(listen_cfg, handshake, remote_addr, local_addr, conn_attrs).await
+        })
+        .inflight(max_inflight)
+        .handshake_timeout(handshake_timeout)
+        .max_size(max_size)
+        .publish(fn_factory_with_config(|session: v3::Session<SessionState>| {
+            ok::<_, MqttError>(fn_service(move |req| publish_v3(session.clone(), req)))
+        }))
+        .control(fn_factory_with_config(|session: v3::Session<SessionState>| {
+            ok::<_, MqttError>(fn_service(move |req| control_message_v3(session.clone(), req)))
+        })))
+        .v5(v5::MqttServer::new(move |handshake: HandshakeV5<QuicStream>| async move {
+            let (listen_cfg, remote_addr, local_addr, conn_attrs) =
+                handshake_args(port, handshake.io()).await?;
+            handshake_v5(listen_cfg, handshake, remote_addr, local_addr, conn_attrs).await
+        })
+        .receive_max(max_inflight as u16)
+        .handshake_timeout(handshake_timeout)
+        .max_size(max_size)
+        .publish(fn_factory_with_config(|session: v5::Session<SessionState>| {
+            ok::<_, MqttError>(fn_service(move |req| publish_v5(session.clone(), req)))
+        }))
+        .control(fn_factory_with_config(|session: v5::Session<SessionState>| {
+            ok::<_, MqttError>(fn_service(move |req| control_message_v5(session.clone(), req)))
+        })))
+        .new_service(())
+        .await
+        .map_err(|e| MqttError::from(format!("create mqtt service failed, {:?}", e)))?;
+    srv.call(stream).await.map_err(|e| MqttError::from(format!("{:?}", e)))?;
+    Ok(())
+}
+
+async fn handshake_args(
+    port: u16,
+    io: &QuicStream,
+) -> Result<(Listener, SocketAddr, SocketAddr, ExtraAttrs)> {
+    let listen_cfg = Runtime::instance().settings.listeners.quic(port).ok_or_else(|| {
+        log::error!("quic listener config is not found, port is {:?}", port);
+        MqttError::ListenerConfigError
+    })?;
+    let local_addr = listen_cfg.addr;
+    let mut conn_attrs = ExtraAttrs::new();
+    tls::set_peer_cert(&mut conn_attrs, io.peer_certs());
+    ocsp::check(port, io.peer_certs(), &mut conn_attrs).await;
+    Ok((listen_cfg, io.remote_addr(), local_addr, conn_attrs))
+}
+
+pub struct QuicStream {
+    conn: Connection,
+    send: SendStream,
+    recv: RecvStream,
+    peer_certs: Option<Box<Vec<CertificateDer<'static>>>>,
+    //Released when the connection is closed
+    _count_guard: ConnCountGuard,
+    _conn_guard: Option<ConnGuard>,
+}
+
+impl QuicStream {
+    ///The current address of the client, it may change after migration
+    #[inline]
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.conn.remote_address()
+    }
+
+    #[inline]
+    pub fn peer_certs(&self) -> Option<&[CertificateDer<'static>]> {
+        self.peer_certs.as_ref().map(|certs| certs.as_slice())
+    }
+}
+
+impl AsyncRead for QuicStream {
+    #[inline]
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    #[inline]
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
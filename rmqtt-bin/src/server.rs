@@ -1,20 +1,21 @@
 #![deny(unsafe_code)]
 
-use std::{fs::File, io::BufReader, process, sync::Arc, time::Duration};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::{process, time::Duration};
 
-#[cfg(not(target_os = "windows"))]
-use rustls::crypto::aws_lc_rs as provider;
-#[cfg(target_os = "windows")]
-use rustls::crypto::ring as provider;
-use rustls::server::WebPkiClientVerifier;
-use rustls::{RootCertStore, ServerConfig};
-
-use rmqtt::anyhow::anyhow;
+use rmqtt::broker::ban;
+use rmqtt::broker::conn_limit::{ConnCounter, ConnLimiter};
+use rmqtt::broker::ip_filter::IpFilters;
 use rmqtt::broker::{
     v3::control_message as control_message_v3, v3::handshake as handshake_v3, v3::publish as publish_v3,
     v5::control_message as control_message_v5, v5::handshake as handshake_v5, v5::publish as publish_v5,
 };
-use rmqtt::futures::future::ok;
+use rmqtt::futures::channel::mpsc;
+use rmqtt::futures::future::{ok, AbortHandle, Abortable, Aborted};
+use rmqtt::futures::StreamExt;
+#[cfg(unix)]
+use rmqtt::ntex::rt::net::UnixStream;
 use rmqtt::ntex::{
     self,
     rt::net::TcpStream,
@@ -28,11 +29,25 @@ use rmqtt::ntex_mqtt::{
     v5::Handshake as HandshakeV5,
     {v3, v5, MqttServer},
 };
-use rmqtt::settings::{listener::Listener, Options, Settings};
+use rmqtt::settings::{
+    listener::{Listener, ListenerChange, ListenerId, ListenerKind},
+    Options, Settings,
+};
 use rmqtt::{log, structopt::StructOpt, tokio};
 use rmqtt::{logger::logger_init, runtime, MqttError, Result, Runtime, SessionState};
 
+mod coap;
+mod limit;
+mod mqttsn;
+mod ocsp;
+mod proxy;
+mod quic;
+mod tls;
+#[cfg(unix)]
+mod unix;
+mod workers;
 mod ws;
+mod ws_deflate;
 
 #[cfg(target_os = "linux")]
 #[global_allocator]
@@ -72,52 +87,193 @@ async fn main() {
     //hook, before startup
     Runtime::instance().extends.hook_mgr().await.before_startup().await;
 
-    //tcp
-    for (_, listen_cfg) in Runtime::instance().settings.listeners.tcps.iter() {
-        let name = format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr);
-        ntex::rt::spawn(async {
-            if let Err(err) = listen(name, listen_cfg).await {
-                log::error!("listen mqtt failed: {}", err);
-                process::exit(1);
-            }
-        });
+    //pull the ban entries of the other nodes
+    ban::start_sync();
+
+    //start listeners, and the listeners added, removed or updated at runtime
+    let changes = Runtime::instance().settings.listeners.watch();
+    for (kind, listen_cfg) in Runtime::instance().settings.listeners.all() {
+        start_listener(kind, listen_cfg, true);
     }
+    ntex::rt::spawn(watch_listeners(changes));
+
+    //reload the listeners on SIGHUP
+    ntex::rt::spawn(reload_on_hangup());
+
+    tokio::select! {
+        res = ntex::rt::signal::ctrl_c() => {
+            res.expect("signal ctrl c");
+        }
+        _ = terminate() => {
+            Runtime::instance().node.drain();
+            drain().await;
+        }
+        _ = Runtime::instance().node.draining() => {
+            drain().await;
+        }
+    }
+    //hook, before shutdown, the storage plugins flush the session data
+    Runtime::instance().extends.hook_mgr().await.before_shutdown().await;
+}
+
+thread_local! {
+    static SERVERS: RefCell<Vec<(ListenerId, ntex::server::Server)>> = const { RefCell::new(Vec::new()) };
+    //The listeners started and the configurations their servers are built with
+    static LISTENERS: RefCell<HashMap<ListenerId, (Listener, AbortHandle)>> = RefCell::new(HashMap::new());
+}
+
+///Keep the server handle for draining and stopping, then wait until the server stops
+async fn serve(id: ListenerId, srv: ntex::server::Server) -> Result<()> {
+    SERVERS.with(|servers| servers.borrow_mut().push((id, srv.clone())));
+    srv.await?;
+    Ok(())
+}
 
-    //tls
-    for (_, listen_cfg) in Runtime::instance().settings.listeners.tlss.iter() {
-        let name = format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr);
-        ntex::rt::spawn(async {
-            if let Err(err) = listen_tls(name, listen_cfg).await {
-                log::error!("listen mqtt tls failed: {}", err);
-                process::exit(1);
+///Start the listener, the process exits if the listener fails at startup,
+///the listener that fails at runtime is removed from the configuration.
+fn start_listener(kind: ListenerKind, listen_cfg: Listener, at_startup: bool) {
+    let id = ListenerId::new(kind, &listen_cfg);
+    if LISTENERS.with(|listeners| listeners.borrow().contains_key(&id)) {
+        log::warn!("{} listener on {} is already started", kind, id);
+        return;
+    }
+    let name = if kind == ListenerKind::Unix {
+        format!("{}/{:?}", &listen_cfg.name, &listen_cfg.path)
+    } else {
+        format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr)
+    };
+    let (abort_handle, abort_registration) = AbortHandle::new_pair();
+    LISTENERS.with(|listeners| listeners.borrow_mut().insert(id.clone(), (listen_cfg.clone(), abort_handle)));
+    ntex::rt::spawn(async move {
+        let listen = async {
+            match kind {
+                ListenerKind::Tcp => listen(name, &listen_cfg).await,
+                ListenerKind::Tls => listen_tls(name, &listen_cfg).await,
+                ListenerKind::Ws => listen_ws(name, &listen_cfg).await,
+                ListenerKind::Wss => listen_wss(name, &listen_cfg).await,
+                ListenerKind::Quic => listen_quic(name, &listen_cfg).await,
+                ListenerKind::Mqttsn => listen_mqttsn(name, &listen_cfg).await,
+                ListenerKind::Coap => listen_coap(name, &listen_cfg).await,
+                #[cfg(unix)]
+                ListenerKind::Unix => listen_unix(name, &listen_cfg).await,
+                #[cfg(not(unix))]
+                ListenerKind::Unix => Err(MqttError::from("unix domain socket is not supported")),
             }
-        });
+        };
+        match Abortable::new(listen, abort_registration).await {
+            Ok(Err(err)) => {
+                log::error!("listen {} failed: {}", kind, err);
+                if at_startup {
+                    process::exit(1);
+                }
+                LISTENERS.with(|listeners| listeners.borrow_mut().remove(&id));
+                Runtime::instance().settings.listeners.remove(&id);
+            }
+            Ok(Ok(())) | Err(Aborted) => {}
+        }
+    });
+}
+
+///Stop the listener, the server of the listener waits for the established connections to close
+///within the shutdown timeout if `graceful` is true. The connections of the QUIC, MQTT-SN and CoAP
+///listeners are closed with their arbiters, and then the udp socket is released.
+async fn stop_listener(kind: ListenerKind, listen_cfg: &Listener, graceful: bool) {
+    let id = ListenerId::new(kind, listen_cfg);
+    let servers = SERVERS.with(|servers| {
+        let mut servers = servers.borrow_mut();
+        let (stopped, running) = servers.drain(..).partition::<Vec<_>, _>(|(i, _)| *i == id);
+        *servers = running;
+        stopped
+    });
+    for (_, srv) in servers {
+        srv.stop(graceful).await;
+    }
+    if let Some((_, abort_handle)) = LISTENERS.with(|listeners| listeners.borrow_mut().remove(&id)) {
+        abort_handle.abort();
     }
+    if let ListenerId::Port(port) = id {
+        IpFilters::instance().unregister(port);
+    }
+    log::info!("{} listener {} is stopped, {}", kind, listen_cfg.name, id);
+}
+
+async fn watch_listeners(mut changes: mpsc::UnboundedReceiver<ListenerChange>) {
+    while let Some(change) = changes.next().await {
+        match change {
+            ListenerChange::Added(kind, listen_cfg) => start_listener(kind, listen_cfg, false),
+            ListenerChange::Removed(kind, listen_cfg) => stop_listener(kind, &listen_cfg, true).await,
+            //The subsequent connections use the updated configuration, it is looked up by the port
+            ListenerChange::Updated(kind, listen_cfg) => rebuild_listener(kind, listen_cfg).await,
+        }
+    }
+}
 
-    //websocket
-    for (_, listen_cfg) in Runtime::instance().settings.listeners.wss.iter() {
-        let name = format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr);
-        ntex::rt::spawn(async {
-            if let Err(err) = listen_ws(name, listen_cfg).await {
-                log::error!("listen websocket failed: {}", err);
-                process::exit(1);
+///Rebuild the server of the listener if the options it is built with are updated, such as
+///max_inflight. The QUIC, MQTT-SN and CoAP listeners look them up for each connection.
+///The port is released before the new server binds it, so the old connections are closed at once
+async fn rebuild_listener(kind: ListenerKind, listen_cfg: Listener) {
+    if matches!(kind, ListenerKind::Quic | ListenerKind::Mqttsn | ListenerKind::Coap) {
+        return;
+    }
+    let id = ListenerId::new(kind, &listen_cfg);
+    let running = match LISTENERS.with(|listeners| listeners.borrow().get(&id).map(|(l, _)| l.clone())) {
+        Some(running) => running,
+        None => return,
+    };
+    let fields = running.rebuild_required(&listen_cfg);
+    if fields.is_empty() {
+        return;
+    }
+    log::info!("{} listener {} is rebuilt, {} changed", kind, listen_cfg.name, fields.join(", "));
+    stop_listener(kind, &running, false).await;
+    start_listener(kind, listen_cfg, false);
+}
+
+async fn reload_on_hangup() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::hangup()) {
+            Ok(mut sig) => {
+                while sig.recv().await.is_some() {
+                    log::info!("SIGHUP is received, reload the listeners");
+                    if let Err(e) = Runtime::instance().settings.reload_listeners() {
+                        log::warn!("reload the listeners failed, {:?}", e);
+                    }
+                }
+            }
+            Err(e) => {
+                log::warn!("listen SIGHUP signal failed, {:?}", e);
             }
-        });
+        }
     }
+}
 
-    //tls-websocket
-    for (_, listen_cfg) in Runtime::instance().settings.listeners.wsss.iter() {
-        let name = format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr);
-        ntex::rt::spawn(async {
-            if let Err(err) = listen_wss(name, listen_cfg).await {
-                log::error!("listen websocket tls failed: {}", err);
-                process::exit(1);
+async fn terminate() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+                return;
             }
-        });
+            Err(e) => {
+                log::warn!("listen SIGTERM signal failed, {:?}", e);
+            }
+        }
     }
+    std::future::pending::<()>().await
+}
 
-    ntex::rt::signal::ctrl_c().await.expect("signal ctrl c");
-    tokio::time::sleep(Duration::from_secs(1)).await;
+///Stop accepting new connections, then disconnect the clients in batches
+async fn drain() {
+    log::info!("the node is draining ...");
+    let servers = SERVERS.with(|servers| servers.borrow().clone());
+    for (_, srv) in servers.iter() {
+        srv.pause().await;
+    }
+    Runtime::instance().node.drain_sessions().await;
 }
 
 async fn listen(name: String, listen_cfg: &Listener) -> Result<()> {
@@ -125,77 +281,100 @@ async fn listen(name: String, listen_cfg: &Listener) -> Result<()> {
         let max_inflight = listen_cfg.max_inflight.get() as usize;
         let handshake_timeout = listen_cfg.handshake_timeout();
         let max_size = listen_cfg.max_packet_size.as_u32();
-        ntex::server::Server::build()
+        let proxy_protocol = listen_cfg.proxy_protocol;
+        let proxy_protocol_timeout = listen_cfg.proxy_protocol_timeout;
+        let conn_counter = ConnCounter::new(ListenerId::Port(listen_cfg.addr.port()));
+        let conn_limiter = ConnLimiter::new(listen_cfg);
+        let ip_filter = IpFilters::instance().register(listen_cfg);
+        let srv = ntex::server::Server::build()
+            .disable_signals()
             .backlog(listen_cfg.backlog)
             .reuseaddr(listen_cfg.reuseaddr)
             .reuseport(listen_cfg.reuseport)
             .bind(name, listen_cfg.addr, move || {
-                MqttServer::new()
-                    .v3(v3::MqttServer::new(move |mut handshake: HandshakeV3<TcpStream>| async {
-                        let remote_addr = handshake.io().peer_addr()?;
-                        let local_addr = handshake.io().local_addr()?;
-                        let listen_cfg =
-                            Runtime::instance().settings.listeners.tcp(local_addr.port()).ok_or_else(
-                                || {
-                                    log::error!(
-                                        "tcp listener config is not found, local addr is {:?}",
-                                        local_addr
-                                    );
-                                    MqttError::ListenerConfigError
+                pipeline_factory(limit::IpFilterServer::new(ip_filter.clone()))
+                    .and_then(proxy::ProxyServer::new(proxy_protocol, proxy_protocol_timeout))
+                    .and_then(limit::IpFilterServer::new(ip_filter.clone()))
+                    .and_then(limit::ConnLimitServer::new(conn_counter.clone(), conn_limiter.clone()))
+                    .and_then(
+                        MqttServer::new()
+                            .v3(v3::MqttServer::new(
+                                move |mut handshake: HandshakeV3<proxy::ProxyStream<TcpStream>>| async {
+                                    let remote_addr = handshake.io().peer_addr()?;
+                                    let local_addr = handshake.io().local_addr()?;
+                                    let conn_attrs = handshake.io().conn_attrs();
+                                    let listen_cfg = Runtime::instance()
+                                        .settings
+                                        .listeners
+                                        .tcp(local_addr.port())
+                                        .ok_or_else(|| {
+                                            log::error!(
+                                                "tcp listener config is not found, local addr is {:?}",
+                                                local_addr
+                                            );
+                                            MqttError::ListenerConfigError
+                                        })?;
+                                    handshake_v3(listen_cfg, handshake, remote_addr, local_addr, conn_attrs)
+                                        .await
                                 },
-                            )?;
-                        handshake_v3(listen_cfg, handshake, remote_addr, local_addr).await
-                    })
-                    // .v3(v3::MqttServer::new(handshake_v3)
-                    .inflight(max_inflight)
-                    .handshake_timeout(handshake_timeout)
-                    .max_size(max_size)
-                    .publish(fn_factory_with_config(|session: v3::Session<SessionState>| {
-                        ok::<_, MqttError>(fn_service(move |req| publish_v3(session.clone(), req)))
-                    }))
-                    .control(fn_factory_with_config(
-                        |session: v3::Session<SessionState>| {
-                            ok::<_, MqttError>(fn_service(move |req| {
-                                control_message_v3(session.clone(), req)
+                            )
+                            // .v3(v3::MqttServer::new(handshake_v3)
+                            .inflight(max_inflight)
+                            .handshake_timeout(handshake_timeout)
+                            .max_size(max_size)
+                            .publish(fn_factory_with_config(|session: v3::Session<SessionState>| {
+                                ok::<_, MqttError>(fn_service(move |req| publish_v3(session.clone(), req)))
                             }))
-                        },
-                    )))
-                    .v5(v5::MqttServer::new(move |mut handshake: HandshakeV5<TcpStream>| async {
-                        let peer_addr = handshake.io().peer_addr()?;
-                        let local_addr = handshake.io().local_addr()?;
-                        let listen_cfg =
-                            Runtime::instance().settings.listeners.tcp(local_addr.port()).ok_or_else(
-                                || {
-                                    log::error!(
-                                        "tcp listener config is not found, local addr is {:?}",
-                                        local_addr
-                                    );
-                                    MqttError::ListenerConfigError
+                            .control(fn_factory_with_config(
+                                |session: v3::Session<SessionState>| {
+                                    ok::<_, MqttError>(fn_service(move |req| {
+                                        control_message_v3(session.clone(), req)
+                                    }))
                                 },
-                            )?;
-                        handshake_v5(listen_cfg, handshake, peer_addr, local_addr).await
-                    })
-                    //v5::MqttServer::new(handshake_v5)
-                    .receive_max(max_inflight as u16)
-                    .handshake_timeout(handshake_timeout)
-                    .max_size(max_size)
-                    // .max_qos(max_qos)
-                    //.max_topic_alias(max_topic_alias),
-                    .publish(fn_factory_with_config(|session: v5::Session<SessionState>| {
-                        ok::<_, MqttError>(fn_service(move |req| publish_v5(session.clone(), req)))
-                    }))
-                    .control(fn_factory_with_config(
-                        |session: v5::Session<SessionState>| {
-                            ok::<_, MqttError>(fn_service(move |req| {
-                                control_message_v5(session.clone(), req)
+                            )))
+                            .v5(v5::MqttServer::new(
+                                move |mut handshake: HandshakeV5<proxy::ProxyStream<TcpStream>>| async {
+                                    let peer_addr = handshake.io().peer_addr()?;
+                                    let local_addr = handshake.io().local_addr()?;
+                                    let conn_attrs = handshake.io().conn_attrs();
+                                    let listen_cfg = Runtime::instance()
+                                        .settings
+                                        .listeners
+                                        .tcp(local_addr.port())
+                                        .ok_or_else(|| {
+                                            log::error!(
+                                                "tcp listener config is not found, local addr is {:?}",
+                                                local_addr
+                                            );
+                                            MqttError::ListenerConfigError
+                                        })?;
+                                    handshake_v5(listen_cfg, handshake, peer_addr, local_addr, conn_attrs)
+                                        .await
+                                },
+                            )
+                            //v5::MqttServer::new(handshake_v5)
+                            .receive_max(max_inflight as u16)
+                            .handshake_timeout(handshake_timeout)
+                            .max_size(max_size)
+                            // .max_qos(max_qos)
+                            //.max_topic_alias(max_topic_alias),
+                            .publish(fn_factory_with_config(|session: v5::Session<SessionState>| {
+                                ok::<_, MqttError>(fn_service(move |req| publish_v5(session.clone(), req)))
                             }))
-                        },
-                    )))
+                            .control(fn_factory_with_config(
+                                |session: v5::Session<SessionState>| {
+                                    ok::<_, MqttError>(fn_service(move |req| {
+                                        control_message_v5(session.clone(), req)
+                                    }))
+                                },
+                            ))),
+                    )
             })?
             .workers(listen_cfg.workers)
-            .maxconn(listen_cfg.max_connections / listen_cfg.workers)
-            .run()
-            .await?;
+            //max_connections is checked by ConnLimitServer, so that it can be changed at runtime
+            .maxconn(usize::MAX)
+            .run();
+        serve(ListenerId::Port(listen_cfg.addr.port()), srv).await?;
         Ok(())
     }
 
@@ -207,56 +386,44 @@ async fn listen(name: String, listen_cfg: &Listener) -> Result<()> {
 
 async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
     async fn _listen_tls(name: &str, listen_cfg: &Listener) -> Result<()> {
-        let cert_file = &mut BufReader::new(File::open(
-            listen_cfg.cert.as_ref().ok_or::<MqttError>("cert is None".into())?,
-        )?);
-        let key_file = &mut BufReader::new(File::open(
-            listen_cfg.key.as_ref().ok_or::<MqttError>("key is None".into())?,
-        )?);
-
-        let cert_chain = rustls_pemfile::certs(cert_file).collect::<Result<Vec<_>, _>>()?;
-        let key = rustls_pemfile::private_key(key_file)?.ok_or::<MqttError>("key_file is None".into())?;
-
-        let provider = Arc::new(provider::default_provider());
-        let client_auth = if listen_cfg.cross_certificate {
-            let root_chain = cert_chain.clone();
-            let mut client_auth_roots = RootCertStore::empty();
-            for root in root_chain {
-                client_auth_roots.add(root).map_err(|e| anyhow!(e))?;
-            }
-            WebPkiClientVerifier::builder_with_provider(client_auth_roots.into(), provider.clone())
-                .build()
-                .map_err(|e| anyhow!(e))?
-        } else {
-            WebPkiClientVerifier::no_client_auth()
-        };
-
-        let tls_config = ServerConfig::builder_with_provider(provider)
-            .with_safe_default_protocol_versions()
-            .map_err(|e| anyhow!(e))?
-            .with_client_cert_verifier(client_auth)
-            .with_single_cert(cert_chain, key)
-            .map_err(|e| anyhow!(format!("bad certs/private key, {}", e)))?;
-
+        let (tls_config, _tls_guard) = tls::server_config(name, listen_cfg)?;
         let tls_acceptor = Acceptor::new(tls_config);
 
         let max_inflight = listen_cfg.max_inflight.get() as usize;
         let handshake_timeout = listen_cfg.handshake_timeout();
         let max_size = listen_cfg.max_packet_size.as_u32();
-        ntex::server::Server::build()
+        let proxy_protocol = listen_cfg.proxy_protocol;
+        let proxy_protocol_timeout = listen_cfg.proxy_protocol_timeout;
+        let conn_counter = ConnCounter::new(ListenerId::Port(listen_cfg.addr.port()));
+        let conn_limiter = ConnLimiter::new(listen_cfg);
+        let ip_filter = IpFilters::instance().register(listen_cfg);
+        let srv = ntex::server::Server::build()
+            .disable_signals()
             .backlog(listen_cfg.backlog)
             .reuseaddr(listen_cfg.reuseaddr)
             .reuseport(listen_cfg.reuseport)
             .bind(name, listen_cfg.addr, move || {
-                pipeline_factory(tls_acceptor.clone())
-                    .map_err(|e| ntex_mqtt::MqttError::Service(MqttError::from(e)))
+                pipeline_factory(limit::IpFilterServer::new(ip_filter.clone()))
+                    .and_then(proxy::ProxyServer::new(proxy_protocol, proxy_protocol_timeout))
+                    .and_then(limit::IpFilterServer::new(ip_filter.clone()))
+                    .and_then(limit::ConnLimitServer::new(conn_counter.clone(), conn_limiter.clone()))
+                    .and_then(
+                        pipeline_factory(tls_acceptor.clone())
+                            .map_err(|e| ntex_mqtt::MqttError::Service(MqttError::from(e))),
+                    )
                     .and_then(
                         MqttServer::new()
                             .v3(v3::MqttServer::new(
-                                move |mut handshake: HandshakeV3<TlsStream<TcpStream>>| async {
-                                    let (io, _) = handshake.io().get_ref();
+                                move |mut handshake: HandshakeV3<
+                                    TlsStream<proxy::ProxyStream<TcpStream>>,
+                                >| async {
+                                    let (io, tls) = handshake.io().get_ref();
                                     let peer_addr = io.peer_addr()?;
                                     let local_addr = io.local_addr()?;
+                                    let mut conn_attrs = io.conn_attrs();
+                                    tls::set_peer_cert(&mut conn_attrs, tls.peer_certificates());
+                                    ocsp::check(local_addr.port(), tls.peer_certificates(), &mut conn_attrs)
+                                        .await;
                                     let listen_cfg = Runtime::instance()
                                         .settings
                                         .listeners
@@ -269,7 +436,8 @@ async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
                                             MqttError::ListenerConfigError
                                         })?;
 
-                                    handshake_v3(listen_cfg, handshake, peer_addr, local_addr).await
+                                    handshake_v3(listen_cfg, handshake, peer_addr, local_addr, conn_attrs)
+                                        .await
                                 },
                             )
                             //.v3(v3::MqttServer::new(handshake_v3)
@@ -289,10 +457,20 @@ async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
                             .v5(
                                 //v5::MqttServer::new(handshake_v5)
                                 v5::MqttServer::new(
-                                    move |mut handshake: HandshakeV5<TlsStream<TcpStream>>| async {
-                                        let (io, _) = handshake.io().get_ref();
+                                    move |mut handshake: HandshakeV5<
+                                        TlsStream<proxy::ProxyStream<TcpStream>>,
+                                    >| async {
+                                        let (io, tls) = handshake.io().get_ref();
                                         let peer_addr = io.peer_addr()?;
                                         let local_addr = io.local_addr()?;
+                                        let mut conn_attrs = io.conn_attrs();
+                                        tls::set_peer_cert(&mut conn_attrs, tls.peer_certificates());
+                                        ocsp::check(
+                                            local_addr.port(),
+                                            tls.peer_certificates(),
+                                            &mut conn_attrs,
+                                        )
+                                        .await;
                                         let listen_cfg = Runtime::instance()
                                             .settings
                                             .listeners
@@ -304,7 +482,8 @@ async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
                                                 );
                                                 MqttError::ListenerConfigError
                                             })?;
-                                        handshake_v5(listen_cfg, handshake, peer_addr, local_addr).await
+                                        handshake_v5(listen_cfg, handshake, peer_addr, local_addr, conn_attrs)
+                                            .await
                                     },
                                 )
                                 .receive_max(max_inflight as u16)
@@ -328,9 +507,10 @@ async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
                     )
             })?
             .workers(listen_cfg.workers)
-            .maxconn(listen_cfg.max_connections / listen_cfg.workers)
-            .run()
-            .await?;
+            //max_connections is checked by ConnLimitServer, so that it can be changed at runtime
+            .maxconn(usize::MAX)
+            .run();
+        serve(ListenerId::Port(listen_cfg.addr.port()), srv).await?;
         Ok(())
     }
 
@@ -352,83 +532,111 @@ async fn listen_ws(name: String, listen_cfg: &Listener) -> Result<()> {
         let max_inflight = listen_cfg.max_inflight.get() as usize;
         let handshake_timeout = listen_cfg.handshake_timeout();
         let max_size = listen_cfg.max_packet_size.as_u32();
-        ntex::server::Server::build()
+        let proxy_protocol = listen_cfg.proxy_protocol;
+        let proxy_protocol_timeout = listen_cfg.proxy_protocol_timeout;
+        let conn_counter = ConnCounter::new(ListenerId::Port(listen_cfg.addr.port()));
+        let conn_limiter = ConnLimiter::new(listen_cfg);
+        let ip_filter = IpFilters::instance().register(listen_cfg);
+        let ws_opts = ws::WsOptions::new(listen_cfg);
+        let srv = ntex::server::Server::build()
+            .disable_signals()
             .backlog(listen_cfg.backlog)
             .reuseaddr(listen_cfg.reuseaddr)
             .reuseport(listen_cfg.reuseport)
             .bind(name, listen_cfg.addr, move || {
-                pipeline_factory(ws::WSServer::new(Duration::from_secs(handshake_timeout as u64))).and_then(
-                    MqttServer::new()
-                        .v3(v3::MqttServer::new(
-                            move |mut handshake: HandshakeV3<ws::WsStream<TcpStream>>| async {
-                                let io = handshake.io().get_ref();
-                                let remote_addr = io.peer_addr()?;
-                                let local_addr = io.local_addr()?;
-                                let listen_cfg =
-                                    Runtime::instance().settings.listeners.ws(local_addr.port()).ok_or_else(
-                                        || {
+                pipeline_factory(limit::IpFilterServer::new(ip_filter.clone()))
+                    .and_then(proxy::ProxyServer::new(proxy_protocol, proxy_protocol_timeout))
+                    .and_then(limit::IpFilterServer::new(ip_filter.clone()))
+                    .and_then(limit::ConnLimitServer::new(conn_counter.clone(), conn_limiter.clone()))
+                    .and_then(ws::WSServer::new(
+                        Duration::from_secs(handshake_timeout as u64),
+                        ws_opts.clone(),
+                    ))
+                    .and_then(
+                        MqttServer::new()
+                            .v3(v3::MqttServer::new(
+                                move |mut handshake: HandshakeV3<
+                                    ws::WsStream<proxy::ProxyStream<TcpStream>>,
+                                >| async {
+                                    let io = handshake.io().get_ref();
+                                    let remote_addr = io.peer_addr()?;
+                                    let local_addr = io.local_addr()?;
+                                    let mut conn_attrs = io.conn_attrs();
+                                    handshake.io().set_request_attrs(&mut conn_attrs);
+                                    let listen_cfg = Runtime::instance()
+                                        .settings
+                                        .listeners
+                                        .ws(local_addr.port())
+                                        .ok_or_else(|| {
                                             log::error!(
                                                 "ws listener config is not found, local addr is {:?}",
                                                 local_addr
                                             );
                                             MqttError::ListenerConfigError
-                                        },
-                                    )?;
-                                handshake_v3(listen_cfg, handshake, remote_addr, local_addr).await
-                            },
-                        )
-                        .inflight(max_inflight)
-                        .handshake_timeout(handshake_timeout)
-                        .max_size(max_size)
-                        .publish(fn_factory_with_config(|session: v3::Session<SessionState>| {
-                            ok::<_, MqttError>(fn_service(move |req| publish_v3(session.clone(), req)))
-                        }))
-                        .control(fn_factory_with_config(
-                            |session: v3::Session<SessionState>| {
-                                ok::<_, MqttError>(fn_service(move |req| {
-                                    control_message_v3(session.clone(), req)
-                                }))
-                            },
-                        )))
-                        .v5(v5::MqttServer::new(
-                            move |mut handshake: HandshakeV5<ws::WsStream<TcpStream>>| async {
-                                let io = handshake.io().get_ref();
-                                let remote_addr = io.peer_addr()?;
-                                let local_addr = io.local_addr()?;
-                                let listen_cfg =
-                                    Runtime::instance().settings.listeners.ws(local_addr.port()).ok_or_else(
-                                        || {
+                                        })?;
+                                    handshake_v3(listen_cfg, handshake, remote_addr, local_addr, conn_attrs)
+                                        .await
+                                },
+                            )
+                            .inflight(max_inflight)
+                            .handshake_timeout(handshake_timeout)
+                            .max_size(max_size)
+                            .publish(fn_factory_with_config(|session: v3::Session<SessionState>| {
+                                ok::<_, MqttError>(fn_service(move |req| publish_v3(session.clone(), req)))
+                            }))
+                            .control(fn_factory_with_config(
+                                |session: v3::Session<SessionState>| {
+                                    ok::<_, MqttError>(fn_service(move |req| {
+                                        control_message_v3(session.clone(), req)
+                                    }))
+                                },
+                            )))
+                            .v5(v5::MqttServer::new(
+                                move |mut handshake: HandshakeV5<
+                                    ws::WsStream<proxy::ProxyStream<TcpStream>>,
+                                >| async {
+                                    let io = handshake.io().get_ref();
+                                    let remote_addr = io.peer_addr()?;
+                                    let local_addr = io.local_addr()?;
+                                    let mut conn_attrs = io.conn_attrs();
+                                    handshake.io().set_request_attrs(&mut conn_attrs);
+                                    let listen_cfg = Runtime::instance()
+                                        .settings
+                                        .listeners
+                                        .ws(local_addr.port())
+                                        .ok_or_else(|| {
                                             log::error!(
                                                 "ws listener config is not found, local addr is {:?}",
                                                 local_addr
                                             );
                                             MqttError::ListenerConfigError
-                                        },
-                                    )?;
-                                handshake_v5(listen_cfg, handshake, remote_addr, local_addr).await
-                            },
-                        )
-                        .receive_max(max_inflight as u16)
-                        .handshake_timeout(handshake_timeout)
-                        .max_size(max_size)
-                        // .max_qos(max_qos)
-                        //.max_topic_alias(max_topic_alias),
-                        .publish(fn_factory_with_config(|session: v5::Session<SessionState>| {
-                            ok::<_, MqttError>(fn_service(move |req| publish_v5(session.clone(), req)))
-                        }))
-                        .control(fn_factory_with_config(
-                            |session: v5::Session<SessionState>| {
-                                ok::<_, MqttError>(fn_service(move |req| {
-                                    control_message_v5(session.clone(), req)
-                                }))
-                            },
-                        ))),
-                )
+                                        })?;
+                                    handshake_v5(listen_cfg, handshake, remote_addr, local_addr, conn_attrs)
+                                        .await
+                                },
+                            )
+                            .receive_max(max_inflight as u16)
+                            .handshake_timeout(handshake_timeout)
+                            .max_size(max_size)
+                            // .max_qos(max_qos)
+                            //.max_topic_alias(max_topic_alias),
+                            .publish(fn_factory_with_config(|session: v5::Session<SessionState>| {
+                                ok::<_, MqttError>(fn_service(move |req| publish_v5(session.clone(), req)))
+                            }))
+                            .control(fn_factory_with_config(
+                                |session: v5::Session<SessionState>| {
+                                    ok::<_, MqttError>(fn_service(move |req| {
+                                        control_message_v5(session.clone(), req)
+                                    }))
+                                },
+                            ))),
+                    )
             })?
             .workers(listen_cfg.workers)
-            .maxconn(listen_cfg.max_connections / listen_cfg.workers)
-            .run()
-            .await?;
+            //max_connections is checked by ConnLimitServer, so that it can be changed at runtime
+            .maxconn(usize::MAX)
+            .run();
+        serve(ListenerId::Port(listen_cfg.addr.port()), srv).await?;
         Ok(())
     }
 
@@ -440,57 +648,50 @@ async fn listen_ws(name: String, listen_cfg: &Listener) -> Result<()> {
 
 async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
     async fn _listen_wss(name: &str, listen_cfg: &Listener) -> Result<()> {
-        let cert_file = &mut BufReader::new(File::open(
-            listen_cfg.cert.as_ref().ok_or::<MqttError>("cert is None".into())?,
-        )?);
-        let key_file = &mut BufReader::new(File::open(
-            listen_cfg.key.as_ref().ok_or::<MqttError>("key is None".into())?,
-        )?);
-
-        let cert_chain = rustls_pemfile::certs(cert_file).collect::<Result<Vec<_>, _>>()?;
-        let key = rustls_pemfile::private_key(key_file)?.ok_or::<MqttError>("key_file is None".into())?;
-
-        let provider = Arc::new(provider::default_provider());
-        let client_auth = if listen_cfg.cross_certificate {
-            let root_chain = cert_chain.clone();
-            let mut client_auth_roots = RootCertStore::empty();
-            for root in root_chain {
-                client_auth_roots.add(root).map_err(|e| anyhow!(e))?;
-            }
-            WebPkiClientVerifier::builder_with_provider(client_auth_roots.into(), provider.clone())
-                .build()
-                .map_err(|e| anyhow!(e))?
-        } else {
-            WebPkiClientVerifier::no_client_auth()
-        };
-
-        let tls_config = ServerConfig::builder_with_provider(provider)
-            .with_safe_default_protocol_versions()
-            .map_err(|e| anyhow!(e))?
-            .with_client_cert_verifier(client_auth)
-            .with_single_cert(cert_chain, key)
-            .map_err(|e| anyhow!(format!("bad certs/private key, {}", e)))?;
-
+        let (tls_config, _tls_guard) = tls::server_config(name, listen_cfg)?;
         let tls_acceptor = Acceptor::new(tls_config);
 
         let max_inflight = listen_cfg.max_inflight.get() as usize;
         let handshake_timeout = listen_cfg.handshake_timeout();
         let max_size = listen_cfg.max_packet_size.as_u32();
-        ntex::server::Server::build()
+        let proxy_protocol = listen_cfg.proxy_protocol;
+        let proxy_protocol_timeout = listen_cfg.proxy_protocol_timeout;
+        let conn_counter = ConnCounter::new(ListenerId::Port(listen_cfg.addr.port()));
+        let conn_limiter = ConnLimiter::new(listen_cfg);
+        let ip_filter = IpFilters::instance().register(listen_cfg);
+        let ws_opts = ws::WsOptions::new(listen_cfg);
+        let srv = ntex::server::Server::build()
+            .disable_signals()
             .backlog(listen_cfg.backlog)
             .reuseaddr(listen_cfg.reuseaddr)
             .reuseport(listen_cfg.reuseport)
             .bind(name, listen_cfg.addr, move || {
-                pipeline_factory(tls_acceptor.clone())
-                    .map_err(|e| ntex_mqtt::MqttError::Service(MqttError::from(e)))
-                    .and_then(ws::WSServer::new(Duration::from_secs(handshake_timeout as u64)))
+                pipeline_factory(limit::IpFilterServer::new(ip_filter.clone()))
+                    .and_then(proxy::ProxyServer::new(proxy_protocol, proxy_protocol_timeout))
+                    .and_then(limit::IpFilterServer::new(ip_filter.clone()))
+                    .and_then(limit::ConnLimitServer::new(conn_counter.clone(), conn_limiter.clone()))
+                    .and_then(
+                        pipeline_factory(tls_acceptor.clone())
+                            .map_err(|e| ntex_mqtt::MqttError::Service(MqttError::from(e))),
+                    )
+                    .and_then(ws::WSServer::new(
+                        Duration::from_secs(handshake_timeout as u64),
+                        ws_opts.clone(),
+                    ))
                     .and_then(
                         MqttServer::new()
                             .v3(v3::MqttServer::new(
-                                move |mut handshake: HandshakeV3<ws::WsStream<TlsStream<TcpStream>>>| async {
-                                    let (io, _) = handshake.io().get_ref().get_ref();
+                                move |mut handshake: HandshakeV3<
+                                    ws::WsStream<TlsStream<proxy::ProxyStream<TcpStream>>>,
+                                >| async {
+                                    let (io, tls) = handshake.io().get_ref().get_ref();
                                     let peer_addr = io.peer_addr()?;
                                     let local_addr = io.local_addr()?;
+                                    let mut conn_attrs = io.conn_attrs();
+                                    tls::set_peer_cert(&mut conn_attrs, tls.peer_certificates());
+                                    ocsp::check(local_addr.port(), tls.peer_certificates(), &mut conn_attrs)
+                                        .await;
+                                    handshake.io().set_request_attrs(&mut conn_attrs);
                                     let listen_cfg = Runtime::instance()
                                         .settings
                                         .listeners
@@ -503,7 +704,8 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
                                             MqttError::ListenerConfigError
                                         })?;
 
-                                    handshake_v3(listen_cfg, handshake, peer_addr, local_addr).await
+                                    handshake_v3(listen_cfg, handshake, peer_addr, local_addr, conn_attrs)
+                                        .await
                                 },
                             )
                             .inflight(max_inflight)
@@ -520,10 +722,17 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
                                 },
                             )))
                             .v5(v5::MqttServer::new(
-                                move |mut handshake: HandshakeV5<ws::WsStream<TlsStream<TcpStream>>>| async {
-                                    let (io, _) = handshake.io().get_ref().get_ref();
+                                move |mut handshake: HandshakeV5<
+                                    ws::WsStream<TlsStream<proxy::ProxyStream<TcpStream>>>,
+                                >| async {
+                                    let (io, tls) = handshake.io().get_ref().get_ref();
                                     let peer_addr = io.peer_addr()?;
                                     let local_addr = io.local_addr()?;
+                                    let mut conn_attrs = io.conn_attrs();
+                                    tls::set_peer_cert(&mut conn_attrs, tls.peer_certificates());
+                                    ocsp::check(local_addr.port(), tls.peer_certificates(), &mut conn_attrs)
+                                        .await;
+                                    handshake.io().set_request_attrs(&mut conn_attrs);
                                     let listen_cfg = Runtime::instance()
                                         .settings
                                         .listeners
@@ -535,7 +744,8 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
                                             );
                                             MqttError::ListenerConfigError
                                         })?;
-                                    handshake_v5(listen_cfg, handshake, peer_addr, local_addr).await
+                                    handshake_v5(listen_cfg, handshake, peer_addr, local_addr, conn_attrs)
+                                        .await
                                 },
                             )
                             .receive_max(max_inflight as u16)
@@ -556,9 +766,10 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
                     )
             })?
             .workers(listen_cfg.workers)
-            .maxconn(listen_cfg.max_connections / listen_cfg.workers)
-            .run()
-            .await?;
+            //max_connections is checked by ConnLimitServer, so that it can be changed at runtime
+            .maxconn(usize::MAX)
+            .run();
+        serve(ListenerId::Port(listen_cfg.addr.port()), srv).await?;
         Ok(())
     }
 
@@ -574,3 +785,137 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
         e
     })
 }
+
+async fn listen_quic(name: String, listen_cfg: &Listener) -> Result<()> {
+    quic::listen(&format!("quic: {}", name), listen_cfg).await.map_err(|e| {
+        log::error!(
+            "listen_quic {:?} failed on {}, cert: {:?}, key: {:?}, {:?}",
+            name,
+            listen_cfg.addr,
+            listen_cfg.cert,
+            listen_cfg.key,
+            e
+        );
+        e
+    })
+}
+
+async fn listen_mqttsn(name: String, listen_cfg: &Listener) -> Result<()> {
+    mqttsn::listen(&format!("mqttsn: {}", name), listen_cfg).await.map_err(|e| {
+        log::error!("listen_mqttsn {:?} failed on {}, {:?}", name, listen_cfg.addr, e);
+        e
+    })
+}
+
+async fn listen_coap(name: String, listen_cfg: &Listener) -> Result<()> {
+    coap::listen(&format!("coap: {}", name), listen_cfg).await.map_err(|e| {
+        log::error!("listen_coap {:?} failed on {}, {:?}", name, listen_cfg.addr, e);
+        e
+    })
+}
+
+#[cfg(unix)]
+async fn listen_unix(name: String, listen_cfg: &Listener) -> Result<()> {
+    async fn _listen_unix(name: &str, listen_cfg: &Listener) -> Result<()> {
+        let path = listen_cfg.path.clone().ok_or::<MqttError>("path is None".into())?;
+        let max_inflight = listen_cfg.max_inflight.get() as usize;
+        let handshake_timeout = listen_cfg.handshake_timeout();
+        let max_size = listen_cfg.max_packet_size.as_u32();
+        let conn_counter = ConnCounter::new(ListenerId::Path(path.clone()));
+        let conn_limiter = ConnLimiter::new(listen_cfg);
+        let lst = unix::bind(listen_cfg)?;
+        let srv_path = path.clone();
+        let srv = ntex::server::Server::build()
+            .disable_signals()
+            .listen_uds(name, lst, move || {
+                let (path_v3, path_v5) = (path.clone(), path.clone());
+                //The connections are wrapped like the tcp connections, without the PROXY protocol
+                pipeline_factory(proxy::ProxyServer::new(false, Duration::ZERO))
+                    .and_then(limit::ConnLimitServer::new(conn_counter.clone(), conn_limiter.clone()))
+                    .and_then(
+                        MqttServer::new()
+                            .v3(v3::MqttServer::new(
+                                move |handshake: HandshakeV3<proxy::ProxyStream<UnixStream>>| {
+                                    let path = path_v3.clone();
+                                    async move {
+                                        let listen_cfg = unix_listener_cfg(&path)?;
+                                        let local_addr = listen_cfg.addr;
+                                        let conn_attrs = unix::conn_attrs(handshake.io());
+                                        handshake_v3(
+                                            listen_cfg,
+                                            handshake,
+                                            unix::PEER_ADDR,
+                                            local_addr,
+                                            conn_attrs,
+                                        )
+                                        .await
+                                    }
+                                },
+                            )
+                            .inflight(max_inflight)
+                            .handshake_timeout(handshake_timeout)
+                            .max_size(max_size)
+                            .publish(fn_factory_with_config(|session: v3::Session<SessionState>| {
+                                ok::<_, MqttError>(fn_service(move |req| publish_v3(session.clone(), req)))
+                            }))
+                            .control(fn_factory_with_config(
+                                |session: v3::Session<SessionState>| {
+                                    ok::<_, MqttError>(fn_service(move |req| {
+                                        control_message_v3(session.clone(), req)
+                                    }))
+                                },
+                            )))
+                            .v5(v5::MqttServer::new(
+                                move |handshake: HandshakeV5<proxy::ProxyStream<UnixStream>>| {
+                                    let path = path_v5.clone();
+                                    async move {
+                                        let listen_cfg = unix_listener_cfg(&path)?;
+                                        let local_addr = listen_cfg.addr;
+                                        let conn_attrs = unix::conn_attrs(handshake.io());
+                                        handshake_v5(
+                                            listen_cfg,
+                                            handshake,
+                                            unix::PEER_ADDR,
+                                            local_addr,
+                                            conn_attrs,
+                                        )
+                                        .await
+                                    }
+                                },
+                            )
+                            .receive_max(max_inflight as u16)
+                            .handshake_timeout(handshake_timeout)
+                            .max_size(max_size)
+                            .publish(fn_factory_with_config(|session: v5::Session<SessionState>| {
+                                ok::<_, MqttError>(fn_service(move |req| publish_v5(session.clone(), req)))
+                            }))
+                            .control(fn_factory_with_config(
+                                |session: v5::Session<SessionState>| {
+                                    ok::<_, MqttError>(fn_service(move |req| {
+                                        control_message_v5(session.clone(), req)
+                                    }))
+                                },
+                            ))),
+                    )
+            })?
+            .workers(listen_cfg.workers)
+            //max_connections is checked by ConnLimitServer, so that it can be changed at runtime
+            .maxconn(usize::MAX)
+            .run();
+        serve(ListenerId::Path(srv_path), srv).await?;
+        Ok(())
+    }
+
+    #[inline]
+    fn unix_listener_cfg(path: &str) -> Result<Listener> {
+        Runtime::instance().settings.listeners.unix(path).ok_or_else(|| {
+            log::error!("unix listener config is not found, path is {:?}", path);
+            MqttError::ListenerConfigError
+        })
+    }
+
+    _listen_unix(&format!("unix: {}", name), listen_cfg).await.map_err(|e| {
+        log::error!("listen_unix {:?} failed on {:?}, {:?}", name, listen_cfg.path, e);
+        e
+    })
+}
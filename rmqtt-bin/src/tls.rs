@@ -0,0 +1,420 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use std::{fmt, fs};
+
+use rustls::client::danger::HandshakeSignatureValid;
+#[cfg(not(target_os = "windows"))]
+use rustls::crypto::aws_lc_rs as provider;
+#[cfg(target_os = "windows")]
+use rustls::crypto::ring as provider;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    CertificateError, DigitallySignedStruct, DistinguishedName, Error as TlsError, RootCertStore,
+    ServerConfig, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use rmqtt::anyhow::anyhow;
+use rmqtt::broker::peer_cert::{PeerCert, PEER_CERT_KEY};
+use rmqtt::futures::future::{AbortHandle, Abortable};
+use rmqtt::ntex;
+use rmqtt::rust_box::std_ext::RwLock;
+use rmqtt::settings::listener::Listener;
+use rmqtt::{log, tokio, ExtraAttrs, MqttError, Result, Runtime};
+
+use crate::ocsp;
+
+///Build the rustls config of the tls/wss listener.
+///
+///The certificates are served by a resolver that picks the certificate by SNI hostname, and reloads
+///the certificate and key files when they change on disk or on SIGHUP. The client CA of `cross_certificate`
+///is loaded only once, the certificate revocation lists of `crl_file` are reloaded in the same way.
+///The reloading and the OCSP check are stopped when the returned guard is dropped, the listener keeps it
+///while it is running.
+pub(crate) fn server_config(name: &str, listen_cfg: &Listener) -> Result<(ServerConfig, TlsGuard)> {
+    let provider = Arc::new(provider::default_provider());
+
+    let cert = listen_cfg.cert.as_ref().ok_or::<MqttError>("cert is None".into())?;
+    let key = listen_cfg.key.as_ref().ok_or::<MqttError>("key is None".into())?;
+    let default = CertEntry::new(cert, key, &provider)?;
+    let snis = listen_cfg
+        .sni
+        .iter()
+        .map(|sni| Ok((sni.hostname.to_ascii_lowercase(), CertEntry::new(&sni.cert, &sni.key, &provider)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let resolver = Arc::new(CertResolver { provider: provider.clone(), default, snis });
+    let mut reloadables: Vec<Arc<dyn Reloadable>> = vec![resolver.clone()];
+
+    let mut ocsp_registration = None;
+    let client_auth: Arc<dyn ClientCertVerifier> = if listen_cfg.cross_certificate {
+        let roots = load_certs(cert)?;
+        let mut client_auth_roots = RootCertStore::empty();
+        for root in roots.iter() {
+            client_auth_roots.add(root.clone()).map_err(|e| anyhow!(e))?;
+        }
+        let verifier = Arc::new(ClientVerifier::new(
+            client_auth_roots.into(),
+            listen_cfg.crl_file.clone(),
+            provider.clone(),
+        )?);
+        reloadables.push(verifier.clone());
+        if listen_cfg.ocsp {
+            ocsp_registration = Some(ocsp::register(listen_cfg, roots, provider.clone())?);
+        }
+        verifier
+    } else {
+        WebPkiClientVerifier::no_client_auth()
+    };
+
+    let (watch_abort, abort_registration) = AbortHandle::new_pair();
+    ntex::rt::spawn(Abortable::new(
+        watch(name.to_owned(), listen_cfg.tls_reload_interval, reloadables),
+        abort_registration,
+    ));
+
+    let tls_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| anyhow!(e))?
+        .with_client_cert_verifier(client_auth)
+        .with_cert_resolver(resolver);
+    Ok((tls_config, TlsGuard { watch_abort, _ocsp_registration: ocsp_registration }))
+}
+
+///Stops the reloading of the files and unregisters the OCSP check of the listener when it is dropped
+pub(crate) struct TlsGuard {
+    watch_abort: AbortHandle,
+    _ocsp_registration: Option<ocsp::Registration>,
+}
+
+impl Drop for TlsGuard {
+    #[inline]
+    fn drop(&mut self) {
+        self.watch_abort.abort();
+    }
+}
+
+///Files of the listener that are reloaded without restarting it
+trait Reloadable: Send + Sync {
+    fn reload(&self, name: &str, force: bool);
+}
+
+///Check the files every interval, all of them are reloaded on SIGHUP
+async fn watch(name: String, interval: Duration, reloadables: Vec<Arc<dyn Reloadable>>) {
+    #[cfg(unix)]
+    let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .map_err(|e| log::warn!("listen SIGHUP signal failed, {:?}", e))
+        .ok();
+    loop {
+        let tick = async {
+            if interval.is_zero() {
+                std::future::pending::<()>().await
+            } else {
+                tokio::time::sleep(interval).await
+            }
+        };
+        #[cfg(unix)]
+        let hangup = async {
+            match sighup.as_mut() {
+                Some(sighup) => {
+                    sighup.recv().await;
+                }
+                None => std::future::pending::<()>().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let hangup = std::future::pending::<()>();
+
+        let force = tokio::select! {
+            _ = tick => false,
+            _ = hangup => true,
+        };
+        for r in reloadables.iter() {
+            r.reload(&name, force);
+        }
+    }
+}
+
+struct CertResolver {
+    provider: Arc<CryptoProvider>,
+    default: CertEntry,
+    //hostname => certificate
+    snis: Vec<(String, CertEntry)>,
+}
+
+impl CertResolver {
+    ///Exact hostname first, then the wildcard of the parent domain
+    fn find(&self, server_name: &str) -> Option<&CertEntry> {
+        let server_name = server_name.to_ascii_lowercase();
+        if let Some((_, entry)) = self.snis.iter().find(|(hostname, _)| *hostname == server_name) {
+            return Some(entry);
+        }
+        let (_, parent) = server_name.split_once('.')?;
+        self.snis
+            .iter()
+            .find(|(hostname, _)| hostname.strip_prefix("*.").map(|h| h == parent).unwrap_or_default())
+            .map(|(_, entry)| entry)
+    }
+}
+
+impl Reloadable for CertResolver {
+    fn reload(&self, name: &str, force: bool) {
+        let entries = std::iter::once(&self.default).chain(self.snis.iter().map(|(_, entry)| entry));
+        for entry in entries {
+            match entry.reload(&self.provider, force) {
+                Ok(true) => log::info!("{} reloaded certificate {:?}", name, entry.cert_file),
+                Ok(false) => {}
+                Err(e) => log::warn!("{} reload certificate {:?} failed, {:?}", name, entry.cert_file, e),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let entry = client_hello.server_name().and_then(|name| self.find(name)).unwrap_or(&self.default);
+        Some(entry.certified_key())
+    }
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("default", &self.default.cert_file)
+            .field("snis", &self.snis.iter().map(|(hostname, _)| hostname).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+///Client certificate verifier of the cross_certificate listener, checks the certificate revocation
+///lists of the crl_file, which are reloaded when the file changes
+struct ClientVerifier {
+    roots: Arc<RootCertStore>,
+    crl_file: Option<String>,
+    provider: Arc<CryptoProvider>,
+    root_hints: Vec<DistinguishedName>,
+    crl_modified: RwLock<Option<SystemTime>>,
+    inner: RwLock<Arc<dyn ClientCertVerifier>>,
+}
+
+impl ClientVerifier {
+    fn new(
+        roots: Arc<RootCertStore>,
+        crl_file: Option<String>,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Self> {
+        let crl_modified = crl_file.as_ref().and_then(|f| fs::metadata(f).and_then(|m| m.modified()).ok());
+        let inner = Self::build(&roots, crl_file.as_deref(), &provider)?;
+        let root_hints = inner.root_hint_subjects().to_vec();
+        Ok(Self {
+            roots,
+            crl_file,
+            provider,
+            root_hints,
+            crl_modified: RwLock::new(crl_modified),
+            inner: RwLock::new(inner),
+        })
+    }
+
+    fn build(
+        roots: &Arc<RootCertStore>,
+        crl_file: Option<&str>,
+        provider: &Arc<CryptoProvider>,
+    ) -> Result<Arc<dyn ClientCertVerifier>> {
+        let mut builder = WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone());
+        if let Some(crl_file) = crl_file {
+            let crls = load_crls(crl_file)?;
+            builder =
+                builder.with_crls(crls).only_check_end_entity_revocation().allow_unknown_revocation_status();
+        }
+        Ok(builder.build().map_err(|e| anyhow!(e))?)
+    }
+
+    #[inline]
+    fn inner(&self) -> Arc<dyn ClientCertVerifier> {
+        self.inner.read().clone()
+    }
+}
+
+impl Reloadable for ClientVerifier {
+    fn reload(&self, name: &str, force: bool) {
+        let crl_file = if let Some(crl_file) = &self.crl_file {
+            crl_file
+        } else {
+            return;
+        };
+        let modified = fs::metadata(crl_file).and_then(|m| m.modified()).ok();
+        if !force && *self.crl_modified.read() == modified {
+            return;
+        }
+        match Self::build(&self.roots, Some(crl_file), &self.provider) {
+            Ok(inner) => {
+                *self.inner.write() = inner;
+                *self.crl_modified.write() = modified;
+                log::info!("{} reloaded crl {:?}", name, crl_file);
+            }
+            Err(e) => log::warn!("{} reload crl {:?} failed, {:?}", name, crl_file, e),
+        }
+    }
+}
+
+impl ClientCertVerifier for ClientVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &self.root_hints
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> std::result::Result<ClientCertVerified, TlsError> {
+        let res = self.inner().verify_client_cert(end_entity, intermediates, now);
+        if let Err(TlsError::InvalidCertificate(CertificateError::Revoked)) = &res {
+            log::info!("client certificate is revoked");
+            Runtime::instance().metrics.client_cert_revoked_inc();
+        }
+        res
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, TlsError> {
+        self.inner().verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, TlsError> {
+        self.inner().verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner().supported_verify_schemes()
+    }
+}
+
+impl fmt::Debug for ClientVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientVerifier").field("crl_file", &self.crl_file).finish()
+    }
+}
+
+type Modified = (Option<SystemTime>, Option<SystemTime>);
+
+struct CertEntry {
+    cert_file: String,
+    key_file: String,
+    modified: RwLock<Modified>,
+    certified_key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertEntry {
+    fn new(cert_file: &str, key_file: &str, provider: &CryptoProvider) -> Result<Self> {
+        let modified = Self::modified(cert_file, key_file);
+        let certified_key = load_certified_key(cert_file, key_file, provider)?;
+        Ok(Self {
+            cert_file: cert_file.to_owned(),
+            key_file: key_file.to_owned(),
+            modified: RwLock::new(modified),
+            certified_key: RwLock::new(Arc::new(certified_key)),
+        })
+    }
+
+    #[inline]
+    fn certified_key(&self) -> Arc<CertifiedKey> {
+        self.certified_key.read().clone()
+    }
+
+    ///Returns true if the certificate is reloaded, the old one is kept if the files can not be loaded
+    fn reload(&self, provider: &CryptoProvider, force: bool) -> Result<bool> {
+        let modified = Self::modified(&self.cert_file, &self.key_file);
+        if !force && *self.modified.read() == modified {
+            return Ok(false);
+        }
+        let certified_key = load_certified_key(&self.cert_file, &self.key_file, provider)?;
+        *self.certified_key.write() = Arc::new(certified_key);
+        *self.modified.write() = modified;
+        Ok(true)
+    }
+
+    #[inline]
+    fn modified(cert_file: &str, key_file: &str) -> Modified {
+        let modified = |f: &str| fs::metadata(f).and_then(|m| m.modified()).ok();
+        (modified(cert_file), modified(key_file))
+    }
+}
+
+fn load_certs(cert_file: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let cert_file = &mut BufReader::new(File::open(cert_file)?);
+    let certs = rustls_pemfile::certs(cert_file).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(MqttError::from("cert_file is empty"));
+    }
+    Ok(certs)
+}
+
+fn load_crls(crl_file: &str) -> Result<Vec<CertificateRevocationListDer<'static>>> {
+    let crl_file = &mut BufReader::new(File::open(crl_file)?);
+    let crls = rustls_pemfile::crls(crl_file).collect::<Result<Vec<_>, _>>()?;
+    if crls.is_empty() {
+        return Err(MqttError::from("crl_file is empty"));
+    }
+    Ok(crls)
+}
+
+fn load_certified_key(cert_file: &str, key_file: &str, provider: &CryptoProvider) -> Result<CertifiedKey> {
+    let cert_chain = load_certs(cert_file)?;
+    let key_file = &mut BufReader::new(File::open(key_file)?);
+    let key = rustls_pemfile::private_key(key_file)?.ok_or::<MqttError>("key_file is None".into())?;
+    let key = provider
+        .key_provider
+        .load_private_key(key)
+        .map_err(|e| anyhow!(format!("bad certs/private key, {}", e)))?;
+    Ok(CertifiedKey::new(cert_chain, key))
+}
+
+///Keep the fields of the client certificate in the connection attributes
+pub(crate) fn set_peer_cert(conn_attrs: &mut ExtraAttrs, peer_certs: Option<&[CertificateDer<'static>]>) {
+    let der = match peer_certs.and_then(|certs| certs.first()) {
+        Some(der) => der,
+        None => return,
+    };
+    let cert = match X509Certificate::from_der(der.as_ref()) {
+        Ok((_, cert)) => cert,
+        Err(e) => {
+            log::warn!("parse the client certificate failed, {:?}", e);
+            return;
+        }
+    };
+    let subject = cert.subject();
+    let cn = subject.iter_common_name().next().and_then(|cn| cn.as_str().ok()).map(|cn| cn.to_owned());
+    let san_dns = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(dns) => Some((*dns).to_owned()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    let fingerprint = Sha256::digest(der.as_ref()).iter().map(|b| format!("{:02x}", b)).collect();
+    conn_attrs
+        .insert(PEER_CERT_KEY.into(), PeerCert { subject: subject.to_string(), cn, san_dns, fingerprint });
+}
@@ -0,0 +1,69 @@
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+
+use rmqtt::broker::peer_cred::{PeerCred, PEER_CRED_KEY};
+use rmqtt::ntex::rt::net::UnixStream;
+use rmqtt::settings::listener::Listener;
+use rmqtt::{log, ExtraAttrs, MqttError, Result};
+
+use crate::proxy::ProxyStream;
+
+///The remote address of the unix socket connections, the peer is always on the local host
+pub(crate) const PEER_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+///Bind the socket file of the listener. The socket is created in a private directory and moved to
+///the path after its permission and ownership are set, so that it is never reachable with the
+///default permission. The umask is not used, it is shared by all threads of the process
+pub(crate) fn bind(listen_cfg: &Listener) -> Result<UnixListener> {
+    let path = Path::new(listen_cfg.path.as_deref().ok_or_else(|| MqttError::from("path is None"))?);
+    let file_name =
+        path.file_name().ok_or_else(|| MqttError::from(format!("invalid socket path {:?}", path)))?;
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let tmp_dir = parent.join(format!(".{}.{}", file_name.to_string_lossy(), std::process::id()));
+    let _ = fs::remove_dir_all(&tmp_dir);
+    fs::DirBuilder::new().mode(0o700).create(&tmp_dir)?;
+
+    let bound = (|| -> Result<UnixListener> {
+        let tmp_path = tmp_dir.join(file_name);
+        let lst = UnixListener::bind(&tmp_path)?;
+        set_permissions(&tmp_path, listen_cfg)?;
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        fs::rename(&tmp_path, path)?;
+        lst.set_nonblocking(true)?;
+        Ok(lst)
+    })();
+    if let Err(e) = fs::remove_dir_all(&tmp_dir) {
+        log::warn!("remove the directory {:?} failed, {:?}", tmp_dir, e);
+    }
+    bound
+}
+
+///Apply the permission and ownership options of the listener to the socket file
+fn set_permissions(path: &Path, listen_cfg: &Listener) -> Result<()> {
+    if let Some(mode) = listen_cfg.path_mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    if listen_cfg.path_uid.is_some() || listen_cfg.path_gid.is_some() {
+        std::os::unix::fs::chown(path, listen_cfg.path_uid, listen_cfg.path_gid)?;
+    }
+    Ok(())
+}
+
+///Keep the credentials of the peer process in the connection attributes
+pub(crate) fn conn_attrs(io: &ProxyStream<UnixStream>) -> ExtraAttrs {
+    let mut attrs = io.conn_attrs();
+    match io.get_ref().peer_cred() {
+        Ok(cred) => {
+            attrs.insert(PEER_CRED_KEY.into(), PeerCred { uid: cred.uid(), gid: cred.gid(), pid: cred.pid() })
+        }
+        Err(e) => log::warn!("get the peer credentials of the unix socket failed, {:?}", e),
+    }
+    attrs
+}
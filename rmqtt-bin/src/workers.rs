@@ -0,0 +1,35 @@
+//! Arbiters of the listeners that are not run by the ntex server(QUIC and UDP), the connections
+//! are distributed to them in turn.
+
+use rmqtt::ntex::rt::Arbiter;
+
+///The arbiters are stopped when it is dropped, that is when the listener returns or is aborted
+pub(crate) struct Workers {
+    arbiters: Vec<Arbiter>,
+    next: usize,
+}
+
+impl Workers {
+    #[inline]
+    pub(crate) fn new(workers: usize) -> Self {
+        Self { arbiters: (0..workers.max(1)).map(|_| Arbiter::new()).collect(), next: 0 }
+    }
+
+    ///Execute the function on the next arbiter
+    #[inline]
+    pub(crate) fn exec_fn<F>(&mut self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.arbiters[self.next].exec_fn(f);
+        self.next = (self.next + 1) % self.arbiters.len();
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        for arbiter in self.arbiters.iter() {
+            arbiter.stop();
+        }
+    }
+}
@@ -1,5 +1,8 @@
+use std::cell::{Cell, RefCell};
 use std::future::Future;
 use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::{
     io::{self, ErrorKind},
@@ -7,6 +10,7 @@ use std::{
     time::Duration,
 };
 
+use rmqtt::broker::ws_request::{WsRequest, WS_REQUEST_KEY};
 use rmqtt::futures::{ready, FutureExt, Sink, Stream};
 use rmqtt::ntex::codec::ReadBuf;
 use rmqtt::ntex::codec::{AsyncRead, AsyncWrite};
@@ -15,27 +19,56 @@ use rmqtt::ntex::util::Ready;
 use rmqtt::ntex::{Service, ServiceFactory};
 use rmqtt::ntex_mqtt;
 use rmqtt::pin_project_lite;
+use rmqtt::settings::listener::Listener;
 use rmqtt::tokio_tungstenite::accept_hdr_async;
 use rmqtt::tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use rmqtt::tokio_tungstenite::tungstenite::http::{header, HeaderValue, StatusCode};
 use rmqtt::tokio_tungstenite::tungstenite::Error as WSError;
 use rmqtt::tokio_tungstenite::tungstenite::Message;
 use rmqtt::tokio_tungstenite::WebSocketStream;
-use rmqtt::{log, MqttError};
+use rmqtt::{log, ExtraAttrs, MqttError};
+
+use crate::ws_deflate::{self, DeflateStream};
+
+///Checks of the upgrade request and the negotiated extension, see `ws_path`, `ws_subprotocols`,
+///`ws_allowed_origins` and `ws_deflate` of the listener
+#[derive(Debug, Clone)]
+pub struct WsOptions {
+    path: Option<String>,
+    subprotocols: Vec<String>,
+    allowed_origins: Vec<String>,
+    deflate: bool,
+    //Limit of the decompressed messages
+    max_message_size: usize,
+}
+
+impl WsOptions {
+    pub fn new(listen_cfg: &Listener) -> Arc<Self> {
+        Arc::new(WsOptions {
+            path: listen_cfg.ws_path.clone(),
+            subprotocols: listen_cfg.ws_subprotocols.clone(),
+            allowed_origins: listen_cfg.ws_allowed_origins.clone(),
+            deflate: listen_cfg.ws_deflate,
+            max_message_size: listen_cfg.max_packet_size.as_u32() as usize,
+        })
+    }
+}
 
 pub struct WSServer<T> {
     timeout: Duration,
+    opts: Arc<WsOptions>,
     io: marker::PhantomData<T>,
 }
 
 impl<T: AsyncRead + AsyncWrite> WSServer<T> {
-    pub fn new(timeout: Duration) -> Self {
-        WSServer { timeout, io: marker::PhantomData }
+    pub fn new(timeout: Duration, opts: Arc<WsOptions>) -> Self {
+        WSServer { timeout, opts, io: marker::PhantomData }
     }
 }
 
 impl<T> Clone for WSServer<T> {
     fn clone(&self) -> Self {
-        Self { timeout: self.timeout, io: marker::PhantomData }
+        Self { timeout: self.timeout, opts: self.opts.clone(), io: marker::PhantomData }
     }
 }
 
@@ -50,13 +83,14 @@ impl<T: AsyncRead + AsyncWrite + Unpin + 'static> ServiceFactory for WSServer<T>
     type Future = Ready<Self::Service, Self::InitError>;
 
     fn new_service(&self, _: ()) -> Self::Future {
-        Ready::Ok(WSService { timeout: self.timeout, io: marker::PhantomData })
+        Ready::Ok(WSService { timeout: self.timeout, opts: self.opts.clone(), io: marker::PhantomData })
     }
 }
 
 pub struct WSService<T> {
     io: marker::PhantomData<T>,
     timeout: Duration,
+    opts: Arc<WsOptions>,
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin + 'static> Service for WSService<T> {
@@ -72,14 +106,32 @@ impl<T: AsyncRead + AsyncWrite + Unpin + 'static> Service for WSService<T> {
 
     #[inline]
     fn call(&self, req: Self::Request) -> Self::Future {
+        let opts = self.opts.clone();
+        let request = Rc::new(RefCell::new(None));
+        let request_ref = request.clone();
+        let deflate = Rc::new(Cell::new(false));
+        let req = DeflateStream::new(req, deflate.clone(), opts.max_message_size);
+        let callback =
+            move |req: &Request, response: Response| -> std::result::Result<Response, ErrorResponse> {
+                let (response, negotiated) = on_handshake(&opts, req, response)?;
+                request_ref.borrow_mut().replace(ws_request(req));
+                deflate.set(negotiated);
+                Ok(response)
+            };
+        let fut = async move {
+            let s = accept_hdr_async(req, callback).await?;
+            let request = request.borrow_mut().take();
+            Ok::<_, WSError>((s, request))
+        };
         WSServiceFut {
-            fut: accept_hdr_async(req, on_handshake).boxed_local(),
+            fut: fut.boxed_local(),
             delay: if self.timeout == Duration::ZERO { None } else { Some(sleep(self.timeout)) },
         }
     }
 }
 
-type WebSocketStreamType<T> = Pin<Box<dyn Future<Output = Result<WebSocketStream<T>, WSError>>>>;
+type WebSocketStreamType<T> =
+    Pin<Box<dyn Future<Output = Result<(WebSocketStream<DeflateStream<T>>, Option<WsRequest>), WSError>>>>;
 
 pin_project_lite::pin_project! {
     pub struct WSServiceFut<T>
@@ -107,7 +159,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Future for WSServiceFut<T> {
             }
         }
         match Pin::new(&mut this.fut).poll(cx) {
-            Poll::Ready(Ok(io)) => Poll::Ready(Ok(WsStream::new(io))),
+            Poll::Ready(Ok((io, request))) => Poll::Ready(Ok(WsStream::new(io, request))),
             Poll::Ready(Err(e)) => Poll::Ready(Err(ntex_mqtt::MqttError::Service(MqttError::from(e)))),
             Poll::Pending => Poll::Pending,
         }
@@ -115,14 +167,23 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Future for WSServiceFut<T> {
 }
 
 pub struct WsStream<S> {
-    s: WebSocketStream<S>,
+    s: WebSocketStream<DeflateStream<S>>,
+    request: Option<WsRequest>,
     cached_data: Option<Vec<u8>>,
     idx: usize,
 }
 
 impl<S> WsStream<S> {
-    pub fn new(s: WebSocketStream<S>) -> Self {
-        Self { s, cached_data: None, idx: 0 }
+    pub fn new(s: WebSocketStream<DeflateStream<S>>, request: Option<WsRequest>) -> Self {
+        Self { s, request, cached_data: None, idx: 0 }
+    }
+
+    ///Puts the upgrade request into the connection attributes, for the auth hooks
+    #[inline]
+    pub fn set_request_attrs(&self, conn_attrs: &mut ExtraAttrs) {
+        if let Some(request) = &self.request {
+            conn_attrs.insert(WS_REQUEST_KEY.into(), request.clone());
+        }
     }
 }
 
@@ -132,7 +193,7 @@ where
 {
     #[inline]
     pub fn get_ref(&self) -> &S {
-        self.s.get_ref()
+        self.s.get_ref().get_ref()
     }
 }
 
@@ -220,19 +281,87 @@ fn to_error(e: WSError) -> io::Error {
     }
 }
 
+///Returns the response and whether permessage-deflate is negotiated
 #[allow(clippy::result_large_err)]
-fn on_handshake(req: &Request, mut response: Response) -> std::result::Result<Response, ErrorResponse> {
-    const PROTOCOL_ERROR: &str = "No \"Sec-WebSocket-Protocol: mqtt\" in client request";
-    let mqtt_protocol = req
-        .headers()
-        .get("Sec-WebSocket-Protocol")
-        .ok_or_else(|| ErrorResponse::new(Some(PROTOCOL_ERROR.into())))?;
-    if mqtt_protocol != "mqtt" {
-        return Err(ErrorResponse::new(Some(PROTOCOL_ERROR.into())));
-    }
-    response.headers_mut().append(
-        "Sec-WebSocket-Protocol",
-        "mqtt".parse().map_err(|_| ErrorResponse::new(Some("InvalidHeaderValue".into())))?,
-    );
-    Ok(response)
+fn on_handshake(
+    opts: &WsOptions,
+    req: &Request,
+    mut response: Response,
+) -> std::result::Result<(Response, bool), ErrorResponse> {
+    if let Some(path) = &opts.path {
+        if req.uri().path() != path {
+            return Err(error_response(
+                StatusCode::NOT_FOUND,
+                format!("Path {} is not found", req.uri().path()),
+            ));
+        }
+    }
+
+    if !opts.allowed_origins.is_empty() {
+        if let Some(origin) = req.headers().get(header::ORIGIN) {
+            if !opts.allowed_origins.iter().any(|o| origin.as_bytes().eq_ignore_ascii_case(o.as_bytes())) {
+                return Err(error_response(
+                    StatusCode::FORBIDDEN,
+                    format!("Origin {:?} is not allowed", origin),
+                ));
+            }
+        }
+    }
+
+    if !opts.subprotocols.is_empty() {
+        //The first subprotocol offered by the client that is allowed
+        let protocol = req
+            .headers()
+            .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|p| p.trim())
+            .find(|p| opts.subprotocols.iter().any(|s| s.eq_ignore_ascii_case(p)))
+            .ok_or_else(|| {
+                error_response(
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "No \"Sec-WebSocket-Protocol: {}\" in client request",
+                        opts.subprotocols.join(", ")
+                    ),
+                )
+            })?;
+        response.headers_mut().append(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_str(protocol)
+                .map_err(|_| error_response(StatusCode::BAD_REQUEST, "InvalidHeaderValue".into()))?,
+        );
+    }
+
+    //Without an acceptable offer, the connection is not compressed
+    let extension = if opts.deflate { ws_deflate::negotiate(req) } else { None };
+    if let Some(extension) = extension {
+        response.headers_mut().insert(
+            header::SEC_WEBSOCKET_EXTENSIONS,
+            HeaderValue::from_str(&extension)
+                .map_err(|_| error_response(StatusCode::BAD_REQUEST, "InvalidHeaderValue".into()))?,
+        );
+        return Ok((response, true));
+    }
+    Ok((response, false))
+}
+
+#[inline]
+fn error_response(status: StatusCode, msg: String) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(msg));
+    *response.status_mut() = status;
+    response
+}
+
+#[inline]
+fn ws_request(req: &Request) -> WsRequest {
+    WsRequest {
+        path: req.uri().path().into(),
+        headers: req
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| value.to_str().ok().map(|v| (name.as_str().into(), v.into())))
+            .collect(),
+    }
 }
@@ -0,0 +1,524 @@
+//! permessage-deflate(RFC 7692) of the WebSocket listeners.
+//!
+//! The websocket library rejects the frames with the RSV1 bit, so the compression is done by
+//! [`DeflateStream`] under it: the compressed messages of the client are decompressed and passed on
+//! as single uncompressed frames, and the data frames written by the library are compressed. The
+//! contexts are not taken over between the messages(`*_no_context_takeover`), so that the idle
+//! connections do not keep the deflate windows.
+
+use std::cell::Cell;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use rmqtt::bytes::{Buf, BufMut, BytesMut};
+use rmqtt::futures::ready;
+use rmqtt::ntex::codec::{AsyncRead, AsyncWrite, ReadBuf};
+use rmqtt::tokio_tungstenite::tungstenite::handshake::server::Request;
+use rmqtt::tokio_tungstenite::tungstenite::http::header;
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const MASK: u8 = 0x80;
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+//The smaller messages, such as PINGRESP and PUBACK, are not worth compressing
+const MIN_COMPRESS_LEN: usize = 64;
+//The written frames are buffered up to it before waiting for the socket
+const WRITE_BUFFER_LEN: usize = 64 * 1024;
+
+///The Sec-WebSocket-Extensions response header if the client offers permessage-deflate with the
+///parameters that can be accepted. An offer limiting the window of the server is declined, the
+///compressor always uses the 15 bits window
+pub(crate) fn negotiate(req: &Request) -> Option<String> {
+    req.headers()
+        .get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .find_map(|offer| {
+            let mut params = offer.split(';').map(|p| p.trim());
+            if !params.next().is_some_and(|name| name.eq_ignore_ascii_case("permessage-deflate")) {
+                return None;
+            }
+            let mut response =
+                String::from("permessage-deflate; server_no_context_takeover; client_no_context_takeover");
+            for param in params {
+                let (name, value) = match param.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (param, None),
+                };
+                match (name.to_ascii_lowercase().as_str(), value) {
+                    ("server_no_context_takeover" | "client_no_context_takeover", None) => {}
+                    ("client_max_window_bits", None) => {}
+                    ("client_max_window_bits", Some(bits)) if matches!(bits.parse::<u8>(), Ok(8..=15)) => {}
+                    ("server_max_window_bits", Some("15")) => {
+                        response.push_str("; server_max_window_bits=15")
+                    }
+                    _ => return None,
+                }
+            }
+            Some(response)
+        })
+}
+
+///The frame header, the payload follows it
+struct Header {
+    b0: u8,
+    mask: Option<[u8; 4]>,
+    len: usize,
+    payload_len: usize,
+}
+
+impl Header {
+    fn parse(buf: &[u8]) -> io::Result<Option<Header>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let (mut len, payload_len) = match buf[1] & 0x7f {
+            126 if buf.len() >= 4 => (4, u16::from_be_bytes([buf[2], buf[3]]) as u64),
+            127 if buf.len() >= 10 => {
+                (10, u64::from_be_bytes([buf[2], buf[3], buf[4], buf[5], buf[6], buf[7], buf[8], buf[9]]))
+            }
+            126 | 127 => return Ok(None),
+            n => (2, n as u64),
+        };
+        let mask = if buf[1] & MASK != 0 {
+            if buf.len() < len + 4 {
+                return Ok(None);
+            }
+            len += 4;
+            Some([buf[len - 4], buf[len - 3], buf[len - 2], buf[len - 1]])
+        } else {
+            None
+        };
+        let payload_len = usize::try_from(payload_len).map_err(|_| too_large())?;
+        Ok(Some(Header { b0: buf[0], mask, len, payload_len }))
+    }
+
+    #[inline]
+    fn opcode(&self) -> u8 {
+        self.b0 & 0x0f
+    }
+
+    #[inline]
+    fn is_control(&self) -> bool {
+        self.opcode() & 0x08 != 0
+    }
+
+    #[inline]
+    fn is_fin(&self) -> bool {
+        self.b0 & FIN != 0
+    }
+
+    fn put(buf: &mut BytesMut, b0: u8, masked: bool, payload_len: usize) {
+        let mask = if masked { MASK } else { 0 };
+        buf.put_u8(b0);
+        if payload_len < 126 {
+            buf.put_u8(mask | payload_len as u8);
+        } else if payload_len <= u16::MAX as usize {
+            buf.put_u8(mask | 126);
+            buf.put_u16(payload_len as u16);
+        } else {
+            buf.put_u8(mask | 127);
+            buf.put_u64(payload_len as u64);
+        }
+        //The zero key does not change the payload
+        if masked {
+            buf.put_slice(&[0; 4]);
+        }
+    }
+}
+
+///Compression state of a connection
+struct Deflate {
+    inflater: Decompress,
+    deflater: Compress,
+    //opcode and payload of the compressed message being received
+    message: Option<(u8, Vec<u8>)>,
+    max_message_size: usize,
+}
+
+impl Deflate {
+    fn new(max_message_size: usize) -> Self {
+        Self {
+            inflater: Decompress::new(false),
+            deflater: Compress::new(Compression::default(), false),
+            message: None,
+            max_message_size,
+        }
+    }
+
+    ///Decode a frame of the client from `src`, the compressed messages are put into `dst` as single
+    ///uncompressed frames, the others are put as is. Returns false if the frame is not complete
+    fn decode(&mut self, src: &mut BytesMut, dst: &mut BytesMut) -> io::Result<bool> {
+        let hdr = match Header::parse(src)? {
+            Some(hdr) => hdr,
+            None => return Ok(false),
+        };
+        if hdr.payload_len > self.max_message_size {
+            return Err(too_large());
+        }
+        if src.len() < hdr.len + hdr.payload_len {
+            return Ok(false);
+        }
+        let compressed = hdr.b0 & RSV1 != 0;
+        let message = if hdr.is_control() {
+            None
+        } else if hdr.opcode() != 0 {
+            if self.message.is_some() {
+                return Err(invalid_data("the compressed message is not finished"));
+            }
+            if compressed {
+                Some(self.message.insert((hdr.opcode(), Vec::new())))
+            } else {
+                None
+            }
+        } else if compressed {
+            return Err(invalid_data("RSV1 is set on a continuation frame"));
+        } else {
+            self.message.as_mut()
+        };
+        let frame = src.split_to(hdr.len + hdr.payload_len);
+        let (opcode, payload) = match message {
+            Some((opcode, payload)) => (*opcode, payload),
+            None => {
+                dst.put_slice(&frame);
+                return Ok(true);
+            }
+        };
+        if payload.len() + hdr.payload_len > self.max_message_size {
+            return Err(too_large());
+        }
+        let start = payload.len();
+        payload.extend_from_slice(&frame[hdr.len..]);
+        if let Some(key) = hdr.mask {
+            payload[start..].iter_mut().zip(key.iter().cycle()).for_each(|(b, k)| *b ^= k);
+        }
+        if hdr.is_fin() {
+            let (_, mut payload) = self.message.take().unwrap_or_default();
+            payload.extend_from_slice(&DEFLATE_TAIL);
+            let data = self.inflate(&payload)?;
+            Header::put(dst, FIN | opcode, true, data.len());
+            dst.put_slice(&data);
+        }
+        Ok(true)
+    }
+
+    ///Encode a frame written by the websocket library from `src` into `dst`, the data messages are
+    ///compressed. Returns false if the frame is not complete
+    fn encode(&mut self, src: &mut BytesMut, dst: &mut BytesMut) -> io::Result<bool> {
+        let hdr = match Header::parse(src)? {
+            Some(hdr) => hdr,
+            None => return Ok(false),
+        };
+        if src.len() < hdr.len + hdr.payload_len {
+            return Ok(false);
+        }
+        let frame = src.split_to(hdr.len + hdr.payload_len);
+        //The library sends a message in one frame
+        if hdr.is_fin()
+            && matches!(hdr.opcode(), 1 | 2)
+            && hdr.mask.is_none()
+            && hdr.payload_len >= MIN_COMPRESS_LEN
+        {
+            let data = self.deflate(&frame[hdr.len..])?;
+            if data.len() < hdr.payload_len {
+                Header::put(dst, hdr.b0 | RSV1, false, data.len());
+                dst.put_slice(&data);
+                return Ok(true);
+            }
+        }
+        dst.put_slice(&frame);
+        Ok(true)
+    }
+
+    fn inflate(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        self.inflater.reset(false);
+        let mut output = Vec::with_capacity((input.len() * 2).min(self.max_message_size));
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity().max(1024));
+            }
+            let (consumed, produced) = (self.inflater.total_in() as usize, output.len());
+            let status =
+                self.inflater.decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)?;
+            if output.len() > self.max_message_size {
+                return Err(too_large());
+            }
+            if self.inflater.total_in() as usize == input.len() && output.len() < output.capacity() {
+                return Ok(output);
+            }
+            if status == Status::StreamEnd
+                || (self.inflater.total_in() as usize == consumed && output.len() == produced)
+            {
+                return Err(invalid_data("invalid compressed message"));
+            }
+        }
+    }
+
+    fn deflate(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        self.deflater.reset();
+        let mut output = Vec::with_capacity(input.len() / 2 + 64);
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+            let consumed = self.deflater.total_in() as usize;
+            self.deflater.compress_vec(&input[consumed..], &mut output, FlushCompress::Sync)?;
+            if self.deflater.total_in() as usize == input.len() && output.len() < output.capacity() {
+                break;
+            }
+        }
+        if output.ends_with(&DEFLATE_TAIL) {
+            output.truncate(output.len() - DEFLATE_TAIL.len());
+        }
+        Ok(output)
+    }
+}
+
+#[inline]
+fn too_large() -> io::Error {
+    invalid_data("websocket message is too large")
+}
+
+#[inline]
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+///The stream under the websocket library, it passes the bytes through until the compression is
+///negotiated in the handshake, then it decodes and encodes the frames after the handshake response
+pub struct DeflateStream<T> {
+    io: T,
+    negotiated: Rc<Cell<bool>>,
+    deflate: Deflate,
+    //matched bytes of the end of the handshake response
+    response_end: Option<usize>,
+    rd_in: BytesMut,
+    rd_out: BytesMut,
+    wr_in: BytesMut,
+    wr_out: BytesMut,
+}
+
+impl<T> DeflateStream<T> {
+    pub(crate) fn new(io: T, negotiated: Rc<Cell<bool>>, max_message_size: usize) -> Self {
+        Self {
+            io,
+            negotiated,
+            deflate: Deflate::new(max_message_size),
+            response_end: Some(0),
+            rd_in: BytesMut::new(),
+            rd_out: BytesMut::new(),
+            wr_in: BytesMut::new(),
+            wr_out: BytesMut::new(),
+        }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+}
+
+impl<T: AsyncWrite + Unpin> DeflateStream<T> {
+    fn poll_write_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.wr_out.is_empty() {
+            let n = ready!(Pin::new(&mut self.io).poll_write(cx, &self.wr_out))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.wr_out.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for DeflateStream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.negotiated.get() {
+            return Pin::new(&mut this.io).poll_read(cx, buf);
+        }
+        loop {
+            if !this.rd_out.is_empty() {
+                let n = this.rd_out.len().min(buf.remaining());
+                buf.put_slice(&this.rd_out.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if this.deflate.decode(&mut this.rd_in, &mut this.rd_out)? {
+                continue;
+            }
+            let mut data = [0u8; 8 * 1024];
+            let mut data_buf = ReadBuf::new(&mut data);
+            ready!(Pin::new(&mut this.io).poll_read(cx, &mut data_buf))?;
+            if data_buf.filled().is_empty() {
+                //EOF
+                return Poll::Ready(Ok(()));
+            }
+            this.rd_in.extend_from_slice(data_buf.filled());
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for DeflateStream<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.negotiated.get() {
+            return Pin::new(&mut this.io).poll_write(cx, buf);
+        }
+        if this.wr_out.len() >= WRITE_BUFFER_LEN {
+            ready!(this.poll_write_out(cx))?;
+        }
+        let mut frames = buf;
+        if let Some(matched) = this.response_end.as_mut() {
+            //The handshake response is written as is
+            let end = buf.iter().position(|b| {
+                *matched = match (*matched, *b) {
+                    (0 | 2, b'\r') => *matched + 1,
+                    (1 | 3, b'\n') => *matched + 1,
+                    (_, b'\r') => 1,
+                    _ => 0,
+                };
+                *matched == 4
+            });
+            let (response, rest) = buf.split_at(end.map(|i| i + 1).unwrap_or(buf.len()));
+            this.wr_out.put_slice(response);
+            if end.is_some() {
+                this.response_end = None;
+            }
+            frames = rest;
+        }
+        this.wr_in.put_slice(frames);
+        while this.deflate.encode(&mut this.wr_in, &mut this.wr_out)? {}
+        if let Poll::Ready(Err(e)) = this.poll_write_out(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(extensions: &str) -> Request {
+        Request::builder().header(header::SEC_WEBSOCKET_EXTENSIONS, extensions).body(()).unwrap()
+    }
+
+    #[test]
+    fn negotiate_offers() {
+        let accepted = "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+        assert_eq!(negotiate(&request("permessage-deflate")).as_deref(), Some(accepted));
+        assert_eq!(
+            negotiate(&request("permessage-deflate; client_max_window_bits")).as_deref(),
+            Some(accepted)
+        );
+        assert_eq!(
+            negotiate(&request("permessage-deflate; server_max_window_bits=10, permessage-deflate"))
+                .as_deref(),
+            Some(accepted)
+        );
+        assert_eq!(
+            negotiate(&request("permessage-deflate; server_max_window_bits=15")),
+            Some(format!("{}; server_max_window_bits=15", accepted))
+        );
+        assert_eq!(negotiate(&request("permessage-deflate; server_max_window_bits=10")), None);
+        assert_eq!(negotiate(&request("x-webkit-deflate-frame")), None);
+        assert_eq!(negotiate(&Request::builder().body(()).unwrap()), None);
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut deflater = Compress::new(Compression::default(), false);
+        let mut output = Vec::with_capacity(data.len() + 64);
+        deflater.compress_vec(data, &mut output, FlushCompress::Sync).unwrap();
+        output.truncate(output.len() - DEFLATE_TAIL.len());
+        output
+    }
+
+    fn client_frame(b0: u8, payload: &[u8]) -> BytesMut {
+        let key = [1, 2, 3, 4];
+        let mut frame = BytesMut::new();
+        frame.put_u8(b0);
+        frame.put_u8(MASK | payload.len() as u8);
+        frame.put_slice(&key);
+        frame.extend(payload.iter().zip(key.iter().cycle()).map(|(b, k)| b ^ k));
+        frame
+    }
+
+    #[test]
+    fn decode() {
+        let mut deflate = Deflate::new(1024);
+        let data = b"hello hello hello hello";
+        let compressed = compress(data);
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+
+        //A fragmented compressed message and an uncompressed ping between the fragments
+        let mut src = client_frame(RSV1 | 0x02, first);
+        src.extend_from_slice(&client_frame(FIN | 0x09, b"ping"));
+        src.extend_from_slice(&client_frame(FIN, second));
+        let mut dst = BytesMut::new();
+        assert!(deflate.decode(&mut src, &mut dst).unwrap());
+        assert!(dst.is_empty());
+        assert!(deflate.decode(&mut src, &mut dst).unwrap());
+        assert_eq!(dst.split().as_ref(), client_frame(FIN | 0x09, b"ping").as_ref());
+        assert!(deflate.decode(&mut src, &mut dst).unwrap());
+        let mut expected = BytesMut::new();
+        Header::put(&mut expected, FIN | 0x02, true, data.len());
+        expected.put_slice(data);
+        assert_eq!(dst.split().as_ref(), expected.as_ref());
+        assert!(!deflate.decode(&mut src, &mut dst).unwrap());
+
+        //The incomplete frame is kept
+        let mut src = client_frame(FIN | RSV1 | 0x02, &compress(data));
+        let mut partial = src.split_to(src.len() - 1);
+        assert!(!deflate.decode(&mut partial, &mut dst).unwrap());
+        partial.extend_from_slice(&src);
+        assert!(deflate.decode(&mut partial, &mut dst).unwrap());
+        assert_eq!(dst.split().as_ref(), expected.as_ref());
+
+        //Decompressed over the limit
+        let mut deflate = Deflate::new(16);
+        let mut src = client_frame(FIN | RSV1 | 0x02, &compress(data));
+        assert!(deflate.decode(&mut src, &mut dst).is_err());
+    }
+
+    #[test]
+    fn encode() {
+        let mut deflate = Deflate::new(1024);
+        let data = [b'a'; 100];
+        let mut src = BytesMut::new();
+        Header::put(&mut src, FIN | 0x02, false, data.len());
+        src.put_slice(&data);
+        Header::put(&mut src, FIN | 0x02, false, 2);
+        src.put_slice(&[0xd0, 0x00]);
+        let mut dst = BytesMut::new();
+        assert!(deflate.encode(&mut src, &mut dst).unwrap());
+        let hdr = Header::parse(&dst).unwrap().unwrap();
+        assert_eq!(hdr.b0, FIN | RSV1 | 0x02);
+        assert!(hdr.payload_len < data.len());
+        let mut payload = dst.split_off(hdr.len).to_vec();
+        payload.extend_from_slice(&DEFLATE_TAIL);
+        assert_eq!(deflate.inflate(&payload).unwrap(), data);
+
+        //The small message is not compressed
+        let mut dst = BytesMut::new();
+        assert!(deflate.encode(&mut src, &mut dst).unwrap());
+        assert_eq!(dst.as_ref(), &[FIN | 0x02, 2, 0xd0, 0x00]);
+        assert!(src.is_empty());
+    }
+}
@@ -0,0 +1,55 @@
+#!/bin/sh
+
+# Records the OCSP responses used by the tests of src/ocsp.rs, run it in this directory.
+# The responses have no nextUpdate, so they do not expire.
+set -e
+
+OUT=$(pwd)
+TMP=$(mktemp -d)
+cd ${TMP}
+
+cat > ext.conf <<EOF
+[ v3_client ]
+basicConstraints = critical, CA:false
+keyUsage = digitalSignature
+extendedKeyUsage = clientAuth
+
+[ v3_responder ]
+basicConstraints = critical, CA:false
+keyUsage = digitalSignature
+extendedKeyUsage = OCSPSigning
+EOF
+
+openssl req -x509 -nodes -newkey rsa:2048 -keyout ca.key -out ca.pem -days 36500 -subj "/CN=OCSP Test CA"
+for name in good revoked responder; do
+    openssl req -nodes -newkey rsa:2048 -keyout ${name}.key -out ${name}.csr -subj "/CN=${name}"
+done
+openssl x509 -req -in good.csr -out good.pem -CA ca.pem -CAkey ca.key -days 36500 -set_serial 789 \
+    -extensions v3_client -extfile ext.conf
+openssl x509 -req -in revoked.csr -out revoked.pem -CA ca.pem -CAkey ca.key -days 36500 -set_serial 790 \
+    -extensions v3_client -extfile ext.conf
+openssl x509 -req -in responder.csr -out responder.pem -CA ca.pem -CAkey ca.key -days 36500 -set_serial 791 \
+    -extensions v3_responder -extfile ext.conf
+
+printf "V\t21240101000000Z\t\t0315\tunknown\t/CN=good\n" > index.txt
+printf "R\t21240101000000Z\t240101000000Z\t0316\tunknown\t/CN=revoked\n" >> index.txt
+
+openssl ocsp -issuer ca.pem -cert good.pem -no_nonce -reqout good.req
+openssl ocsp -issuer ca.pem -cert revoked.pem -no_nonce -reqout revoked.req
+# signed by the CA
+openssl ocsp -index index.txt -rsigner ca.pem -rkey ca.key -CA ca.pem -reqin good.req -respout good.resp
+openssl ocsp -index index.txt -rsigner ca.pem -rkey ca.key -CA ca.pem -reqin revoked.req -respout revoked.resp
+# signed by the delegated responder, the responder certificate is included in the response
+openssl ocsp -index index.txt -rsigner responder.pem -rkey responder.key -CA ca.pem -reqin revoked.req \
+    -respout delegated.resp
+# signed by a client certificate, which is not authorized to sign the responses
+openssl ocsp -index index.txt -rsigner good.pem -rkey good.key -CA ca.pem -reqin good.req \
+    -respout unauthorized.resp
+
+for name in ca good revoked; do
+    openssl x509 -in ${name}.pem -outform der -out ${OUT}/${name}.der
+done
+cp good.req good.resp revoked.resp delegated.resp unauthorized.resp ${OUT}/
+
+cd ${OUT}
+rm -r ${TMP}
@@ -5,6 +5,7 @@ use serde::de::{self, Deserialize, Deserializer};
 use serde::ser::{self, Serialize};
 
 use rmqtt::broker::hook::Priority;
+use rmqtt::broker::peer_cert;
 use rmqtt::broker::topic::TopicTree;
 use rmqtt::{
     ahash, dashmap, log,
@@ -351,7 +352,10 @@ impl std::convert::TryFrom<Option<&serde_json::Value>> for Topics {
                 for topic in topics.iter() {
                     match topic {
                         Value::String(topic) => {
-                            if topic.contains(PH_U) || topic.contains(PH_C) {
+                            if topic.contains(PH_U)
+                                || topic.contains(PH_C)
+                                || peer_cert::has_placeholders(topic)
+                            {
                                 placeholders.push(topic.clone());
                             } else {
                                 tree.insert(&Topic::from_str(topic.as_str())?, ());
@@ -359,7 +363,7 @@ impl std::convert::TryFrom<Option<&serde_json::Value>> for Topics {
                         }
                         Value::Object(eq_map) => match eq_map.get("eq") {
                             Some(Value::String(eq)) => {
-                                if eq.contains(PH_U) || eq.contains(PH_C) {
+                                if eq.contains(PH_U) || eq.contains(PH_C) || peer_cert::has_placeholders(eq) {
                                     eq_placeholders.push(eq.clone());
                                 } else {
                                     eqs.insert(eq.clone());
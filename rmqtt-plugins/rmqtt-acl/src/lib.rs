@@ -16,6 +16,7 @@ use rmqtt::{
 };
 use rmqtt::{
     broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    broker::peer_cert::{self, PeerCert, PEER_CERT_KEY},
     broker::types::{AuthResult, PublishAclResult, SubscribeAckReason, SubscribeAclResult, Topic},
     plugin::{PackageInfo, Plugin},
     register, Result, Runtime,
@@ -107,10 +108,22 @@ impl Handler for AclHandler {
                 let cfg = self.cfg.clone();
                 let client_id = session.id.client_id.clone();
                 let username = session.id.username.clone();
+                let cert = session.extra_attrs.read().await.get::<PeerCert>(PEER_CERT_KEY).cloned();
                 let build_placeholders = async move {
                     for rule in cfg.read().await.rules() {
                         for ph_tf in &rule.topics.placeholders {
-                            let mut tf = ph_tf.replace(PH_C, &client_id);
+                            let mut tf = match peer_cert::replaces(cert.as_ref(), ph_tf, true) {
+                                Some(tf) => tf,
+                                None => {
+                                    log::debug!(
+                                        "{} the certificate field of {} is not available",
+                                        client_id,
+                                        ph_tf
+                                    );
+                                    continue;
+                                }
+                            };
+                            tf = tf.replace(PH_C, &client_id);
                             if let Some(un) = &username {
                                 tf = tf.replace(PH_U, un);
                             } else {
@@ -125,7 +138,18 @@ impl Handler for AclHandler {
                         }
 
                         for eq_ph_t in &rule.topics.eq_placeholders {
-                            let mut t = eq_ph_t.replace(PH_C, &client_id);
+                            let mut t = match peer_cert::replaces(cert.as_ref(), eq_ph_t, true) {
+                                Some(t) => t,
+                                None => {
+                                    log::debug!(
+                                        "{} the certificate field of {} is not available",
+                                        client_id,
+                                        eq_ph_t
+                                    );
+                                    continue;
+                                }
+                            };
+                            t = t.replace(PH_C, &client_id);
                             if let Some(un) = &username {
                                 t = t.replace(PH_U, un);
                             } else {
@@ -144,7 +168,7 @@ impl Handler for AclHandler {
                 tokio::spawn(build_placeholders);
             }
 
-            Parameter::ClientAuthenticate(connect_info) => {
+            Parameter::ClientAuthenticate(connect_info, _) => {
                 log::debug!("ClientAuthenticate acl");
                 if matches!(
                     acc,
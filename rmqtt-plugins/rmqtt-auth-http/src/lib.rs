@@ -20,17 +20,21 @@ use rmqtt::{
 };
 use rmqtt::{
     broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    broker::peer_cert::{self, PeerCert, PEER_CERT_KEY},
+    broker::peer_cred::{PeerCred, PEER_CRED_KEY},
+    broker::session::Session,
     broker::types::{
         AuthResult, ConnectInfo, Message, Password, PublishAclResult, Reason, SubscribeAckReason,
         SubscribeAclResult, Superuser,
     },
     plugin::{PackageInfo, Plugin},
-    register, timestamp_millis, Id, MqttError, Result, Runtime, TopicName,
+    register, timestamp_millis, ExtraAttrs, Id, MqttError, Result, Runtime, TopicName,
 };
 
 use config::PluginConfig;
 use rmqtt::reqwest::header::CONTENT_TYPE;
 use rmqtt::settings::acl::{AuthInfo, Rule};
+use rmqtt::settings::listener::PublishLimit;
 
 mod config;
 
@@ -47,12 +51,39 @@ struct ResponseResult {
     cacheable: Cacheable,
     expire_at: Option<Duration>,
     acl_data: Option<serde_json::Value>,
+    publish_limit: Option<PublishLimit>,
+    mountpoint: Option<String>,
 }
 
 impl ResponseResult {
     #[inline]
     fn new(permission: Permission, superuser: Superuser, cacheable: Cacheable) -> ResponseResult {
-        ResponseResult { permission, superuser, cacheable, expire_at: None, acl_data: None }
+        ResponseResult {
+            permission,
+            superuser,
+            cacheable,
+            expire_at: None,
+            acl_data: None,
+            publish_limit: None,
+            mountpoint: None,
+        }
+    }
+}
+
+///The connection attributes passed to the placeholders and the JSON body of the requests
+#[derive(Debug, Default)]
+struct ConnAttrs {
+    cert: Option<PeerCert>,
+    cred: Option<PeerCred>,
+}
+
+impl ConnAttrs {
+    #[inline]
+    fn new(attrs: &ExtraAttrs) -> Self {
+        Self {
+            cert: attrs.get::<PeerCert>(PEER_CERT_KEY).cloned(),
+            cred: attrs.get::<PeerCred>(PEER_CRED_KEY).copied(),
+        }
     }
 }
 
@@ -225,8 +256,21 @@ impl AuthHandler {
                         obj.get("expire_at").and_then(|res| res.as_u64().map(Duration::from_secs));
                     let permission = Permission::try_from((result, superuser))?;
                     let acl_data = obj.remove("acl");
-
-                    ResponseResult { permission, superuser, cacheable: cache_timeout, expire_at, acl_data }
+                    let publish_limit = match obj.remove("publish_limit") {
+                        Some(publish_limit) => Some(serde_json::from_value::<PublishLimit>(publish_limit)?),
+                        None => None,
+                    };
+                    let mountpoint = obj.get("mountpoint").and_then(|res| res.as_str().map(String::from));
+
+                    ResponseResult {
+                        permission,
+                        superuser,
+                        cacheable: cache_timeout,
+                        expire_at,
+                        acl_data,
+                        publish_limit,
+                        mountpoint,
+                    }
                 } else if let Some(body) = body.as_str() {
                     log::debug!("body: {:?}", body);
                     ResponseResult::new(Permission::try_from((body, superuser))?, superuser, cache_timeout)
@@ -327,13 +371,21 @@ impl AuthHandler {
         password: Option<&Password>,
         protocol: Option<u8>,
         sub_or_pub: Option<(ACLType, &TopicName)>,
+        attrs: &ConnAttrs,
     ) -> Result<()> {
         let password =
             if let Some(p) = password { ByteString::try_from(p.clone())? } else { ByteString::default() };
         let client_id = id.client_id.as_ref();
         let username = id.username.as_ref().map(|n| n.as_ref()).unwrap_or("");
         let remote_addr = id.remote_addr.map(|addr| addr.ip().to_string()).unwrap_or_default();
+        let uid = attrs.cred.map(|c| c.uid.to_string()).unwrap_or_default();
+        let gid = attrs.cred.map(|c| c.gid.to_string()).unwrap_or_default();
         for v in params.values_mut() {
+            if peer_cert::has_placeholders(v) {
+                *v = peer_cert::replaces(attrs.cert.as_ref(), v, false).unwrap_or_default();
+            }
+            *v = v.replace("%U", &uid);
+            *v = v.replace("%G", &gid);
             *v = v.replace("%u", username);
             *v = v.replace("%c", client_id);
             *v = v.replace("%a", &remote_addr);
@@ -353,6 +405,7 @@ impl AuthHandler {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn request(
         &self,
         id: &Id,
@@ -360,6 +413,7 @@ impl AuthHandler {
         password: Option<&Password>,
         protocol: Option<u8>,
         sub_or_pub: Option<(ACLType, &TopicName)>,
+        attrs: &ConnAttrs,
     ) -> Result<ResponseResult> {
         log::debug!("{:?} req_cfg.url.path(): {:?}", id, req_cfg.url.path());
         let (headers, timeout) = {
@@ -379,16 +433,21 @@ impl AuthHandler {
 
         let auth_result = if req_cfg.is_get() {
             let body = &mut req_cfg.params;
-            Self::replaces(body, id, password, protocol, sub_or_pub)?;
+            Self::replaces(body, id, password, protocol, sub_or_pub, attrs)?;
             Self::http_get_request(req_cfg.url, body, headers, timeout).await?
         } else if req_cfg.json_body() {
             let body = &mut req_cfg.params;
-            Self::replaces(body, id, password, protocol, sub_or_pub)?;
-            Self::http_json_request(req_cfg.url, req_cfg.method, body, headers, timeout).await?
+            Self::replaces(body, id, password, protocol, sub_or_pub, attrs)?;
+            //The peer credentials of the unix socket connections are passed as an object
+            let mut body = serde_json::to_value(&*body)?;
+            if let (Some(cred), Some(body)) = (attrs.cred, body.as_object_mut()) {
+                body.insert(PEER_CRED_KEY.into(), cred.to_json());
+            }
+            Self::http_json_request(req_cfg.url, req_cfg.method, &body, headers, timeout).await?
         } else {
             //form body
             let body = &mut req_cfg.params;
-            Self::replaces(body, id, password, protocol, sub_or_pub)?;
+            Self::replaces(body, id, password, protocol, sub_or_pub, attrs)?;
             Self::http_form_request(req_cfg.url, req_cfg.method, body, headers, timeout).await?
         };
         log::debug!("auth_result: {:?}", auth_result);
@@ -396,7 +455,11 @@ impl AuthHandler {
     }
 
     #[inline]
-    async fn auth(&self, connect_info: &ConnectInfo) -> (Permission, Option<AuthInfo>) {
+    async fn auth(
+        &self,
+        connect_info: &ConnectInfo,
+        conn_attrs: &ExtraAttrs,
+    ) -> (Permission, Option<AuthInfo>) {
         if let Some(req) = { self.cfg.read().await.http_auth_req.clone() } {
             match self
                 .request(
@@ -405,6 +468,7 @@ impl AuthHandler {
                     connect_info.password(),
                     Some(connect_info.proto_ver()),
                     None,
+                    &ConnAttrs::new(conn_attrs),
                 )
                 .await
             {
@@ -424,6 +488,8 @@ impl AuthHandler {
                                         superuser: auth_res.superuser,
                                         expire_at: auth_res.expire_at,
                                         rules,
+                                        publish_limit: auth_res.publish_limit,
+                                        mountpoint: auth_res.mountpoint,
                                     };
                                     log::debug!("auth_info: {:?}", auth_info);
                                     Some(auth_info)
@@ -433,6 +499,14 @@ impl AuthHandler {
                                     None
                                 }
                             }
+                        } else if auth_res.publish_limit.is_some() || auth_res.mountpoint.is_some() {
+                            Some(AuthInfo {
+                                superuser: auth_res.superuser,
+                                expire_at: auth_res.expire_at,
+                                rules: Vec::new(),
+                                publish_limit: auth_res.publish_limit,
+                                mountpoint: auth_res.mountpoint,
+                            })
                         } else {
                             None
                         }
@@ -458,18 +532,19 @@ impl AuthHandler {
     #[inline]
     async fn acl(
         &self,
-        id: &Id,
-        protocol: Option<u8>,
+        session: &Session,
         sub_or_pub: Option<(ACLType, &TopicName)>,
     ) -> (Permission, Cacheable) {
         if let Some(req) = { self.cfg.read().await.http_acl_req.clone() } {
-            match self.request(id, req, None, protocol, sub_or_pub).await {
+            let protocol = session.protocol().await.ok();
+            let attrs = ConnAttrs::new(&*session.extra_attrs.read().await);
+            match self.request(&session.id, req, None, protocol, sub_or_pub, &attrs).await {
                 Ok(acl_res) => {
                     log::debug!("acl result: {:?}", acl_res);
                     (acl_res.permission, acl_res.cacheable)
                 }
                 Err(e) => {
-                    log::warn!("{:?} acl error, {:?}", id, e);
+                    log::warn!("{:?} acl error, {:?}", session.id, e);
                     if self.cfg.read().await.deny_if_error {
                         (Permission::Deny, None)
                     } else {
@@ -487,7 +562,7 @@ impl AuthHandler {
 impl Handler for AuthHandler {
     async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
         match param {
-            Parameter::ClientAuthenticate(connect_info) => {
+            Parameter::ClientAuthenticate(connect_info, conn_attrs) => {
                 log::debug!("ClientAuthenticate auth-http");
                 if matches!(
                     acc,
@@ -497,7 +572,7 @@ impl Handler for AuthHandler {
                     return (false, acc);
                 }
 
-                return match self.auth(connect_info).await {
+                return match self.auth(connect_info, conn_attrs).await {
                     (Permission::Allow(superuser), auth_info) => {
                         if auth_info.as_ref().map(|ai| ai.is_expired()).unwrap_or_default() {
                             log::warn!("{} authentication information has expired.", connect_info.id());
@@ -527,13 +602,7 @@ impl Handler for AuthHandler {
                 }
 
                 //Permission, Cacheable
-                let (acl_res, _) = self
-                    .acl(
-                        &session.id,
-                        session.protocol().await.ok(),
-                        Some((ACLType::Sub, &subscribe.topic_filter)),
-                    )
-                    .await;
+                let (acl_res, _) = self.acl(session, Some((ACLType::Sub, &subscribe.topic_filter))).await;
                 return match acl_res {
                     Permission::Allow(_) => (
                         false,
@@ -586,13 +655,7 @@ impl Handler for AuthHandler {
                     acl_res
                 } else {
                     //Permission, Cacheable
-                    let (acl_res, cacheable) = self
-                        .acl(
-                            &session.id,
-                            session.protocol().await.ok(),
-                            Some((ACLType::Pub, publish.topic())),
-                        )
-                        .await;
+                    let (acl_res, cacheable) = self.acl(session, Some((ACLType::Pub, publish.topic()))).await;
                     if let Some(tm) = cacheable {
                         let expire = if tm < 0 { tm } else { timestamp_millis() + tm };
                         if let Some(cache_map) = session
@@ -23,6 +23,7 @@ use rmqtt::{
     settings::acl::{
         AuthInfo, Rule, PLACEHOLDER_CLIENTID, PLACEHOLDER_IPADDR, PLACEHOLDER_PROTOCOL, PLACEHOLDER_USERNAME,
     },
+    settings::listener::PublishLimit,
     ConnectInfo, Message, MqttError, Reason, Result, Runtime,
 };
 
@@ -278,7 +279,7 @@ impl AuthHandler {
 impl Handler for AuthHandler {
     async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
         match param {
-            Parameter::ClientAuthenticate(connect_info) => {
+            Parameter::ClientAuthenticate(connect_info, _) => {
                 log::debug!("ClientAuthenticate auth-jwt");
                 if matches!(
                     acc,
@@ -333,7 +334,21 @@ impl Handler for AuthHandler {
                 log::debug!("rules: {:?}", rules);
                 let expire_at =
                     token_data.claims.get("exp").and_then(|exp| exp.as_u64().map(Duration::from_secs));
-                let auth_info = AuthInfo { superuser, expire_at, rules };
+                let publish_limit = match token_data.claims.get("publish_limit") {
+                    Some(publish_limit) => {
+                        match serde_json::from_value::<PublishLimit>(publish_limit.clone()) {
+                            Ok(publish_limit) => Some(publish_limit),
+                            Err(e) => {
+                                log::warn!("{} publish_limit claim error, {}", connect_info.id(), e);
+                                return (false, Some(HookResult::AuthResult(AuthResult::NotAuthorized)));
+                            }
+                        }
+                    }
+                    None => None,
+                };
+                let mountpoint =
+                    token_data.claims.get("mountpoint").and_then(|mp| mp.as_str().map(String::from));
+                let auth_info = AuthInfo { superuser, expire_at, rules, publish_limit, mountpoint };
                 return (false, Some(HookResult::AuthResult(AuthResult::Allow(superuser, Some(auth_info)))));
             }
 
@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use rmqtt::base64::prelude::{Engine, BASE64_STANDARD};
+use rmqtt::broker::hook::Priority;
+use rmqtt::rand::{self, Rng};
+use rmqtt::settings::deserialize_duration;
+use rmqtt::{ahash, serde_json, MqttError, Result};
+
+use crate::scram::Credential;
+
+type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    ///Hook priority
+    #[serde(default = "PluginConfig::priority_default")]
+    pub priority: Priority,
+
+    ///PBKDF2 iteration count, used when the credential is derived from a password
+    #[serde(default = "PluginConfig::iterations_default")]
+    pub iterations: u32,
+
+    ///Maximum time allowed between the server-first-message and the client-final-message
+    #[serde(default = "PluginConfig::exchange_timeout_default", deserialize_with = "deserialize_duration")]
+    pub exchange_timeout: Duration,
+
+    #[serde(default)]
+    pub users: Vec<User>,
+}
+
+impl PluginConfig {
+    fn priority_default() -> Priority {
+        50
+    }
+
+    fn iterations_default() -> u32 {
+        4096
+    }
+
+    fn exchange_timeout_default() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    #[inline]
+    pub fn credentials(&self) -> Result<HashMap<String, Credential>> {
+        let mut credentials = HashMap::default();
+        for user in &self.users {
+            credentials.insert(user.username.clone(), user.credential(self.iterations)?);
+        }
+        Ok(credentials)
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> Result<serde_json::Value> {
+        let users =
+            self.users.iter().map(|u| serde_json::json!({"username": u.username, "superuser": u.superuser}));
+        Ok(serde_json::json!({
+            "priority": self.priority,
+            "iterations": self.iterations,
+            "exchange_timeout": format!("{:?}", self.exchange_timeout),
+            "users": users.collect::<Vec<_>>(),
+        }))
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct User {
+    pub username: String,
+    #[serde(default)]
+    pub superuser: bool,
+
+    ///Plain password, the credential is derived at load time
+    #[serde(default)]
+    pub password: Option<String>,
+
+    ///Or precomputed credential, base64 encoded
+    #[serde(default)]
+    pub salt: Option<String>,
+    #[serde(default)]
+    pub iterations: Option<u32>,
+    #[serde(default)]
+    pub stored_key: Option<String>,
+    #[serde(default)]
+    pub server_key: Option<String>,
+}
+
+impl std::fmt::Debug for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "User {{ username: {}, superuser: {} }}", self.username, self.superuser)
+    }
+}
+
+impl User {
+    #[inline]
+    fn credential(&self, iterations: u32) -> Result<Credential> {
+        let decode = |v: &str| BASE64_STANDARD.decode(v).map_err(|e| MqttError::from(e.to_string()));
+        match (&self.salt, &self.stored_key, &self.server_key, &self.password) {
+            (Some(salt), Some(stored_key), Some(server_key), _) => Ok(Credential {
+                salt: decode(salt)?,
+                iterations: self.iterations.unwrap_or(iterations),
+                stored_key: decode(stored_key)?,
+                server_key: decode(server_key)?,
+                superuser: self.superuser,
+            }),
+            (salt, _, _, Some(password)) => {
+                let salt = match salt {
+                    Some(salt) => decode(salt)?,
+                    None => rand::thread_rng().gen::<[u8; 16]>().to_vec(),
+                };
+                Ok(Credential::from_password(
+                    password.as_bytes(),
+                    salt,
+                    self.iterations.unwrap_or(iterations),
+                    self.superuser,
+                ))
+            }
+            _ => Err(MqttError::from(format!("{} credential is not configured", self.username))),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+#[macro_use]
+extern crate rmqtt_macros;
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+
+use rmqtt::{ahash, async_trait, dashmap, log, ntex::util::Bytes, serde_json, tokio};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    plugin::{PackageInfo, Plugin},
+    register, AuthStage, ConnId, ConnectInfo, ExtendedAuth, ExtendedAuthResult, Result, Runtime,
+};
+
+use crate::config::PluginConfig;
+use crate::scram::{ClientFirst, Credential, ServerFirst, SCRAM_SHA_256};
+
+mod config;
+mod scram;
+
+type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
+type DashMap<K, V> = dashmap::DashMap<K, V, ahash::RandomState>;
+
+register!(AuthScramPlugin::new);
+
+#[derive(Plugin)]
+struct AuthScramPlugin {
+    runtime: &'static Runtime,
+    register: Box<dyn Register>,
+    cfg: Arc<RwLock<PluginConfig>>,
+    credentials: Arc<RwLock<HashMap<String, Credential>>>,
+}
+
+impl AuthScramPlugin {
+    #[inline]
+    async fn new<S: Into<String>>(runtime: &'static Runtime, name: S) -> Result<Self> {
+        let name = name.into();
+        let cfg = runtime.settings.plugins.load_config::<PluginConfig>(&name)?;
+        log::info!("{} AuthScramPlugin cfg: {:?}", name, cfg);
+        let credentials = Arc::new(RwLock::new(cfg.credentials()?));
+        let cfg = Arc::new(RwLock::new(cfg));
+        let register = runtime.extends.hook_mgr().await.register();
+        Ok(Self { runtime, register, cfg, credentials })
+    }
+}
+
+#[async_trait]
+impl Plugin for AuthScramPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name());
+        let priority = self.cfg.read().await.priority;
+        self.register
+            .add_priority(
+                Type::ClientAuthenticateExtended,
+                priority,
+                Box::new(ScramHandler::new(&self.cfg, &self.credentials)),
+            )
+            .await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        self.cfg.read().await.to_json()
+    }
+
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(self.name())?;
+        *self.credentials.write().await = new_cfg.credentials()?;
+        *self.cfg.write().await = new_cfg;
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name());
+        self.register.start().await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name());
+        self.register.stop().await;
+        Ok(true)
+    }
+}
+
+struct ScramHandler {
+    cfg: Arc<RwLock<PluginConfig>>,
+    credentials: Arc<RwLock<HashMap<String, Credential>>>,
+    exchanges: DashMap<ConnId, (Instant, ServerFirst)>,
+}
+
+impl ScramHandler {
+    fn new(cfg: &Arc<RwLock<PluginConfig>>, credentials: &Arc<RwLock<HashMap<String, Credential>>>) -> Self {
+        Self { cfg: cfg.clone(), credentials: credentials.clone(), exchanges: DashMap::default() }
+    }
+
+    #[inline]
+    async fn authenticate(&self, connect_info: &ConnectInfo, auth: &ExtendedAuth) -> ExtendedAuthResult {
+        let data = match auth.data.as_ref() {
+            Some(data) => data,
+            None => return ExtendedAuthResult::NotAuthorized,
+        };
+        let exchange_timeout = self.cfg.read().await.exchange_timeout;
+        match auth.stage {
+            AuthStage::Connect | AuthStage::Reauth => {
+                self.exchanges.retain(|_, (at, _)| at.elapsed() < exchange_timeout);
+                let client_first = match ClientFirst::parse(data) {
+                    Ok(client_first) => client_first,
+                    Err(e) => {
+                        log::warn!("{:?} invalid client-first-message, {}", connect_info.id(), e);
+                        return ExtendedAuthResult::NotAuthorized;
+                    }
+                };
+                if connect_info.username().map(|u| u.as_ref() != client_first.username).unwrap_or_default() {
+                    log::warn!("{:?} SCRAM username does not match the CONNECT username", connect_info.id());
+                    return ExtendedAuthResult::NotAuthorized;
+                }
+                let (server_first, msg) = match self.credentials.read().await.get(&client_first.username) {
+                    Some(cred) => client_first.server_first(cred),
+                    None => {
+                        log::warn!("{:?} SCRAM user is not found", connect_info.id());
+                        return ExtendedAuthResult::NotAuthorized;
+                    }
+                };
+                self.exchanges.insert(auth.conn_id, (Instant::now(), server_first));
+                ExtendedAuthResult::Continue(Some(Bytes::from(msg)))
+            }
+            AuthStage::Continue => {
+                let server_first = match self.exchanges.remove(&auth.conn_id) {
+                    Some((_, (at, server_first))) if at.elapsed() < exchange_timeout => server_first,
+                    _ => return ExtendedAuthResult::NotAuthorized,
+                };
+                let credentials = self.credentials.read().await;
+                let cred = match credentials.get(&server_first.username) {
+                    Some(cred) => cred,
+                    None => return ExtendedAuthResult::NotAuthorized,
+                };
+                match server_first.verify(cred, data) {
+                    Ok(server_final) => {
+                        ExtendedAuthResult::Success(cred.superuser, None, Some(Bytes::from(server_final)))
+                    }
+                    Err(e) => {
+                        log::warn!("{:?} SCRAM authentication failed, {}", connect_info.id(), e);
+                        ExtendedAuthResult::NotAuthorized
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for ScramHandler {
+    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+        match param {
+            Parameter::ClientAuthenticateExtended(connect_info, auth) => {
+                if auth.method != SCRAM_SHA_256 {
+                    return (true, acc);
+                }
+                let result = self.authenticate(connect_info, auth).await;
+                log::debug!("{:?} {:?}, result: {:?}", connect_info.id(), auth.stage, result);
+                return (false, Some(HookResult::ExtendedAuthResult(result)));
+            }
+            _ => {
+                log::error!("unimplemented, {:?}", param)
+            }
+        }
+        (true, acc)
+    }
+}
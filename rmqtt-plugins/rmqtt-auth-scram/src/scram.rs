@@ -0,0 +1,200 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use rmqtt::base64::prelude::{Engine, BASE64_STANDARD};
+use rmqtt::rand::{self, Rng};
+use rmqtt::{MqttError, Result};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+
+#[derive(Clone)]
+pub struct Credential {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+    pub superuser: bool,
+}
+
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Credential {{ iterations: {}, superuser: {} }}", self.iterations, self.superuser)
+    }
+}
+
+impl Credential {
+    #[inline]
+    pub fn from_password(password: &[u8], salt: Vec<u8>, iterations: u32, superuser: bool) -> Self {
+        let mut salted_password = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password, &salt, iterations, &mut salted_password);
+        let client_key = hmac(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(&client_key).to_vec();
+        let server_key = hmac(&salted_password, b"Server Key");
+        Self { salt, iterations, stored_key, server_key, superuser }
+    }
+}
+
+///client-first-message, RFC 5802
+#[derive(Debug, Clone)]
+pub struct ClientFirst {
+    pub username: String,
+    gs2_header: String,
+    bare: String,
+    nonce: String,
+}
+
+impl ClientFirst {
+    #[inline]
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let msg = std::str::from_utf8(data)?;
+        //gs2-header = gs2-cbind-flag "," [ authzid ] ","
+        let (cbind_flag, rest) = msg.split_once(',').ok_or_else(|| MqttError::from("invalid gs2 header"))?;
+        if cbind_flag != "n" && cbind_flag != "y" {
+            return Err(MqttError::from("channel binding is not supported"));
+        }
+        let (authzid, bare) = rest.split_once(',').ok_or_else(|| MqttError::from("invalid gs2 header"))?;
+        let gs2_header = format!("{},{},", cbind_flag, authzid);
+
+        let mut attrs = bare.split(',');
+        let username = match attrs.next().and_then(|a| a.strip_prefix("n=")) {
+            Some(username) => decode_saslname(username)?,
+            None => return Err(MqttError::from("username is missing")),
+        };
+        let nonce = match attrs.next().and_then(|a| a.strip_prefix("r=")) {
+            Some(nonce) if !nonce.is_empty() => nonce.to_owned(),
+            _ => return Err(MqttError::from("client nonce is missing")),
+        };
+        Ok(Self { username, gs2_header, bare: bare.to_owned(), nonce })
+    }
+
+    #[inline]
+    pub fn server_first(self, cred: &Credential) -> (ServerFirst, String) {
+        let server_nonce = BASE64_STANDARD.encode(rand::thread_rng().gen::<[u8; 18]>());
+        self.server_first_with_nonce(cred, &server_nonce)
+    }
+
+    #[inline]
+    fn server_first_with_nonce(self, cred: &Credential, server_nonce: &str) -> (ServerFirst, String) {
+        let nonce = format!("{}{}", self.nonce, server_nonce);
+        let msg = format!("r={},s={},i={}", nonce, BASE64_STANDARD.encode(&cred.salt), cred.iterations);
+        let server_first = ServerFirst {
+            username: self.username,
+            gs2_header: self.gs2_header,
+            client_first_bare: self.bare,
+            server_first: msg.clone(),
+            nonce,
+        };
+        (server_first, msg)
+    }
+}
+
+///Server state between server-first-message and client-final-message
+#[derive(Debug, Clone)]
+pub struct ServerFirst {
+    pub username: String,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+}
+
+impl ServerFirst {
+    ///Verify the client-final-message, returns the server-final-message
+    #[inline]
+    pub fn verify(&self, cred: &Credential, data: &[u8]) -> Result<String> {
+        let msg = std::str::from_utf8(data)?;
+        let (without_proof, proof) =
+            msg.rsplit_once(",p=").ok_or_else(|| MqttError::from("client proof is missing"))?;
+
+        let mut attrs = without_proof.split(',');
+        let cbind = attrs
+            .next()
+            .and_then(|a| a.strip_prefix("c="))
+            .ok_or_else(|| MqttError::from("channel binding is missing"))?;
+        if BASE64_STANDARD.decode(cbind).map_err(|e| MqttError::from(e.to_string()))?
+            != self.gs2_header.as_bytes()
+        {
+            return Err(MqttError::from("channel binding mismatch"));
+        }
+        if attrs.next().and_then(|a| a.strip_prefix("r=")) != Some(self.nonce.as_str()) {
+            return Err(MqttError::from("nonce mismatch"));
+        }
+
+        let proof = BASE64_STANDARD.decode(proof).map_err(|e| MqttError::from(e.to_string()))?;
+        let auth_message = format!("{},{},{}", self.client_first_bare, self.server_first, without_proof);
+        let client_signature = hmac(&cred.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return Err(MqttError::from("invalid client proof"));
+        }
+        let client_key = proof.iter().zip(client_signature.iter()).map(|(p, s)| p ^ s).collect::<Vec<_>>();
+        if !constant_time_eq(&Sha256::digest(&client_key), &cred.stored_key) {
+            return Err(MqttError::from("invalid client proof"));
+        }
+
+        let server_signature = hmac(&cred.server_key, auth_message.as_bytes());
+        Ok(format!("v={}", BASE64_STANDARD.encode(server_signature)))
+    }
+}
+
+#[inline]
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[inline]
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[inline]
+fn decode_saslname(name: &str) -> Result<String> {
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c == '=' {
+            match (chars.next(), chars.next()) {
+                (Some('2'), Some('C')) => out.push(','),
+                (Some('3'), Some('D')) => out.push('='),
+                _ => return Err(MqttError::from("invalid username encoding")),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc7677() {
+        let salt = BASE64_STANDARD.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let cred = Credential::from_password(b"pencil", salt, 4096, false);
+
+        let client_first = ClientFirst::parse(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO").unwrap();
+        assert_eq!(client_first.username, "user");
+        let (server_first, msg) =
+            client_first.server_first_with_nonce(&cred, "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0");
+        assert_eq!(
+            msg,
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+        );
+
+        let server_final = server_first
+            .verify(
+                &cred,
+                b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+            )
+            .unwrap();
+        assert_eq!(server_final, "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+
+        let bad_proof = b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=AAAAAapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+        assert!(server_first.verify(&cred, bad_proof).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+#[macro_use]
+extern crate rmqtt_macros;
+
+use std::sync::Arc;
+
+use rmqtt::{
+    async_trait::async_trait,
+    futures::StreamExt,
+    log,
+    serde_json::{self, json},
+};
+
+use rmqtt::{
+    broker::default::DefaultBanManager,
+    broker::types::{Ban, BanKey},
+    broker::BanManager,
+    plugin::{PackageInfo, Plugin},
+    register, timestamp_secs, Id, Result, Runtime,
+};
+
+use rmqtt_storage::{init_db, DefaultStorageDB, StorageType};
+
+use config::PluginConfig;
+
+mod config;
+
+const BAN_PREFIX: &[u8] = b"ban|";
+
+register!(BanStoragePlugin::new);
+
+#[derive(Plugin)]
+struct BanStoragePlugin {
+    runtime: &'static Runtime,
+    cfg: Arc<PluginConfig>,
+    ban_mgr: StorageBanManager,
+}
+
+impl BanStoragePlugin {
+    #[inline]
+    async fn new<S: Into<String>>(runtime: &'static Runtime, name: S) -> Result<Self> {
+        let name = name.into();
+        let mut cfg = runtime.settings.plugins.load_config_default::<PluginConfig>(&name)?;
+        match cfg.storage.typ {
+            StorageType::Sled => {
+                cfg.storage.sled.path =
+                    cfg.storage.sled.path.replace("{node}", &format!("{}", runtime.node.id()));
+            }
+            StorageType::Redis => {
+                cfg.storage.redis.prefix =
+                    cfg.storage.redis.prefix.replace("{node}", &format!("{}", runtime.node.id()));
+            }
+            StorageType::RedisCluster => {
+                cfg.storage.redis_cluster.prefix =
+                    cfg.storage.redis_cluster.prefix.replace("{node}", &format!("{}", runtime.node.id()));
+            }
+        }
+
+        log::info!("{} BanStoragePlugin cfg: {:?}", name, cfg);
+
+        let storage_db = init_db(&cfg.storage).await?;
+        let ban_mgr = StorageBanManager { storage_db, cache: DefaultBanManager::instance() };
+        Ok(Self { runtime, cfg: Arc::new(cfg), ban_mgr })
+    }
+}
+
+#[async_trait]
+impl Plugin for BanStoragePlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name());
+        let loaded = self.ban_mgr.load().await?;
+        log::info!("{} loaded ban entries: {}", self.name(), loaded);
+        Ok(())
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        Ok(self.cfg.to_json())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name());
+        *self.runtime.extends.ban_mgr_mut().await = Box::new(self.ban_mgr.clone());
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::warn!("{} stop, the ban storage plug-in, it cannot be stopped", self.name());
+        Ok(false)
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        json!({
+            "bans": self.ban_mgr.cache.count().await,
+        })
+    }
+}
+
+///Ban entries are kept in memory for lookups and written through to the storage
+#[derive(Clone)]
+struct StorageBanManager {
+    storage_db: DefaultStorageDB,
+    cache: &'static DefaultBanManager,
+}
+
+impl StorageBanManager {
+    #[inline]
+    fn stored_key(key: &BanKey) -> Vec<u8> {
+        [BAN_PREFIX, key.kind().as_bytes(), b"|", key.who().as_bytes()].concat()
+    }
+
+    ///Load all unexpired ban entries from the storage into memory
+    async fn load(&self) -> Result<usize> {
+        let mut db = self.storage_db.clone();
+        let mut keys = Vec::new();
+        let mut iter = db.scan([BAN_PREFIX, b"*"].concat()).await?;
+        while let Some(key) = iter.next().await {
+            match key {
+                Ok(key) if key.starts_with(BAN_PREFIX) => keys.push(key),
+                Ok(_) => {}
+                Err(e) => log::warn!("scan ban entries error, {:?}", e),
+            }
+        }
+        drop(iter);
+
+        let mut loaded = 0;
+        for key in keys {
+            match self.storage_db.get::<_, Ban>(key.as_slice()).await {
+                Ok(Some(ban)) if !ban.is_expired() => {
+                    self.cache.add(ban).await?;
+                    loaded += 1;
+                }
+                Ok(Some(_)) => {
+                    if let Err(e) = self.storage_db.remove(key.as_slice()).await {
+                        log::warn!("remove expired ban entry error, {:?}", e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => log::warn!("load ban entry error, {:?}", e),
+            }
+        }
+        Ok(loaded)
+    }
+}
+
+#[async_trait]
+impl BanManager for StorageBanManager {
+    #[inline]
+    async fn add(&self, ban: Ban) -> Result<()> {
+        let key = Self::stored_key(&ban.key);
+        self.storage_db.insert(key.as_slice(), &ban).await?;
+        if let Some(until) = ban.until {
+            let ttl_millis = (until - timestamp_secs()).max(1) * 1000;
+            self.storage_db.expire(key.as_slice(), ttl_millis).await?;
+        }
+        self.cache.add(ban).await
+    }
+
+    #[inline]
+    async fn remove(&self, key: &BanKey) -> Result<Option<Ban>> {
+        self.storage_db.remove(Self::stored_key(key).as_slice()).await?;
+        self.cache.remove(key).await
+    }
+
+    #[inline]
+    async fn get(&self, key: &BanKey) -> Result<Option<Ban>> {
+        self.cache.get(key).await
+    }
+
+    #[inline]
+    async fn list(&self) -> Result<Vec<Ban>> {
+        self.cache.list().await
+    }
+
+    #[inline]
+    async fn check(&self, id: &Id) -> Option<Ban> {
+        self.cache.check(id).await
+    }
+
+    #[inline]
+    async fn count(&self) -> usize {
+        self.cache.count().await
+    }
+}
@@ -1,6 +1,9 @@
+use std::time::Duration;
+
+use rmqtt::broker::discovery::Discovery;
 use rmqtt::grpc::MessageType;
 use rmqtt::serde_json;
-use rmqtt::settings::NodeAddr;
+use rmqtt::settings::{deserialize_duration, NodeAddr};
 use rmqtt::Result;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -8,7 +11,23 @@ pub struct PluginConfig {
     #[serde(default = "PluginConfig::message_type_default")]
     pub message_type: MessageType,
 
+    #[serde(default)]
     pub node_grpc_addrs: Vec<NodeAddr>,
+
+    ///The discovered nodes are merged into node_grpc_addrs
+    #[serde(default)]
+    pub discovery: Option<Discovery>,
+
+    ///Publish messages are only forwarded to the nodes whose topic filters can match the topic
+    #[serde(default)]
+    pub route_aware: bool,
+
+    ///Interval of checking and resyncing the topic filters of the other nodes
+    #[serde(
+        default = "PluginConfig::route_sync_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub route_sync_interval: Duration,
 }
 
 impl PluginConfig {
@@ -16,6 +35,10 @@ impl PluginConfig {
         98
     }
 
+    fn route_sync_interval_default() -> Duration {
+        Duration::from_secs(10)
+    }
+
     #[inline]
     pub fn to_json(&self) -> Result<serde_json::Value> {
         Ok(serde_json::to_value(self)?)
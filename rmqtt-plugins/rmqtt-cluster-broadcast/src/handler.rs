@@ -6,7 +6,7 @@ use rmqtt::{
         types::{From, Publish, SubRelationsMap, SubscriptionClientIds},
     },
     grpc::{Message, MessageReply},
-    Id, Runtime,
+    Id, MqttError, Runtime,
 };
 
 use super::{hook_message_dropped, router::ClusterRouter, shared::ClusterShared};
@@ -127,6 +127,13 @@ impl Handler for HookHandler {
                         let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::SessionStatus(status)));
                         return (false, Some(new_acc));
                     }
+                    Message::Data(data) => {
+                        let reply = match self.shared.routes() {
+                            Some(routes) => routes.on_message(data),
+                            None => Err(MqttError::from("route-aware forwarding is not enabled")),
+                        };
+                        return (false, Some(HookResult::GrpcMessageReply(reply)));
+                    }
 
                     _ => {
                         log::error!("unimplemented, {:?}", param)
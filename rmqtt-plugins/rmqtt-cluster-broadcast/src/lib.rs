@@ -14,17 +14,21 @@ use rmqtt::{
     async_trait::async_trait,
     log,
     serde_json::{self, json},
+    tokio,
     tokio::sync::RwLock,
 };
 use rmqtt::{
     broker::{
+        discovery::{merge_node_addrs, Discovery},
         error::MqttError,
         hook::{Register, Type},
         types::{From, OfflineSession, Publish, Reason, To},
     },
     grpc::{GrpcClients, Message, MessageReply, MessageType},
     plugin::{PackageInfo, Plugin},
-    register, Result, Runtime,
+    register,
+    settings::NodeAddr,
+    Result, Runtime,
 };
 use router::ClusterRouter;
 use shared::ClusterShared;
@@ -32,6 +36,7 @@ use shared::ClusterShared;
 mod config;
 mod handler;
 mod router;
+mod routes;
 mod shared;
 
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
@@ -43,7 +48,6 @@ struct ClusterPlugin {
     runtime: &'static Runtime,
     register: Box<dyn Register>,
     cfg: Arc<RwLock<PluginConfig>>,
-    grpc_clients: GrpcClients,
     shared: &'static ClusterShared,
     router: &'static ClusterRouter,
 }
@@ -58,22 +62,66 @@ impl ClusterPlugin {
         log::debug!("{} ClusterPlugin cfg: {:?}", name, cfg.read().await);
 
         let register = runtime.extends.hook_mgr().await.register();
-        let mut grpc_clients = HashMap::default();
-        let node_grpc_addrs = cfg.read().await.node_grpc_addrs.clone();
-        for node_addr in &node_grpc_addrs {
-            if node_addr.id != runtime.node.id() {
-                grpc_clients.insert(
-                    node_addr.id,
-                    (node_addr.addr.clone(), runtime.node.new_grpc_client(&node_addr.addr).await?),
-                );
+        let mut node_grpc_addrs = cfg.read().await.node_grpc_addrs.clone();
+        if let Some(discovery) = cfg.read().await.discovery.as_ref() {
+            merge_node_addrs(
+                &mut node_grpc_addrs,
+                discovery.grpc_addrs(&discovery.discover_at_startup().await),
+            );
+            log::info!("node_grpc_addrs: {:?}", node_grpc_addrs);
+        }
+        let grpc_clients = new_grpc_clients(&node_grpc_addrs, &GrpcClients::default()).await?;
+        let (message_type, route_aware) = {
+            let cfg = cfg.read().await;
+            (cfg.message_type, cfg.route_aware)
+        };
+        let shared = ClusterShared::get_or_init(grpc_clients, message_type, route_aware);
+        let router = ClusterRouter::get_or_init(shared, message_type);
+        Ok(Self { runtime, register, cfg, shared, router })
+    }
+
+    //periodically merge the discovered nodes into node_grpc_addrs
+    fn start_discovery(&self, discovery: Discovery) {
+        let (cfg, shared) = (self.cfg.clone(), self.shared);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(discovery.refresh_interval).await;
+                let nodes = match discovery.discover().await {
+                    Ok(nodes) => nodes,
+                    Err(e) => {
+                        log::warn!("discover the cluster nodes error, {}", e);
+                        continue;
+                    }
+                };
+                let mut node_grpc_addrs = cfg.read().await.node_grpc_addrs.clone();
+                merge_node_addrs(&mut node_grpc_addrs, discovery.grpc_addrs(&nodes));
+                match new_grpc_clients(&node_grpc_addrs, &shared.grpc_clients()).await {
+                    Ok(grpc_clients) => shared.set_grpc_clients(grpc_clients),
+                    Err(e) => log::warn!("update the gRPC clients error, {}", e),
+                }
             }
+        });
+    }
+}
+
+//the clients whose address is not changed are reused
+async fn new_grpc_clients(node_grpc_addrs: &[NodeAddr], olds: &GrpcClients) -> Result<GrpcClients> {
+    let runtime = Runtime::instance();
+    let mut grpc_clients = HashMap::default();
+    for node_addr in node_grpc_addrs {
+        if node_addr.id == runtime.node.id() {
+            continue;
         }
-        let grpc_clients = Arc::new(grpc_clients);
-        let message_type = cfg.read().await.message_type;
-        let router = ClusterRouter::get_or_init(grpc_clients.clone(), message_type);
-        let shared = ClusterShared::get_or_init(grpc_clients.clone(), message_type);
-        Ok(Self { runtime, register, cfg, grpc_clients, shared, router })
+        let client = match olds.get(&node_addr.id) {
+            Some((addr, c)) if *addr == node_addr.addr => c.clone(),
+            _ => {
+                log::info!("add gRPC client, node: {:?}", node_addr);
+                runtime.node.new_grpc_client(&node_addr.addr).await?
+            }
+        };
+        grpc_clients.insert(node_addr.id, (node_addr.addr.clone(), client));
     }
+    Ok(Arc::new(grpc_clients))
 }
 
 #[async_trait]
@@ -84,6 +132,12 @@ impl Plugin for ClusterPlugin {
         self.register
             .add(Type::GrpcMessageReceived, Box::new(HookHandler::new(self.shared, self.router)))
             .await;
+        if let Some(discovery) = self.cfg.read().await.discovery.clone() {
+            self.start_discovery(discovery);
+        }
+        if let Some(routes) = self.shared.routes() {
+            routes.start(self.shared, self.cfg.read().await.route_sync_interval);
+        }
         Ok(())
     }
 
@@ -110,7 +164,7 @@ impl Plugin for ClusterPlugin {
     #[inline]
     async fn attrs(&self) -> serde_json::Value {
         let mut nodes = HashMap::default();
-        for (id, (addr, c)) in self.grpc_clients.iter() {
+        for (id, (addr, c)) in self.shared.grpc_clients().iter() {
             let stats = json!({
                 "channel_tasks": c.channel_tasks(),
                 "active_tasks": c.active_tasks(),
@@ -119,6 +173,7 @@ impl Plugin for ClusterPlugin {
         }
         json!({
             "grpc_clients": nodes,
+            "routes": self.shared.routes().map(|routes| routes.to_json()),
         })
     }
 }
@@ -5,25 +5,30 @@ use rmqtt::{async_trait::async_trait, itertools, log, once_cell, serde_json};
 use rmqtt::{
     broker::{
         default::DefaultRouter,
-        types::{AllRelationsMap, Id, NodeId, Route, SubRelationsMap, SubscriptionOptions, TopicName},
+        types::{
+            AllRelationsMap, ClientId, Id, IsOnline, NodeId, Route, SharedGroup, SubRelationsMap,
+            SubsSearchParams, SubscriptionIdentifier, SubscriptionOptions, TopicName,
+        },
         Router,
     },
-    grpc::{GrpcClients, Message, MessageBroadcaster, MessageReply, MessageSender, MessageType},
+    grpc::{Message, MessageBroadcaster, MessageReply, MessageSender, MessageType},
     stats::Counter,
-    HashMap, Result, TopicFilter,
+    HashMap, Result, Runtime, TopicFilter,
 };
 
+use super::ClusterShared;
+
 pub(crate) struct ClusterRouter {
     inner: &'static DefaultRouter,
-    grpc_clients: GrpcClients,
+    shared: &'static ClusterShared,
     message_type: MessageType,
 }
 
 impl ClusterRouter {
     #[inline]
-    pub(crate) fn get_or_init(grpc_clients: GrpcClients, message_type: MessageType) -> &'static Self {
+    pub(crate) fn get_or_init(shared: &'static ClusterShared, message_type: MessageType) -> &'static Self {
         static INSTANCE: OnceCell<ClusterRouter> = OnceCell::new();
-        INSTANCE.get_or_init(|| Self { inner: DefaultRouter::instance(), grpc_clients, message_type })
+        INSTANCE.get_or_init(|| Self { inner: DefaultRouter::instance(), shared, message_type })
     }
 
     #[inline]
@@ -36,12 +41,22 @@ impl ClusterRouter {
 impl Router for &'static ClusterRouter {
     #[inline]
     async fn add(&self, topic_filter: &str, id: Id, opts: SubscriptionOptions) -> Result<()> {
-        self.inner.add(topic_filter, id, opts).await
+        self.inner.add(topic_filter, id, opts).await?;
+        if let Some(routes) = self.shared.routes() {
+            if let Some(seq) = routes.changed(topic_filter, &self.inner.relations) {
+                routes.wait_acked(seq, self.shared.grpc_clients()).await;
+            }
+        }
+        Ok(())
     }
 
     #[inline]
     async fn remove(&self, topic_filter: &str, id: Id) -> Result<bool> {
-        self.inner.remove(topic_filter, id).await
+        let removed = self.inner.remove(topic_filter, id).await?;
+        if let Some(routes) = self.shared.routes() {
+            routes.changed(topic_filter, &self.inner.relations);
+        }
+        Ok(removed)
     }
 
     #[inline]
@@ -49,15 +64,114 @@ impl Router for &'static ClusterRouter {
         self.inner.matches(id, topic).await
     }
 
-    ///Check online or offline
+    #[inline]
+    async fn matches_excluded(&self, id: Id, topic: &TopicName, excluded: &Id) -> Result<SubRelationsMap> {
+        //the shared subscription members of this node are chosen again together with the other nodes
+        let mut relations_map = self.inner.matches_excluded(id.clone(), topic, excluded).await?;
+        for relations in relations_map.values_mut() {
+            relations.retain(|(_, _, _, _, group)| group.is_none());
+        }
+        relations_map.retain(|_, relations| !relations.is_empty());
+
+        let q = SubsSearchParams {
+            _limit: usize::MAX,
+            _match_topic: Some(topic.to_string()),
+            ..Default::default()
+        };
+        let mut subs = self.shared.inner()._query_subscriptions(&q).await;
+        let replys = MessageBroadcaster::new(
+            self.shared.grpc_clients(),
+            self.message_type,
+            Message::SubscriptionsSearch(q),
+        )
+        .join_all()
+        .await;
+        for (node_id, reply) in replys {
+            match reply {
+                Ok(MessageReply::SubscriptionsSearch(o_subs)) => subs.extend(o_subs),
+                Ok(reply) => {
+                    log::warn!("matches_excluded, unexpected reply from node {}, {:?}", node_id, reply)
+                }
+                Err(e) => {
+                    log::warn!("matches_excluded, query subscriptions from node {} error, {:?}", node_id, e)
+                }
+            }
+        }
+
+        #[allow(clippy::mutable_key_type)]
+        let mut groups: HashMap<
+            (TopicFilter, SharedGroup),
+            Vec<(
+                NodeId,
+                ClientId,
+                SubscriptionOptions,
+                Option<Vec<SubscriptionIdentifier>>,
+                Option<IsOnline>,
+            )>,
+        > = HashMap::default();
+        for sub in subs {
+            if sub.node_id == excluded.node_id && sub.clientid == excluded.client_id {
+                continue;
+            }
+            //MQTT V5: No Local
+            if sub.opts.no_local().unwrap_or_default()
+                && sub.node_id == id.node_id
+                && sub.clientid == id.client_id
+            {
+                continue;
+            }
+            if let Some(group) = sub.opts.shared_group().cloned() {
+                groups.entry((sub.topic, group)).or_default().push((
+                    sub.node_id,
+                    sub.clientid,
+                    sub.opts,
+                    None,
+                    None,
+                ));
+            }
+        }
+
+        for ((topic_filter, group), mut subs) in groups {
+            let group_cids = subs.iter().map(|(_, cid, _, _, _)| cid.clone()).collect();
+            if let Some((idx, is_online)) = Runtime::instance()
+                .extends
+                .shared_subscription()
+                .await
+                .choice_for(&topic_filter, &group, topic, &id.client_id, &subs)
+                .await
+            {
+                let (node_id, client_id, opts, sub_ids, _) = subs.remove(idx);
+                relations_map.entry(node_id).or_default().push((
+                    topic_filter,
+                    client_id,
+                    opts,
+                    sub_ids,
+                    Some((group, is_online, group_cids)),
+                ));
+            }
+        }
+        Ok(relations_map)
+    }
+
+    ///Check online or offline, the clients of the other nodes are checked on their node
     async fn is_online(&self, node_id: NodeId, client_id: &str) -> bool {
-        self.inner.is_online(node_id, client_id).await
+        if node_id == Runtime::instance().node.id() {
+            return self.inner.is_online(node_id, client_id).await;
+        }
+        let c = match self.shared.grpc_clients().get(&node_id) {
+            Some((_, c)) => c.clone(),
+            None => return false,
+        };
+        matches!(
+            MessageSender::new(c, self.message_type, Message::Online(ClientId::from(client_id))).send().await,
+            Ok(MessageReply::Online(true))
+        )
     }
 
     #[inline]
     async fn gets(&self, limit: usize) -> Vec<Route> {
         let mut routes = self.inner.gets(limit).await;
-        for (_id, (_addr, c)) in self.grpc_clients.iter() {
+        for (_id, (_addr, c)) in self.shared.grpc_clients().iter() {
             if routes.len() < limit {
                 let reply = MessageSender::new(
                     c.clone(),
@@ -87,7 +201,7 @@ impl Router for &'static ClusterRouter {
         let routes = self.inner._get_routes(topic).await?;
 
         let mut replys = MessageBroadcaster::new(
-            self.grpc_clients.clone(),
+            self.shared.grpc_clients(),
             self.message_type,
             Message::RoutesGetBy(TopicFilter::from(topic)),
         )
@@ -0,0 +1,507 @@
+use std::collections::{HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmqtt::{
+    ahash, anyhow, bincode, futures, log,
+    rust_box::std_ext::RwLock,
+    serde_json::{self, json},
+    tokio::{self, sync::Notify},
+};
+use rmqtt::{
+    broker::{
+        topic::{Topic, TopicTree},
+        types::{AllRelationsMap, NodeId, TopicFilter},
+    },
+    grpc::{GrpcClients, Message, MessageReply, MessageSender},
+    timestamp_millis, MqttError, Result, Runtime,
+};
+
+use super::shared::ClusterShared;
+
+type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
+
+//the changes that are not acknowledged by every node are kept at most, the nodes that are
+//further behind find the gap and resync all topic filters
+const MAX_DELTAS: usize = 100_000;
+const MAX_DELTAS_PER_MESSAGE: usize = 1_000;
+const DELTAS_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+//how long a new subscription waits for the other nodes to acknowledge the topic filter
+const ACK_TIMEOUT: Duration = Duration::from_secs(3);
+
+///(epoch, seq), the epoch is changed after the node is restarted
+pub(crate) type Version = (u64, u64);
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum RouteMessage {
+    ///The changes of the topic filters of the node, (node id, epoch, [(seq, is_added, topic filter)])
+    Deltas(NodeId, u64, Vec<(u64, bool, TopicFilter)>),
+    ///All topic filters, unless the known version is the current one
+    FiltersGet(Option<Version>),
+}
+
+impl RouteMessage {
+    #[inline]
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self).map_err(anyhow::Error::new)?)
+    }
+    #[inline]
+    pub fn decode(data: &[u8]) -> Result<RouteMessage> {
+        Ok(bincode::deserialize::<RouteMessage>(data).map_err(anyhow::Error::new)?)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum RouteReply {
+    Unchanged,
+    Filters(Version, Vec<TopicFilter>),
+}
+
+impl RouteReply {
+    #[inline]
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self).map_err(anyhow::Error::new)?)
+    }
+    #[inline]
+    pub fn decode(data: &[u8]) -> Result<RouteReply> {
+        Ok(bincode::deserialize::<RouteReply>(data).map_err(anyhow::Error::new)?)
+    }
+}
+
+#[derive(Default)]
+struct LocalFilters {
+    seq: u64,
+    filters: HashSet<TopicFilter>,
+    //the changes that are not acknowledged by every node yet, oldest first
+    deltas: VecDeque<(u64, bool, TopicFilter)>,
+}
+
+#[derive(Default)]
+struct PeerState {
+    //the last change acknowledged by the node
+    acked: u64,
+    //the last send failed, the node is retried but not waited for
+    failed: bool,
+}
+
+struct NodeFilters {
+    version: Version,
+    topics: TopicTree<()>,
+    filters: usize,
+    //the deltas are lost, the node is treated as unknown until it is resynced
+    stale: bool,
+}
+
+///The topic filters of this node and the other nodes, publish messages are only forwarded
+///to the nodes whose topic filters can match the topic.
+pub(crate) struct Routes {
+    epoch: u64,
+    local: RwLock<LocalFilters>,
+    nodes: RwLock<HashMap<NodeId, NodeFilters>>,
+    peers: RwLock<HashMap<NodeId, PeerState>>,
+    deltas_notify: Notify,
+    acked_notify: Notify,
+}
+
+impl Routes {
+    #[inline]
+    pub(crate) fn new() -> Self {
+        Self {
+            epoch: timestamp_millis() as u64,
+            local: RwLock::new(LocalFilters::default()),
+            nodes: RwLock::new(HashMap::default()),
+            peers: RwLock::new(HashMap::default()),
+            deltas_notify: Notify::new(),
+            acked_notify: Notify::new(),
+        }
+    }
+
+    ///Records the change of the local topic filter, it is called after the router is updated.
+    ///Returns the sequence of the change if the topic filter is added.
+    #[inline]
+    pub(crate) fn changed(&self, topic_filter: &str, relations: &AllRelationsMap) -> Option<u64> {
+        let mut local = self.local.write();
+        let is_added = relations.contains_key(topic_filter);
+        let changed = if is_added {
+            local.filters.insert(TopicFilter::from(topic_filter))
+        } else {
+            local.filters.remove(topic_filter)
+        };
+        if changed {
+            local.seq += 1;
+            let seq = local.seq;
+            local.deltas.push_back((seq, is_added, TopicFilter::from(topic_filter)));
+            if local.deltas.len() > MAX_DELTAS {
+                local.deltas.pop_front();
+            }
+            drop(local);
+            self.deltas_notify.notify_one();
+            if is_added {
+                return Some(seq);
+            }
+        }
+        None
+    }
+
+    ///Waits until the change is acknowledged by the nodes, so that the publish messages of the
+    ///other nodes are forwarded to this node once the subscription is acknowledged to the client.
+    ///The nodes that can not be reached are not waited for.
+    pub(crate) async fn wait_acked(&self, seq: u64, grpc_clients: GrpcClients) {
+        let waiting = async {
+            loop {
+                let notified = self.acked_notify.notified();
+                if self.is_acked(seq, &grpc_clients) {
+                    break;
+                }
+                notified.await;
+            }
+        };
+        if tokio::time::timeout(ACK_TIMEOUT, waiting).await.is_err() {
+            log::warn!(
+                "the topic filter change {} is not acknowledged by all nodes in {:?}",
+                seq,
+                ACK_TIMEOUT
+            );
+        }
+    }
+
+    #[inline]
+    fn is_acked(&self, seq: u64, grpc_clients: &GrpcClients) -> bool {
+        let peers = self.peers.read();
+        grpc_clients.keys().all(|id| peers.get(id).map(|p| p.failed || p.acked >= seq).unwrap_or(false))
+    }
+
+    ///Returns the nodes whose topic filters can match the topic, including the unknown nodes
+    #[inline]
+    pub(crate) fn matched_clients(&self, grpc_clients: GrpcClients, topic: &str) -> GrpcClients {
+        let topic = match Topic::from_str(topic) {
+            Ok(topic) => topic,
+            Err(_) => return grpc_clients,
+        };
+        let nodes = self.nodes.read();
+        Arc::new(
+            grpc_clients
+                .iter()
+                .filter(|(id, _)| Self::is_match(&nodes, *id, &topic))
+                .map(|(id, c)| (*id, c.clone()))
+                .collect(),
+        )
+    }
+
+    #[inline]
+    fn is_match(nodes: &HashMap<NodeId, NodeFilters>, node_id: NodeId, topic: &Topic) -> bool {
+        nodes.get(&node_id).map(|n| n.stale || n.topics.is_match(topic)).unwrap_or(true)
+    }
+
+    pub(crate) fn on_message(&self, data: &[u8]) -> Result<MessageReply> {
+        match RouteMessage::decode(data)? {
+            RouteMessage::Deltas(node_id, epoch, deltas) => {
+                self.apply_deltas(node_id, epoch, deltas);
+                Ok(MessageReply::Success)
+            }
+            RouteMessage::FiltersGet(known) => Ok(MessageReply::Data(self.filters_get(known).encode()?)),
+        }
+    }
+
+    fn apply_deltas(&self, node_id: NodeId, epoch: u64, deltas: Vec<(u64, bool, TopicFilter)>) {
+        let mut nodes = self.nodes.write();
+        let node = match nodes.get_mut(&node_id) {
+            Some(node) if !node.stale => node,
+            _ => return,
+        };
+        if node.version.0 != epoch {
+            node.stale = true;
+            return;
+        }
+        for (seq, is_added, topic_filter) in deltas {
+            if seq <= node.version.1 {
+                continue;
+            }
+            if seq != node.version.1 + 1 {
+                log::info!(
+                    "topic filter changes of node {} are lost, {} => {}",
+                    node_id,
+                    node.version.1,
+                    seq
+                );
+                node.stale = true;
+                return;
+            }
+            match Topic::from_str(&topic_filter) {
+                Ok(topic) if is_added => {
+                    if node.topics.insert(&topic, ()) {
+                        node.filters += 1;
+                    }
+                }
+                Ok(topic) => {
+                    if node.topics.remove(&topic, &()) {
+                        node.filters -= 1;
+                    }
+                }
+                Err(e) => log::warn!("invalid topic filter {:?} of node {}, {:?}", topic_filter, node_id, e),
+            }
+            node.version.1 = seq;
+        }
+    }
+
+    fn filters_get(&self, known: Option<Version>) -> RouteReply {
+        let local = self.local.read();
+        let version = (self.epoch, local.seq);
+        if known == Some(version) {
+            RouteReply::Unchanged
+        } else {
+            RouteReply::Filters(version, local.filters.iter().cloned().collect())
+        }
+    }
+
+    fn set_filters(&self, node_id: NodeId, version: Version, filters: Vec<TopicFilter>) {
+        let mut topics = TopicTree::default();
+        for topic_filter in filters.iter() {
+            match Topic::from_str(topic_filter) {
+                Ok(topic) => {
+                    topics.insert(&topic, ());
+                }
+                Err(e) => log::warn!("invalid topic filter {:?} of node {}, {:?}", topic_filter, node_id, e),
+            }
+        }
+        let mut nodes = self.nodes.write();
+        //the changes after the version may already be applied
+        if nodes.get(&node_id).map(|n| n.version >= version).unwrap_or(false) {
+            log::debug!("topic filters of node {} are outdated, version: {:?}", node_id, version);
+            return;
+        }
+        log::debug!("topic filters of node {} are synced, version: {:?}", node_id, version);
+        nodes.insert(node_id, NodeFilters { version, topics, filters: filters.len(), stale: false });
+    }
+
+    #[inline]
+    fn known_version(&self, node_id: NodeId) -> Option<Version> {
+        self.nodes.read().get(&node_id).filter(|n| !n.stale).map(|n| n.version)
+    }
+
+    ///Returns the changes that are not acknowledged by the node yet, and the last sequence of them
+    #[inline]
+    fn unacked_deltas(&self, node_id: NodeId, this_node_id: NodeId) -> Option<(u64, RouteMessage)> {
+        let acked = self.peers.write().entry(node_id).or_default().acked;
+        let deltas = self
+            .local
+            .read()
+            .deltas
+            .iter()
+            .filter(|(seq, _, _)| *seq > acked)
+            .take(MAX_DELTAS_PER_MESSAGE)
+            .cloned()
+            .collect::<Vec<_>>();
+        let last = deltas.last()?.0;
+        Some((last, RouteMessage::Deltas(this_node_id, self.epoch, deltas)))
+    }
+
+    ///Sends the unacknowledged changes to each node, the nodes that fail are retried later
+    async fn send_deltas(&self, shared: &'static ClusterShared) {
+        let grpc_clients = shared.grpc_clients();
+        let this_node_id = Runtime::instance().node.id();
+        self.peers.write().retain(|id, _| grpc_clients.contains_key(id));
+        let sends = grpc_clients.iter().filter_map(|(id, (_, c))| {
+            let (last, msg) = self.unacked_deltas(*id, this_node_id)?;
+            Some(async move {
+                let reply = match msg.encode() {
+                    Ok(data) => {
+                        MessageSender::new(c.clone(), shared.message_type, Message::Data(data)).send().await
+                    }
+                    Err(e) => Err(e),
+                };
+                (*id, last, reply)
+            })
+        });
+        let mut has_more = false;
+        for (id, last, reply) in futures::future::join_all(sends).await {
+            let mut peers = self.peers.write();
+            let peer = peers.entry(id).or_default();
+            match reply {
+                Ok(MessageReply::Success) => {
+                    peer.acked = last;
+                    peer.failed = false;
+                    has_more |= self.local.read().seq > last;
+                }
+                Ok(reply) => {
+                    if !peer.failed {
+                        log::warn!("send the topic filter changes to node {} error, {:?}", id, reply);
+                    }
+                    peer.failed = true;
+                }
+                Err(e) => {
+                    if !peer.failed {
+                        log::warn!("send the topic filter changes to node {} error, {:?}", id, e);
+                    }
+                    peer.failed = true;
+                }
+            }
+        }
+        self.acked_notify.notify_waiters();
+
+        //the changes acknowledged by every node are no longer needed
+        let acked = self.peers.read().values().map(|p| p.acked).min().unwrap_or(0);
+        let mut local = self.local.write();
+        while local.deltas.front().map(|(seq, _, _)| *seq <= acked).unwrap_or(false) {
+            local.deltas.pop_front();
+        }
+        drop(local);
+        if has_more {
+            self.deltas_notify.notify_one();
+        }
+    }
+
+    #[inline]
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let nodes = self
+            .nodes
+            .read()
+            .iter()
+            .map(|(id, n)| (id.to_string(), json!({"filters": n.filters, "stale": n.stale})))
+            .collect::<serde_json::Map<_, _>>();
+        json!({
+            "filters": self.local.read().filters.len(),
+            "nodes": nodes,
+        })
+    }
+
+    ///Starts the tasks that send the local changes to the other nodes in order until they are
+    ///acknowledged, and periodically resync the topic filters of the nodes whose version is not
+    ///the current one
+    pub(crate) fn start(&'static self, shared: &'static ClusterShared, sync_interval: Duration) {
+        let routes = self;
+        tokio::spawn(async move {
+            loop {
+                let _ = tokio::time::timeout(DELTAS_RETRY_INTERVAL, routes.deltas_notify.notified()).await;
+                routes.send_deltas(shared).await;
+            }
+        });
+
+        tokio::spawn(async move {
+            loop {
+                let grpc_clients = shared.grpc_clients();
+                routes.nodes.write().retain(|id, _| grpc_clients.contains_key(id));
+                let syncs = grpc_clients.iter().map(|(id, (_, c))| async move {
+                    let msg = RouteMessage::FiltersGet(routes.known_version(*id)).encode()?;
+                    match MessageSender::new(c.clone(), shared.message_type, Message::Data(msg))
+                        .send()
+                        .await?
+                    {
+                        MessageReply::Data(data) => match RouteReply::decode(&data)? {
+                            RouteReply::Unchanged => {}
+                            RouteReply::Filters(version, filters) => {
+                                routes.set_filters(*id, version, filters)
+                            }
+                        },
+                        reply => return Err(MqttError::from(format!("unexpected reply, {:?}", reply))),
+                    }
+                    Ok::<_, MqttError>(())
+                });
+                for (res, id) in futures::future::join_all(syncs).await.into_iter().zip(grpc_clients.keys()) {
+                    if let Err(e) = res {
+                        log::warn!("sync the topic filters of node {} error, {:?}", id, e);
+                    }
+                }
+                tokio::time::sleep(sync_interval).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(routes: &Routes, node_id: NodeId, topic: &str) -> bool {
+        Routes::is_match(&routes.nodes.read(), node_id, &Topic::from_str(topic).unwrap())
+    }
+
+    fn version(routes: &Routes, node_id: NodeId) -> Option<Version> {
+        routes.nodes.read().get(&node_id).map(|n| n.version)
+    }
+
+    #[test]
+    fn filters_get() {
+        let routes = Routes::new();
+        {
+            let mut local = routes.local.write();
+            local.seq = 1;
+            local.filters.insert(TopicFilter::from("a/#"));
+        }
+        match routes.filters_get(None) {
+            RouteReply::Filters(version, filters) => {
+                assert_eq!(version, (routes.epoch, 1));
+                assert_eq!(filters, vec![TopicFilter::from("a/#")]);
+            }
+            reply => panic!("unexpected reply, {:?}", reply),
+        }
+        assert!(matches!(routes.filters_get(Some((routes.epoch, 1))), RouteReply::Unchanged));
+        assert!(matches!(routes.filters_get(Some((routes.epoch, 0))), RouteReply::Filters(..)));
+        assert!(matches!(routes.filters_get(Some((routes.epoch + 1, 1))), RouteReply::Filters(..)));
+    }
+
+    #[test]
+    fn apply_deltas() {
+        let routes = Routes::new();
+        //unknown nodes receive all messages
+        routes.apply_deltas(2, 1, vec![(1, true, TopicFilter::from("a/#"))]);
+        assert!(is_match(&routes, 2, "x/y"));
+
+        routes.set_filters(2, (1, 1), vec![TopicFilter::from("a/#")]);
+        assert!(is_match(&routes, 2, "a/b"));
+        assert!(!is_match(&routes, 2, "b/c"));
+
+        routes.apply_deltas(
+            2,
+            1,
+            vec![(2, true, TopicFilter::from("b/+")), (3, false, TopicFilter::from("a/#"))],
+        );
+        assert_eq!(version(&routes, 2), Some((1, 3)));
+        assert!(is_match(&routes, 2, "b/c"));
+        assert!(!is_match(&routes, 2, "a/b"));
+
+        //the changes that are already applied are skipped
+        routes.apply_deltas(2, 1, vec![(3, true, TopicFilter::from("a/#"))]);
+        assert_eq!(version(&routes, 2), Some((1, 3)));
+        assert!(!is_match(&routes, 2, "a/b"));
+
+        //a gap makes the node stale until it is resynced
+        routes.apply_deltas(2, 1, vec![(5, true, TopicFilter::from("c"))]);
+        assert!(routes.nodes.read().get(&2).unwrap().stale);
+        assert!(is_match(&routes, 2, "x/y"));
+        assert_eq!(routes.known_version(2), None);
+
+        routes.set_filters(2, (1, 5), vec![TopicFilter::from("c")]);
+        assert!(!routes.nodes.read().get(&2).unwrap().stale);
+        assert!(is_match(&routes, 2, "c"));
+        assert!(!is_match(&routes, 2, "x/y"));
+    }
+
+    #[test]
+    fn apply_deltas_epoch_changed() {
+        let routes = Routes::new();
+        routes.set_filters(2, (1, 1), vec![TopicFilter::from("a/#")]);
+        //the node is restarted
+        routes.apply_deltas(2, 2, vec![(1, true, TopicFilter::from("b"))]);
+        assert!(routes.nodes.read().get(&2).unwrap().stale);
+        assert!(is_match(&routes, 2, "x/y"));
+
+        routes.set_filters(2, (2, 1), vec![TopicFilter::from("b")]);
+        assert_eq!(version(&routes, 2), Some((2, 1)));
+        assert!(is_match(&routes, 2, "b"));
+        assert!(!is_match(&routes, 2, "a/b"));
+    }
+
+    #[test]
+    fn set_filters_outdated() {
+        let routes = Routes::new();
+        routes.set_filters(2, (1, 1), vec![TopicFilter::from("a/#")]);
+        routes.apply_deltas(2, 1, vec![(2, true, TopicFilter::from("b"))]);
+        //the reply of a resync that was sent before the change
+        routes.set_filters(2, (1, 1), vec![TopicFilter::from("a/#")]);
+        routes.set_filters(2, (1, 2), vec![]);
+        assert_eq!(version(&routes, 2), Some((1, 2)));
+        assert!(is_match(&routes, 2, "b"));
+    }
+}
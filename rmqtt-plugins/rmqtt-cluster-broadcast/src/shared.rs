@@ -3,7 +3,7 @@ use std::convert::From as _f;
 use once_cell::sync::OnceCell;
 
 use rmqtt::grpc::MessageSender;
-use rmqtt::{ahash, async_trait::async_trait, futures, log, once_cell, tokio};
+use rmqtt::{ahash, async_trait::async_trait, futures, log, once_cell, rust_box::std_ext::RwLock, tokio};
 use rmqtt::{
     broker::{
         default::DefaultShared,
@@ -20,7 +20,7 @@ use rmqtt::{
     MqttError, Result, Runtime,
 };
 
-use super::{hook_message_dropped, kick};
+use super::{hook_message_dropped, kick, routes::Routes};
 
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 
@@ -89,7 +89,7 @@ impl Entry for ClusterLockEntry {
         }
 
         match kick(
-            self.cluster_shared.grpc_clients.clone(),
+            self.cluster_shared.grpc_clients(),
             self.cluster_shared.message_type,
             Message::Kick(self.id(), clean_start, true, is_admin),
         )
@@ -113,7 +113,7 @@ impl Entry for ClusterLockEntry {
         }
 
         MessageBroadcaster::new(
-            self.cluster_shared.grpc_clients.clone(),
+            self.cluster_shared.grpc_clients(),
             self.cluster_shared.message_type,
             Message::Online(self.id().client_id.clone()),
         )
@@ -164,7 +164,7 @@ impl Entry for ClusterLockEntry {
             return Some(subs);
         }
         MessageBroadcaster::new(
-            self.cluster_shared.grpc_clients.clone(),
+            self.cluster_shared.grpc_clients(),
             self.cluster_shared.message_type,
             Message::SubscriptionsGet(self.id().client_id.clone()),
         )
@@ -182,7 +182,8 @@ impl Entry for ClusterLockEntry {
 
 pub struct ClusterShared {
     inner: &'static DefaultShared,
-    grpc_clients: GrpcClients,
+    grpc_clients: RwLock<GrpcClients>,
+    routes: Option<Routes>,
     pub message_type: MessageType,
 }
 
@@ -191,9 +192,30 @@ impl ClusterShared {
     pub(crate) fn get_or_init(
         grpc_clients: GrpcClients,
         message_type: MessageType,
+        route_aware: bool,
     ) -> &'static ClusterShared {
         static INSTANCE: OnceCell<ClusterShared> = OnceCell::new();
-        INSTANCE.get_or_init(|| Self { inner: DefaultShared::instance(), grpc_clients, message_type })
+        INSTANCE.get_or_init(|| Self {
+            inner: DefaultShared::instance(),
+            grpc_clients: RwLock::new(grpc_clients),
+            routes: if route_aware { Some(Routes::new()) } else { None },
+            message_type,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn grpc_clients(&self) -> GrpcClients {
+        self.grpc_clients.read().clone()
+    }
+
+    #[inline]
+    pub(crate) fn set_grpc_clients(&self, grpc_clients: GrpcClients) {
+        *self.grpc_clients.write() = grpc_clients;
+    }
+
+    #[inline]
+    pub(crate) fn routes(&self) -> Option<&Routes> {
+        self.routes.as_ref()
     }
 
     #[inline]
@@ -261,8 +283,11 @@ impl Shared for &'static ClusterShared {
         let local_res = self.inner.forwards_to(from.clone(), &publish, relations).await;
         log::debug!("forwards, from: {:?}, local_res: {:?}", from, local_res);
 
-        //forwards to remote
-        let grpc_clients = self.grpc_clients.clone();
+        //forwards to remote, only the nodes whose topic filters can match the topic in route-aware mode
+        let grpc_clients = match self.routes() {
+            Some(routes) => routes.matched_clients(self.grpc_clients(), topic),
+            None => self.grpc_clients(),
+        };
         let message_type = self.message_type;
         let inner = self.inner;
         let (sub_client_ids_tx, sub_client_ids_rx) = tokio::sync::oneshot::channel();
@@ -371,9 +396,13 @@ impl Shared for &'static ClusterShared {
             //shared subscription choice
             let mut node_shared_subs: HashMap<NodeId, SubRelations> = HashMap::default();
             for (topic_filter, sub_groups) in shared_sub_groups.iter_mut() {
-                for (_group, subs) in sub_groups.iter_mut() {
-                    if let Some((idx, _is_online)) =
-                        Runtime::instance().extends.shared_subscription().await.choice(subs).await
+                for (group, subs) in sub_groups.iter_mut() {
+                    if let Some((idx, _is_online)) = Runtime::instance()
+                        .extends
+                        .shared_subscription()
+                        .await
+                        .choice_for(topic_filter, group, &publish.topic, &from.id.client_id, subs)
+                        .await
                     {
                         let (node_id, client_id, opts, sub_ids, _is_online) = subs.remove(idx);
                         node_shared_subs.entry(node_id).or_default().push((
@@ -443,6 +472,54 @@ impl Shared for &'static ClusterShared {
         self.inner.forwards_to(from, publish, relations).await
     }
 
+    #[inline]
+    async fn forwards_to_nodes(
+        &self,
+        from: From,
+        publish: &Publish,
+        mut relations_map: SubRelationsMap,
+    ) -> Result<(), Vec<(To, From, Publish, Reason)>> {
+        let mut errs = Vec::new();
+        if let Some(relations) = relations_map.remove(&Runtime::instance().node.id()) {
+            if let Err(e) = self.inner.forwards_to(from.clone(), publish, relations).await {
+                errs.extend(e);
+            }
+        }
+        let grpc_clients = self.grpc_clients();
+        let mut delivers = Vec::new();
+        for (node_id, relations) in relations_map {
+            if let Some((_addr, grpc_client)) = grpc_clients.get(&node_id) {
+                let tos = relations
+                    .iter()
+                    .map(|(_, client_id, _, _, _)| To::from(node_id, client_id.clone()))
+                    .collect::<Vec<_>>();
+                let msg = Message::ForwardsTo(from.clone(), publish.clone(), relations);
+                delivers.push(async move { (tos, grpc_client.send_message(self.message_type, msg).await) });
+            } else {
+                for (_, client_id, _, _, _) in relations {
+                    errs.push((
+                        To::from(node_id, client_id),
+                        from.clone(),
+                        publish.clone(),
+                        Reason::from_static("grpc_client is not exist"),
+                    ));
+                }
+            }
+        }
+        for (tos, res) in futures::future::join_all(delivers).await {
+            if let Err(e) = res {
+                log::error!("forwards_to_nodes error, {:?}", e);
+                let reason = Reason::from(format!("forwards to other node error, {}", e));
+                errs.extend(tos.into_iter().map(|to| (to, from.clone(), publish.clone(), reason.clone())));
+            }
+        }
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(errs)
+        }
+    }
+
     #[inline]
     fn iter(&self) -> Box<dyn Iterator<Item = Box<dyn Entry>> + Sync + Send> {
         self.inner.iter()
@@ -459,7 +536,7 @@ impl Shared for &'static ClusterShared {
             return Some(status);
         }
         MessageBroadcaster::new(
-            self.grpc_clients.clone(),
+            self.grpc_clients(),
             self.message_type,
             Message::SessionStatus(ClientId::from(client_id)),
         )
@@ -520,6 +597,6 @@ impl Shared for &'static ClusterShared {
 
     #[inline]
     fn get_grpc_clients(&self) -> GrpcClients {
-        self.grpc_clients.clone()
+        self.grpc_clients()
     }
 }
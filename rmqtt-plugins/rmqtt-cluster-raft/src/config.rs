@@ -7,6 +7,7 @@ use serde::de::{self, Deserialize, Deserializer};
 use serde::ser::Serializer;
 use serde::Serialize;
 
+use rmqtt::broker::discovery::Discovery;
 use rmqtt::grpc::MessageType;
 use rmqtt::settings::{deserialize_duration, deserialize_duration_option, NodeAddr, Options};
 use rmqtt::{once_cell::sync::Lazy, serde_json};
@@ -29,10 +30,20 @@ pub struct PluginConfig {
 
     pub laddr: Option<Addr>,
 
+    #[serde(default)]
     pub node_grpc_addrs: Vec<NodeAddr>,
 
+    #[serde(default)]
     pub raft_peer_addrs: Vec<NodeAddr>,
 
+    ///Raft address of any node in a running cluster, this node joins the cluster through it
+    #[serde(default)]
+    pub seed_addr: Option<Addr>,
+
+    ///The discovered nodes are merged into node_grpc_addrs and raft_peer_addrs
+    #[serde(default)]
+    pub discovery: Option<Discovery>,
+
     #[serde(default)]
     pub leader_id: NodeId,
 
@@ -70,6 +81,11 @@ impl PluginConfig {
         }
     }
 
+    #[inline]
+    pub fn grpc_addr(&self, id: NodeId) -> Option<&Addr> {
+        self.node_grpc_addrs.iter().find(|n| n.id == id).map(|n| &n.addr)
+    }
+
     #[inline]
     pub fn to_json(&self) -> Result<serde_json::Value> {
         Ok(serde_json::to_value(self)?)
@@ -109,6 +125,9 @@ impl PluginConfig {
         if let Some(raft_leader_id) = opts.raft_leader_id.as_ref() {
             self.leader_id = *raft_leader_id;
         }
+        if let Some(raft_seed_addr) = opts.raft_seed_addr.as_ref() {
+            self.seed_addr = Some(Addr::from(raft_seed_addr.as_str()));
+        }
     }
 }
 
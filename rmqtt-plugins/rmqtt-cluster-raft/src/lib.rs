@@ -20,13 +20,15 @@ use rmqtt::{
     async_trait::async_trait,
     log, rand,
     serde_json::{self, json},
-    tokio, NodeId,
+    tokio, Addr, NodeId,
 };
 use rmqtt::{
     broker::{
+        discovery::{merge_node_addrs, Discovery},
         error::MqttError,
         hook::{Register, Type},
         types::{From, Publish, Reason, To},
+        Shared,
     },
     grpc::{client::NodeGrpcClient, GrpcClients, Message, MessageReply, MessageType},
     plugin::{PackageInfo, Plugin},
@@ -56,7 +58,6 @@ struct ClusterPlugin {
     runtime: &'static Runtime,
     register: Box<dyn Register>,
     cfg: Arc<PluginConfig>,
-    grpc_clients: GrpcClients,
     shared: &'static ClusterShared,
 
     router: &'static ClusterRouter,
@@ -70,38 +71,36 @@ impl ClusterPlugin {
         let env_list_keys = ["node_grpc_addrs", "raft_peer_addrs"];
         let mut cfg = runtime.settings.plugins.load_config_with::<PluginConfig>(&name, &env_list_keys)?;
         cfg.merge(&runtime.settings.opts);
+        if let Some(discovery) = cfg.discovery.clone() {
+            let nodes = discovery.discover_at_startup().await;
+            merge_node_addrs(&mut cfg.node_grpc_addrs, discovery.grpc_addrs(&nodes));
+            merge_node_addrs(&mut cfg.raft_peer_addrs, discovery.raft_addrs(&nodes));
+        }
         log::info!("{} ClusterPlugin cfg: {:?}", name, cfg);
 
         init_task_exec_queue(cfg.task_exec_queue_workers, cfg.task_exec_queue_max);
 
         let register = runtime.extends.hook_mgr().await.register();
-        let mut grpc_clients = HashMap::default();
-        let mut node_names = HashMap::default();
+        let router = ClusterRouter::get_or_init(cfg.try_lock_timeout, cfg.compression);
 
         let node_grpc_addrs = cfg.node_grpc_addrs.clone();
         log::info!("node_grpc_addrs: {:?}", node_grpc_addrs);
-        for node_addr in &node_grpc_addrs {
-            if node_addr.id != runtime.node.id() {
-                grpc_clients.insert(
-                    node_addr.id,
-                    (node_addr.addr.clone(), runtime.node.new_grpc_client(&node_addr.addr).await?),
-                );
+        //when joining through the seed, the other members are learned from the cluster
+        if cfg.seed_addr.is_none() {
+            for node_addr in &node_grpc_addrs {
+                router.add_node(node_addr.id, node_addr.addr.clone());
             }
-            node_names.insert(node_addr.id, format!("{}@{}", node_addr.id, node_addr.addr));
         }
-        let grpc_clients = Arc::new(grpc_clients);
-        let router = ClusterRouter::get_or_init(cfg.try_lock_timeout, cfg.compression);
         let shared = ClusterShared::get_or_init(
             router,
-            grpc_clients.clone(),
-            node_names,
             cfg.message_type,
             cfg.task_exec_queue_max,
             cfg.task_exec_queue_workers,
         );
+        shared.sync_nodes().await?;
         let raft_mailbox = None;
         let cfg = Arc::new(cfg);
-        Ok(Self { runtime, register, cfg, grpc_clients, shared, router, raft_mailbox })
+        Ok(Self { runtime, register, cfg, shared, router, raft_mailbox })
     }
 
     //raft init ...
@@ -143,8 +142,20 @@ impl ClusterPlugin {
         }
         log::info!("peer_addrs: {:?}", peer_addrs);
 
-        let leader_info = match cfg.leader()? {
-            Some(leader_info) => {
+        let leader_info = match (cfg.seed_addr.as_ref(), cfg.leader()?) {
+            (Some(seed_addr), _) => {
+                let seed_addr = if cfg.verify_addr {
+                    parse_addr(seed_addr).await?.to_string()
+                } else {
+                    seed_addr.to_string()
+                };
+                log::info!("Join the cluster through the seed: {:?}", seed_addr);
+                let actual_leader_info = find_actual_leader(&raft, vec![seed_addr.clone()], 60).await?;
+                Some(actual_leader_info.ok_or_else(|| {
+                    MqttError::from(format!("Leader does not exist, seed address: {}", seed_addr))
+                })?)
+            }
+            (None, Some(leader_info)) => {
                 log::info!("Specify a leader: {:?}", leader_info);
                 if id == leader_info.id {
                     //First, check if the Leader exists.
@@ -167,7 +178,7 @@ impl ClusterPlugin {
                     Some((actual_leader_id, actual_leader_addr))
                 }
             }
-            None => {
+            (None, None) => {
                 log::info!("Search for the existing leader ... ");
                 let leader_info =
                     raft.find_leader_info(peer_addrs).await.map_err(|e| MqttError::StdError(Box::new(e)))?;
@@ -227,6 +238,58 @@ impl ClusterPlugin {
             unreachable!()
         }
     }
+
+    //periodically propose the newly discovered nodes, or the nodes whose address is changed,
+    //to the cluster members through raft
+    fn start_discovery(&self, discovery: Discovery) {
+        let (router, raft_mailbox) = (self.router, self.raft_mailbox());
+        tokio::spawn(async move {
+            loop {
+                sleep(discovery.refresh_interval).await;
+                let nodes = match discovery.discover().await {
+                    Ok(nodes) => nodes,
+                    Err(e) => {
+                        log::warn!("discover the cluster nodes error, {}", e);
+                        continue;
+                    }
+                };
+                for node_addr in discovery.grpc_addrs(&nodes) {
+                    if router.node_addr(node_addr.id).as_ref() == Some(&node_addr.addr) {
+                        continue;
+                    }
+                    log::info!("discovered node: {:?}", node_addr);
+                    if let Err(e) = add_node(&raft_mailbox, node_addr.id, &node_addr.addr).await {
+                        log::warn!("add the discovered node {:?} error, {}", node_addr, e);
+                    }
+                }
+            }
+        });
+    }
+
+    //the gRPC address of this node is replicated to all members
+    async fn announce_node(&self) -> Result<()> {
+        let id = self.runtime.node.id();
+        let grpc_addr = match self.cfg.grpc_addr(id) {
+            Some(grpc_addr) => grpc_addr,
+            None if self.cfg.seed_addr.is_some() => {
+                return Err(MqttError::from("The gRPC address of this node is not in node_grpc_addrs"))
+            }
+            None => return Ok(()),
+        };
+        add_node(&self.raft_mailbox(), id, &grpc_addr).await
+    }
+}
+
+//the node is added to the cluster members of all nodes, or its address is updated
+async fn add_node(raft_mailbox: &Mailbox, id: NodeId, grpc_addr: &Addr) -> Result<()> {
+    let msg = message::Message::AddNode { id, grpc_addr: grpc_addr.as_ref() }.encode()?;
+    let reply = raft_mailbox.send_proposal(msg).await.map_err(anyhow::Error::new)?;
+    if !reply.is_empty() {
+        if let message::MessageReply::Error(e) = message::MessageReply::decode(&reply)? {
+            return Err(MqttError::Msg(e));
+        }
+    }
+    Ok(())
 }
 
 #[async_trait]
@@ -255,6 +318,19 @@ impl Plugin for ClusterPlugin {
         self.raft_mailbox.replace(raft_mailbox.clone());
         self.router.set_raft_mailbox(raft_mailbox).await;
 
+        let (router, shared) = (self.router, self.shared);
+        tokio::spawn(async move {
+            loop {
+                router.nodes_changed().await;
+                if let Err(e) = shared.sync_nodes().await {
+                    log::error!("update the cluster members error, {:?}", e);
+                }
+            }
+        });
+        if let Some(discovery) = self.cfg.discovery.clone() {
+            self.start_discovery(discovery);
+        }
+
         self.hook_register(Type::ClientDisconnected).await;
         self.hook_register(Type::SessionTerminated).await;
         self.hook_register(Type::GrpcMessageReceived).await;
@@ -286,7 +362,7 @@ impl Plugin for ClusterPlugin {
                 Ok(reply) => match message::MessageReply::decode(&reply)? {
                     message::MessageReply::Ping => {
                         log::info!("ping ok");
-                        return Ok(());
+                        return self.announce_node().await;
                     }
                     message::MessageReply::Error(e) => {
                         log::warn!("ping error, {:?}", e);
@@ -326,7 +402,7 @@ impl Plugin for ClusterPlugin {
         }
 
         let mut nodes = HashMap::default();
-        for (node_id, (_, c)) in self.grpc_clients.iter() {
+        for (node_id, (_, c)) in self.shared.get_grpc_clients().iter() {
             let stats = json!({
                 "channel_tasks": c.channel_tasks(),
                 "active_tasks": c.active_tasks(),
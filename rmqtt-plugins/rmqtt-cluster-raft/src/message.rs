@@ -17,6 +17,9 @@ pub enum Message<'a> {
     //get client node id
     GetClientNodeId { client_id: &'a str },
     Ping,
+    //cluster membership, with the gRPC address of the node
+    AddNode { id: NodeId, grpc_addr: &'a str },
+    RemoveNode { id: NodeId },
 }
 
 impl<'a> Message<'a> {
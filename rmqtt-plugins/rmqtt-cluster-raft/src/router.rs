@@ -9,15 +9,17 @@ use rmqtt_raft::{Error, Mailbox, Result as RaftResult, Store};
 
 use rmqtt::rust_box::task_exec_queue::SpawnExt;
 use rmqtt::{
-    ahash, anyhow, async_trait::async_trait, bincode, dashmap, log, once_cell, serde_json, timestamp_millis,
-    tokio, tokio::sync::RwLock,
+    ahash, anyhow,
+    async_trait::async_trait,
+    bincode, dashmap, log, once_cell, serde_json, timestamp_millis, tokio,
+    tokio::sync::{Notify, RwLock},
 };
 use rmqtt::{
     broker::{
         default::DefaultRouter,
         types::{
-            AllRelationsMap, ClientId, Id, IsOnline, NodeId, Route, SubRelationsMap, SubscriptionOptions,
-            TimestampMillis, Topic, TopicFilter, TopicName,
+            Addr, AllRelationsMap, ClientId, Id, IsOnline, NodeId, Route, SubRelationsMap,
+            SubscriptionOptions, TimestampMillis, Topic, TopicFilter, TopicName,
         },
         Router,
     },
@@ -33,6 +35,12 @@ use super::message::{Message, MessageReply};
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 type DashMap<K, V> = dashmap::DashMap<K, V, ahash::RandomState>;
 
+type Relations = Vec<(TopicFilter, HashMap<ClientId, (Id, SubscriptionOptions)>)>;
+///Relations, client states, topics count, relations count and the cluster members
+type Snapshot = (Relations, Vec<(ClientId, ClientStatus)>, Counter, Counter, Vec<(NodeId, Addr)>);
+///Snapshot of the versions before the cluster members are replicated
+type SnapshotV1 = (Relations, Vec<(ClientId, ClientStatus)>, Counter, Counter);
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct ClientStatus {
     pub id: Id,
@@ -57,6 +65,9 @@ pub(crate) struct ClusterRouter {
     inner: &'static DefaultRouter,
     raft_mailbox: Arc<RwLock<Option<Mailbox>>>,
     client_states: DashMap<ClientId, ClientStatus>,
+    //cluster members, NodeId => gRPC address
+    nodes: DashMap<NodeId, Addr>,
+    nodes_changed: Notify,
     pub try_lock_timeout: Duration,
     compression: Option<Compression>,
 }
@@ -69,6 +80,8 @@ impl ClusterRouter {
             inner: DefaultRouter::instance(),
             raft_mailbox: Arc::new(RwLock::new(None)),
             client_states: DashMap::default(),
+            nodes: DashMap::default(),
+            nodes_changed: Notify::new(),
             try_lock_timeout,
             compression,
         })
@@ -113,6 +126,34 @@ impl ClusterRouter {
         self.client_states.get(client_id).map(|entry| entry.value().clone())
     }
 
+    #[inline]
+    pub(crate) fn add_node(&self, id: NodeId, grpc_addr: Addr) {
+        if self.nodes.insert(id, grpc_addr.clone()).as_ref() != Some(&grpc_addr) {
+            self.nodes_changed.notify_one();
+        }
+    }
+
+    #[inline]
+    pub(crate) fn nodes(&self) -> Vec<(NodeId, Addr)> {
+        self.nodes.iter().map(|entry| (*entry.key(), entry.value().clone())).collect()
+    }
+
+    #[inline]
+    pub(crate) fn node_addr(&self, id: NodeId) -> Option<Addr> {
+        self.nodes.get(&id).map(|entry| entry.value().clone())
+    }
+
+    #[inline]
+    pub(crate) fn contains_node(&self, id: NodeId) -> bool {
+        self.nodes.contains_key(&id)
+    }
+
+    ///Wait for the cluster members to change
+    #[inline]
+    pub(crate) async fn nodes_changed(&self) {
+        self.nodes_changed.notified().await
+    }
+
     #[inline]
     pub(crate) fn _handshakings(&self) -> usize {
         self.client_states.iter().filter_map(|entry| if entry.handshaking { Some(()) } else { None }).count()
@@ -165,6 +206,11 @@ impl Router for &'static ClusterRouter {
         self.inner.matches(id, topic).await
     }
 
+    #[inline]
+    async fn matches_excluded(&self, id: Id, topic: &TopicName, excluded: &Id) -> Result<SubRelationsMap> {
+        self.inner.matches_excluded(id, topic, excluded).await
+    }
+
     ///Check online or offline
     async fn is_online(&self, node_id: NodeId, client_id: &str) -> bool {
         log::debug!("[Router.is_online] node_id: {:?}, client_id: {:?}", node_id, client_id);
@@ -324,6 +370,39 @@ impl Store for &'static ClusterRouter {
                 return Ok(data);
             }
             Message::Ping => return MessageReply::Ping.encode().map_err(|_e| Error::Unknown),
+            Message::AddNode { id, grpc_addr } => {
+                log::info!("[Router.AddNode] id: {}, grpc_addr: {}", id, grpc_addr);
+                self.add_node(id, Addr::from(grpc_addr));
+            }
+            Message::RemoveNode { id } => {
+                log::info!("[Router.RemoveNode] id: {}", id);
+                self.nodes.remove(&id);
+                //the clients of the removed node are gone, with their subscriptions
+                for mut entry in self.client_states.iter_mut() {
+                    if entry.id.node_id == id {
+                        entry.online = false;
+                        entry.handshaking = false;
+                    }
+                }
+                let subs = self
+                    .inner
+                    .relations
+                    .iter()
+                    .flat_map(|entry| {
+                        entry
+                            .value()
+                            .values()
+                            .filter(|(sub_id, _)| sub_id.node_id == id)
+                            .map(|(sub_id, _)| (entry.key().clone(), sub_id.clone()))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>();
+                log::info!("[Router.RemoveNode] id: {}, remove {} subscriptions", id, subs.len());
+                for (topic_filter, sub_id) in subs {
+                    self.inner.remove(&topic_filter, sub_id).await.map_err(|e| Error::Other(Box::new(e)))?;
+                }
+                self.nodes_changed.notify_one();
+            }
         }
 
         Ok(Vec::new())
@@ -362,8 +441,9 @@ impl Store for &'static ClusterRouter {
 
         let topics_count = &self.inner.topics_count;
         let relations_count = &self.inner.relations_count;
+        let nodes = &self.nodes();
 
-        let snapshot = bincode::serialize(&(relations, client_states, topics_count, relations_count))
+        let snapshot = bincode::serialize(&(relations, client_states, topics_count, relations_count, nodes))
             .map_err(|e| Error::Other(e))?;
         log::info!(
             "create snapshot, len: {},  topics_count: {:?}, relations_count: {:?}, cost time: {:?}",
@@ -443,12 +523,20 @@ impl Store for &'static ClusterRouter {
         }
 
         let now = std::time::Instant::now();
-        let (relations, client_states, topics_count, relations_count): (
-            Vec<(TopicFilter, HashMap<ClientId, (Id, SubscriptionOptions)>)>,
-            Vec<(ClientId, ClientStatus)>,
-            Counter,
-            Counter,
-        ) = bincode::deserialize(uncompressed.as_ref()).map_err(|e| Error::Other(e))?;
+        let (relations, client_states, topics_count, relations_count, nodes) =
+            match bincode::deserialize::<Snapshot>(uncompressed.as_ref()) {
+                Ok((relations, client_states, topics_count, relations_count, nodes)) => {
+                    (relations, client_states, topics_count, relations_count, Some(nodes))
+                }
+                //The snapshot of the older versions, without the cluster members
+                Err(e) => {
+                    let (relations, client_states, topics_count, relations_count) =
+                        bincode::deserialize::<SnapshotV1>(uncompressed.as_ref())
+                            .map_err(|_| Error::Other(e))?;
+                    log::info!("restore, the snapshot has no cluster members, the members are kept");
+                    (relations, client_states, topics_count, relations_count, None)
+                }
+            };
 
         self.inner.topics_count.set(&topics_count);
 
@@ -466,6 +554,14 @@ impl Store for &'static ClusterRouter {
             self.client_states.insert(client_id, content);
         }
 
+        if let Some(nodes) = nodes {
+            self.nodes.clear();
+            for (id, grpc_addr) in nodes {
+                self.nodes.insert(id, grpc_addr);
+            }
+            self.nodes_changed.notify_one();
+        }
+
         log::info!(
             "restore, topics_count: {:?}, relations_count: {:?}, cost time: {:?}",
             topics_count,
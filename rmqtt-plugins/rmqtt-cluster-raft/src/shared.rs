@@ -1,10 +1,12 @@
 use std::collections::HashSet;
 use std::convert::From as _;
+use std::sync::Arc;
 use std::time::Duration;
 
 use rmqtt::{
     anyhow, anyhow::Error, async_trait::async_trait, futures, futures::future::FutureExt, log,
-    once_cell::sync::OnceCell, rust_box::task_exec_queue::SpawnExt, serde_json, serde_json::json,
+    once_cell::sync::OnceCell, rust_box::std_ext::RwLock, rust_box::task_exec_queue::SpawnExt, serde_json,
+    serde_json::json,
 };
 use rmqtt::{
     broker::{
@@ -279,8 +281,8 @@ impl Entry for ClusterLockEntry {
 pub struct ClusterShared {
     inner: &'static DefaultShared,
     router: &'static ClusterRouter,
-    grpc_clients: GrpcClients,
-    node_names: HashMap<NodeId, NodeName>,
+    grpc_clients: RwLock<GrpcClients>,
+    node_names: RwLock<HashMap<NodeId, NodeName>>,
     pub(crate) message_type: MessageType,
     exec_queue_busy_limit: isize,
     exec_queue_workers_busy_limit: isize,
@@ -290,8 +292,6 @@ impl ClusterShared {
     #[inline]
     pub(crate) fn get_or_init(
         router: &'static ClusterRouter,
-        grpc_clients: GrpcClients,
-        node_names: HashMap<NodeId, NodeName>,
         message_type: MessageType,
         exec_queue_max: usize,
         exec_queue_workers: usize,
@@ -300,8 +300,8 @@ impl ClusterShared {
         INSTANCE.get_or_init(|| Self {
             inner: DefaultShared::instance(),
             router,
-            grpc_clients,
-            node_names,
+            grpc_clients: RwLock::new(GrpcClients::default()),
+            node_names: RwLock::new(HashMap::default()),
             message_type,
             exec_queue_busy_limit: (exec_queue_max as f64 * 0.7) as isize,
             exec_queue_workers_busy_limit: (exec_queue_workers as f64 * 0.9) as isize,
@@ -315,7 +315,47 @@ impl ClusterShared {
 
     #[inline]
     pub(crate) fn grpc_client(&self, node_id: u64) -> Option<NodeGrpcClient> {
-        self.grpc_clients.get(&node_id).map(|(_, c)| c.clone())
+        self.grpc_clients.read().get(&node_id).map(|(_, c)| c.clone())
+    }
+
+    ///Update the gRPC clients and node names according to the cluster members
+    pub(crate) async fn sync_nodes(&self) -> Result<()> {
+        let this_node_id = Runtime::instance().node.id();
+        let olds = self.grpc_clients.read().clone();
+        let mut grpc_clients = HashMap::default();
+        let mut node_names = HashMap::default();
+        for (id, addr) in self.router.nodes() {
+            node_names.insert(id, format!("{}@{}", id, addr));
+            if id == this_node_id {
+                continue;
+            }
+            let client = match olds.get(&id) {
+                Some((old_addr, c)) if *old_addr == addr => c.clone(),
+                _ => {
+                    log::info!("add gRPC client, node: {}@{}", id, addr);
+                    Runtime::instance().node.new_grpc_client(&addr).await?
+                }
+            };
+            grpc_clients.insert(id, (addr, client));
+        }
+        for id in olds.keys().filter(|id| !grpc_clients.contains_key(id)) {
+            log::info!("remove gRPC client, node: {}", id);
+        }
+        *self.grpc_clients.write() = Arc::new(grpc_clients);
+        *self.node_names.write() = node_names;
+        Ok(())
+    }
+
+    #[inline]
+    async fn send_node_message(&self, msg: RaftMessage<'_>) -> Result<()> {
+        let msg = msg.encode()?;
+        let raft_mailbox = self.router.raft_mailbox().await;
+        async move { raft_mailbox.send_proposal(msg).await.map_err(anyhow::Error::new) }
+            .spawn(task_exec_queue())
+            .result()
+            .await
+            .map_err(|_| MqttError::from("ClusterShared::send_node_message(..), task execution failure"))??;
+        Ok(())
     }
 }
 
@@ -433,6 +473,73 @@ impl Shared for &'static ClusterShared {
         self.inner.forwards_to(from, publish, relations).await
     }
 
+    #[inline]
+    async fn forwards_to_nodes(
+        &self,
+        from: From,
+        publish: &Publish,
+        mut relations_map: SubRelationsMap,
+    ) -> Result<(), Vec<(To, From, Publish, Reason)>> {
+        let mut errs = Vec::new();
+        if let Some(relations) = relations_map.remove(&Runtime::instance().node.id()) {
+            if let Err(e) = self.forwards_to(from.clone(), publish, relations).await {
+                errs.extend(e);
+            }
+        }
+        let mut fut_senders = Vec::new();
+        for (node_id, relations) in relations_map {
+            if let Some(client) = self.grpc_client(node_id) {
+                let msg_type = self.message_type;
+                let tos = relations
+                    .iter()
+                    .map(|(_, client_id, _, _, _)| To::from(node_id, client_id.clone()))
+                    .collect::<Vec<_>>();
+                let msg = Message::ForwardsTo(from.clone(), publish.clone(), relations);
+                fut_senders.push(async move {
+                    let mut msg_sender = MessageSender {
+                        client,
+                        msg_type,
+                        msg,
+                        max_retries: 1,
+                        retry_interval: Duration::from_millis(500),
+                    };
+                    (node_id, tos, msg_sender.send().await)
+                });
+            } else {
+                log::error!(
+                    "forwards_to_nodes error, grpc_client is not exist, node_id: {}, relations: {:?}",
+                    node_id,
+                    relations
+                );
+                for (_, client_id, _, _, _) in relations {
+                    errs.push((
+                        To::from(node_id, client_id),
+                        from.clone(),
+                        publish.clone(),
+                        Reason::from_static("grpc_client is not exist"),
+                    ));
+                }
+            }
+        }
+        for (node_id, tos, reply) in futures::future::join_all(fut_senders).await {
+            if let Err(e) = reply {
+                log::error!(
+                    "forwards_to_nodes Message::ForwardsTo to other node, from: {:?}, to: {:?}, error: {:?}",
+                    from,
+                    node_id,
+                    e
+                );
+                let reason = Reason::from(format!("forwards to other node error, {}", e));
+                errs.extend(tos.into_iter().map(|to| (to, from.clone(), publish.clone(), reason.clone())));
+            }
+        }
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(errs)
+        }
+    }
+
     #[inline]
     async fn forwards_and_get_shareds(
         &self,
@@ -483,12 +590,31 @@ impl Shared for &'static ClusterShared {
     }
     #[inline]
     fn get_grpc_clients(&self) -> GrpcClients {
-        self.grpc_clients.clone()
+        self.grpc_clients.read().clone()
     }
 
     #[inline]
     fn node_name(&self, id: NodeId) -> String {
-        self.node_names.get(&id).cloned().unwrap_or_default()
+        self.node_names.read().get(&id).cloned().unwrap_or_default()
+    }
+
+    #[inline]
+    async fn remove_node(&self, id: NodeId) -> Result<bool> {
+        if !self.router.contains_node(id) {
+            return Ok(false);
+        }
+        let raft_mailbox = self.router.raft_mailbox().await;
+        if id == Runtime::instance().node.id() {
+            log::info!("leaving the cluster, node: {}", id);
+            self.send_node_message(RaftMessage::RemoveNode { id }).await?;
+            raft_mailbox.leave().await.map_err(anyhow::Error::new)?;
+            log::info!("left the cluster, this node can be shut down");
+        } else {
+            log::info!("removing node {} from the cluster", id);
+            raft_mailbox.remove_node(id).await.map_err(anyhow::Error::new)?;
+            self.send_node_message(RaftMessage::RemoveNode { id }).await?;
+        }
+        Ok(true)
     }
 
     #[inline]
@@ -504,10 +630,9 @@ impl Shared for &'static ClusterShared {
         leader_ids.insert(status.leader_id);
 
         let data = RaftGrpcMessage::GetRaftStatus.encode()?;
-        let replys =
-            MessageBroadcaster::new(self.grpc_clients.clone(), self.message_type, Message::Data(data))
-                .join_all()
-                .await;
+        let replys = MessageBroadcaster::new(self.get_grpc_clients(), self.message_type, Message::Data(data))
+            .join_all()
+            .await;
 
         for (node_id, reply) in replys {
             match reply {
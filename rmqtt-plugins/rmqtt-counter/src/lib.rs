@@ -5,7 +5,8 @@ extern crate rmqtt_macros;
 
 use rmqtt::broker::hook::Priority;
 use rmqtt::{
-    async_trait::async_trait, log, ConnectAckReason, ConnectAckReasonV3, ConnectAckReasonV5, FromType,
+    async_trait::async_trait, log, AuthStage, ConnectAckReason, ConnectAckReasonV3, ConnectAckReasonV5,
+    FromType,
 };
 use rmqtt::{
     broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
@@ -38,6 +39,9 @@ impl Plugin for CounterPlugin {
         self.register
             .add_priority(Type::ClientAuthenticate, Priority::MAX, Box::new(CounterHandler::new()))
             .await;
+        self.register
+            .add_priority(Type::ClientAuthenticateExtended, Priority::MAX, Box::new(CounterHandler::new()))
+            .await;
         self.register.add_priority(Type::ClientConnack, Priority::MAX, Box::new(CounterHandler::new())).await;
         self.register
             .add_priority(Type::ClientConnected, Priority::MAX, Box::new(CounterHandler::new()))
@@ -128,9 +132,14 @@ impl Handler for CounterHandler {
                     self.metrics.client_auth_anonymous_inc();
                 }
             }
-            Parameter::ClientAuthenticate(_) => {
+            Parameter::ClientAuthenticate(_, _) => {
                 self.metrics.client_authenticate_inc();
             }
+            Parameter::ClientAuthenticateExtended(_, auth) => {
+                if !matches!(auth.stage, AuthStage::Continue) {
+                    self.metrics.client_authenticate_inc();
+                }
+            }
             Parameter::ClientConnack(connect_info, reason) => {
                 self.metrics.client_connack_inc();
                 match **reason {
@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use rmqtt::grpc::MessageType;
+use rmqtt::serde_json;
+use rmqtt::settings::deserialize_duration;
+use rmqtt::{MqttError, Result};
+
+use rmqtt_storage::Config;
+
+pub type ClusterId = String;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    ///Id of this cluster, the same on all its nodes and unique among the linked clusters
+    pub cluster_id: ClusterId,
+
+    #[serde(default = "PluginConfig::message_type_default")]
+    pub message_type: MessageType,
+
+    ///Interval of pulling the subscribed topic filters of the linked clusters
+    #[serde(
+        default = "PluginConfig::interest_sync_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub interest_sync_interval: Duration,
+
+    ///Interval of retrying the unavailable links
+    #[serde(default = "PluginConfig::retry_interval_default", deserialize_with = "deserialize_duration")]
+    pub retry_interval: Duration,
+
+    ///Maximum number of messages sent in one request
+    #[serde(default = "PluginConfig::batch_size_default")]
+    pub batch_size: usize,
+
+    #[serde(default = "PluginConfig::message_channel_capacity_default")]
+    pub message_channel_capacity: usize,
+
+    ///Maximum number of messages buffered for each link, the oldest is dropped when it is exceeded
+    #[serde(default = "PluginConfig::buffer_max_default")]
+    pub buffer_max: usize,
+
+    ///Storage of the messages buffered during link outages
+    #[serde(default)]
+    pub storage: Config,
+
+    ///Whether the retained messages of the linked clusters are retained
+    #[serde(default = "PluginConfig::message_retain_available_default")]
+    pub message_retain_available: bool,
+
+    ///Expiry interval of the received and buffered messages, unless the message has its own
+    #[serde(
+        default = "PluginConfig::message_expiry_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub message_expiry_interval: Duration,
+
+    #[serde(default)]
+    pub links: Vec<Link>,
+}
+
+impl PluginConfig {
+    fn message_type_default() -> MessageType {
+        88
+    }
+
+    fn interest_sync_interval_default() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn retry_interval_default() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn batch_size_default() -> usize {
+        100
+    }
+
+    fn message_channel_capacity_default() -> usize {
+        100_000
+    }
+
+    fn buffer_max_default() -> usize {
+        1_000_000
+    }
+
+    fn message_retain_available_default() -> bool {
+        false
+    }
+
+    fn message_expiry_interval_default() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    #[inline]
+    pub fn check(&self) -> Result<()> {
+        if self.cluster_id.is_empty() {
+            return Err(MqttError::from("cluster_id is not specified"));
+        }
+        for link in self.links.iter() {
+            if link.name.is_empty() || link.addrs.is_empty() {
+                return Err(MqttError::from(format!("the name or addrs of the link is empty, {:?}", link)));
+            }
+            if self.links.iter().filter(|l| l.name == link.name).count() > 1 {
+                return Err(MqttError::from(format!("duplicate link name, {}", link.name)));
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Link {
+    ///Link name, it is also the key of the buffered messages
+    pub name: String,
+    ///gRPC addresses of the nodes of the linked cluster, the next one is used when the current one fails
+    pub addrs: Vec<String>,
+    ///Topic filters shared with the linked cluster, only the local messages are shared
+    #[serde(default)]
+    pub topics: Vec<String>,
+}
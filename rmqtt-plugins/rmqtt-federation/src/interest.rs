@@ -0,0 +1,202 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use rmqtt::{
+    broker::types::{AllRelationsMap, NodeId, TopicFilter},
+    grpc::{Message as GrpcMessage, MessageReply as GrpcMessageReply, MessageSender, MessageType},
+    timestamp_millis, MqttError, Result, Runtime,
+};
+use rmqtt::{futures, log, rust_box::std_ext::RwLock};
+
+use crate::message::{Message, MessageReply};
+
+///(epoch, seq) of the topic filters of a node, the epoch is changed after the node is restarted
+pub(crate) type Version = (u64, u64);
+///The versions of the topic filters of the nodes of the cluster
+pub(crate) type ClusterVersion = Vec<(NodeId, Version)>;
+
+//the marked topic filters are checked again until the router is surely updated
+const SETTLE: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct LocalFilters {
+    seq: u64,
+    filters: HashSet<TopicFilter>,
+    //the topic filters whose local subscriptions may be changed, and when they are marked
+    pending: HashMap<TopicFilter, Instant>,
+}
+
+///The distinct topic filters subscribed in the cluster, each node keeps the ones of its own clients
+///and they are merged by the node that the linked cluster asks. The topic filters of this node are
+///updated by the subscription hooks, and are only sent to the other nodes when their version changes.
+pub(crate) struct Interest {
+    epoch: u64,
+    local: RwLock<LocalFilters>,
+    nodes: RwLock<HashMap<NodeId, (Version, Vec<TopicFilter>)>>,
+}
+
+impl Interest {
+    #[inline]
+    pub(crate) fn new() -> Self {
+        Self {
+            epoch: timestamp_millis() as u64,
+            local: RwLock::new(LocalFilters::default()),
+            nodes: RwLock::new(HashMap::default()),
+        }
+    }
+
+    ///Loads the topic filters subscribed on this node, the later changes are marked by the hooks
+    pub(crate) async fn init(&self) {
+        let router = Runtime::instance().extends.router().await;
+        let node_id = Runtime::instance().node.id();
+        let filters = router
+            .relations()
+            .iter()
+            .filter(|entry| entry.value().values().any(|(id, _)| id.node_id == node_id))
+            .map(|entry| entry.key().clone())
+            .collect::<HashSet<_>>();
+        let mut local = self.local.write();
+        local.seq += 1;
+        local.filters = filters;
+    }
+
+    ///Marks the topic filters whose subscriptions on this node may be changed
+    #[inline]
+    pub(crate) fn mark<I: IntoIterator<Item = TopicFilter>>(&self, topic_filters: I) {
+        let now = Instant::now();
+        let mut local = self.local.write();
+        for topic_filter in topic_filters {
+            local.pending.insert(topic_filter, now);
+        }
+    }
+
+    #[inline]
+    fn is_subscribed(relations: &AllRelationsMap, topic_filter: &str, node_id: NodeId) -> bool {
+        relations
+            .get(topic_filter)
+            .map(|entry| entry.values().any(|(id, _)| id.node_id == node_id))
+            .unwrap_or(false)
+    }
+
+    //Applies the marked topic filters, the ones that are marked recently are checked again next time
+    async fn settle(&self) {
+        if self.local.read().pending.is_empty() {
+            return;
+        }
+        let router = Runtime::instance().extends.router().await;
+        let node_id = Runtime::instance().node.id();
+        let mut local = self.local.write();
+        let LocalFilters { seq, filters, pending } = &mut *local;
+        let mut changed = false;
+        pending.retain(|topic_filter, marked_at| {
+            changed |= if Self::is_subscribed(router.relations(), topic_filter, node_id) {
+                filters.insert(topic_filter.clone())
+            } else {
+                filters.remove(topic_filter)
+            };
+            marked_at.elapsed() < SETTLE
+        });
+        if changed {
+            *seq += 1;
+        }
+    }
+
+    #[inline]
+    fn local_reply(&self, known: Option<Version>) -> MessageReply {
+        let local = self.local.read();
+        let version = (self.epoch, local.seq);
+        if known == Some(version) {
+            MessageReply::InterestUnchanged
+        } else {
+            MessageReply::NodeInterest(version, local.filters.iter().cloned().collect())
+        }
+    }
+
+    ///The topic filters subscribed on this node, unless the known version is the current one
+    pub(crate) async fn local_get(&self, known: Option<Version>) -> MessageReply {
+        self.settle().await;
+        self.local_reply(known)
+    }
+
+    ///The topic filters subscribed in the cluster, unless the known version is the current one.
+    ///The topic filters are None if a node has never replied, then all shared messages are wanted
+    pub(crate) async fn get(
+        &self,
+        known: Option<ClusterVersion>,
+        message_type: MessageType,
+    ) -> Result<MessageReply> {
+        self.settle().await;
+        let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+        let syncs = grpc_clients.iter().map(|(id, (_, c))| async move {
+            let known = self.nodes.read().get(id).map(|(version, _)| *version);
+            let msg = Message::NodeInterestGet(known).encode()?;
+            match MessageSender::new(c.clone(), message_type, GrpcMessage::Data(msg)).send().await? {
+                GrpcMessageReply::Data(data) => MessageReply::decode(&data),
+                reply => Err(MqttError::from(format!("unexpected reply, {:?}", reply))),
+            }
+        });
+        let replies = futures::future::join_all(syncs).await;
+
+        let mut nodes = self.nodes.write();
+        nodes.retain(|id, _| grpc_clients.contains_key(id));
+        for (id, reply) in grpc_clients.keys().zip(replies) {
+            match reply {
+                Ok(MessageReply::NodeInterest(version, filters)) => {
+                    nodes.insert(*id, (version, filters));
+                }
+                Ok(MessageReply::InterestUnchanged) => {}
+                Ok(reply) => {
+                    log::warn!("get the interest of node {} error, unexpected reply, {:?}", id, reply)
+                }
+                Err(e) => log::warn!("get the interest of node {} error, {:?}", id, e),
+            }
+        }
+
+        let local = self.local.read();
+        let mut version = vec![(Runtime::instance().node.id(), (self.epoch, local.seq))];
+        let mut filters = local.filters.clone();
+        for id in grpc_clients.keys() {
+            match nodes.get(id) {
+                Some((node_version, node_filters)) => {
+                    version.push((*id, *node_version));
+                    filters.extend(node_filters.iter().cloned());
+                }
+                None => return Ok(MessageReply::Interest(Vec::new(), None)),
+            }
+        }
+        version.sort_unstable();
+        if known.as_ref() == Some(&version) {
+            Ok(MessageReply::InterestUnchanged)
+        } else {
+            Ok(MessageReply::Interest(version, Some(filters.into_iter().collect())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_reply() {
+        let interest = Interest::new();
+        {
+            let mut local = interest.local.write();
+            local.seq = 1;
+            local.filters.insert(TopicFilter::from("a/#"));
+        }
+        match interest.local_reply(None) {
+            MessageReply::NodeInterest(version, filters) => {
+                assert_eq!(version, (interest.epoch, 1));
+                assert_eq!(filters, vec![TopicFilter::from("a/#")]);
+            }
+            reply => panic!("unexpected reply, {:?}", reply),
+        }
+        assert!(matches!(interest.local_reply(Some((interest.epoch, 1))), MessageReply::InterestUnchanged));
+        assert!(matches!(interest.local_reply(Some((interest.epoch, 0))), MessageReply::NodeInterest(..)));
+        assert!(matches!(
+            interest.local_reply(Some((interest.epoch + 1, 1))),
+            MessageReply::NodeInterest(..)
+        ));
+    }
+}
@@ -0,0 +1,312 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+#[macro_use]
+extern crate rmqtt_macros;
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmqtt::{
+    async_trait::async_trait,
+    log,
+    serde_json::{self, json},
+    tokio,
+};
+use rmqtt::{
+    broker::{
+        hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+        topic::Topic,
+        types::{ClientId, FromType},
+    },
+    grpc::{Message as GrpcMessage, MessageReply as GrpcMessageReply},
+    plugin::{PackageInfo, Plugin},
+    register, From, Id, Publish, Result, Runtime, Session, SessionState,
+};
+
+use rmqtt_storage::{init_db, StorageType};
+
+use config::{ClusterId, PluginConfig};
+use interest::Interest;
+use link::Link;
+use message::{Message, MessageReply};
+
+mod config;
+mod interest;
+mod link;
+mod message;
+
+///Client id prefix of the messages received from the linked clusters, followed by the origin cluster id
+const FEDERATION_CLIENT_ID_PREFIX: &str = "$federation/";
+
+register!(FederationPlugin::new);
+
+#[derive(Plugin)]
+struct FederationPlugin {
+    runtime: &'static Runtime,
+    cfg: Arc<PluginConfig>,
+    register: Box<dyn Register>,
+    links: Arc<Vec<Arc<Link>>>,
+    interest: Arc<Interest>,
+}
+
+impl FederationPlugin {
+    #[inline]
+    fn handler(&self) -> HookHandler {
+        HookHandler::new(self.cfg.clone(), self.links.clone(), self.interest.clone())
+    }
+
+    #[inline]
+    async fn new<S: Into<String>>(runtime: &'static Runtime, name: S) -> Result<Self> {
+        let name = name.into();
+        let mut cfg = runtime.settings.plugins.load_config::<PluginConfig>(&name)?;
+        cfg.check()?;
+        match cfg.storage.typ {
+            StorageType::Sled => {
+                cfg.storage.sled.path =
+                    cfg.storage.sled.path.replace("{node}", &format!("{}", runtime.node.id()));
+            }
+            StorageType::Redis => {
+                cfg.storage.redis.prefix =
+                    cfg.storage.redis.prefix.replace("{node}", &format!("{}", runtime.node.id()));
+            }
+            StorageType::RedisCluster => {
+                cfg.storage.redis_cluster.prefix =
+                    cfg.storage.redis_cluster.prefix.replace("{node}", &format!("{}", runtime.node.id()));
+            }
+        }
+        log::info!("{} FederationPlugin cfg: {:?}", name, cfg);
+
+        let storage_db = init_db(&cfg.storage).await?;
+        let cfg = Arc::new(cfg);
+        let mut links = Vec::new();
+        for link_cfg in cfg.links.iter() {
+            let (link, rx) = Link::new(cfg.clone(), link_cfg, &storage_db).await?;
+            tokio::spawn(link.clone().run(rx));
+            links.push(link);
+        }
+        link::start_interest_sync(links.clone(), cfg.interest_sync_interval);
+
+        let register = runtime.extends.hook_mgr().await.register();
+        Ok(Self { runtime, cfg, register, links: Arc::new(links), interest: Arc::new(Interest::new()) })
+    }
+}
+
+#[async_trait]
+impl Plugin for FederationPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name());
+        for typ in [
+            Type::MessagePublish,
+            Type::GrpcMessageReceived,
+            Type::SessionSubscribed,
+            Type::SessionUnsubscribed,
+            Type::SessionTerminated,
+            Type::ClientConnected,
+        ] {
+            self.register.add(typ, Box::new(self.handler())).await;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        self.cfg.to_json()
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name());
+        self.interest.init().await;
+        self.register.start().await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name());
+        self.register.stop().await;
+        Ok(true)
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        let mut links = Vec::new();
+        for link in self.links.iter() {
+            links.push(link.to_json().await);
+        }
+        json!({
+            "cluster_id": self.cfg.cluster_id,
+            "node_id": self.runtime.node.id(),
+            "links": links,
+        })
+    }
+}
+
+struct HookHandler {
+    cfg: Arc<PluginConfig>,
+    links: Arc<Vec<Arc<Link>>>,
+    interest: Arc<Interest>,
+}
+
+impl HookHandler {
+    fn new(cfg: Arc<PluginConfig>, links: Arc<Vec<Arc<Link>>>, interest: Arc<Interest>) -> Self {
+        Self { cfg, links, interest }
+    }
+
+    //the subscriptions of the session are changed, or moved from the previous session
+    #[inline]
+    async fn mark_subscriptions(&self, session: &Session) {
+        match session.subscriptions().await {
+            Ok(subs) => self.interest.mark(subs.to_topic_filters().await),
+            Err(e) => log::warn!("{:?} get the subscriptions error, {:?}", session.id, e),
+        }
+    }
+
+    //only the local messages are shared, the received ones are never sent to other linked clusters
+    #[inline]
+    async fn forwards(&self, from: &From, publish: &Publish) {
+        if matches!(from.typ(), FromType::Bridge) && from.client_id.starts_with(FEDERATION_CLIENT_ID_PREFIX) {
+            return;
+        }
+        let topic = match Topic::from_str(&publish.topic) {
+            Ok(topic) => topic,
+            Err(e) => {
+                log::warn!("invalid topic {:?}, {:?}", publish.topic, e);
+                return;
+            }
+        };
+        for link in self.links.iter().filter(|link| link.is_wanted(&topic)) {
+            link.send(publish.clone()).await;
+        }
+    }
+
+    async fn on_message(&self, data: &[u8]) -> Result<GrpcMessageReply> {
+        match Message::decode(data)? {
+            Message::Publishes { origin, publishs } => {
+                if origin == self.cfg.cluster_id {
+                    log::warn!("the messages of this cluster are received again, they are dropped");
+                } else {
+                    self.receive(origin, publishs).await;
+                }
+                Ok(GrpcMessageReply::Data(MessageReply::Received.encode()?))
+            }
+            Message::InterestGet(known) => {
+                let reply = self.interest.get(known, self.cfg.message_type).await?;
+                Ok(GrpcMessageReply::Data(reply.encode()?))
+            }
+            Message::NodeInterestGet(known) => {
+                Ok(GrpcMessageReply::Data(self.interest.local_get(known).await.encode()?))
+            }
+        }
+    }
+
+    async fn receive(&self, origin: ClusterId, publishs: Vec<Publish>) {
+        let from = From::from_bridge(Id::new(
+            Runtime::instance().node.id(),
+            None,
+            None,
+            ClientId::from(format!("{}{}", FEDERATION_CLIENT_ID_PREFIX, origin)),
+            None,
+        ));
+        let storage_available = Runtime::instance().extends.message_mgr().await.enable();
+        for publish in publishs {
+            let expiry_interval = publish
+                .properties
+                .message_expiry_interval
+                .map(|interval| Duration::from_secs(interval.get() as u64))
+                .unwrap_or(self.cfg.message_expiry_interval);
+
+            //hook, message_publish
+            let publish = Runtime::instance()
+                .extends
+                .hook_mgr()
+                .await
+                .message_publish(None, from.clone(), &publish)
+                .await
+                .unwrap_or(publish);
+
+            if let Err(e) = SessionState::forwards(
+                from.clone(),
+                publish,
+                self.cfg.message_retain_available,
+                storage_available,
+                Some(expiry_interval),
+            )
+            .await
+            {
+                log::warn!("{:?}", e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for HookHandler {
+    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+        match param {
+            Parameter::MessagePublish(_s, from, publish) => {
+                self.forwards(from, publish).await;
+            }
+            Parameter::SessionSubscribed(_s, sub) => self.interest.mark([sub.topic_filter.clone()]),
+            Parameter::SessionUnsubscribed(_s, unsub) => self.interest.mark([unsub.topic_filter.clone()]),
+            Parameter::SessionTerminated(s, _) | Parameter::ClientConnected(s) => {
+                self.mark_subscriptions(s).await;
+            }
+            Parameter::GrpcMessageReceived(typ, GrpcMessage::Data(data)) if *typ == self.cfg.message_type => {
+                let reply = self.on_message(data).await;
+                return (false, Some(HookResult::GrpcMessageReply(reply)));
+            }
+            Parameter::GrpcMessageReceived(..) => {}
+            _ => {
+                log::error!("unimplemented, {:?}", param)
+            }
+        }
+        (true, acc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::link::tests::{config, link, publish};
+    use super::*;
+
+    fn id(client_id: &str) -> Id {
+        Id::new(1, None, None, ClientId::from(client_id), None)
+    }
+
+    #[test]
+    fn forwards() {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+            let cfg = Arc::new(config());
+            let (link, mut rx) = link(config(), &["a/#"], Arc::default(), Arc::default());
+            let handler = HookHandler::new(cfg, Arc::new(vec![link]), Arc::new(Interest::new()));
+
+            handler.forwards(&From::from_custom(id("dev1")), &publish("a/1")).await;
+            assert_eq!(rx.try_recv().unwrap().topic, "a/1");
+            //the topics that are not shared
+            handler.forwards(&From::from_custom(id("dev1")), &publish("b/1")).await;
+            assert!(rx.try_recv().is_err());
+            //the messages received from the linked clusters are not sent again
+            let from = From::from_bridge(id(&format!("{}c2", FEDERATION_CLIENT_ID_PREFIX)));
+            handler.forwards(&from, &publish("a/1")).await;
+            assert!(rx.try_recv().is_err());
+            //only the received messages are skipped
+            handler.forwards(&From::from_bridge(id("bridge1")), &publish("a/2")).await;
+            assert_eq!(rx.try_recv().unwrap().topic, "a/2");
+
+            //the messages of this cluster are dropped, otherwise they would be forwarded through the runtime
+            let msg = Message::Publishes { origin: "c1".into(), publishs: vec![publish("a/1")] };
+            let reply = handler.on_message(&msg.encode().unwrap()).await.unwrap();
+            match reply {
+                GrpcMessageReply::Data(data) => {
+                    assert!(matches!(MessageReply::decode(&data).unwrap(), MessageReply::Received))
+                }
+                reply => panic!("unexpected reply, {:?}", reply),
+            }
+        });
+    }
+}
@@ -0,0 +1,599 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmqtt::{
+    async_trait::async_trait,
+    log,
+    rust_box::std_ext::RwLock,
+    serde_json::{self, json},
+    tokio::{
+        self,
+        sync::mpsc::{self, error::TrySendError},
+        time::Instant,
+    },
+};
+use rmqtt::{
+    broker::topic::{Topic, TopicTree},
+    grpc::{client::NodeGrpcClient, Message as GrpcMessage, MessageReply as GrpcMessageReply, MessageType},
+    timestamp_millis, MqttError, Publish, Result, Runtime,
+};
+
+use rmqtt_storage::{DefaultStorageDB, List, StorageList};
+
+use crate::config::{self, PluginConfig};
+use crate::interest::ClusterVersion;
+use crate::message::{Message, MessageReply};
+
+///The connection to the linked cluster
+#[async_trait]
+pub(crate) trait Transport: Send + Sync {
+    async fn request(&self, msg: Message) -> Result<GrpcMessageReply>;
+
+    fn addr(&self) -> &str;
+}
+
+///The messages buffered while the linked cluster is unavailable, oldest first
+#[async_trait]
+pub(crate) trait Buffer: Send + Sync {
+    async fn push_back(&self, publish: &Publish, limit: usize, pop_front_if_full: bool) -> Result<()>;
+
+    async fn get(&self, idx: usize) -> Result<Option<Publish>>;
+
+    async fn pop_front(&self) -> Result<()>;
+
+    async fn len(&self) -> Result<usize>;
+}
+
+#[async_trait]
+impl Buffer for StorageList {
+    #[inline]
+    async fn push_back(&self, publish: &Publish, limit: usize, pop_front_if_full: bool) -> Result<()> {
+        List::push_limit::<Publish>(self, publish, limit, pop_front_if_full).await?;
+        Ok(())
+    }
+
+    #[inline]
+    async fn get(&self, idx: usize) -> Result<Option<Publish>> {
+        Ok(List::get_index::<Publish>(self, idx).await?)
+    }
+
+    #[inline]
+    async fn pop_front(&self) -> Result<()> {
+        List::pop::<Publish>(self).await?;
+        Ok(())
+    }
+
+    #[inline]
+    async fn len(&self) -> Result<usize> {
+        Ok(List::len(self).await?)
+    }
+}
+
+//the next address is used when the current one fails
+struct GrpcTransport {
+    addrs: Vec<String>,
+    clients: Vec<NodeGrpcClient>,
+    curr: AtomicUsize,
+    message_type: MessageType,
+}
+
+#[async_trait]
+impl Transport for GrpcTransport {
+    async fn request(&self, msg: Message) -> Result<GrpcMessageReply> {
+        let curr = self.curr.load(Ordering::SeqCst);
+        let client = &self.clients[curr % self.clients.len()];
+        let reply = client.send_message(self.message_type, GrpcMessage::Data(msg.encode()?)).await;
+        if reply.is_err() {
+            let _ =
+                self.curr.compare_exchange(curr, curr.wrapping_add(1), Ordering::SeqCst, Ordering::SeqCst);
+        }
+        reply
+    }
+
+    #[inline]
+    fn addr(&self) -> &str {
+        &self.addrs[self.curr.load(Ordering::SeqCst) % self.addrs.len()]
+    }
+}
+
+pub(crate) struct Link {
+    pub(crate) name: String,
+    cfg: Arc<PluginConfig>,
+    transport: Arc<dyn Transport>,
+    topics: TopicTree<()>,
+    //None until the topic filters of the linked cluster are pulled, all shared messages are sent
+    interest: RwLock<Option<(ClusterVersion, TopicTree<()>)>>,
+    tx: mpsc::Sender<Publish>,
+    buffer: Arc<dyn Buffer>,
+    available: AtomicBool,
+    sents: AtomicUsize,
+    buffereds: AtomicUsize,
+    droppeds: AtomicUsize,
+}
+
+impl Link {
+    pub(crate) async fn new(
+        cfg: Arc<PluginConfig>,
+        link: &config::Link,
+        storage_db: &DefaultStorageDB,
+    ) -> Result<(Arc<Self>, mpsc::Receiver<Publish>)> {
+        let mut clients = Vec::new();
+        for addr in link.addrs.iter() {
+            clients.push(Runtime::instance().node.new_grpc_client(addr).await?);
+        }
+        let transport = GrpcTransport {
+            addrs: link.addrs.clone(),
+            clients,
+            curr: AtomicUsize::new(0),
+            message_type: cfg.message_type,
+        };
+        let buffer = storage_db.list(format!("link-{}", link.name), None).await?;
+        Self::build(cfg, link, Arc::new(transport), Arc::new(buffer))
+    }
+
+    fn build(
+        cfg: Arc<PluginConfig>,
+        link: &config::Link,
+        transport: Arc<dyn Transport>,
+        buffer: Arc<dyn Buffer>,
+    ) -> Result<(Arc<Self>, mpsc::Receiver<Publish>)> {
+        let mut topics = TopicTree::default();
+        for topic_filter in link.topics.iter() {
+            topics.insert(&Topic::from_str(topic_filter)?, ());
+        }
+        let (tx, rx) = mpsc::channel(cfg.message_channel_capacity);
+        let link = Self {
+            name: link.name.clone(),
+            cfg,
+            transport,
+            topics,
+            interest: RwLock::new(None),
+            tx,
+            buffer,
+            available: AtomicBool::new(true),
+            sents: AtomicUsize::new(0),
+            buffereds: AtomicUsize::new(0),
+            droppeds: AtomicUsize::new(0),
+        };
+        Ok((Arc::new(link), rx))
+    }
+
+    ///Whether the topic is shared with and subscribed in the linked cluster
+    #[inline]
+    pub(crate) fn is_wanted(&self, topic: &Topic) -> bool {
+        self.topics.is_match(topic)
+            && self.interest.read().as_ref().map(|(_, interest)| interest.is_match(topic)).unwrap_or(true)
+    }
+
+    ///Queues the message, it is buffered when the channel is full, then it may be sent before
+    ///the queued messages
+    #[inline]
+    pub(crate) async fn send(&self, publish: Publish) {
+        match self.tx.try_send(publish) {
+            Ok(()) => {}
+            Err(TrySendError::Full(publish)) => {
+                //the buffered messages may be being sent, only the new message is dropped when it is full
+                self.store(&publish, false).await;
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.droppeds.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    ///Sends the messages in order, they are buffered while the linked cluster is unavailable
+    pub(crate) async fn run(self: Arc<Self>, mut rx: mpsc::Receiver<Publish>) {
+        let batch_size = self.cfg.batch_size.max(1);
+        let mut retry_at: Option<Instant> = None;
+        loop {
+            let mut publishs = Vec::new();
+            tokio::select! {
+                publish = rx.recv() => match publish {
+                    Some(publish) => publishs.push(publish),
+                    None => break,
+                },
+                _ = tokio::time::sleep(self.cfg.retry_interval) => {}
+            }
+            while publishs.len() < batch_size {
+                match rx.try_recv() {
+                    Ok(publish) => publishs.push(publish),
+                    Err(_) => break,
+                }
+            }
+
+            //the buffered messages are sent first to keep the order
+            let available = match retry_at {
+                Some(at) if Instant::now() < at => false,
+                _ => self.flush(batch_size).await,
+            };
+            let available = available && (publishs.is_empty() || self.publish(publishs.clone()).await);
+            if available {
+                retry_at = None;
+            } else {
+                retry_at = Some(Instant::now() + self.cfg.retry_interval);
+                for publish in publishs.iter() {
+                    self.store(publish, true).await;
+                }
+            }
+            self.available.store(available, Ordering::SeqCst);
+        }
+    }
+
+    //Returns false if the linked cluster is unavailable, the buffered messages are only removed
+    //after they are sent, the expired ones are dropped
+    async fn flush(&self, batch_size: usize) -> bool {
+        loop {
+            let mut publishs = Vec::new();
+            let mut peeked = 0;
+            while peeked < batch_size {
+                match self.buffer.get(peeked).await {
+                    Ok(Some(publish)) => {
+                        peeked += 1;
+                        if !self.is_expired(&publish) {
+                            publishs.push(publish);
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        log::warn!("{} get the buffered messages error, {:?}", self.name, e);
+                        break;
+                    }
+                }
+            }
+            if peeked == 0 {
+                return true;
+            }
+            let expireds = peeked - publishs.len();
+            if !publishs.is_empty() && !self.publish(publishs).await {
+                return false;
+            }
+            self.droppeds.fetch_add(expireds, Ordering::SeqCst);
+            for _ in 0..peeked {
+                if let Err(e) = self.buffer.pop_front().await {
+                    log::warn!("{} remove the sent messages error, {:?}", self.name, e);
+                    return false;
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn is_expired(&self, publish: &Publish) -> bool {
+        let expiry_interval = publish
+            .properties
+            .message_expiry_interval
+            .map(|interval| interval.get() as i64 * 1000)
+            .unwrap_or(self.cfg.message_expiry_interval.as_millis() as i64);
+        timestamp_millis() - publish.create_time() > expiry_interval
+    }
+
+    async fn publish(&self, publishs: Vec<Publish>) -> bool {
+        let len = publishs.len();
+        let msg = Message::Publishes { origin: self.cfg.cluster_id.clone(), publishs };
+        match self.request(msg).await {
+            Ok(GrpcMessageReply::Data(data))
+                if matches!(MessageReply::decode(&data), Ok(MessageReply::Received)) =>
+            {
+                self.sents.fetch_add(len, Ordering::SeqCst);
+                true
+            }
+            Ok(reply) => {
+                log::warn!("{} the messages are not received by the linked cluster, {:?}", self.name, reply);
+                false
+            }
+            Err(e) => {
+                log::warn!("{} send messages to the linked cluster error, {:?}", self.name, e);
+                false
+            }
+        }
+    }
+
+    async fn store(&self, publish: &Publish, pop_front_if_full: bool) {
+        match self.buffer.push_back(publish, self.cfg.buffer_max, pop_front_if_full).await {
+            Ok(()) => {
+                self.buffereds.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(e) => {
+                self.droppeds.fetch_add(1, Ordering::SeqCst);
+                log::warn!("{} buffer the message error, {:?}", self.name, e);
+            }
+        }
+    }
+
+    ///Pulls the topic filters subscribed in the linked cluster if they are changed
+    pub(crate) async fn sync_interest(&self) -> Result<()> {
+        let known = self.interest.read().as_ref().map(|(version, _)| version.clone());
+        let data = match self.request(Message::InterestGet(known)).await? {
+            GrpcMessageReply::Data(data) => data,
+            reply => return Err(MqttError::from(format!("unexpected reply, {:?}", reply))),
+        };
+        let (version, topic_filters) = match MessageReply::decode(&data)? {
+            MessageReply::InterestUnchanged => return Ok(()),
+            MessageReply::Interest(version, Some(topic_filters)) => (version, topic_filters),
+            MessageReply::Interest(_, None) => {
+                log::debug!("{} the interest of the linked cluster is unknown", self.name);
+                *self.interest.write() = None;
+                return Ok(());
+            }
+            reply => return Err(MqttError::from(format!("unexpected reply, {:?}", reply))),
+        };
+        let mut interest = TopicTree::default();
+        for topic_filter in topic_filters.iter() {
+            match Topic::from_str(topic_filter) {
+                Ok(topic) => {
+                    interest.insert(&topic, ());
+                }
+                Err(e) => log::warn!("{} invalid topic filter {:?}, {:?}", self.name, topic_filter, e),
+            }
+        }
+        log::debug!("{} interest: {:?}, version: {:?}", self.name, topic_filters, version);
+        *self.interest.write() = Some((version, interest));
+        Ok(())
+    }
+
+    #[inline]
+    async fn request(&self, msg: Message) -> Result<GrpcMessageReply> {
+        self.transport.request(msg).await
+    }
+
+    pub(crate) async fn to_json(&self) -> serde_json::Value {
+        let buffer_len = self.buffer.len().await.ok();
+        let interest_synced = self.interest.read().is_some();
+        json!({
+            "name": self.name,
+            "addr": self.transport.addr(),
+            "available": self.available.load(Ordering::SeqCst),
+            "interest_synced": interest_synced,
+            "channel_len": self.cfg.message_channel_capacity - self.tx.capacity(),
+            "buffer_len": buffer_len,
+            "sents": self.sents.load(Ordering::SeqCst),
+            "buffereds": self.buffereds.load(Ordering::SeqCst),
+            "droppeds": self.droppeds.load(Ordering::SeqCst),
+        })
+    }
+}
+
+///Periodically pulls the topic filters subscribed in the linked clusters
+pub(crate) fn start_interest_sync(links: Vec<Arc<Link>>, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            for link in links.iter() {
+                if let Err(e) = link.sync_interest().await {
+                    log::warn!("{} sync the interest of the linked cluster error, {:?}", link.name, e);
+                }
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use rmqtt::{ntex::util::Bytes, QoS, TopicFilter, TopicName};
+
+    use super::*;
+
+    pub(crate) fn config() -> PluginConfig {
+        let mut cfg: PluginConfig = serde_json::from_value(json!({ "cluster_id": "c1" })).unwrap();
+        cfg.retry_interval = Duration::from_millis(20);
+        cfg.batch_size = 2;
+        cfg.message_channel_capacity = 16;
+        cfg
+    }
+
+    pub(crate) fn publish(topic: &str) -> Publish {
+        Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: TopicName::from(topic),
+            packet_id: None,
+            payload: Bytes::from_static(b"22.5"),
+            properties: Default::default(),
+            delay_interval: None,
+            create_time: timestamp_millis(),
+        }
+    }
+
+    #[derive(Default)]
+    pub(crate) struct MemBuffer(Mutex<VecDeque<Publish>>);
+
+    #[async_trait]
+    impl Buffer for MemBuffer {
+        async fn push_back(&self, publish: &Publish, limit: usize, pop_front_if_full: bool) -> Result<()> {
+            let mut publishs = self.0.lock().unwrap();
+            if publishs.len() >= limit {
+                if !pop_front_if_full {
+                    return Err(MqttError::from("is full"));
+                }
+                publishs.pop_front();
+            }
+            publishs.push_back(publish.clone());
+            Ok(())
+        }
+
+        async fn get(&self, idx: usize) -> Result<Option<Publish>> {
+            Ok(self.0.lock().unwrap().get(idx).cloned())
+        }
+
+        async fn pop_front(&self) -> Result<()> {
+            self.0.lock().unwrap().pop_front();
+            Ok(())
+        }
+
+        async fn len(&self) -> Result<usize> {
+            Ok(self.0.lock().unwrap().len())
+        }
+    }
+
+    #[derive(Default)]
+    pub(crate) struct MockTransport {
+        pub(crate) unavailable: AtomicBool,
+        //replies like a node without the federation plugin
+        pub(crate) no_plugin: AtomicBool,
+        pub(crate) interest: Mutex<Vec<TopicFilter>>,
+        pub(crate) received: Mutex<Vec<TopicName>>,
+    }
+
+    #[async_trait]
+    impl Transport for MockTransport {
+        async fn request(&self, msg: Message) -> Result<GrpcMessageReply> {
+            if self.unavailable.load(Ordering::SeqCst) {
+                return Err(MqttError::from("unavailable"));
+            }
+            if self.no_plugin.load(Ordering::SeqCst) {
+                return Ok(GrpcMessageReply::Success);
+            }
+            match msg {
+                Message::Publishes { publishs, .. } => {
+                    self.received.lock().unwrap().extend(publishs.into_iter().map(|p| p.topic));
+                    Ok(GrpcMessageReply::Data(MessageReply::Received.encode()?))
+                }
+                Message::InterestGet(known) => {
+                    let interest = self.interest.lock().unwrap().clone();
+                    let version = vec![(1, (1, interest.len() as u64))];
+                    let reply = if known.as_ref() == Some(&version) {
+                        MessageReply::InterestUnchanged
+                    } else {
+                        MessageReply::Interest(version, Some(interest))
+                    };
+                    Ok(GrpcMessageReply::Data(reply.encode()?))
+                }
+                Message::NodeInterestGet(_) => Err(MqttError::from("unexpected message")),
+            }
+        }
+
+        fn addr(&self) -> &str {
+            "mock"
+        }
+    }
+
+    pub(crate) fn link(
+        cfg: PluginConfig,
+        topics: &[&str],
+        transport: Arc<MockTransport>,
+        buffer: Arc<MemBuffer>,
+    ) -> (Arc<Link>, mpsc::Receiver<Publish>) {
+        let link_cfg = config::Link {
+            name: "l1".into(),
+            addrs: vec!["mock".into()],
+            topics: topics.iter().map(|t| t.to_string()).collect(),
+        };
+        Link::build(Arc::new(cfg), &link_cfg, transport, buffer).unwrap()
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+    }
+
+    fn topics(names: &[&str]) -> Vec<TopicName> {
+        names.iter().map(|n| TopicName::from(*n)).collect()
+    }
+
+    #[test]
+    fn is_wanted() {
+        runtime().block_on(async {
+            let transport = Arc::new(MockTransport::default());
+            let (link, _rx) = link(config(), &["a/#"], transport.clone(), Arc::default());
+            let topic = |t: &str| Topic::from_str(t).unwrap();
+            //all shared messages are sent until the interest is synced
+            assert!(link.is_wanted(&topic("a/1")));
+            assert!(link.is_wanted(&topic("a/2")));
+            assert!(!link.is_wanted(&topic("b/1")));
+
+            transport.interest.lock().unwrap().push(TopicFilter::from("a/1"));
+            link.sync_interest().await.unwrap();
+            assert!(link.is_wanted(&topic("a/1")));
+            assert!(!link.is_wanted(&topic("a/2")));
+            assert!(!link.is_wanted(&topic("b/1")));
+            //the interest is kept if it is unchanged
+            link.sync_interest().await.unwrap();
+            assert!(link.is_wanted(&topic("a/1")));
+            assert!(!link.is_wanted(&topic("a/2")));
+        });
+    }
+
+    #[test]
+    fn send_spills_when_full() {
+        runtime().block_on(async {
+            let mut cfg = config();
+            cfg.message_channel_capacity = 1;
+            cfg.buffer_max = 2;
+            let buffer = Arc::new(MemBuffer::default());
+            let (link, mut rx) = link(cfg, &["#"], Arc::default(), buffer.clone());
+            for t in ["t/1", "t/2", "t/3", "t/4"] {
+                link.send(publish(t)).await;
+            }
+            assert_eq!(rx.try_recv().unwrap().topic, "t/1");
+            //the buffered messages are kept when it is full
+            assert_eq!(buffer.get(0).await.unwrap().unwrap().topic, "t/2");
+            assert_eq!(buffer.len().await.unwrap(), 2);
+            assert_eq!(link.buffereds.load(Ordering::SeqCst), 2);
+            assert_eq!(link.droppeds.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn run_outage_and_recovery() {
+        runtime().block_on(async {
+            let transport = Arc::new(MockTransport::default());
+            let buffer = Arc::new(MemBuffer::default());
+            let (link, rx) = link(config(), &["#"], transport.clone(), buffer.clone());
+            tokio::spawn(link.clone().run(rx));
+
+            link.send(publish("t/1")).await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(*transport.received.lock().unwrap(), topics(&["t/1"]));
+
+            transport.unavailable.store(true, Ordering::SeqCst);
+            let mut expired = publish("t/expired");
+            expired.create_time = 0;
+            for p in [publish("t/2"), publish("t/3"), expired, publish("t/4"), publish("t/5")] {
+                link.send(p).await;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(!link.available.load(Ordering::SeqCst));
+            assert_eq!(buffer.len().await.unwrap(), 5);
+            //the messages are only removed from the buffer after they are sent
+            assert!(!link.flush(2).await);
+            assert_eq!(buffer.len().await.unwrap(), 5);
+
+            transport.unavailable.store(false, Ordering::SeqCst);
+            link.send(publish("t/6")).await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(link.available.load(Ordering::SeqCst));
+            assert_eq!(
+                *transport.received.lock().unwrap(),
+                topics(&["t/1", "t/2", "t/3", "t/4", "t/5", "t/6"])
+            );
+            assert_eq!(buffer.len().await.unwrap(), 0);
+            assert_eq!(link.droppeds.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn publish_not_received() {
+        runtime().block_on(async {
+            let transport = Arc::new(MockTransport::default());
+            let buffer = Arc::new(MemBuffer::default());
+            let (link, _rx) = link(config(), &["#"], transport.clone(), buffer.clone());
+            link.store(&publish("t/1"), true).await;
+
+            //the batch is kept in the buffer unless the federation plugin acknowledges it
+            transport.no_plugin.store(true, Ordering::SeqCst);
+            assert!(!link.flush(2).await);
+            assert_eq!(buffer.len().await.unwrap(), 1);
+            assert_eq!(link.sents.load(Ordering::SeqCst), 0);
+
+            transport.no_plugin.store(false, Ordering::SeqCst);
+            assert!(link.flush(2).await);
+            assert_eq!(buffer.len().await.unwrap(), 0);
+            assert_eq!(*transport.received.lock().unwrap(), topics(&["t/1"]));
+        });
+    }
+}
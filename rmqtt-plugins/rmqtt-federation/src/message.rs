@@ -0,0 +1,48 @@
+use rmqtt::{anyhow, bincode};
+use rmqtt::{Publish, Result, TopicFilter};
+
+use crate::config::ClusterId;
+use crate::interest::{ClusterVersion, Version};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Message {
+    ///Messages published in the origin cluster
+    Publishes { origin: ClusterId, publishs: Vec<Publish> },
+    ///Topic filters subscribed in the cluster, unless the known version is the current one
+    InterestGet(Option<ClusterVersion>),
+    ///Topic filters subscribed on the node, sent between the nodes of the cluster
+    NodeInterestGet(Option<Version>),
+}
+
+impl Message {
+    #[inline]
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self).map_err(anyhow::Error::new)?)
+    }
+    #[inline]
+    pub fn decode(data: &[u8]) -> Result<Message> {
+        Ok(bincode::deserialize::<Message>(data).map_err(anyhow::Error::new)?)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum MessageReply {
+    ///None if the topic filters of a node are unknown, then all shared messages are wanted
+    Interest(ClusterVersion, Option<Vec<TopicFilter>>),
+    NodeInterest(Version, Vec<TopicFilter>),
+    InterestUnchanged,
+    ///The messages are received by the federation plugin of the linked cluster, the other replies,
+    ///such as from a node without the plugin, are treated as unavailable
+    Received,
+}
+
+impl MessageReply {
+    #[inline]
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self).map_err(anyhow::Error::new)?)
+    }
+    #[inline]
+    pub fn decode(data: &[u8]) -> Result<MessageReply> {
+        Ok(bincode::deserialize::<MessageReply>(data).map_err(anyhow::Error::new)?)
+    }
+}
@@ -22,22 +22,25 @@ use rmqtt::{
     HashMap,
 };
 use rmqtt::{
+    broker::ban,
+    broker::ip_filter::{IpFilterRules, IpFilters},
     broker::types::NodeId,
     grpc::{
         client::NodeGrpcClient, Message as GrpcMessage, MessageBroadcaster, MessageReply as GrpcMessageReply,
         MessageSender, MessageType,
     },
     node::NodeStatus,
-    timestamp_millis, ClientId, From, Id, MqttError, Publish, PublishProperties, QoS, Result, Runtime,
-    SessionState, SubsSearchParams, TopicFilter, TopicName, UserName,
+    settings::listener::ListenerId,
+    timestamp_millis, BanKey, ClientId, From, Id, MqttError, Publish, PublishProperties, QoS, Result,
+    Runtime, SessionState, SubsSearchParams, TopicFilter, TopicName, UserName,
 };
 
 use super::prome;
 use super::types::{
-    ClientSearchParams, ClientSearchResult, Message, MessageReply, PrometheusDataType, PublishParams,
-    SubscribeParams, UnsubscribeParams,
+    BanParams, ClientSearchParams, ClientSearchResult, IpFilterParams, Message, MessageReply,
+    PrometheusDataType, PublishParams, SubscribeParams, UnsubscribeParams,
 };
-use super::{clients, plugin, subs, PluginConfigType};
+use super::{clients, listeners, plugin, subs, PluginConfigType};
 
 struct BearerValidator {
     token: String,
@@ -68,8 +71,27 @@ fn route(cfg: PluginConfigType, token: Option<String>) -> Router {
     router
         .get(list_apis)
         .push(Router::with_path("brokers").get(get_brokers).push(Router::with_path("<id>").get(get_brokers)))
-        .push(Router::with_path("nodes").get(get_nodes).push(Router::with_path("<id>").get(get_nodes)))
+        .push(
+            Router::with_path("nodes")
+                .get(get_nodes)
+                .push(Router::with_path("<id>").get(get_nodes).delete(remove_node))
+                .push(Router::with_path("<id>/drain").put(node_drain)),
+        )
         .push(Router::with_path("health/check").get(check_health))
+        .push(
+            Router::with_path("banned")
+                .get(get_banneds)
+                .post(add_banned)
+                .push(Router::with_path("<as>/<**who>").get(get_banned).delete(delete_banned)),
+        )
+        .push(
+            Router::with_path("listeners")
+                .push(Router::with_path("<node>").get(get_listeners).post(add_listener))
+                .push(Router::with_path("<node>/ipfilter").get(get_ip_filters))
+                .push(Router::with_path("<node>/unix").put(update_listener).delete(remove_listener))
+                .push(Router::with_path("<node>/<port>").put(update_listener).delete(remove_listener))
+                .push(Router::with_path("<node>/<port>/ipfilter").put(set_ip_filter)),
+        )
         .push(
             Router::with_path("clients")
                 .push(Router::with_path("offlines").get(search_offlines).delete(kick_offlines))
@@ -164,12 +186,96 @@ async fn list_apis(res: &mut Response) {
             "path": "/nodes/{node}",
             "descr": "Returns the status of the node"
         },
+        {
+            "name": "remove_node",
+            "method": "DELETE",
+            "path": "/nodes/{node}",
+            "descr": "Remove the node from the cluster"
+        },
+        {
+            "name": "node_drain",
+            "method": "PUT",
+            "path": "/nodes/{node}/drain",
+            "descr": "Stop accepting connections on the node and disconnect its clients"
+        },
         {
             "name": "check_health",
             "method": "GET",
             "path": "/health/check",
             "descr": "Node health check"
         },
+        {
+            "name": "get_banneds",
+            "method": "GET",
+            "path": "/banned",
+            "descr": "Return all ban entries"
+        },
+        {
+            "name": "add_banned",
+            "method": "POST",
+            "path": "/banned",
+            "descr": "Add a ban entry to the cluster"
+        },
+        {
+            "name": "get_banned",
+            "method": "GET",
+            "path": "/banned/{as}/{who}",
+            "descr": "Get a ban entry"
+        },
+        {
+            "name": "delete_banned",
+            "method": "DELETE",
+            "path": "/banned/{as}/{who}",
+            "descr": "Remove a ban entry from the cluster"
+        },
+        {
+            "name": "get_listeners",
+            "method": "GET",
+            "path": "/listeners/{node}",
+            "descr": "Return all listeners on the node"
+        },
+        {
+            "name": "add_listener",
+            "method": "POST",
+            "path": "/listeners/{node}",
+            "descr": "Add and start a listener on the node"
+        },
+        {
+            "name": "update_listener",
+            "method": "PUT",
+            "path": "/listeners/{node}/{port}",
+            "descr": "Update a listener on the node, the existing sessions keep the old options"
+        },
+        {
+            "name": "remove_listener",
+            "method": "DELETE",
+            "path": "/listeners/{node}/{port}",
+            "descr": "Stop and remove a listener on the node"
+        },
+        {
+            "name": "update_listener",
+            "method": "PUT",
+            "path": "/listeners/{node}/unix?path={path}",
+            "descr": "Update a unix listener on the node, it is identified by the socket path"
+        },
+        {
+            "name": "remove_listener",
+            "method": "DELETE",
+            "path": "/listeners/{node}/unix?path={path}",
+            "descr": "Stop and remove a unix listener on the node, it is identified by the socket path"
+        },
+        {
+            "name": "get_ip_filters",
+            "method": "GET",
+            "path": "/listeners/{node}/ipfilter",
+            "descr": "Return the IP allow/deny lists of all listeners on the node"
+        },
+        {
+            "name": "set_ip_filter",
+            "method": "PUT",
+            "path": "/listeners/{node}/{port}/ipfilter",
+            "descr": "Replace the IP allow/deny lists of a listener on the node"
+        },
         {
             "name": "search_clients",
             "method": "GET",
@@ -491,6 +597,414 @@ async fn get_nodes(req: &mut Request, depot: &mut Depot, res: &mut Response) ->
     Ok(())
 }
 
+#[handler]
+async fn remove_node(req: &mut Request, res: &mut Response) {
+    let node_id = if let Some(node_id) = req.param::<NodeId>("id") {
+        node_id
+    } else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    match Runtime::instance().extends.shared().await.remove_node(node_id).await {
+        Ok(true) => res.render(Json(true)),
+        Ok(false) => {
+            res.status_code(StatusCode::NOT_FOUND);
+        }
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn node_drain(req: &mut Request, depot: &mut Depot, res: &mut Response) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("id") {
+        node_id
+    } else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return Ok(());
+    };
+
+    match _node_drain(node_id, message_type).await {
+        Ok(r) => res.render(Json(r)),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+async fn _node_drain(node_id: NodeId, message_type: MessageType) -> Result<bool> {
+    if node_id == Runtime::instance().node.id() {
+        Ok(Runtime::instance().node.drain())
+    } else {
+        let c = get_grpc_client(node_id).await?;
+        let msg = Message::NodeDrain.encode()?;
+        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
+        match reply {
+            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
+                MessageReply::NodeDrain(ok) => Ok(ok),
+                _ => unreachable!(),
+            },
+            reply => {
+                log::info!("Drain GrpcMessage::NodeDrain from other node({}), reply: {:?}", node_id, reply);
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[handler]
+async fn get_banneds(res: &mut Response) {
+    match Runtime::instance().extends.ban_mgr().await.list().await {
+        Ok(bans) => res.render(Json(bans.iter().map(|ban| ban.to_json()).collect::<Vec<_>>())),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn get_banned(req: &mut Request, res: &mut Response) {
+    let key = match ban_key(req) {
+        Ok(key) => key,
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e.to_string()));
+            return;
+        }
+    };
+    match Runtime::instance().extends.ban_mgr().await.get(&key).await {
+        Ok(Some(ban)) => res.render(Json(ban.to_json())),
+        Ok(None) => {
+            res.status_code(StatusCode::NOT_FOUND);
+        }
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn add_banned(req: &mut Request, res: &mut Response) {
+    let params = match req.parse_json::<BanParams>().await {
+        Ok(p) => p,
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e.to_string()));
+            return;
+        }
+    };
+    let ban = match params.into_ban() {
+        Ok(ban) => ban,
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e.to_string()));
+            return;
+        }
+    };
+    let reply = ban.to_json();
+    match ban::add(ban).await {
+        Ok(()) => res.render(Json(reply)),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn delete_banned(req: &mut Request, res: &mut Response) {
+    let key = match ban_key(req) {
+        Ok(key) => key,
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e.to_string()));
+            return;
+        }
+    };
+    match ban::remove(&key).await {
+        Ok(removed) => res.render(Json(removed.is_some())),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn get_ip_filters(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("node") {
+        node_id
+    } else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return Ok(());
+    };
+
+    match _get_ip_filters(node_id, message_type).await {
+        Ok(filters) => res.render(Json(
+            filters
+                .iter()
+                .map(|(port, rules)| {
+                    let mut rules = rules.to_json();
+                    if let Some(obj) = rules.as_object_mut() {
+                        obj.insert("port".into(), json!(port));
+                    }
+                    rules
+                })
+                .collect::<Vec<_>>(),
+        )),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+async fn _get_ip_filters(node_id: NodeId, message_type: MessageType) -> Result<Vec<(u16, IpFilterRules)>> {
+    if node_id == Runtime::instance().node.id() {
+        Ok(IpFilters::instance().list().into_iter().map(|(port, rules)| (port, (*rules).clone())).collect())
+    } else {
+        let c = get_grpc_client(node_id).await?;
+        let msg = Message::GetIpFilters.encode()?;
+        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
+        match reply {
+            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
+                MessageReply::GetIpFilters(filters) => Ok(filters),
+                _ => unreachable!(),
+            },
+            GrpcMessageReply::Error(e) => Err(MqttError::from(e)),
+            reply => Err(MqttError::from(format!("unexpected reply, {:?}", reply))),
+        }
+    }
+}
+
+#[handler]
+async fn set_ip_filter(req: &mut Request, depot: &mut Depot, res: &mut Response) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    let (node_id, port) = match (req.param::<NodeId>("node"), req.param::<u16>("port")) {
+        (Some(node_id), Some(port)) => (node_id, port),
+        _ => {
+            res.status_code(StatusCode::NOT_FOUND);
+            return Ok(());
+        }
+    };
+    let rules = match req.parse_json::<IpFilterParams>().await.map_err(|e| MqttError::from(e.to_string())) {
+        Ok(params) => params.into_rules(),
+        Err(e) => Err(e),
+    };
+    let rules = match rules {
+        Ok(rules) => rules,
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e.to_string()));
+            return Ok(());
+        }
+    };
+    let reply = rules.to_json();
+    match _set_ip_filter(node_id, port, rules, message_type).await {
+        Ok(()) => res.render(Json(reply)),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+async fn _set_ip_filter(
+    node_id: NodeId,
+    port: u16,
+    rules: IpFilterRules,
+    message_type: MessageType,
+) -> Result<()> {
+    if node_id == Runtime::instance().node.id() {
+        IpFilters::instance().set_rules(port, rules)
+    } else {
+        let c = get_grpc_client(node_id).await?;
+        let msg = Message::SetIpFilter { port, rules }.encode()?;
+        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
+        match reply {
+            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
+                MessageReply::SetIpFilter => Ok(()),
+                _ => unreachable!(),
+            },
+            GrpcMessageReply::Error(e) => Err(MqttError::from(e)),
+            reply => Err(MqttError::from(format!("unexpected reply, {:?}", reply))),
+        }
+    }
+}
+
+#[inline]
+fn render_json(res: &mut Response, body: Vec<u8>) {
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+    res.write_body(body).ok();
+}
+
+#[handler]
+async fn get_listeners(req: &mut Request, depot: &mut Depot, res: &mut Response) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("node") {
+        node_id
+    } else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return Ok(());
+    };
+    match _get_listeners(node_id, message_type).await {
+        Ok(listeners) => render_json(res, listeners),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+async fn _get_listeners(node_id: NodeId, message_type: MessageType) -> Result<Vec<u8>> {
+    if node_id == Runtime::instance().node.id() {
+        listeners::get()
+    } else {
+        match _send_listener_message(node_id, Message::GetListeners, message_type).await? {
+            MessageReply::GetListeners(listeners) => Ok(listeners),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[handler]
+async fn add_listener(req: &mut Request, depot: &mut Depot, res: &mut Response) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("node") {
+        node_id
+    } else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return Ok(());
+    };
+    let body = match req.parse_json::<serde_json::Value>().await {
+        Ok(body) => body.to_string().into_bytes(),
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e.to_string()));
+            return Ok(());
+        }
+    };
+    match _add_listener(node_id, body, message_type).await {
+        Ok(listener) => render_json(res, listener),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+async fn _add_listener(node_id: NodeId, body: Vec<u8>, message_type: MessageType) -> Result<Vec<u8>> {
+    if node_id == Runtime::instance().node.id() {
+        listeners::add(&body)
+    } else {
+        match _send_listener_message(node_id, Message::AddListener { body }, message_type).await? {
+            MessageReply::AddListener(listener) => Ok(listener),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[handler]
+async fn update_listener(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    let (node_id, id) = match (req.param::<NodeId>("node"), listener_id(req)) {
+        (Some(node_id), Some(id)) => (node_id, id),
+        _ => {
+            res.status_code(StatusCode::NOT_FOUND);
+            return Ok(());
+        }
+    };
+    let body = match req.parse_json::<serde_json::Value>().await {
+        Ok(body) => body.to_string().into_bytes(),
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e.to_string()));
+            return Ok(());
+        }
+    };
+    match _update_listener(node_id, id, body, message_type).await {
+        Ok(listener) => render_json(res, listener),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+async fn _update_listener(
+    node_id: NodeId,
+    id: ListenerId,
+    body: Vec<u8>,
+    message_type: MessageType,
+) -> Result<Vec<u8>> {
+    if node_id == Runtime::instance().node.id() {
+        listeners::update(&id, &body)
+    } else {
+        match _send_listener_message(node_id, Message::UpdateListener { id, body }, message_type).await? {
+            MessageReply::UpdateListener(listener) => Ok(listener),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[handler]
+async fn remove_listener(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    let (node_id, id) = match (req.param::<NodeId>("node"), listener_id(req)) {
+        (Some(node_id), Some(id)) => (node_id, id),
+        _ => {
+            res.status_code(StatusCode::NOT_FOUND);
+            return Ok(());
+        }
+    };
+    match _remove_listener(node_id, id, message_type).await {
+        Ok(Some(listener)) => render_json(res, listener),
+        Ok(None) => {
+            res.status_code(StatusCode::NOT_FOUND);
+        }
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+async fn _remove_listener(
+    node_id: NodeId,
+    id: ListenerId,
+    message_type: MessageType,
+) -> Result<Option<Vec<u8>>> {
+    if node_id == Runtime::instance().node.id() {
+        listeners::remove(&id)
+    } else {
+        match _send_listener_message(node_id, Message::RemoveListener { id }, message_type).await? {
+            MessageReply::RemoveListener(listener) => Ok(listener),
+            _ => unreachable!(),
+        }
+    }
+}
+
+async fn _send_listener_message(
+    node_id: NodeId,
+    msg: Message<'_>,
+    message_type: MessageType,
+) -> Result<MessageReply> {
+    let c = get_grpc_client(node_id).await?;
+    let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg.encode()?)).send().await?;
+    match reply {
+        GrpcMessageReply::Data(msg) => MessageReply::decode(&msg),
+        GrpcMessageReply::Error(e) => Err(MqttError::from(e)),
+        reply => Err(MqttError::from(format!("unexpected reply, {:?}", reply))),
+    }
+}
+
+///The port in the path, or the socket path in the "path" query of the unix listener
+#[inline]
+fn listener_id(req: &mut Request) -> Option<ListenerId> {
+    match req.param::<u16>("port") {
+        Some(port) => Some(ListenerId::Port(port)),
+        None => req.query::<String>("path").map(ListenerId::Path),
+    }
+}
+
+#[inline]
+fn ban_key(req: &mut Request) -> Result<BanKey> {
+    match (req.param::<String>("as"), req.param::<String>("who")) {
+        (Some(kind), Some(who)) => BanKey::parse(&kind, &who),
+        _ => Err(MqttError::from("as and who are required")),
+    }
+}
+
 #[inline]
 async fn _get_nodes(message_type: MessageType) -> Result<Vec<serde_json::Value>> {
     let mut nodes = vec![Runtime::instance().node.node_info().await.to_json()];
@@ -1,11 +1,13 @@
 use rmqtt::{async_trait::async_trait, log};
 use rmqtt::{
     broker::hook::{Handler, HookResult, Parameter, ReturnType},
+    broker::ip_filter::IpFilters,
     grpc::{Message as GrpcMessage, MessageReply as GrpcMessageReply, MessageType},
     Runtime,
 };
 
 use super::clients;
+use super::listeners;
 use super::plugin;
 use super::subs;
 use super::types::{Message, MessageReply};
@@ -226,6 +228,91 @@ impl Handler for HookHandler {
                                     ))),
                                 }
                             }
+                            Ok(Message::GetIpFilters) => {
+                                let filters = IpFilters::instance()
+                                    .list()
+                                    .into_iter()
+                                    .map(|(port, rules)| (port, (*rules).clone()))
+                                    .collect();
+                                match MessageReply::GetIpFilters(filters).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::SetIpFilter { port, rules }) => {
+                                let reply = match IpFilters::instance().set_rules(port, rules) {
+                                    Ok(()) => MessageReply::SetIpFilter.encode(),
+                                    Err(e) => Err(e),
+                                };
+                                match reply {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::GetListeners) => {
+                                match listeners::get().and_then(|l| MessageReply::GetListeners(l).encode()) {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::AddListener { body }) => {
+                                match listeners::add(&body)
+                                    .and_then(|l| MessageReply::AddListener(l).encode())
+                                {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::UpdateListener { id, body }) => {
+                                let reply = listeners::update(&id, &body)
+                                    .and_then(|l| MessageReply::UpdateListener(l).encode());
+                                match reply {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::RemoveListener { id }) => {
+                                let reply = listeners::remove(&id)
+                                    .and_then(|l| MessageReply::RemoveListener(l).encode());
+                                match reply {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::NodeDrain) => {
+                                match MessageReply::NodeDrain(Runtime::instance().node.drain()).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
                         };
                         return (false, Some(new_acc));
                     }
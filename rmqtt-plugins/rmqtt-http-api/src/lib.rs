@@ -23,6 +23,7 @@ mod api;
 mod clients;
 mod config;
 mod handler;
+mod listeners;
 mod plugin;
 mod prome;
 mod subs;
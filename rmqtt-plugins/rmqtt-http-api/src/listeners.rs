@@ -0,0 +1,45 @@
+use rmqtt::settings::listener::{ListenerId, ListenerInner, ListenerKind};
+use rmqtt::{serde_json, MqttError, Result, Runtime};
+
+#[derive(Deserialize)]
+struct ListenerParams {
+    kind: ListenerKind,
+    #[serde(default)]
+    name: String,
+}
+
+pub(crate) fn get() -> Result<Vec<u8>> {
+    let listeners = Runtime::instance()
+        .settings
+        .listeners
+        .all()
+        .iter()
+        .map(|(kind, l)| l.to_json(*kind))
+        .collect::<Vec<_>>();
+    Ok(serde_json::to_vec(&listeners)?)
+}
+
+///The body is the listener configuration in JSON, with the "kind" of the listener
+pub(crate) fn add(body: &[u8]) -> Result<Vec<u8>> {
+    let params = serde_json::from_slice::<ListenerParams>(body)?;
+    let inner = serde_json::from_slice::<ListenerInner>(body)?;
+    let name = if params.name.is_empty() { params.kind.to_string() } else { params.name };
+    let listener = Runtime::instance().settings.listeners.add(params.kind, name, inner)?;
+    Ok(serde_json::to_vec(&listener.to_json(params.kind))?)
+}
+
+///The body is the full listener configuration in JSON, the omitted options take their defaults
+pub(crate) fn update(id: &ListenerId, body: &[u8]) -> Result<Vec<u8>> {
+    let inner = serde_json::from_slice::<ListenerInner>(body)?;
+    let listeners = &Runtime::instance().settings.listeners;
+    let kind = listeners.kind(id).ok_or_else(|| MqttError::from(format!("listener {} is not found", id)))?;
+    let listener = listeners.update(id, inner)?;
+    Ok(serde_json::to_vec(&listener.to_json(kind))?)
+}
+
+pub(crate) fn remove(id: &ListenerId) -> Result<Option<Vec<u8>>> {
+    match Runtime::instance().settings.listeners.remove(id) {
+        Some((kind, listener)) => Ok(Some(serde_json::to_vec(&listener.to_json(kind))?)),
+        None => Ok(None),
+    }
+}
@@ -2,13 +2,15 @@ use serde::de::{self, Deserialize};
 use serde::ser::{self, Serialize};
 use std::time::Duration;
 
+use rmqtt::broker::ip_filter::IpFilterRules;
 use rmqtt::chrono::LocalResult;
 use rmqtt::node::{BrokerInfo, NodeInfo, NodeStatus};
 use rmqtt::plugin::PluginInfo;
+use rmqtt::settings::listener::ListenerId;
 use rmqtt::settings::{deserialize_datetime_option, serialize_datetime_option};
 use rmqtt::{anyhow, bincode, chrono, serde_json, HashMap, MqttError, QoS};
 use rmqtt::{metrics::Metrics, stats::Stats};
-use rmqtt::{ClientId, NodeId, Timestamp, TopicFilter, TopicName, UserName};
+use rmqtt::{Ban, BanKey, Cidr, ClientId, NodeId, Timestamp, TopicFilter, TopicName, UserName};
 use rmqtt::{PublishProperties, Result};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -27,6 +29,13 @@ pub enum Message<'a> {
     ReloadPluginConfig { name: &'a str },
     LoadPlugin { name: &'a str },
     UnloadPlugin { name: &'a str },
+    NodeDrain,
+    GetIpFilters,
+    SetIpFilter { port: u16, rules: IpFilterRules },
+    GetListeners,
+    AddListener { body: Vec<u8> },
+    UpdateListener { id: ListenerId, body: Vec<u8> },
+    RemoveListener { id: ListenerId },
 }
 
 impl Message<'_> {
@@ -56,6 +65,13 @@ pub enum MessageReply {
     ReloadPluginConfig,
     LoadPlugin,
     UnloadPlugin(bool),
+    NodeDrain(bool),
+    GetIpFilters(Vec<(u16, IpFilterRules)>),
+    SetIpFilter,
+    GetListeners(Vec<u8>),
+    AddListener(Vec<u8>),
+    UpdateListener(Vec<u8>),
+    RemoveListener(Option<Vec<u8>>),
 }
 
 impl MessageReply {
@@ -69,6 +85,41 @@ impl MessageReply {
     }
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BanParams {
+    ///clientid, username, ip or cidr
+    #[serde(rename = "as")]
+    pub kind: String,
+    pub who: String,
+    pub reason: Option<String>,
+    pub by: Option<String>,
+    ///Expiry time, unix timestamp in seconds, never expires if not set
+    pub until: Option<Timestamp>,
+}
+
+impl BanParams {
+    #[inline]
+    pub fn into_ban(self) -> Result<Ban> {
+        Ok(Ban::new(BanKey::parse(&self.kind, &self.who)?, self.reason, self.by, self.until))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct IpFilterParams {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl IpFilterParams {
+    #[inline]
+    pub fn into_rules(self) -> Result<IpFilterRules> {
+        let parse = |cidrs: Vec<String>| cidrs.iter().map(|c| c.parse::<Cidr>()).collect::<Result<Vec<_>>>();
+        Ok(IpFilterRules { allow: parse(self.allow)?, deny: parse(self.deny)? })
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct ClientSearchParams {
     #[serde(default)]
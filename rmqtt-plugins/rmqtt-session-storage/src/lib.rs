@@ -304,6 +304,17 @@ impl Plugin for StoragePlugin {
                 )),
             )
             .await;
+        self.register
+            .add(
+                Type::BeforeShutdown,
+                Box::new(StorageHandler::new(
+                    self.storage_db.clone(),
+                    self.cfg.clone(),
+                    self.stored_session_infos.clone(),
+                    self.rebuild_tx.clone(),
+                )),
+            )
+            .await;
         self.register
             .add(
                 Type::OfflineMessage,
@@ -494,7 +505,7 @@ impl StorageHandler {
 
                 //get listener config
                 let listen_cfg = if let Some(listen_cfg) =
-                    id.local_addr.and_then(|addr| Runtime::instance().settings.listeners.get(addr.port()))
+                    id.local_addr.and_then(|addr| Runtime::instance().settings.listeners.by_local_addr(&addr))
                 {
                     listen_cfg
                 } else {
@@ -617,6 +628,12 @@ impl Handler for StorageHandler {
                 self.rebuild_offline_sessions(rebuild_done_tx).await;
                 let _ = rebuild_done_rx.await;
             }
+            Parameter::BeforeShutdown => {
+                log::info!("BeforeShutdown storage_type: {:?}, flush the session data", self.cfg.storage.typ);
+                if let Err(e) = self.storage_db.flush().await {
+                    log::error!("flush the session data error, {:?}", e);
+                }
+            }
             _ => {
                 log::error!("unimplemented, {:?}", param)
             }
@@ -116,6 +116,7 @@ impl Plugin for SystemTopicPlugin {
         self.register.add(Type::SessionTerminated, Box::new(SystemTopicHandler::new(cfg))).await;
         self.register.add(Type::ClientConnected, Box::new(SystemTopicHandler::new(cfg))).await;
         self.register.add(Type::ClientDisconnected, Box::new(SystemTopicHandler::new(cfg))).await;
+        self.register.add(Type::ClientFlapping, Box::new(SystemTopicHandler::new(cfg))).await;
         self.register.add(Type::SessionSubscribed, Box::new(SystemTopicHandler::new(cfg))).await;
         self.register.add(Type::SessionUnsubscribed, Box::new(SystemTopicHandler::new(cfg))).await;
         self.register.add(Type::MessageDropped, Box::new(SystemTopicHandler::new(cfg))).await;
@@ -236,6 +237,21 @@ impl Handler for SystemTopicHandler {
                     format!("$SYS/brokers/{}/clients/{}/disconnected", self.nodeid, session.id.client_id);
                 Some((topic, body))
             }
+            Parameter::ClientFlapping(connect_info, ban) => {
+                let id = connect_info.id();
+                let body = json!({
+                    "node": id.node(),
+                    "ipaddress": id.remote_addr,
+                    "clientid": id.client_id,
+                    "username": id.username_ref(),
+                    "reason": ban.reason,
+                    "banned_at": ban.at,
+                    "banned_until": ban.until,
+                    "time": now_time
+                });
+                let topic = format!("$SYS/brokers/{}/clients/{}/flapping", self.nodeid, id.client_id);
+                Some((topic, body))
+            }
 
             Parameter::SessionSubscribed(session, subscribe) => {
                 let body = json!({
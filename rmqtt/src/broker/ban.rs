@@ -0,0 +1,123 @@
+//! Ban entries are applied on all nodes of the cluster, the online sessions they match are kicked
+
+use std::time::Duration;
+
+use crate::broker::types::*;
+use crate::grpc::{self, MessageBroadcaster, MessageReply, MESSAGE_TYPE_BAN};
+use crate::{MqttError, Result, Runtime};
+
+const SYNC_RETRIES: usize = 10;
+const SYNC_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+///Add the ban entry on this node and the other nodes
+#[inline]
+pub async fn add(ban: Ban) -> Result<()> {
+    apply(ban.clone()).await?;
+    broadcast(grpc::Message::BanAdd(ban)).await;
+    Ok(())
+}
+
+///Remove the ban entry on this node and the other nodes
+#[inline]
+pub async fn remove(key: &BanKey) -> Result<Option<Ban>> {
+    let removed = Runtime::instance().extends.ban_mgr().await.remove(key).await?;
+    broadcast(grpc::Message::BanRemove(key.clone())).await;
+    Ok(removed)
+}
+
+///Add the ban entry on this node, the matching sessions of this node are kicked
+#[inline]
+pub(crate) async fn apply(ban: Ban) -> Result<()> {
+    Runtime::instance().extends.ban_mgr().await.add(ban.clone()).await?;
+    tokio::spawn(kick(ban));
+    Ok(())
+}
+
+async fn kick(ban: Ban) {
+    let ids = Runtime::instance()
+        .extends
+        .shared()
+        .await
+        .iter()
+        .filter_map(|entry| entry.session().map(|s| s.id.clone()))
+        .filter(|id| ban.is_match(id))
+        .collect::<Vec<_>>();
+    for id in ids {
+        let mut entry = Runtime::instance().extends.shared().await.entry(id.clone());
+        match entry.kick(true, true, true).await {
+            Ok(_) => log::info!("{:?} kicked, banned as {} {}", id, ban.key.kind(), ban.key.who()),
+            Err(e) => log::warn!("{:?} kick the banned session error, {:?}", id, e),
+        }
+    }
+}
+
+async fn broadcast(msg: grpc::Message) {
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if grpc_clients.is_empty() {
+        return;
+    }
+    let desc = format!("{:?}", msg);
+    for (id, reply) in MessageBroadcaster::new(grpc_clients, MESSAGE_TYPE_BAN, msg).join_all().await {
+        match reply {
+            Ok(MessageReply::Success) => {}
+            Ok(reply) => log::warn!("Broadcast {} to other node({}), reply: {:?}", desc, id, reply),
+            Err(e) => log::warn!("Broadcast {} to other node({}), error: {:?}", desc, id, e),
+        }
+    }
+}
+
+///Pull the ban entries of the other nodes, the newer entry wins when both nodes have the key.
+///Returns an error if no node replied
+pub async fn sync() -> Result<usize> {
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if grpc_clients.is_empty() {
+        return Ok(0);
+    }
+    let mut replied = false;
+    let mut synced = 0;
+    for (id, reply) in
+        MessageBroadcaster::new(grpc_clients, MESSAGE_TYPE_BAN, grpc::Message::BanList).join_all().await
+    {
+        let bans = match reply {
+            Ok(MessageReply::BanList(bans)) => bans,
+            Ok(reply) => {
+                log::warn!("Get ban entries from other node({}), reply: {:?}", id, reply);
+                continue;
+            }
+            Err(e) => {
+                log::warn!("Get ban entries from other node({}), error: {:?}", id, e);
+                continue;
+            }
+        };
+        replied = true;
+        for ban in bans.into_iter().filter(|ban| !ban.is_expired()) {
+            let exist = Runtime::instance().extends.ban_mgr().await.get(&ban.key).await?;
+            if exist.map(|exist| exist.at < ban.at).unwrap_or(true) {
+                apply(ban).await?;
+                synced += 1;
+            }
+        }
+    }
+    if replied {
+        Ok(synced)
+    } else {
+        Err(MqttError::from("no node replied the ban entries"))
+    }
+}
+
+///Pull the ban entries of the other nodes after the node joins the cluster, it is retried
+///until one of the nodes replies
+pub fn start_sync() {
+    tokio::spawn(async move {
+        for _ in 0..SYNC_RETRIES {
+            match sync().await {
+                Ok(synced) => {
+                    log::info!("ban entries synced from the other nodes: {}", synced);
+                    return;
+                }
+                Err(e) => log::warn!("sync ban entries error, {:?}", e),
+            }
+            tokio::time::sleep(SYNC_RETRY_INTERVAL).await;
+        }
+    });
+}
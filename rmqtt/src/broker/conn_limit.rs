@@ -0,0 +1,268 @@
+//! Connection rate and per source IP connection limits of a listener,
+//! checked before the TLS or MQTT handshake starts
+
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use governor::{
+    clock::DefaultClock,
+    state::{keyed::DefaultKeyedStateStore, InMemoryState, NotKeyed},
+    Quota, RateLimiter,
+};
+
+use crate::broker::types::*;
+use crate::settings::listener::{Listener, ListenerId};
+use crate::Runtime;
+
+type DirectLimiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock>;
+type KeyedLimiter = RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, DefaultClock>;
+
+//Interval to clean up the per IP rate limiter states, unit: milliseconds
+const CLEANUP_INTERVAL: TimestampMillis = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnLimitExceeded {
+    ConnRate,
+    ConnRatePerIp,
+    ConnectionsPerIp,
+}
+
+pub struct ConnLimiter {
+    conn_rate: Option<DirectLimiter>,
+    conn_rate_per_ip: Option<KeyedLimiter>,
+    max_connections_per_ip: usize,
+    //ip => connections
+    conns: DashMap<IpAddr, usize>,
+    last_cleanup: AtomicI64,
+}
+
+impl ConnLimiter {
+    ///Returns None if no limit is configured for the listener
+    #[inline]
+    pub fn new(listen_cfg: &Listener) -> Option<Arc<Self>> {
+        Self::with_limits(
+            listen_cfg.max_conn_rate,
+            listen_cfg.max_conn_rate_per_ip,
+            listen_cfg.max_connections_per_ip,
+        )
+    }
+
+    #[inline]
+    fn with_limits(
+        max_conn_rate: u32,
+        max_conn_rate_per_ip: u32,
+        max_connections_per_ip: usize,
+    ) -> Option<Arc<Self>> {
+        let conn_rate = NonZeroU32::new(max_conn_rate).map(|n| RateLimiter::direct(Quota::per_second(n)));
+        let conn_rate_per_ip =
+            NonZeroU32::new(max_conn_rate_per_ip).map(|n| RateLimiter::keyed(Quota::per_second(n)));
+        if conn_rate.is_none() && conn_rate_per_ip.is_none() && max_connections_per_ip == 0 {
+            return None;
+        }
+        Some(Arc::new(Self {
+            conn_rate,
+            conn_rate_per_ip,
+            max_connections_per_ip,
+            conns: DashMap::default(),
+            last_cleanup: AtomicI64::new(timestamp_millis()),
+        }))
+    }
+
+    ///Accept a connection from the ip, the returned guard holds the connection slot until it is dropped.
+    ///
+    ///The rejection is counted in Metrics.
+    #[inline]
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> std::result::Result<ConnGuard, ConnLimitExceeded> {
+        let res = self._acquire(ip);
+        match res {
+            Err(ConnLimitExceeded::ConnRate) | Err(ConnLimitExceeded::ConnRatePerIp) => {
+                Runtime::instance().metrics.client_connect_rate_limited_inc()
+            }
+            Err(ConnLimitExceeded::ConnectionsPerIp) => {
+                Runtime::instance().metrics.client_connect_ip_limited_inc()
+            }
+            Ok(_) => {}
+        }
+        res
+    }
+
+    #[inline]
+    fn _acquire(self: &Arc<Self>, ip: IpAddr) -> std::result::Result<ConnGuard, ConnLimitExceeded> {
+        //The per IP limits are checked first, the connections over them do not take from the conn_rate
+        if self.max_connections_per_ip > 0 {
+            let mut conns = self.conns.entry(ip).or_insert(0);
+            if *conns >= self.max_connections_per_ip {
+                return Err(ConnLimitExceeded::ConnectionsPerIp);
+            }
+            *conns += 1;
+        }
+        //The connection slot is released if the connection is refused by the rates
+        let guard = ConnGuard { limiter: self.clone(), ip };
+        if let Some(l) = &self.conn_rate_per_ip {
+            self.cleanup(l);
+            if l.check_key(&ip).is_err() {
+                return Err(ConnLimitExceeded::ConnRatePerIp);
+            }
+        }
+        if let Some(l) = &self.conn_rate {
+            if l.check().is_err() {
+                return Err(ConnLimitExceeded::ConnRate);
+            }
+        }
+        Ok(guard)
+    }
+
+    #[inline]
+    fn cleanup(&self, l: &KeyedLimiter) {
+        let now = timestamp_millis();
+        let last = self.last_cleanup.load(Ordering::Relaxed);
+        if now - last > CLEANUP_INTERVAL
+            && self.last_cleanup.compare_exchange(last, now, Ordering::SeqCst, Ordering::Relaxed).is_ok()
+        {
+            l.retain_recent();
+        }
+    }
+
+    ///Number of connections from the ip, only counted if max_connections_per_ip is set
+    #[inline]
+    pub fn connections(&self, ip: &IpAddr) -> usize {
+        self.conns.get(ip).map(|c| *c).unwrap_or_default()
+    }
+}
+
+pub struct ConnGuard {
+    limiter: Arc<ConnLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnGuard {
+    #[inline]
+    fn drop(&mut self) {
+        if self.limiter.max_connections_per_ip > 0 {
+            self.limiter.conns.remove_if_mut(&self.ip, |_, conns| {
+                *conns = conns.saturating_sub(1);
+                *conns == 0
+            });
+        }
+    }
+}
+
+///Connections of a listener, limited by the max_connections of the current listener configuration,
+///so that the limit can be changed at runtime
+pub struct ConnCounter {
+    id: ListenerId,
+    conns: AtomicUsize,
+}
+
+impl ConnCounter {
+    #[inline]
+    pub fn new(id: ListenerId) -> Arc<Self> {
+        Arc::new(Self { id, conns: AtomicUsize::new(0) })
+    }
+
+    ///Returns None if the max_connections of the listener is reached
+    #[inline]
+    pub fn acquire(self: &Arc<Self>) -> Option<ConnCountGuard> {
+        let max_connections = Runtime::instance()
+            .settings
+            .listeners
+            .by_id(&self.id)
+            .map(|l| l.max_connections)
+            .unwrap_or_default();
+        self._acquire(max_connections)
+    }
+
+    #[inline]
+    fn _acquire(self: &Arc<Self>, max_connections: usize) -> Option<ConnCountGuard> {
+        self.conns
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |c| {
+                if c < max_connections {
+                    Some(c + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| ConnCountGuard { counter: self.clone() })
+    }
+
+    #[inline]
+    pub fn connections(&self) -> usize {
+        self.conns.load(Ordering::SeqCst)
+    }
+}
+
+pub struct ConnCountGuard {
+    counter: Arc<ConnCounter>,
+}
+
+impl Drop for ConnCountGuard {
+    #[inline]
+    fn drop(&mut self) {
+        self.counter.conns.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_per_ip() {
+        assert!(ConnLimiter::with_limits(0, 0, 0).is_none());
+        let limiter = ConnLimiter::with_limits(0, 0, 2).unwrap();
+        let ip1: IpAddr = "127.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "127.0.0.2".parse().unwrap();
+        let g1 = limiter._acquire(ip1).unwrap();
+        let _g2 = limiter._acquire(ip1).unwrap();
+        assert_eq!(limiter._acquire(ip1).err(), Some(ConnLimitExceeded::ConnectionsPerIp));
+        assert!(limiter._acquire(ip2).is_ok());
+        assert_eq!(limiter.connections(&ip2), 0);
+        drop(g1);
+        assert_eq!(limiter.connections(&ip1), 1);
+        assert!(limiter._acquire(ip1).is_ok());
+    }
+
+    #[test]
+    fn max_connections() {
+        let counter = ConnCounter::new(ListenerId::Port(1883));
+        let g1 = counter._acquire(2).unwrap();
+        let _g2 = counter._acquire(2).unwrap();
+        assert!(counter._acquire(2).is_none());
+        //The limit is raised at runtime
+        let _g3 = counter._acquire(3).unwrap();
+        assert_eq!(counter.connections(), 3);
+        drop(g1);
+        assert_eq!(counter.connections(), 2);
+        assert!(counter._acquire(1).is_none());
+    }
+
+    #[test]
+    fn conn_rate_per_ip() {
+        let limiter = ConnLimiter::with_limits(0, 1, 0).unwrap();
+        let ip1: IpAddr = "127.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "127.0.0.2".parse().unwrap();
+        assert!(limiter._acquire(ip1).is_ok());
+        assert_eq!(limiter._acquire(ip1).err(), Some(ConnLimitExceeded::ConnRatePerIp));
+        assert!(limiter._acquire(ip2).is_ok());
+    }
+
+    #[test]
+    fn conn_rate_after_per_ip_limits() {
+        let limiter = ConnLimiter::with_limits(2, 0, 1).unwrap();
+        let ip1: IpAddr = "127.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "127.0.0.2".parse().unwrap();
+        let _g1 = limiter._acquire(ip1).unwrap();
+        //The connections over the per IP limit do not drain the conn_rate
+        for _ in 0..5 {
+            assert_eq!(limiter._acquire(ip1).err(), Some(ConnLimitExceeded::ConnectionsPerIp));
+        }
+        let _g2 = limiter._acquire(ip2).unwrap();
+        //The slot of a connection refused by the conn_rate is released
+        let ip3: IpAddr = "127.0.0.3".parse().unwrap();
+        assert_eq!(limiter._acquire(ip3).err(), Some(ConnLimitExceeded::ConnRate));
+        assert_eq!(limiter.connections(&ip3), 0);
+    }
+}
@@ -10,6 +10,7 @@ use bitflags::Flags;
 use itertools::Itertools;
 use ntex_mqtt::types::{MQTT_LEVEL_31, MQTT_LEVEL_311, MQTT_LEVEL_5};
 use once_cell::sync::OnceCell;
+use rand::seq::SliceRandom;
 use tokio::sync::oneshot;
 use tokio::sync::RwLock;
 use tokio::sync::{self, Mutex, OwnedMutexGuard};
@@ -23,13 +24,14 @@ use crate::broker::session::{Session, SessionLike, SessionManager};
 use crate::broker::topic::{Topic, VecToTopic};
 use crate::broker::types::*;
 use crate::settings::acl::AuthInfo;
-use crate::settings::listener::Listener;
+use crate::settings::listener::{Listener, PublishLimit};
+use crate::settings::SharedSubscriptionStrategy;
 use crate::stats::Counter;
 use crate::{grpc, MqttError, Result, Runtime, SessionState};
 
 use super::{
-    retain::RetainTree, topic::TopicTree, AutoSubscription, DelayedSender, Entry, RetainStorage, Router,
-    Shared, SharedSubscription,
+    retain::RetainTree, topic::TopicTree, AutoSubscription, BanManager, DelayedSender, Entry, RetainStorage,
+    Router, Shared, SharedSubscription,
 };
 
 type DashSet<V> = dashmap::DashSet<V, ahash::RandomState>;
@@ -695,9 +697,19 @@ impl DefaultRouter {
         Ok(routes)
     }
 
-    #[allow(clippy::type_complexity)]
     #[inline]
     pub async fn _matches(&self, this_id: Id, topic_name: &TopicName) -> Result<SubRelationsMap> {
+        self._matches_excluded(this_id, topic_name, None).await
+    }
+
+    #[allow(clippy::type_complexity)]
+    #[inline]
+    pub async fn _matches_excluded(
+        &self,
+        this_id: Id,
+        topic_name: &TopicName,
+        excluded: Option<&Id>,
+    ) -> Result<SubRelationsMap> {
         let mut collector_map: SubscriptioRelationsCollectorMap = HashMap::default();
         let topic = Topic::from_str(topic_name)?;
         for (topic_filter, _node_ids) in self.topics.read().await.matches(&topic).iter() {
@@ -723,6 +735,12 @@ impl DefaultRouter {
                         }
                     }
                     if let Some(group) = opts.shared_group() {
+                        if excluded
+                            .map(|ex| ex.node_id == id.node_id && ex.client_id == *client_id)
+                            .unwrap_or_default()
+                        {
+                            continue;
+                        }
                         let router = Runtime::instance().extends.router().await;
                         groups.entry(group.clone()).or_default().push((
                             id.node_id,
@@ -746,8 +764,12 @@ impl DefaultRouter {
             for (group, mut s_subs) in groups.drain() {
                 log::debug!("group: {}, s_subs: {:?}", group, s_subs);
                 let group_cids = s_subs.iter().map(|(_, cid, _, _, _)| cid.clone()).collect();
-                if let Some((idx, is_online)) =
-                    Runtime::instance().extends.shared_subscription().await.choice(&s_subs).await
+                if let Some((idx, is_online)) = Runtime::instance()
+                    .extends
+                    .shared_subscription()
+                    .await
+                    .choice_for(&topic_filter, &group, topic_name, &this_id.client_id, &s_subs)
+                    .await
                 {
                     let (node_id, client_id, opts, _, _) = s_subs.remove(idx);
                     collector_map.entry(node_id).or_default().add(
@@ -974,11 +996,17 @@ impl Router for &'static DefaultRouter {
                 }
             }).unwrap_or(false);
             if remove_enable {
-                let remove_ok = rels.value_mut().remove(&id.client_id).is_some();
+                let removed = rels.value_mut().remove(&id.client_id);
+                let remove_ok = removed.is_some();
                 if remove_ok {
                     self.relations_count.dec();
                 }
-                Some((rels.is_empty(), remove_ok))
+                //(share group, whether it is the last member of the group on this node)
+                let group = removed.and_then(|(_, opts)| opts.shared_group().cloned()).map(|group| {
+                    let is_last = !rels.value().values().any(|(_, opts)| opts.shared_group() == Some(&group));
+                    (group, is_last)
+                });
+                Some((rels.is_empty(), remove_ok, group))
             } else {
                 None
             }
@@ -988,7 +1016,10 @@ impl Router for &'static DefaultRouter {
 
         log::debug!("{:?} remove, topic_filter: {:?}, res: {:?}", id, topic_filter, res);
 
-        let remove_ok = if let Some((is_empty, remove_ok)) = res {
+        let remove_ok = if let Some((is_empty, remove_ok, group)) = res {
+            if let Some((group, is_last)) = group {
+                DefaultSharedSubscription::instance().evict(topic_filter, &group, &id, is_last);
+            }
             if is_empty {
                 if self.relations.remove(topic_filter).is_some() {
                     self.topics_count.dec();
@@ -1008,6 +1039,11 @@ impl Router for &'static DefaultRouter {
         Ok(self._matches(id, topic).await?)
     }
 
+    #[inline]
+    async fn matches_excluded(&self, id: Id, topic: &TopicName, excluded: &Id) -> Result<SubRelationsMap> {
+        Ok(self._matches_excluded(id, topic, Some(excluded)).await?)
+    }
+
     #[inline]
     async fn gets(&self, limit: usize) -> Vec<Route> {
         let mut curr: usize = 0;
@@ -1136,18 +1172,193 @@ impl Router for &'static DefaultRouter {
     }
 }
 
-pub struct DefaultSharedSubscription {}
+type SharedSubscriber =
+    (NodeId, ClientId, SubscriptionOptions, Option<Vec<SubscriptionIdentifier>>, Option<IsOnline>);
+
+pub struct DefaultSharedSubscription {
+    round_robins: DashMap<(TopicFilter, SharedGroup), usize>,
+    stickies: DashMap<(TopicFilter, SharedGroup), (NodeId, ClientId)>,
+}
 
 impl DefaultSharedSubscription {
     #[inline]
     pub fn instance() -> &'static DefaultSharedSubscription {
         static INSTANCE: OnceCell<DefaultSharedSubscription> = OnceCell::new();
-        INSTANCE.get_or_init(|| Self {})
+        INSTANCE.get_or_init(|| Self { round_robins: DashMap::default(), stickies: DashMap::default() })
+    }
+
+    #[inline]
+    async fn is_online(nc: &SharedSubscriber) -> IsOnline {
+        if let Some(is_online) = nc.4 {
+            is_online
+        } else {
+            Runtime::instance().extends.router().await.is_online(nc.0, &nc.1).await
+        }
+    }
+
+    ///Select the first online subscriber in the given order, or the first one if none is online
+    #[inline]
+    async fn first_online(ncs: &[SharedSubscriber], idxs: Vec<usize>) -> Option<(usize, IsOnline)> {
+        for idx in idxs.iter() {
+            if Self::is_online(&ncs[*idx]).await {
+                return Some((*idx, true));
+            }
+        }
+        idxs.first().map(|idx| (*idx, false))
+    }
+
+    #[inline]
+    fn shuffled(ncs: &[SharedSubscriber]) -> Vec<usize> {
+        let mut idxs = (0..ncs.len()).collect::<Vec<_>>();
+        idxs.shuffle(&mut rand::thread_rng());
+        idxs
+    }
+
+    ///Sorted by (node_id, client_id), so that the order does not depend on how the subscribers were collected
+    #[inline]
+    fn sorted(ncs: &[SharedSubscriber]) -> Vec<usize> {
+        let mut idxs = (0..ncs.len()).collect::<Vec<_>>();
+        idxs.sort_by(|a, b| (ncs[*a].0, &ncs[*a].1).cmp(&(ncs[*b].0, &ncs[*b].1)));
+        idxs
+    }
+
+    ///Rendezvous hashing, a subscriber keeps its keys when other subscribers join or leave
+    #[inline]
+    fn hashed(ncs: &[SharedSubscriber], key: &str) -> Vec<usize> {
+        use std::hash::{Hash, Hasher};
+        let weight = |nc: &SharedSubscriber| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            (key, nc.0, &nc.1).hash(&mut hasher);
+            hasher.finish()
+        };
+        let mut idxs = (0..ncs.len()).collect::<Vec<_>>();
+        idxs.sort_by_key(|idx| std::cmp::Reverse(weight(&ncs[*idx])));
+        idxs
+    }
+
+    ///Forgets the state of the share group when its member unsubscribes
+    #[inline]
+    fn evict(&self, topic_filter: &str, group: &SharedGroup, id: &Id, is_last: bool) {
+        let key = (TopicFilter::from(topic_filter), group.clone());
+        if is_last {
+            self.round_robins.remove(&key);
+        }
+        self.stickies
+            .remove_if(&key, |_, (node_id, client_id)| *node_id == id.node_id && *client_id == id.client_id);
+    }
+
+    ///Subscribers on this node sorted by load, others are placed last in random order.
+    ///
+    ///The load of the subscribers on other nodes is unknown, so they are only chosen when no
+    ///subscriber on this node is online.
+    #[inline]
+    async fn least_loaded(ncs: &[SharedSubscriber], inflight: bool) -> Vec<usize> {
+        let node_id = Runtime::instance().node.id();
+        let mut loads = Vec::with_capacity(ncs.len());
+        for idx in Self::shuffled(ncs) {
+            let (nc_node_id, client_id, _, _, _) = &ncs[idx];
+            let session = if *nc_node_id == node_id {
+                Runtime::instance()
+                    .extends
+                    .shared()
+                    .await
+                    .entry(Id::from(node_id, client_id.clone()))
+                    .session()
+            } else {
+                None
+            };
+            let load = match session {
+                Some(s) if inflight => s.inflight_win().read().await.len(),
+                Some(s) => s.deliver_queue().len(),
+                None => usize::MAX,
+            };
+            loads.push((idx, load));
+        }
+        loads.sort_by_key(|(_, load)| *load);
+        loads.into_iter().map(|(idx, _)| idx).collect()
     }
 }
 
 #[async_trait]
-impl SharedSubscription for &'static DefaultSharedSubscription {}
+impl SharedSubscription for &'static DefaultSharedSubscription {
+    #[inline]
+    async fn choice_for(
+        &self,
+        topic_filter: &TopicFilter,
+        group: &SharedGroup,
+        topic: &TopicName,
+        publisher: &ClientId,
+        ncs: &[SharedSubscriber],
+    ) -> Option<(usize, IsOnline)> {
+        if ncs.is_empty() {
+            return None;
+        }
+        let strategy = Runtime::instance().settings.mqtt.shared_subscription_strategy(group);
+        log::debug!("{} {} shared subscription strategy: {:?}", group, topic_filter, strategy);
+        match strategy {
+            SharedSubscriptionStrategy::Random => {
+                DefaultSharedSubscription::first_online(ncs, DefaultSharedSubscription::shuffled(ncs)).await
+            }
+            SharedSubscriptionStrategy::RoundRobin => {
+                let mut idxs = DefaultSharedSubscription::sorted(ncs);
+                let start = {
+                    let mut counter =
+                        self.round_robins.entry((topic_filter.clone(), group.clone())).or_insert(0);
+                    let start = *counter % idxs.len();
+                    *counter = counter.wrapping_add(1);
+                    start
+                };
+                idxs.rotate_left(start);
+                DefaultSharedSubscription::first_online(ncs, idxs).await
+            }
+            SharedSubscriptionStrategy::Sticky => {
+                let key = (topic_filter.clone(), group.clone());
+                let sticky = self.stickies.get(&key).and_then(|sticky| {
+                    ncs.iter().position(|(node_id, client_id, _, _, _)| {
+                        *node_id == sticky.0 && client_id == &sticky.1
+                    })
+                });
+                if let Some(idx) = sticky {
+                    if DefaultSharedSubscription::is_online(&ncs[idx]).await {
+                        return Some((idx, true));
+                    }
+                }
+                let res =
+                    DefaultSharedSubscription::first_online(ncs, DefaultSharedSubscription::shuffled(ncs))
+                        .await;
+                if let Some((idx, true)) = res {
+                    self.stickies.insert(key, (ncs[idx].0, ncs[idx].1.clone()));
+                }
+                res
+            }
+            SharedSubscriptionStrategy::HashClientid => {
+                DefaultSharedSubscription::first_online(
+                    ncs,
+                    DefaultSharedSubscription::hashed(ncs, publisher),
+                )
+                .await
+            }
+            SharedSubscriptionStrategy::HashTopic => {
+                DefaultSharedSubscription::first_online(ncs, DefaultSharedSubscription::hashed(ncs, topic))
+                    .await
+            }
+            SharedSubscriptionStrategy::Local => {
+                let node_id = Runtime::instance().node.id();
+                let mut idxs = DefaultSharedSubscription::shuffled(ncs);
+                idxs.sort_by_key(|idx| ncs[*idx].0 != node_id);
+                DefaultSharedSubscription::first_online(ncs, idxs).await
+            }
+            SharedSubscriptionStrategy::LeastInflight => {
+                let idxs = DefaultSharedSubscription::least_loaded(ncs, true).await;
+                DefaultSharedSubscription::first_online(ncs, idxs).await
+            }
+            SharedSubscriptionStrategy::LeastQueued => {
+                let idxs = DefaultSharedSubscription::least_loaded(ncs, false).await;
+                DefaultSharedSubscription::first_online(ncs, idxs).await
+            }
+        }
+    }
+}
 
 pub struct DefaultRetainStorage {
     pub messages: RwLock<RetainTree<TimedValue<Retain>>>,
@@ -1328,6 +1539,11 @@ impl Fitter for DefaultFitter {
         self.listen_cfg.mqueue_rate_limit
     }
 
+    #[inline]
+    fn publish_limit(&self) -> PublishLimit {
+        self.listen_cfg.publish_limit()
+    }
+
     #[inline]
     fn max_inflight(&self) -> NonZeroU16 {
         let receive_max = if let ConnectInfo::V5(_, connect) = self.conn_info.as_ref() {
@@ -1473,6 +1689,11 @@ impl HookManager for &'static DefaultHookManager {
         self.exec(Type::BeforeStartup, Parameter::BeforeStartup).await;
     }
 
+    #[inline]
+    async fn before_shutdown(&self) {
+        self.exec(Type::BeforeShutdown, Parameter::BeforeShutdown).await;
+    }
+
     #[inline]
     async fn client_connect(&self, connect_info: &ConnectInfo) -> Option<UserProperties> {
         let result = self.exec(Type::ClientConnect, Parameter::ClientConnect(connect_info)).await;
@@ -1488,6 +1709,7 @@ impl HookManager for &'static DefaultHookManager {
     async fn client_authenticate(
         &self,
         connect_info: &ConnectInfo,
+        conn_attrs: &ExtraAttrs,
         allow_anonymous: bool,
     ) -> (ConnectAckReason, Superuser, Option<AuthInfo>) {
         let proto_ver = connect_info.proto_ver();
@@ -1503,7 +1725,9 @@ impl HookManager for &'static DefaultHookManager {
             return (ok(), false, None);
         }
 
-        let result = self.exec(Type::ClientAuthenticate, Parameter::ClientAuthenticate(connect_info)).await;
+        let result = self
+            .exec(Type::ClientAuthenticate, Parameter::ClientAuthenticate(connect_info, conn_attrs))
+            .await;
         log::debug!("{:?} result: {:?}", connect_info.id(), result);
         let (bad_user_or_pass, not_auth) = match result {
             Some(HookResult::AuthResult(AuthResult::BadUsernameOrPassword)) => (true, false),
@@ -1550,6 +1774,24 @@ impl HookManager for &'static DefaultHookManager {
         (ok(), false, None)
     }
 
+    ///extended authenticate, MQTT 5.0 AUTH packet exchange
+    #[inline]
+    async fn client_authenticate_extended(
+        &self,
+        connect_info: &ConnectInfo,
+        auth: &ExtendedAuth,
+    ) -> ExtendedAuthResult {
+        let result = self
+            .exec(Type::ClientAuthenticateExtended, Parameter::ClientAuthenticateExtended(connect_info, auth))
+            .await;
+        log::debug!("{:?} auth method: {:?}, result: {:?}", connect_info.id(), auth.method, result);
+        if let Some(HookResult::ExtendedAuthResult(r)) = result {
+            r
+        } else {
+            ExtendedAuthResult::BadAuthenticationMethod
+        }
+    }
+
     ///When sending mqtt:: connectack message
     async fn client_connack(
         &self,
@@ -1576,6 +1818,11 @@ impl HookManager for &'static DefaultHookManager {
         }
     }
 
+    #[inline]
+    async fn client_flapping(&self, connect_info: &ConnectInfo, ban: &Ban) {
+        let _ = self.exec(Type::ClientFlapping, Parameter::ClientFlapping(connect_info, ban)).await;
+    }
+
     ///Publish message Dropped
     #[inline]
     async fn message_dropped(&self, to: Option<To>, from: From, publish: Publish, reason: Reason) {
@@ -2204,3 +2451,92 @@ impl DefaultAutoSubscription {
 
 #[async_trait]
 impl AutoSubscription for &'static DefaultAutoSubscription {}
+
+pub struct DefaultBanManager {
+    bans: DashMap<BanKey, Ban>,
+}
+
+impl DefaultBanManager {
+    #[inline]
+    pub fn instance() -> &'static DefaultBanManager {
+        static INSTANCE: OnceCell<DefaultBanManager> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { bans: DashMap::default() })
+    }
+
+    #[inline]
+    pub fn remove_expired(&self) -> usize {
+        let len = self.bans.len();
+        self.bans.retain(|_, ban| !ban.is_expired());
+        len - self.bans.len()
+    }
+
+    #[inline]
+    fn _get(&self, key: &BanKey) -> Option<Ban> {
+        let ban = self.bans.get(key).map(|ban| ban.value().clone())?;
+        if ban.is_expired() {
+            self.bans.remove_if(key, |_, ban| ban.is_expired());
+            None
+        } else {
+            Some(ban)
+        }
+    }
+}
+
+#[async_trait]
+impl BanManager for &'static DefaultBanManager {
+    #[inline]
+    async fn add(&self, ban: Ban) -> Result<()> {
+        self.bans.insert(ban.key.clone(), ban);
+        Ok(())
+    }
+
+    #[inline]
+    async fn remove(&self, key: &BanKey) -> Result<Option<Ban>> {
+        Ok(self.bans.remove(key).map(|(_, ban)| ban))
+    }
+
+    #[inline]
+    async fn get(&self, key: &BanKey) -> Result<Option<Ban>> {
+        Ok(self._get(key))
+    }
+
+    #[inline]
+    async fn list(&self) -> Result<Vec<Ban>> {
+        self.remove_expired();
+        Ok(self.bans.iter().map(|ban| ban.value().clone()).collect())
+    }
+
+    #[inline]
+    async fn check(&self, id: &Id) -> Option<Ban> {
+        if self.bans.is_empty() {
+            return None;
+        }
+        if let Some(ban) = self._get(&BanKey::ClientId(id.client_id.clone())) {
+            return Some(ban);
+        }
+        if let Some(username) = id.username.as_ref() {
+            if let Some(ban) = self._get(&BanKey::Username(username.clone())) {
+                return Some(ban);
+            }
+        }
+        if let Some(remote_addr) = id.remote_addr {
+            let ip = remote_addr.ip();
+            if let Some(ban) = self._get(&BanKey::Ip(ip)) {
+                return Some(ban);
+            }
+            return self
+                .bans
+                .iter()
+                .find(|ban| {
+                    matches!(ban.key(), BanKey::Cidr(cidr) if cidr.contains(&ip)) && !ban.is_expired()
+                })
+                .map(|ban| ban.value().clone());
+        }
+        None
+    }
+
+    #[inline]
+    async fn count(&self) -> usize {
+        self.bans.len()
+    }
+}
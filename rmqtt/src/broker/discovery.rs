@@ -0,0 +1,238 @@
+//! Discovery of the cluster nodes from the DNS records of a headless service(A/AAAA or SRV)
+//! or from a file, the node id is derived from the ordinal of the hostname, such as rmqtt-2 => 3
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use hickory_resolver::TokioAsyncResolver;
+
+use crate::settings::{deserialize_duration, NodeAddr};
+use crate::{Addr, MqttError, NodeId, Result};
+
+const STARTUP_ATTEMPTS: usize = 5;
+const STARTUP_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryType {
+    ///A/AAAA records, the node id is derived from the hostname of the PTR record
+    Dns,
+    ///SRV records, the node id is derived from the target hostname
+    Srv,
+    ///One node per line, "host" or "id@host"
+    File,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Discovery {
+    #[serde(rename = "type")]
+    pub typ: DiscoveryType,
+    ///DNS name of the dns and srv type
+    #[serde(default)]
+    pub name: String,
+    ///Path of the file type
+    #[serde(default)]
+    pub file: String,
+    #[serde(default = "Discovery::grpc_port_default")]
+    pub grpc_port: u16,
+    #[serde(default = "Discovery::raft_port_default")]
+    pub raft_port: u16,
+    #[serde(default = "Discovery::refresh_interval_default", deserialize_with = "deserialize_duration")]
+    pub refresh_interval: Duration,
+}
+
+impl Discovery {
+    fn grpc_port_default() -> u16 {
+        5363
+    }
+
+    fn raft_port_default() -> u16 {
+        6003
+    }
+
+    fn refresh_interval_default() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    ///Returns the discovered nodes, (node id, host)
+    pub async fn discover(&self) -> Result<Vec<(NodeId, String)>> {
+        let mut nodes = match self.typ {
+            DiscoveryType::Dns => self.discover_dns().await?,
+            DiscoveryType::Srv => self.discover_srv().await?,
+            DiscoveryType::File => self.discover_file().await?,
+        };
+        nodes.sort();
+        nodes.dedup_by_key(|(id, _)| *id);
+        log::debug!("discovered nodes: {:?}", nodes);
+        Ok(nodes)
+    }
+
+    ///Discovers the nodes at startup, the failures are retried a few times. If it still fails, the
+    ///configured nodes are used and the others are added by the periodic discovery
+    pub async fn discover_at_startup(&self) -> Vec<(NodeId, String)> {
+        for attempt in 1..=STARTUP_ATTEMPTS {
+            match self.discover().await {
+                Ok(nodes) => return nodes,
+                Err(e) => {
+                    log::warn!("discover the cluster nodes error({}/{}), {}", attempt, STARTUP_ATTEMPTS, e)
+                }
+            }
+            if attempt < STARTUP_ATTEMPTS {
+                tokio::time::sleep(STARTUP_RETRY_INTERVAL).await;
+            }
+        }
+        log::warn!("the cluster nodes are not discovered, start with the configured nodes");
+        Vec::new()
+    }
+
+    async fn discover_dns(&self) -> Result<Vec<(NodeId, String)>> {
+        let resolver = resolver()?;
+        let ips = resolver.lookup_ip(self.name.as_str()).await.map_err(|e| dns_error(&self.name, e))?;
+        let mut nodes = Vec::new();
+        for ip in ips.iter() {
+            let hostname = match resolver.reverse_lookup(ip).await {
+                Ok(names) => names.iter().next().map(|name| name.to_string()),
+                Err(e) => {
+                    log::warn!("reverse lookup of {} error, {}", ip, e);
+                    None
+                }
+            };
+            match hostname.as_deref().and_then(ordinal_id) {
+                Some(id) => nodes.push((id, host_of(ip))),
+                None => log::warn!("the node id of {} can not be derived, hostname: {:?}", ip, hostname),
+            }
+        }
+        Ok(nodes)
+    }
+
+    async fn discover_srv(&self) -> Result<Vec<(NodeId, String)>> {
+        let srvs = resolver()?.srv_lookup(self.name.as_str()).await.map_err(|e| dns_error(&self.name, e))?;
+        let mut nodes = Vec::new();
+        for srv in srvs.iter() {
+            let target = srv.target().to_string();
+            let host = target.trim_end_matches('.');
+            match ordinal_id(host) {
+                Some(id) => nodes.push((id, host.to_string())),
+                None => log::warn!("the node id of {} can not be derived", host),
+            }
+        }
+        Ok(nodes)
+    }
+
+    async fn discover_file(&self) -> Result<Vec<(NodeId, String)>> {
+        let content = tokio::fs::read_to_string(&self.file)
+            .await
+            .map_err(|e| MqttError::from(format!("read the discovery file {:?} error, {}", self.file, e)))?;
+        parse_nodes(&content)
+    }
+
+    #[inline]
+    pub fn grpc_addrs(&self, nodes: &[(NodeId, String)]) -> Vec<NodeAddr> {
+        node_addrs(nodes, self.grpc_port)
+    }
+
+    #[inline]
+    pub fn raft_addrs(&self, nodes: &[(NodeId, String)]) -> Vec<NodeAddr> {
+        node_addrs(nodes, self.raft_port)
+    }
+}
+
+///Merges the discovered addresses, the configured addresses take precedence
+pub fn merge_node_addrs(addrs: &mut Vec<NodeAddr>, discovered: Vec<NodeAddr>) {
+    for node_addr in discovered {
+        if !addrs.iter().any(|a| a.id == node_addr.id) {
+            addrs.push(node_addr);
+        }
+    }
+}
+
+///Node id derived from the ordinal of the hostname, the first label ends with "-<ordinal>",
+///such as rmqtt-0.rmqtt-headless.default.svc.cluster.local => 1
+pub fn ordinal_id(hostname: &str) -> Option<NodeId> {
+    let label = hostname.split('.').next()?;
+    let (_, ordinal) = label.rsplit_once('-')?;
+    ordinal.parse::<NodeId>().ok().and_then(|o| o.checked_add(1))
+}
+
+///Node id of this node, derived from its hostname
+pub fn hostname_id() -> Result<NodeId> {
+    let hostname = gethostname::gethostname();
+    let hostname = hostname.to_string_lossy();
+    ordinal_id(&hostname).ok_or_else(|| {
+        MqttError::from(format!("the node id can not be derived from the hostname {:?}", hostname))
+    })
+}
+
+fn parse_nodes(content: &str) -> Result<Vec<(NodeId, String)>> {
+    let mut nodes = Vec::new();
+    for line in content.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let node = match line.split_once('@') {
+            Some((id, host)) => (
+                id.parse::<NodeId>().map_err(|e| MqttError::from(format!("{:?}, {}", line, e)))?,
+                host.to_string(),
+            ),
+            None => (
+                ordinal_id(line).ok_or_else(|| {
+                    MqttError::from(format!("the node id of {:?} can not be derived", line))
+                })?,
+                line.to_string(),
+            ),
+        };
+        nodes.push(node);
+    }
+    Ok(nodes)
+}
+
+#[inline]
+fn node_addrs(nodes: &[(NodeId, String)], port: u16) -> Vec<NodeAddr> {
+    nodes
+        .iter()
+        .map(|(id, host)| NodeAddr { id: *id, addr: Addr::from(format!("{}:{}", host, port)) })
+        .collect()
+}
+
+#[inline]
+fn host_of(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    }
+}
+
+#[inline]
+fn resolver() -> Result<TokioAsyncResolver> {
+    TokioAsyncResolver::tokio_from_system_conf()
+        .map_err(|e| MqttError::from(format!("DNS resolver error, {}", e)))
+}
+
+#[inline]
+fn dns_error<E: std::fmt::Display>(name: &str, e: E) -> MqttError {
+    MqttError::from(format!("DNS lookup of {:?} error, {}", name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordinal() {
+        assert_eq!(ordinal_id("rmqtt-0"), Some(1));
+        assert_eq!(ordinal_id("rmqtt-2.rmqtt-headless.default.svc.cluster.local."), Some(3));
+        assert_eq!(ordinal_id("my-rmqtt-10.local"), Some(11));
+        assert_eq!(ordinal_id("rmqtt"), None);
+        assert_eq!(ordinal_id("rmqtt-a"), None);
+    }
+
+    #[test]
+    fn nodes_file() {
+        let nodes = parse_nodes("# nodes\n rmqtt-0.svc \n\n5@10.0.0.5\n").unwrap();
+        assert_eq!(nodes, vec![(1, "rmqtt-0.svc".to_string()), (5, "10.0.0.5".to_string())]);
+        assert!(parse_nodes("rmqtt").is_err());
+
+        let mut addrs = vec![NodeAddr { id: 1, addr: Addr::from("127.0.0.1:5363") }];
+        merge_node_addrs(&mut addrs, node_addrs(&nodes, 5363));
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[0].addr, "127.0.0.1:5363");
+        assert_eq!(addrs[1].addr, "10.0.0.5:5363");
+    }
+}
@@ -1,5 +1,7 @@
 use itertools::Itertools;
+use std::cell::Cell;
 use std::ops::Deref;
+use std::rc::Rc;
 use std::thread::ThreadId;
 use std::time::Duration;
 use std::time::Instant;
@@ -18,42 +20,64 @@ use crate::Runtime;
 pub type Port = u16;
 
 std::thread_local! {
-    pub static HANDSHAKE_EXECUTORS: DashMap<Port, LocalTaskExecQueue> = DashMap::default();
+    pub static HANDSHAKE_EXECUTORS: DashMap<Port, HandshakeExec> = DashMap::default();
 }
 
+///Handshake executor of the listener, with the limits it is built with
+pub struct HandshakeExec {
+    exec: LocalTaskExecQueue,
+    //max_handshaking_limit and max_connections of the listener
+    limits: (usize, usize),
+    //The statistics of the replaced executor are no longer updated
+    replaced: Rc<Cell<bool>>,
+}
+
+impl HandshakeExec {
+    #[inline]
+    fn new(name: Port, listen_cfg: &Listener) -> Self {
+        let (exec, task_runner) = LocalBuilder::default()
+            .workers(listen_cfg.max_handshaking_limit / listen_cfg.workers)
+            .queue_max(listen_cfg.max_connections / listen_cfg.workers)
+            .build();
+
+        let busy_limit = if Runtime::instance().settings.node.busy.handshaking == 0 {
+            (listen_cfg.max_handshaking_limit as f64 * 0.35) as usize
+        } else {
+            Runtime::instance().settings.node.busy.handshaking
+        };
+
+        set_active_count(name, exec.active_count(), Some(busy_limit));
+        let replaced = Rc::new(Cell::new(false));
+        let exec1 = exec.clone();
+        let replaced1 = replaced.clone();
+        spawn_local(async move {
+            futures::future::join(task_runner, async move {
+                while !replaced1.get() {
+                    set_active_count(name, exec1.active_count(), None);
+                    set_rate(name, exec1.rate().await);
+                    tokio::time::sleep(Duration::from_secs(3)).await;
+                }
+            })
+            .await;
+        });
+
+        Self { exec, limits: (listen_cfg.max_handshaking_limit, listen_cfg.max_connections), replaced }
+    }
+}
+
+///The executor is rebuilt if max_handshaking_limit or max_connections of the listener is updated,
+///the handshakes already queued are executed by the replaced one
 #[inline]
 pub(crate) fn get_handshake_exec(name: Port, listen_cfg: Listener) -> LocalTaskExecQueue {
     HANDSHAKE_EXECUTORS.with(|m| {
-        m.entry(name)
-            .or_insert_with(|| {
-                let (exec, task_runner) = LocalBuilder::default()
-                    .workers(listen_cfg.max_handshaking_limit / listen_cfg.workers)
-                    .queue_max(listen_cfg.max_connections / listen_cfg.workers)
-                    .build();
-
-                let busy_limit = if Runtime::instance().settings.node.busy.handshaking == 0 {
-                    (listen_cfg.max_handshaking_limit as f64 * 0.35) as usize
-                } else {
-                    Runtime::instance().settings.node.busy.handshaking
-                };
-
-                set_active_count(name, exec.active_count(), Some(busy_limit));
-                let exec1 = exec.clone();
-                spawn_local(async move {
-                    futures::future::join(task_runner, async move {
-                        loop {
-                            set_active_count(name, exec1.active_count(), None);
-                            set_rate(name, exec1.rate().await);
-                            tokio::time::sleep(Duration::from_secs(3)).await;
-                        }
-                    })
-                    .await;
-                });
-
-                exec
-            })
-            .value()
-            .clone()
+        let limits = (listen_cfg.max_handshaking_limit, listen_cfg.max_connections);
+        let mut entry = m.entry(name).or_insert_with(|| HandshakeExec::new(name, &listen_cfg));
+        if entry.limits != limits {
+            log::info!("the handshake executor of port {} is rebuilt, limits: {:?}", name, limits);
+            entry.replaced.set(true);
+            *entry = HandshakeExec::new(name, &listen_cfg);
+        }
+        entry.exec.clone()
     })
 }
 
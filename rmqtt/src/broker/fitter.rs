@@ -3,7 +3,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use crate::broker::types::*;
-use crate::settings::listener::Listener;
+use crate::settings::listener::{Listener, PublishLimit};
 use crate::Result;
 
 pub trait FitterManager: Sync + Send {
@@ -23,6 +23,9 @@ pub trait Fitter: Sync + Send {
     /// default value: 100 / 10s
     fn mqueue_rate_limit(&self) -> (NonZeroU32, Duration);
 
+    ///Inbound publish limits, messages/second and bytes/second, AuthInfo::publish_limit overrides it
+    fn publish_limit(&self) -> PublishLimit;
+
     ///max inflight
     fn max_inflight(&self) -> std::num::NonZeroU16;
 
@@ -0,0 +1,125 @@
+//! Flapping detection, a client id that connects too frequently is banned for a while
+
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::ban;
+use crate::broker::types::*;
+use crate::settings::FlappingDetect;
+use crate::{Result, Runtime};
+
+type DashMap<K, V> = dashmap::DashMap<K, V, ahash::RandomState>;
+
+pub struct FlappingDetector {
+    //client id => (window start time, connects in the window)
+    clients: DashMap<ClientId, (TimestampMillis, usize)>,
+    last_cleanup: AtomicI64,
+}
+
+impl FlappingDetector {
+    #[inline]
+    pub fn instance() -> &'static FlappingDetector {
+        static INSTANCE: OnceCell<FlappingDetector> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { clients: DashMap::default(), last_cleanup: AtomicI64::new(0) })
+    }
+
+    ///Count a connect of the client, returns the number of connects in the window if it is flapping
+    #[inline]
+    pub fn detect(&self, client_id: &ClientId, cfg: &FlappingDetect) -> Option<usize> {
+        let now = timestamp_millis();
+        let window = cfg.window_time.as_millis() as TimestampMillis;
+        self.cleanup(now, window);
+        let count = {
+            let mut entry = self.clients.entry(client_id.clone()).or_insert((now, 0));
+            let (start, count) = entry.value_mut();
+            if now - *start > window {
+                *start = now;
+                *count = 0;
+            }
+            *count += 1;
+            *count
+        };
+        if count > cfg.max_count {
+            self.clients.remove(client_id);
+            Some(count)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn cleanup(&self, now: TimestampMillis, window: TimestampMillis) {
+        let last = self.last_cleanup.load(Ordering::Relaxed);
+        if now - last > window
+            && self.last_cleanup.compare_exchange(last, now, Ordering::SeqCst, Ordering::Relaxed).is_ok()
+        {
+            self.clients.retain(|_, (start, _)| now - *start <= window);
+        }
+    }
+
+    ///Number of clients being tracked
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.clients.len()
+    }
+
+    ///Called at handshake after the client is authenticated, the flapping client is banned on all nodes
+    ///and the client_flapping hook is triggered, returns the ban entry if it is flapping
+    #[inline]
+    pub(crate) async fn check(connect_info: &ConnectInfo) -> Result<Option<Ban>> {
+        let cfg = &Runtime::instance().settings.mqtt.flapping_detect;
+        if !cfg.enable {
+            return Ok(None);
+        }
+        let id = connect_info.id();
+        if let Some(count) = Self::instance().detect(&id.client_id, cfg) {
+            log::warn!(
+                "{:?} flapping detected, {} connects within {:?}, banned for {:?}",
+                id,
+                count,
+                cfg.window_time,
+                cfg.ban_time
+            );
+            let ban = Ban::new(
+                BanKey::ClientId(id.client_id.clone()),
+                Some(format!("flapping, {} connects within {:?}", count, cfg.window_time)),
+                Some("flapping_detect".into()),
+                Some(timestamp_secs() + cfg.ban_time.as_secs() as Timestamp),
+            );
+            ban::add(ban.clone()).await?;
+            Runtime::instance().metrics.client_flapping_inc();
+            //hook, client flapping
+            Runtime::instance().extends.hook_mgr().await.client_flapping(connect_info, &ban).await;
+            Ok(Some(ban))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn detect() {
+        let cfg = FlappingDetect {
+            enable: true,
+            max_count: 3,
+            window_time: Duration::from_secs(60),
+            ban_time: Duration::from_secs(60),
+        };
+        let detector = FlappingDetector { clients: DashMap::default(), last_cleanup: AtomicI64::new(0) };
+        let client_id = ClientId::from("c1");
+        assert_eq!(detector.detect(&client_id, &cfg), None);
+        assert_eq!(detector.detect(&client_id, &cfg), None);
+        assert_eq!(detector.detect(&client_id, &cfg), None);
+        assert_eq!(detector.detect(&client_id, &cfg), Some(4));
+        assert_eq!(detector.count(), 0);
+        assert_eq!(detector.detect(&ClientId::from("c2"), &cfg), None);
+        assert_eq!(detector.count(), 1);
+    }
+}
@@ -0,0 +1,502 @@
+//! Sessions of the gateway protocols, such as MQTT-SN. The gateway converts the packets of its
+//! clients to MQTT 3.1.1 packets, the sessions go through the same handshake, hooks, auth and ACL
+//! as the MQTT clients. The name of the gateway protocol is kept in the connection attributes
+//! under [`GATEWAY_KEY`]
+
+use std::net::SocketAddr;
+use std::num::NonZeroU16;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use ntex_mqtt::error::SendPacketError;
+use once_cell::sync::OnceCell;
+use rust_box::task_exec_queue::LocalSpawnExt;
+use uuid::Uuid;
+
+use crate::broker::executor::{get_handshake_exec, is_too_many_unavailable, unavailable_stats};
+use crate::broker::inflight::MomentStatus;
+use crate::broker::queue::PublishLimiter;
+use crate::broker::types::*;
+use crate::broker::v3::connect_session;
+use crate::runtime::Runtime;
+use crate::settings::acl::AuthInfo;
+use crate::settings::listener::{Listener, PublishLimit, PublishLimitAction};
+use crate::{MqttError, Result, Session, SessionState};
+
+pub const GATEWAY_KEY: &str = "gateway";
+
+///Packets sent to the client of the gateway
+#[derive(Debug)]
+pub enum GatewayPacket {
+    Packet(PacketV3),
+    ///The session is closed, the gateway disconnects the client
+    Close,
+}
+
+#[derive(Clone, Debug)]
+pub struct GatewaySink {
+    tx: mpsc::UnboundedSender<GatewayPacket>,
+}
+
+impl GatewaySink {
+    #[inline]
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<GatewayPacket>) {
+        let (tx, rx) = mpsc::unbounded();
+        (Self { tx }, rx)
+    }
+
+    #[inline]
+    pub(crate) fn send(&self, p: PacketV3) -> Result<()> {
+        self.tx
+            .unbounded_send(GatewayPacket::Packet(p))
+            .map_err(|_| MqttError::from(SendPacketError::Disconnected))
+    }
+
+    #[inline]
+    pub(crate) fn close(&self) {
+        let _ = self.tx.unbounded_send(GatewayPacket::Close);
+    }
+}
+
+///Creates the session of the gateway client, the same as the handshake of the MQTT 3.1.1 clients.
+///Returns the session and whether the session is present, or the reason of the refusal
+#[inline]
+pub async fn connect(
+    listen_cfg: Listener,
+    mut connect: ConnectV3,
+    remote_addr: SocketAddr,
+    local_addr: SocketAddr,
+    conn_attrs: ExtraAttrs,
+    sink: GatewaySink,
+) -> Result<std::result::Result<(SessionState, bool), ConnectAckReasonV3>> {
+    log::debug!(
+        "new gateway Connection: local_addr: {:?}, remote: {:?}, {:?}, listen_cfg: {:?}",
+        local_addr,
+        remote_addr,
+        connect,
+        listen_cfg,
+    );
+
+    if is_too_many_unavailable().await {
+        log::warn!(
+            "{:?} Connection Refused, gateway handshake fail, reason: too busy, fails rate: {}",
+            remote_addr,
+            unavailable_stats().rate()
+        );
+        return Ok(Err(ConnectAckReasonV3::ServiceUnavailable));
+    }
+
+    if Runtime::instance().node.is_draining() {
+        log::info!(
+            "Connection Refused, gateway handshake fail, reason: the node is draining, remote: {:?}",
+            remote_addr
+        );
+        return Ok(Err(ConnectAckReasonV3::ServiceUnavailable));
+    }
+
+    if connect.client_id.is_empty() {
+        if connect.clean_session {
+            connect.client_id =
+                ClientId::from(Uuid::new_v4().as_simple().encode_lower(&mut Uuid::encode_buffer()).to_owned())
+        } else {
+            log::info!(
+                "{:?} Connection Refused, gateway handshake error, reason: invalid client id",
+                remote_addr
+            );
+            return Ok(Err(ConnectAckReasonV3::IdentifierRejected));
+        }
+    }
+
+    let id = Id::new(
+        Runtime::instance().node.id(),
+        Some(local_addr),
+        Some(remote_addr),
+        connect.client_id.clone(),
+        connect.username.clone(),
+    );
+
+    let exec = get_handshake_exec(local_addr.port(), listen_cfg.clone());
+    let session = {
+        let id = id.clone();
+        async move { connect_session(id, listen_cfg, &mut connect, conn_attrs, Sink::Gateway(sink)).await }
+    };
+    match session.spawn(&exec).result().await {
+        Ok(Ok(res)) => Ok(res.map(|(state, session_present, _)| (state, session_present))),
+        Ok(Err(e)) => {
+            unavailable_stats().inc();
+            log::warn!(
+                "{:?} Connection Refused, gateway handshake error, reason: {:?}, fails rate: {}",
+                id,
+                e.to_string(),
+                unavailable_stats().rate()
+            );
+            Err(e)
+        }
+        Err(e) => {
+            Runtime::instance().metrics.client_handshaking_timeout_inc();
+            unavailable_stats().inc();
+            let err = MqttError::from("Connection Refused, execute gateway handshake timeout");
+            log::warn!("{:?} {:?}, reason: {:?}", id, err, e.to_string(),);
+            Err(err)
+        }
+    }
+}
+
+///Publish of the gateway client
+#[inline]
+pub async fn publish(state: &SessionState, publish: Publish) -> Result<bool> {
+    let _ = state.send(Message::Keepalive(false));
+    state.publish_gateway(publish).await
+}
+
+///Result of the publish without a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionlessPublish {
+    Published,
+    ///Authentication failed
+    NotAuthorized,
+    ///Refused by the ACL
+    Refused,
+    ///Dropped by the publish limit
+    RateLimited,
+    ///The node is draining
+    Unavailable,
+}
+
+///Authentication of the clients without a session, None if the authentication fails
+type ConnectionlessAuth = Option<(Superuser, Option<AuthInfo>)>;
+
+///Caches the authentication of the clients without a session by their addresses, so that the
+///datagrams of a client are not authenticated one by one. It can only be used if the client is
+///identified by its address, such as the QoS -1 publish of MQTT-SN which has no credentials
+pub struct AuthCache {
+    entries: DashMap<SocketAddr, (Instant, ConnectionlessAuth)>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl AuthCache {
+    #[inline]
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self { entries: DashMap::default(), ttl, capacity }
+    }
+
+    ///Whether the authentication of the address is cached and not expired
+    #[inline]
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.get(addr).is_some()
+    }
+
+    #[inline]
+    fn get(&self, addr: &SocketAddr) -> Option<ConnectionlessAuth> {
+        self.entries.get(addr).filter(|entry| entry.0.elapsed() < self.ttl).map(|entry| entry.1.clone())
+    }
+
+    ///The authentication is not cached if the cache is full of the unexpired entries
+    #[inline]
+    fn insert(&self, addr: SocketAddr, auth: ConnectionlessAuth) {
+        if self.entries.len() >= self.capacity {
+            self.entries.retain(|_, (at, _)| at.elapsed() < self.ttl);
+        }
+        if self.entries.len() < self.capacity {
+            self.entries.insert(addr, (Instant::now(), auth));
+        }
+    }
+}
+
+///Publish without a session, such as the QoS -1 publish of MQTT-SN or the POST of CoAP. The client
+///is authenticated with the client id, username and password of the connect, and checked by the ACL.
+///The authentication is looked up in `auth_cache` first, if it is given
+#[inline]
+pub async fn publish_connectionless(
+    listen_cfg: Listener,
+    connect: ConnectV3,
+    remote_addr: SocketAddr,
+    local_addr: SocketAddr,
+    conn_attrs: ExtraAttrs,
+    publish: Publish,
+    auth_cache: Option<&AuthCache>,
+) -> Result<ConnectionlessPublish> {
+    if Runtime::instance().node.is_draining() {
+        log::debug!("{:?} connectionless publish refused, the node is draining", remote_addr);
+        return Ok(ConnectionlessPublish::Unavailable);
+    }
+
+    let id = Id::new(
+        Runtime::instance().node.id(),
+        Some(local_addr),
+        Some(remote_addr),
+        connect.client_id.clone(),
+        connect.username.clone(),
+    );
+    let connect_info = Arc::new(ConnectInfo::V3(id.clone(), connect));
+    let auth = match auth_cache.and_then(|cache| cache.get(&remote_addr)) {
+        Some(auth) => auth,
+        None => {
+            let (ack, superuser, auth_info) = Runtime::instance()
+                .extends
+                .hook_mgr()
+                .await
+                .client_authenticate(&connect_info, &conn_attrs, listen_cfg.allow_anonymous)
+                .await;
+            let auth = if ack.success() {
+                Some((superuser, auth_info))
+            } else {
+                log::debug!("{:?} connectionless publish refused, {:?}", id, ack);
+                None
+            };
+            if let Some(cache) = auth_cache {
+                cache.insert(remote_addr, auth.clone());
+            }
+            auth
+        }
+    };
+    let (superuser, auth_info) = match auth {
+        Some(auth) => auth,
+        None => return Ok(ConnectionlessPublish::NotAuthorized),
+    };
+    //Reject the banned client
+    if let Some(ban) = Runtime::instance().extends.ban_mgr().await.check(&id).await {
+        log::debug!(
+            "{:?} connectionless publish refused, banned, {}: {}, reason: {:?}",
+            id,
+            ban.key.kind(),
+            ban.key.who(),
+            ban.reason
+        );
+        return Ok(ConnectionlessPublish::NotAuthorized);
+    }
+    if let Err(e) = Session::mountpoint(&listen_cfg, auth_info.as_ref(), &connect_info) {
+        log::debug!("{:?} connectionless publish refused, {}", id, e);
+        return Ok(ConnectionlessPublish::NotAuthorized);
+    }
+
+    //The session is not registered, it is only used by the hooks
+    let fitter = Runtime::instance().extends.fitter_mgr().await.create(
+        connect_info.clone(),
+        id.clone(),
+        listen_cfg.clone(),
+    );
+    let now = timestamp_millis();
+    let session = Session::new(
+        id,
+        0,
+        listen_cfg.clone(),
+        fitter,
+        auth_info,
+        NonZeroU16::new(1).unwrap(),
+        now,
+        connect_info,
+        false,
+        superuser,
+        false,
+        now,
+        SessionSubs::new(),
+        None,
+        None,
+    )
+    .await?;
+    session.extra_attrs.write().await.extend(conn_attrs);
+    let hook = Runtime::instance().extends.hook_mgr().await.hook(&session);
+    let from = From::from_custom(session.id.clone());
+
+    //publish rate limit, there is no connection to disconnect or pause, so the message is dropped
+    //unless the action is throttle
+    let publish_limit = session
+        .auth_info
+        .as_ref()
+        .and_then(|auth_info| auth_info.publish_limit)
+        .unwrap_or_else(|| session.fitter.publish_limit());
+    if let Some(limiter) =
+        ConnectionlessLimiters::instance().get(remote_addr, &session.id.client_id, publish_limit)
+    {
+        let size = publish.topic.len() + publish.payload.len();
+        if matches!(listen_cfg.publish_limit_action, PublishLimitAction::Throttle) {
+            limiter.until_ready(size).await;
+        } else if let Err(exceeded) = limiter.check(size) {
+            log::debug!("{:?} connectionless publish limit exceeded, {:?}", session.id, exceeded);
+            //hook, Message dropped
+            Runtime::instance()
+                .extends
+                .hook_mgr()
+                .await
+                .message_dropped(None, from, publish, Reason::PublishRateLimited)
+                .await;
+            return Ok(ConnectionlessPublish::RateLimited);
+        }
+    }
+
+    //hook, message_publish
+    let mut publish = hook.message_publish(from.clone(), &publish).await.unwrap_or(publish);
+
+    //hook, message_publish_check_acl
+    if let PublishAclResult::Rejected(_) = hook.message_publish_check_acl(&publish).await {
+        Runtime::instance().metrics.client_publish_auth_error_inc();
+        Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .message_dropped(None, from, publish, Reason::PublishRefused)
+            .await;
+        return Ok(ConnectionlessPublish::Refused);
+    }
+
+    publish.topic = session.mount(publish.topic);
+
+    let message_storage_available = Runtime::instance().extends.message_mgr().await.enable();
+    let message_expiry_interval =
+        if message_storage_available || (listen_cfg.retain_available && publish.retain()) {
+            Some(session.fitter.message_expiry_interval(&publish))
+        } else {
+            None
+        };
+    SessionState::forwards(
+        from,
+        publish,
+        listen_cfg.retain_available,
+        message_storage_available,
+        message_expiry_interval,
+    )
+    .await?;
+    Ok(ConnectionlessPublish::Published)
+}
+
+///Publish limiters of the clients without a session, by their addresses and client ids. A limiter
+///that is not used for a second has refilled its burst and is the same as a new one, so it is removed
+struct ConnectionlessLimiters {
+    entries: DashMap<(SocketAddr, ClientId), (Instant, PublishLimit, Arc<PublishLimiter>)>,
+    pruned_at: AtomicI64,
+}
+
+impl ConnectionlessLimiters {
+    const IDLE: Duration = Duration::from_secs(1);
+
+    #[inline]
+    fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<ConnectionlessLimiters> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { entries: DashMap::default(), pruned_at: AtomicI64::new(0) })
+    }
+
+    ///Returns None if the client has no publish limit
+    #[inline]
+    fn get(
+        &self,
+        addr: SocketAddr,
+        client_id: &ClientId,
+        limit: PublishLimit,
+    ) -> Option<Arc<PublishLimiter>> {
+        let now = timestamp_millis();
+        let pruned_at = self.pruned_at.load(Ordering::Relaxed);
+        if now - pruned_at >= Self::IDLE.as_millis() as i64
+            && self.pruned_at.compare_exchange(pruned_at, now, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+        {
+            self.entries.retain(|_, (used_at, _, _)| used_at.elapsed() < Self::IDLE);
+        }
+
+        let key = (addr, client_id.clone());
+        if let Some(mut entry) = self.entries.get_mut(&key) {
+            if entry.1 == limit {
+                entry.0 = Instant::now();
+                return Some(entry.2.clone());
+            }
+        }
+        match PublishLimiter::new(limit.messages_rate, limit.bytes_rate) {
+            Some(limiter) => {
+                let limiter = Arc::new(limiter);
+                self.entries.insert(key, (Instant::now(), limit, limiter.clone()));
+                Some(limiter)
+            }
+            None => {
+                self.entries.remove(&key);
+                None
+            }
+        }
+    }
+}
+
+///PUBACK or PUBCOMP of the message delivered to the gateway client
+#[inline]
+pub async fn acked(state: &SessionState, packet_id: NonZeroU16) {
+    let _ = state.send(Message::Keepalive(false));
+    if let Some(iflt_msg) = state.inflight_win().write().await.remove(&packet_id.get()) {
+        //hook, message_ack
+        state.hook.message_acked(iflt_msg.from, &iflt_msg.publish).await;
+    }
+}
+
+///PUBREC of the message delivered to the gateway client, the gateway sends the PUBREL
+#[inline]
+pub async fn received(state: &SessionState, packet_id: NonZeroU16) {
+    let _ = state.send(Message::Keepalive(false));
+    state.inflight_win().write().await.update_status(&packet_id.get(), MomentStatus::UnComplete);
+}
+
+///Removes the message from the inflight window, it can not be delivered to the gateway client
+#[inline]
+pub async fn rejected(state: &SessionState, packet_id: NonZeroU16, reason: Reason) {
+    if let Some(iflt_msg) = state.inflight_win().write().await.remove(&packet_id.get()) {
+        Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .message_dropped(Some(state.id.clone()), iflt_msg.from, iflt_msg.publish, reason)
+            .await;
+    }
+}
+
+///Returns the granted QoS, None if the subscription is refused
+#[inline]
+pub async fn subscribe(state: &SessionState, topic_filter: &TopicFilter, qos: QoS) -> Result<Option<QoS>> {
+    let _ = state.send(Message::Keepalive(false));
+    let listen_cfg = state.listen_cfg();
+    let shared_subscription =
+        Runtime::instance().extends.shared_subscription().await.is_supported(listen_cfg);
+    let sub = Subscribe::from_v3(topic_filter, qos, shared_subscription, listen_cfg.limit_subscription)?;
+    match state.subscribe(sub).await {
+        Ok(ret) => Ok(ret.success()),
+        Err(e) => {
+            log::warn!("{:?} Subscribe failed, reason: {}", state.id, e);
+            state.disconnected_reason_add(Reason::SubscribeFailed(Some(e.to_string().into()))).await?;
+            Err(e)
+        }
+    }
+}
+
+#[inline]
+pub async fn unsubscribe(state: &SessionState, topic_filter: &TopicFilter) -> Result<()> {
+    let _ = state.send(Message::Keepalive(false));
+    let listen_cfg = state.listen_cfg();
+    let shared_subscription =
+        Runtime::instance().extends.shared_subscription().await.is_supported(listen_cfg);
+    let unsub = Unsubscribe::from(topic_filter, shared_subscription, listen_cfg.limit_subscription)?;
+    if let Err(e) = state.unsubscribe(unsub).await {
+        log::warn!("{:?} Unsubscribe failed, reason: {}", state.id, e);
+        state.disconnected_reason_add(Reason::UnsubscribeFailed(Some(e.to_string().into()))).await?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[inline]
+pub fn keepalive(state: &SessionState, ping: IsPing) {
+    let _ = state.send(Message::Keepalive(ping));
+}
+
+///DISCONNECT of the gateway client, the last will message is not sent
+#[inline]
+pub fn disconnect(state: &SessionState) {
+    if let Err(e) = state.send(Message::Disconnect(Disconnect::V3)) {
+        log::debug!("{:?} Disconnect error, reason: {}", state.id, e);
+    }
+    closed(state, Reason::ConnectRemoteClose);
+}
+
+///The gateway client is lost, such as the keepalive of the sleeping client is timeout
+#[inline]
+pub fn closed(state: &SessionState, reason: Reason) {
+    if let Err(e) = state.send(Message::Closed(reason)) {
+        log::debug!("{:?} Closed error, reason: {}", state.id, e);
+    }
+}
@@ -16,16 +16,31 @@ pub trait HookManager: Sync + Send {
     ///Before the server startup
     async fn before_startup(&self);
 
+    ///Before the server shutdown, after the clients are disconnected when the node is draining
+    async fn before_shutdown(&self);
+
     ///When a connect message is received
     async fn client_connect(&self, connect_info: &ConnectInfo) -> Option<UserProperties>;
 
-    ///authenticate
+    ///authenticate, `conn_attrs` are the attributes of the connection, such as the PROXY protocol header,
+    ///the TLS client certificate or the credentials of the unix socket peer
     async fn client_authenticate(
         &self,
         connect_info: &ConnectInfo,
+        conn_attrs: &ExtraAttrs,
         allow_anonymous: bool,
     ) -> (ConnectAckReason, Superuser, Option<AuthInfo>);
 
+    ///extended authenticate, MQTT 5.0 AUTH packet exchange
+    async fn client_authenticate_extended(
+        &self,
+        connect_info: &ConnectInfo,
+        auth: &ExtendedAuth,
+    ) -> ExtendedAuthResult;
+
+    ///The client is banned for reconnecting too frequently
+    async fn client_flapping(&self, connect_info: &ConnectInfo, ban: &Ban);
+
     ///When sending mqtt:: connectack message
     async fn client_connack(
         &self,
@@ -125,6 +140,7 @@ pub trait Hook: Sync + Send {
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Deserialize, Serialize)]
 pub enum Type {
     BeforeStartup,
+    BeforeShutdown,
 
     SessionCreated,
     SessionTerminated,
@@ -132,6 +148,7 @@ pub enum Type {
     SessionUnsubscribed,
 
     ClientAuthenticate,
+    ClientAuthenticateExtended,
     ClientConnect,
     ClientConnack,
     ClientConnected,
@@ -140,6 +157,7 @@ pub enum Type {
     ClientUnsubscribe,
     ClientSubscribeCheckAcl,
     ClientKeepalive,
+    ClientFlapping,
 
     MessagePublishCheckAcl,
     MessagePublish,
@@ -159,6 +177,7 @@ impl std::convert::From<&str> for Type {
     fn from(t: &str) -> Type {
         match t {
             "before_startup" => Type::BeforeStartup,
+            "before_shutdown" => Type::BeforeShutdown,
 
             "session_created" => Type::SessionCreated,
             "session_terminated" => Type::SessionTerminated,
@@ -166,6 +185,7 @@ impl std::convert::From<&str> for Type {
             "session_unsubscribed" => Type::SessionUnsubscribed,
 
             "client_authenticate" => Type::ClientAuthenticate,
+            "client_authenticate_extended" => Type::ClientAuthenticateExtended,
             "client_connect" => Type::ClientConnect,
             "client_connack" => Type::ClientConnack,
             "client_connected" => Type::ClientConnected,
@@ -174,6 +194,7 @@ impl std::convert::From<&str> for Type {
             "client_unsubscribe" => Type::ClientUnsubscribe,
             "client_subscribe_check_acl" => Type::ClientSubscribeCheckAcl,
             "client_keepalive" => Type::ClientKeepalive,
+            "client_flapping" => Type::ClientFlapping,
 
             "message_publish_check_acl" => Type::MessagePublishCheckAcl,
             "message_publish" => Type::MessagePublish,
@@ -196,6 +217,7 @@ impl std::convert::From<&str> for Type {
 #[derive(Debug, Clone)]
 pub enum Parameter<'a> {
     BeforeStartup,
+    BeforeShutdown,
 
     SessionCreated(&'a Session),
     SessionTerminated(&'a Session, Reason),
@@ -204,13 +226,15 @@ pub enum Parameter<'a> {
 
     ClientConnect(&'a ConnectInfo),
     ClientConnack(&'a ConnectInfo, &'a ConnectAckReason),
-    ClientAuthenticate(&'a ConnectInfo),
+    ClientAuthenticate(&'a ConnectInfo, &'a ExtraAttrs),
+    ClientAuthenticateExtended(&'a ConnectInfo, &'a ExtendedAuth),
     ClientConnected(&'a Session),
     ClientDisconnected(&'a Session, Reason),
     ClientSubscribe(&'a Session, &'a Subscribe),
     ClientUnsubscribe(&'a Session, &'a Unsubscribe),
     ClientSubscribeCheckAcl(&'a Session, &'a Subscribe),
     ClientKeepalive(&'a Session, IsPing),
+    ClientFlapping(&'a ConnectInfo, &'a Ban),
 
     MessagePublishCheckAcl(&'a Session, &'a Publish),
     MessagePublish(Option<&'a Session>, From, &'a Publish),
@@ -230,13 +254,15 @@ impl Parameter<'_> {
     pub fn get_type(&self) -> Type {
         match self {
             Parameter::BeforeStartup => Type::BeforeStartup,
+            Parameter::BeforeShutdown => Type::BeforeShutdown,
 
             Parameter::SessionCreated(_) => Type::SessionCreated,
             Parameter::SessionTerminated(_, _) => Type::SessionTerminated,
             Parameter::SessionSubscribed(_, _) => Type::SessionSubscribed,
             Parameter::SessionUnsubscribed(_, _) => Type::SessionUnsubscribed,
 
-            Parameter::ClientAuthenticate(_) => Type::ClientAuthenticate,
+            Parameter::ClientAuthenticate(_, _) => Type::ClientAuthenticate,
+            Parameter::ClientAuthenticateExtended(_, _) => Type::ClientAuthenticateExtended,
             Parameter::ClientConnect(_) => Type::ClientConnect,
             Parameter::ClientConnack(_, _) => Type::ClientConnack,
             Parameter::ClientConnected(_) => Type::ClientConnected,
@@ -245,6 +271,7 @@ impl Parameter<'_> {
             Parameter::ClientUnsubscribe(_, _) => Type::ClientUnsubscribe,
             Parameter::ClientSubscribeCheckAcl(_, _) => Type::ClientSubscribeCheckAcl,
             Parameter::ClientKeepalive(_, _) => Type::ClientKeepalive,
+            Parameter::ClientFlapping(_, _) => Type::ClientFlapping,
 
             Parameter::MessagePublishCheckAcl(_, _) => Type::MessagePublishCheckAcl,
             Parameter::MessagePublish(_, _, _) => Type::MessagePublish,
@@ -268,6 +295,8 @@ pub enum HookResult {
     UserProperties(UserProperties),
     ///Authentication failed, for ClientAuthenticate
     AuthResult(AuthResult),
+    ///Extended authentication result, for ClientAuthenticateExtended
+    ExtendedAuthResult(ExtendedAuthResult),
     ///ConnectAckReason, for ClientConnack
     ConnectAckReason(ConnectAckReason),
     ///TopicFilters, for ClientSubscribe/ClientUnsubscribe
@@ -0,0 +1,133 @@
+//! Listener level IP allow/deny lists, checked right after accept and again
+//! against the address provided by the PROXY protocol
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use once_cell::sync::OnceCell;
+use rust_box::std_ext::RwLock;
+
+use crate::broker::types::*;
+use crate::settings::listener::Listener;
+use crate::{MqttError, Result};
+
+type Port = u16;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IpFilterRules {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl IpFilterRules {
+    ///The deny list takes precedence, if the allow list is not empty, only the addresses in it are accepted
+    #[inline]
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "allow": self.allow.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+            "deny": self.deny.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+pub struct IpFilter {
+    rules: RwLock<Arc<IpFilterRules>>,
+}
+
+impl IpFilter {
+    #[inline]
+    fn new(rules: IpFilterRules) -> Self {
+        Self { rules: RwLock::new(Arc::new(rules)) }
+    }
+
+    #[inline]
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        self.rules.read().is_allowed(ip)
+    }
+
+    #[inline]
+    pub fn rules(&self) -> Arc<IpFilterRules> {
+        self.rules.read().clone()
+    }
+
+    #[inline]
+    pub fn set_rules(&self, rules: IpFilterRules) {
+        *self.rules.write() = Arc::new(rules);
+    }
+}
+
+///The IP filters of all listeners on this node, keyed by the listening port
+pub struct IpFilters {
+    filters: DashMap<Port, Arc<IpFilter>>,
+}
+
+impl IpFilters {
+    #[inline]
+    pub fn instance() -> &'static IpFilters {
+        static INSTANCE: OnceCell<IpFilters> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { filters: DashMap::default() })
+    }
+
+    ///Create the filter of the listener from its allow/deny configuration
+    #[inline]
+    pub fn register(&self, listen_cfg: &Listener) -> Arc<IpFilter> {
+        let rules = IpFilterRules { allow: listen_cfg.allow.clone(), deny: listen_cfg.deny.clone() };
+        let filter = Arc::new(IpFilter::new(rules));
+        self.filters.insert(listen_cfg.addr.port(), filter.clone());
+        filter
+    }
+
+    #[inline]
+    pub fn unregister(&self, port: Port) {
+        self.filters.remove(&port);
+    }
+
+    #[inline]
+    pub fn get(&self, port: Port) -> Option<Arc<IpFilter>> {
+        self.filters.get(&port).map(|f| f.value().clone())
+    }
+
+    ///Replace the rules of the listener at runtime, the new rules apply to subsequent connections
+    #[inline]
+    pub fn set_rules(&self, port: Port, rules: IpFilterRules) -> Result<()> {
+        let filter =
+            self.get(port).ok_or_else(|| MqttError::from(format!("listener {} is not found", port)))?;
+        log::info!("listener {} ip filter rules are updated, {:?}", port, rules);
+        filter.set_rules(rules);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn list(&self) -> Vec<(Port, Arc<IpFilterRules>)> {
+        self.filters.iter().map(|entry| (*entry.key(), entry.value().rules())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_allowed() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let cidr = |s: &str| s.parse::<Cidr>().unwrap();
+        assert!(IpFilterRules::default().is_allowed(&ip("1.2.3.4")));
+
+        let rules = IpFilterRules { allow: vec![cidr("10.0.0.0/8")], deny: vec![cidr("10.1.0.0/16")] };
+        assert!(rules.is_allowed(&ip("10.2.3.4")));
+        assert!(!rules.is_allowed(&ip("10.1.3.4")));
+        assert!(!rules.is_allowed(&ip("192.168.1.1")));
+
+        let rules = IpFilterRules { allow: vec![], deny: vec![cidr("192.168.1.1")] };
+        assert!(!rules.is_allowed(&ip("192.168.1.1")));
+        assert!(rules.is_allowed(&ip("192.168.1.2")));
+    }
+}
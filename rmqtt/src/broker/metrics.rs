@@ -8,7 +8,13 @@ pub struct Metrics {
     client_auth_anonymous: AtomicUsize,
     client_auth_anonymous_error: AtomicUsize,
     client_handshaking_timeout: AtomicUsize,
+    client_flapping: AtomicUsize,
     client_connect: AtomicUsize,
+    client_connect_rate_limited: AtomicUsize,
+    client_connect_ip_limited: AtomicUsize,
+    client_connect_ip_denied: AtomicUsize,
+    client_cert_revoked: AtomicUsize,
+    client_cert_check_error: AtomicUsize,
     client_connack: AtomicUsize,
     client_connack_auth_error: AtomicUsize,
     client_connack_unavailable_error: AtomicUsize,
@@ -70,4 +76,7 @@ pub struct Metrics {
     messages_nonsubscribed_lastwill: AtomicUsize,
     messages_nonsubscribed_system: AtomicUsize,
     messages_nonsubscribed_bridge: AtomicUsize,
+
+    grpc_auth_failed: AtomicUsize,
+    grpc_auth_missing: AtomicUsize,
 }
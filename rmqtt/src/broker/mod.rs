@@ -13,13 +13,22 @@ use crate::{grpc, MqttError, Result, Runtime};
 
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 
+pub mod ban;
+pub mod conn_limit;
 pub mod default;
+pub mod discovery;
 pub mod error;
 pub mod executor;
 pub mod fitter;
+pub mod flapping;
+pub mod gateway;
 pub mod hook;
 pub mod inflight;
+pub mod ip_filter;
 pub mod metrics;
+pub mod peer_cert;
+pub mod peer_cred;
+pub mod proxy;
 pub mod queue;
 pub mod retain;
 pub mod session;
@@ -28,6 +37,7 @@ pub mod topic;
 pub mod types;
 pub mod v3;
 pub mod v5;
+pub mod ws_request;
 
 #[async_trait]
 pub trait Entry: Sync + Send {
@@ -84,6 +94,37 @@ pub trait Shared: Sync + Send {
         relations: SubRelations,
     ) -> Result<(), Vec<(To, From, Publish, Reason)>>;
 
+    ///Forwards the message to the given subscription relations, which may be located on other nodes
+    #[inline]
+    async fn forwards_to_nodes(
+        &self,
+        from: From,
+        publish: &Publish,
+        mut relations_map: SubRelationsMap,
+    ) -> Result<(), Vec<(To, From, Publish, Reason)>> {
+        let mut errs = Vec::new();
+        if let Some(relations) = relations_map.remove(&Runtime::instance().node.id()) {
+            if let Err(e) = self.forwards_to(from.clone(), publish, relations).await {
+                errs.extend(e);
+            }
+        }
+        for (node_id, relations) in relations_map {
+            for (_, client_id, _, _, _) in relations {
+                errs.push((
+                    To::from(node_id, client_id),
+                    from.clone(),
+                    publish.clone(),
+                    Reason::from_static("Forwarding to other nodes is not supported"),
+                ));
+            }
+        }
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(errs)
+        }
+    }
+
     /// Iter
     fn iter(&self) -> Box<dyn Iterator<Item = Box<dyn Entry>> + Sync + Send>;
 
@@ -120,6 +161,13 @@ pub trait Shared: Sync + Send {
         Ok(Some(json!({"status": "Ok", "nodes": []})))
     }
 
+    ///Remove the node from the cluster, when it is this node, leave the cluster gracefully.
+    ///Returns false if the node is not a member of the cluster
+    #[inline]
+    async fn remove_node(&self, _id: NodeId) -> Result<bool> {
+        Err(MqttError::from("Changing the cluster membership is not supported"))
+    }
+
     #[inline]
     fn operation_is_busy(&self) -> bool {
         false
@@ -178,6 +226,12 @@ pub trait Router: Sync + Send {
     /// Match with id and topic
     async fn matches(&self, id: Id, topic: &TopicName) -> Result<SubRelationsMap>;
 
+    /// Match with id and topic, the excluded subscriber is not chosen from the shared subscription groups
+    #[inline]
+    async fn matches_excluded(&self, id: Id, topic: &TopicName, _excluded: &Id) -> Result<SubRelationsMap> {
+        self.matches(id, topic).await
+    }
+
     ///Check online or offline
     #[inline]
     async fn is_online(&self, node_id: NodeId, client_id: &str) -> bool {
@@ -229,6 +283,28 @@ pub trait SharedSubscription: Sync + Send {
         listen_cfg.shared_subscription
     }
 
+    ///Shared subscription strategy, select a subscriber for the message, default is [`Self::choice`]
+    ///
+    ///topic_filter and group identify the shared subscription, topic and publisher belong to the
+    ///message being forwarded.
+    #[inline]
+    async fn choice_for(
+        &self,
+        _topic_filter: &TopicFilter,
+        _group: &SharedGroup,
+        _topic: &TopicName,
+        _publisher: &ClientId,
+        ncs: &[(
+            NodeId,
+            ClientId,
+            SubscriptionOptions,
+            Option<Vec<SubscriptionIdentifier>>,
+            Option<IsOnline>,
+        )],
+    ) -> Option<(usize, IsOnline)> {
+        self.choice(ncs).await
+    }
+
     ///Shared subscription strategy, select a subscriber, default is "random"
     #[inline]
     async fn choice(
@@ -368,6 +444,27 @@ impl DefaultMessageManager {
 
 impl MessageManager for &'static DefaultMessageManager {}
 
+#[async_trait]
+pub trait BanManager: Sync + Send {
+    ///Add a ban entry, the existing entry with the same key is replaced
+    async fn add(&self, ban: Ban) -> Result<()>;
+
+    ///Remove the ban entry
+    async fn remove(&self, key: &BanKey) -> Result<Option<Ban>>;
+
+    ///Get the ban entry, expired entries are not returned
+    async fn get(&self, key: &BanKey) -> Result<Option<Ban>>;
+
+    ///All unexpired ban entries
+    async fn list(&self) -> Result<Vec<Ban>>;
+
+    ///Check whether the client is banned by its client id, username or ip address
+    async fn check(&self, id: &Id) -> Option<Ban>;
+
+    ///Number of ban entries
+    async fn count(&self) -> usize;
+}
+
 #[async_trait]
 pub trait DelayedSender: Sync + Send {
     ///Parse the topic and extract the delayed sending parameters.
@@ -0,0 +1,245 @@
+//! Fields of the TLS client certificate, they are kept in the session's extra attributes
+//! under [`PEER_CERT_KEY`] and can be mapped to the username or client id of the connection.
+//! The result of the OCSP revocation check is kept under [`PEER_CERT_STATUS_KEY`]
+
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer};
+
+use crate::broker::types::*;
+use crate::{MqttError, Result};
+
+pub const PEER_CERT_KEY: &str = "peer_cert";
+pub const PEER_CERT_STATUS_KEY: &str = "peer_cert_status";
+
+///Placeholders of the certificate fields in the ACL rules and the auth-http request parameters,
+///the common name, the subject distinguished name and the first DNS name of the subject alternative name
+pub const PH_CERT_CN: &str = "%C";
+pub const PH_CERT_DN: &str = "%d";
+pub const PH_CERT_SAN_DNS: &str = "%n";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerCert {
+    ///Distinguished name of the subject, such as "CN=device-1,O=Example"
+    pub subject: String,
+    ///Common name of the subject
+    pub cn: Option<String>,
+    ///DNS names of the subject alternative name extension
+    pub san_dns: Vec<String>,
+    ///Hex encoded SHA-256 digest of the DER encoded certificate
+    pub fingerprint: String,
+}
+
+impl PeerCert {
+    ///The value of the certificate field, None if it is absent or empty
+    #[inline]
+    pub fn get(&self, field: PeerCertField) -> Option<String> {
+        let v = match field {
+            PeerCertField::Cn => self.cn.clone(),
+            PeerCertField::Dn => Some(self.subject.clone()),
+            PeerCertField::SanDns => self.san_dns.first().cloned(),
+            PeerCertField::Fingerprint => Some(self.fingerprint.clone()),
+        };
+        v.filter(|v| !v.is_empty())
+    }
+}
+
+///Whether the string contains the placeholders of the certificate fields
+#[inline]
+pub fn has_placeholders(s: &str) -> bool {
+    s.contains(PH_CERT_CN) || s.contains(PH_CERT_DN) || s.contains(PH_CERT_SAN_DNS)
+}
+
+///Replace the placeholders of the certificate fields, the absent fields are replaced with an empty string.
+///In a topic filter, None is returned if the field is absent or it is not a valid topic level
+#[inline]
+pub fn replaces(cert: Option<&PeerCert>, s: &str, in_topic: bool) -> Option<String> {
+    let mut s = s.to_owned();
+    for (ph, v) in [
+        (PH_CERT_CN, cert.and_then(|c| c.cn.as_deref())),
+        (PH_CERT_DN, cert.map(|c| c.subject.as_str())),
+        (PH_CERT_SAN_DNS, cert.and_then(|c| c.san_dns.first()).map(|v| v.as_str())),
+    ] {
+        if s.contains(ph) {
+            let v = v.unwrap_or_default();
+            if in_topic && (v.is_empty() || !is_valid_value(v)) {
+                return None;
+            }
+            s = s.replace(ph, v);
+        }
+    }
+    Some(s)
+}
+
+//The username and client id are used in the topic placeholders of the ACL rules and mountpoints,
+//the topic level separator, the wildcards and the control characters are not allowed
+#[inline]
+fn is_valid_value(v: &str) -> bool {
+    v.len() <= u16::MAX as usize && !v.chars().any(|c| matches!(c, '/' | '+' | '#') || c.is_control())
+}
+
+///The connection is refused if the revocation check of the client certificate does not pass
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerCertStatus {
+    Revoked,
+    CheckFailed(String),
+}
+
+///The refused reason of a revoked client certificate, it names the certificate so that it can be
+///told apart from an authentication failure in the logs
+#[inline]
+pub(crate) fn revoked_reason(conn_attrs: &ExtraAttrs) -> String {
+    match conn_attrs.get::<PeerCert>(PEER_CERT_KEY) {
+        Some(cert) => format!(
+            "client certificate is revoked, subject: {}, fingerprint: {}",
+            cert.subject, cert.fingerprint
+        ),
+        None => "client certificate is revoked".into(),
+    }
+}
+
+///The certificate field used as the username or client id, see `peer_cert_as_username`
+///and `peer_cert_as_clientid` of the listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerCertField {
+    Cn,
+    Dn,
+    SanDns,
+    Fingerprint,
+}
+
+impl FromStr for PeerCertField {
+    type Err = MqttError;
+
+    #[inline]
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "cn" => Ok(PeerCertField::Cn),
+            "dn" => Ok(PeerCertField::Dn),
+            "san_dns" => Ok(PeerCertField::SanDns),
+            "fingerprint" => Ok(PeerCertField::Fingerprint),
+            _ => Err(MqttError::from(format!("unsupported certificate field, {}", s))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PeerCertField {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        PeerCertField::from_str(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+///Replace the username and client id of the CONNECT packet with the certificate fields
+///configured on the listener, the fields that are absent in the certificate are left unchanged.
+///An error is returned if the field contains '/', '+', '#' or control characters
+#[inline]
+pub(crate) fn apply(
+    conn_attrs: &ExtraAttrs,
+    as_username: Option<PeerCertField>,
+    as_clientid: Option<PeerCertField>,
+    username: &mut Option<UserName>,
+    client_id: &mut ClientId,
+) -> Result<()> {
+    if as_username.is_none() && as_clientid.is_none() {
+        return Ok(());
+    }
+    let cert = if let Some(cert) = conn_attrs.get::<PeerCert>(PEER_CERT_KEY) {
+        cert
+    } else {
+        return Ok(());
+    };
+    if let Some(v) = as_username.and_then(|f| cert.get(f)) {
+        if !is_valid_value(&v) {
+            return Err(MqttError::from(format!(
+                "certificate field is not allowed as the username, {:?}",
+                v
+            )));
+        }
+        *username = Some(UserName::from(v));
+    }
+    if let Some(v) = as_clientid.and_then(|f| cert.get(f)) {
+        if !is_valid_value(&v) {
+            return Err(MqttError::from(format!(
+                "certificate field is not allowed as the client id, {:?}",
+                v
+            )));
+        }
+        *client_id = ClientId::from(v);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_fields() {
+        let cert = PeerCert {
+            subject: "CN=device-1,O=Example".into(),
+            cn: Some("device-1".into()),
+            san_dns: vec![],
+            fingerprint: "ab01".into(),
+        };
+        let mut conn_attrs = ExtraAttrs::new();
+        conn_attrs.insert(PEER_CERT_KEY.into(), cert);
+
+        let mut username = None;
+        let mut client_id = ClientId::from("c1");
+        apply(
+            &conn_attrs,
+            Some(PeerCertField::Cn),
+            Some(PeerCertField::SanDns),
+            &mut username,
+            &mut client_id,
+        )
+        .unwrap();
+        assert_eq!(username, Some(UserName::from("device-1")));
+        assert_eq!(client_id, ClientId::from("c1"));
+
+        apply(&conn_attrs, None, Some(PeerCertField::Fingerprint), &mut username, &mut client_id).unwrap();
+        assert_eq!(client_id, ClientId::from("ab01"));
+        assert_eq!("SAN_DNS".parse::<PeerCertField>().unwrap(), PeerCertField::SanDns);
+        assert!("email".parse::<PeerCertField>().is_err());
+    }
+
+    #[test]
+    fn apply_invalid_fields() {
+        let mut conn_attrs = ExtraAttrs::new();
+        conn_attrs.insert(
+            PEER_CERT_KEY.into(),
+            PeerCert { subject: "CN=a/b".into(), cn: Some("+".into()), ..Default::default() },
+        );
+        let mut username = None;
+        let mut client_id = ClientId::from("c1");
+        assert!(apply(&conn_attrs, Some(PeerCertField::Cn), None, &mut username, &mut client_id).is_err());
+        assert!(apply(&conn_attrs, None, Some(PeerCertField::Dn), &mut username, &mut client_id).is_err());
+        assert_eq!(username, None);
+        assert_eq!(client_id, ClientId::from("c1"));
+    }
+
+    #[test]
+    fn replace_placeholders() {
+        let cert = PeerCert {
+            subject: "CN=device-1,O=Example".into(),
+            cn: Some("device-1".into()),
+            san_dns: vec!["a.example.com".into()],
+            fingerprint: "ab01".into(),
+        };
+        assert!(has_placeholders("devices/%C/#"));
+        assert!(!has_placeholders("devices/%c/#"));
+        assert_eq!(
+            replaces(Some(&cert), "devices/%C/%n", true),
+            Some("devices/device-1/a.example.com".into())
+        );
+        assert_eq!(replaces(Some(&cert), "%d", false), Some("CN=device-1,O=Example".into()));
+        assert_eq!(replaces(None, "devices/%C/#", true), None);
+        assert_eq!(replaces(None, "cn=%C", false), Some("cn=".into()));
+        let cert = PeerCert { cn: Some("#".into()), ..Default::default() };
+        assert_eq!(replaces(Some(&cert), "devices/%C", true), None);
+    }
+}
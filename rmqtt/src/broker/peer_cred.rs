@@ -0,0 +1,23 @@
+//! Credentials of the peer process of a unix domain socket connection, they are kept in the
+//! connection attributes under [`PEER_CRED_KEY`] and passed to the authentication hooks
+
+pub const PEER_CRED_KEY: &str = "peer_cred";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerCred {
+    pub uid: u32,
+    pub gid: u32,
+    ///None if the platform does not provide it
+    pub pid: Option<i32>,
+}
+
+impl PeerCred {
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "uid": self.uid,
+            "gid": self.gid,
+            "pid": self.pid,
+        })
+    }
+}
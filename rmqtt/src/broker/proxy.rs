@@ -0,0 +1,257 @@
+//! PROXY protocol v1/v2 header parser, see https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use crate::{MqttError, Result};
+
+pub const PROXY_INFO_KEY: &str = "proxy_protocol";
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+const PP2_TYPE_ALPN: u8 = 0x01;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_VERSION: u8 = 0x21;
+const PP2_SUBTYPE_SSL_CN: u8 = 0x22;
+const PP2_SUBTYPE_SSL_CIPHER: u8 = 0x23;
+const PP2_SUBTYPE_SSL_SIG_ALG: u8 = 0x24;
+const PP2_SUBTYPE_SSL_KEY_ALG: u8 = 0x25;
+const PP2_CLIENT_SSL: u8 = 0x01;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyInfo {
+    pub version: u8,
+    ///Original client address, None for the LOCAL command or UNKNOWN/UNSPEC protocols
+    pub source_addr: Option<SocketAddr>,
+    pub dest_addr: Option<SocketAddr>,
+    pub alpn: Option<String>,
+    pub authority: Option<String>,
+    pub unique_id: Option<Vec<u8>>,
+    pub ssl: Option<ProxySsl>,
+    ///All TLVs as received, (type, value)
+    pub tlvs: Vec<(u8, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxySsl {
+    pub client_ssl: bool,
+    ///The client certificate was presented and successfully verified
+    pub verified: bool,
+    pub version: Option<String>,
+    ///Client certificate Common Name
+    pub cn: Option<String>,
+    pub cipher: Option<String>,
+    pub sig_alg: Option<String>,
+    pub key_alg: Option<String>,
+}
+
+///Parse a PROXY protocol header from the beginning of the buf.
+///
+///Returns Ok(None) if more data is needed, otherwise the header and its length.
+#[inline]
+pub fn parse(buf: &[u8]) -> Result<Option<(ProxyInfo, usize)>> {
+    if buf.len() < V1_PREFIX.len().min(V2_SIGNATURE.len()) {
+        if V1_PREFIX.starts_with(buf) || V2_SIGNATURE.starts_with(buf) {
+            return Ok(None);
+        }
+        return Err(MqttError::from("invalid PROXY protocol header"));
+    }
+    if buf.starts_with(V1_PREFIX) {
+        parse_v1(buf)
+    } else if V2_SIGNATURE.starts_with(&buf[..buf.len().min(V2_SIGNATURE.len())]) {
+        parse_v2(buf)
+    } else {
+        Err(MqttError::from("invalid PROXY protocol header"))
+    }
+}
+
+#[inline]
+fn parse_v1(buf: &[u8]) -> Result<Option<(ProxyInfo, usize)>> {
+    let end = match buf.iter().take(V1_MAX_LEN).position(|b| *b == b'\n') {
+        Some(pos) => pos,
+        None if buf.len() < V1_MAX_LEN => return Ok(None),
+        None => return Err(MqttError::from("PROXY protocol v1 header is too long")),
+    };
+    if end == 0 || buf[end - 1] != b'\r' {
+        return Err(MqttError::from("invalid PROXY protocol v1 header"));
+    }
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end - 1])?;
+    let parts = line.split(' ').collect::<Vec<_>>();
+    let mut info = ProxyInfo { version: 1, ..Default::default() };
+    match parts.as_slice() {
+        ["UNKNOWN", ..] => {}
+        [proto @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let src = IpAddr::from_str(src)?;
+            let dst = IpAddr::from_str(dst)?;
+            if (*proto == "TCP4") != (src.is_ipv4() && dst.is_ipv4()) {
+                return Err(MqttError::from("invalid PROXY protocol v1 address family"));
+            }
+            info.source_addr = Some(SocketAddr::new(src, u16::from_str(sport)?));
+            info.dest_addr = Some(SocketAddr::new(dst, u16::from_str(dport)?));
+        }
+        _ => return Err(MqttError::from("invalid PROXY protocol v1 header")),
+    }
+    Ok(Some((info, end + 1)))
+}
+
+#[inline]
+fn parse_v2(buf: &[u8]) -> Result<Option<(ProxyInfo, usize)>> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let ver_cmd = buf[12];
+    if ver_cmd >> 4 != 2 {
+        return Err(MqttError::from("unsupported PROXY protocol version"));
+    }
+    let fam = buf[13];
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < V2_HEADER_LEN + len {
+        return Ok(None);
+    }
+    let payload = &buf[V2_HEADER_LEN..V2_HEADER_LEN + len];
+
+    let mut info = ProxyInfo { version: 2, ..Default::default() };
+    let addr_len = match fam >> 4 {
+        //AF_INET
+        0x1 => 12,
+        //AF_INET6
+        0x2 => 36,
+        //AF_UNIX
+        0x3 => 216,
+        _ => 0,
+    };
+    if payload.len() < addr_len {
+        return Err(MqttError::from("invalid PROXY protocol v2 address length"));
+    }
+    match ver_cmd & 0x0F {
+        //LOCAL, the connection was established by the proxy itself
+        0x0 => {}
+        //PROXY
+        0x1 => match fam >> 4 {
+            0x1 => {
+                let src = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+                let dst = Ipv4Addr::new(payload[4], payload[5], payload[6], payload[7]);
+                let sport = u16::from_be_bytes([payload[8], payload[9]]);
+                let dport = u16::from_be_bytes([payload[10], payload[11]]);
+                info.source_addr = Some(SocketAddr::new(src.into(), sport));
+                info.dest_addr = Some(SocketAddr::new(dst.into(), dport));
+            }
+            0x2 => {
+                let mut src = [0u8; 16];
+                let mut dst = [0u8; 16];
+                src.copy_from_slice(&payload[0..16]);
+                dst.copy_from_slice(&payload[16..32]);
+                let sport = u16::from_be_bytes([payload[32], payload[33]]);
+                let dport = u16::from_be_bytes([payload[34], payload[35]]);
+                info.source_addr = Some(SocketAddr::new(Ipv6Addr::from(src).into(), sport));
+                info.dest_addr = Some(SocketAddr::new(Ipv6Addr::from(dst).into(), dport));
+            }
+            _ => {}
+        },
+        _ => return Err(MqttError::from("unsupported PROXY protocol v2 command")),
+    }
+
+    for (typ, value) in tlvs(&payload[addr_len..])? {
+        match typ {
+            PP2_TYPE_ALPN => info.alpn = Some(String::from_utf8_lossy(value).into_owned()),
+            PP2_TYPE_AUTHORITY => info.authority = Some(String::from_utf8_lossy(value).into_owned()),
+            PP2_TYPE_UNIQUE_ID => info.unique_id = Some(value.to_vec()),
+            PP2_TYPE_SSL => info.ssl = Some(parse_ssl(value)?),
+            _ => {}
+        }
+        info.tlvs.push((typ, value.to_vec()));
+    }
+    Ok(Some((info, V2_HEADER_LEN + len)))
+}
+
+#[inline]
+fn parse_ssl(value: &[u8]) -> Result<ProxySsl> {
+    if value.len() < 5 {
+        return Err(MqttError::from("invalid PROXY protocol v2 SSL TLV"));
+    }
+    let client = value[0];
+    let verify = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+    let mut ssl =
+        ProxySsl { client_ssl: client & PP2_CLIENT_SSL != 0, verified: verify == 0, ..Default::default() };
+    for (typ, value) in tlvs(&value[5..])? {
+        let value = Some(String::from_utf8_lossy(value).into_owned());
+        match typ {
+            PP2_SUBTYPE_SSL_VERSION => ssl.version = value,
+            PP2_SUBTYPE_SSL_CN => ssl.cn = value,
+            PP2_SUBTYPE_SSL_CIPHER => ssl.cipher = value,
+            PP2_SUBTYPE_SSL_SIG_ALG => ssl.sig_alg = value,
+            PP2_SUBTYPE_SSL_KEY_ALG => ssl.key_alg = value,
+            _ => {}
+        }
+    }
+    Ok(ssl)
+}
+
+#[inline]
+fn tlvs(mut buf: &[u8]) -> Result<Vec<(u8, &[u8])>> {
+    let mut items = Vec::new();
+    while !buf.is_empty() {
+        if buf.len() < 3 {
+            return Err(MqttError::from("invalid PROXY protocol v2 TLV"));
+        }
+        let len = u16::from_be_bytes([buf[1], buf[2]]) as usize;
+        if buf.len() < 3 + len {
+            return Err(MqttError::from("invalid PROXY protocol v2 TLV length"));
+        }
+        items.push((buf[0], &buf[3..3 + len]));
+        buf = &buf[3 + len..];
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1() {
+        let data = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n\x10";
+        assert!(parse(&data[..20]).unwrap().is_none());
+        let (info, len) = parse(data).unwrap().unwrap();
+        assert_eq!(len, data.len() - 1);
+        assert_eq!(info.source_addr, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(info.dest_addr, Some("192.168.0.11:1883".parse().unwrap()));
+
+        let (info, _) = parse(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!(info.source_addr, None);
+
+        assert!(parse(b"PROXY TCP4 ::1 192.168.0.11 56324 1883\r\n").is_err());
+        assert!(parse(b"\x10\x0c\x00\x04MQTT").is_err());
+    }
+
+    #[test]
+    fn v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        let ssl_cn = b"client-1";
+        let ssl_len = 5 + 3 + ssl_cn.len();
+        let len = 12 + 3 + ssl_len;
+        data.extend_from_slice(&[0x21, 0x11]);
+        data.extend_from_slice(&(len as u16).to_be_bytes());
+        data.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        data.extend_from_slice(&56324u16.to_be_bytes());
+        data.extend_from_slice(&8883u16.to_be_bytes());
+        data.extend_from_slice(&[PP2_TYPE_SSL]);
+        data.extend_from_slice(&(ssl_len as u16).to_be_bytes());
+        data.extend_from_slice(&[PP2_CLIENT_SSL, 0, 0, 0, 0, PP2_SUBTYPE_SSL_CN]);
+        data.extend_from_slice(&(ssl_cn.len() as u16).to_be_bytes());
+        data.extend_from_slice(ssl_cn);
+
+        assert!(parse(&data[..data.len() - 1]).unwrap().is_none());
+        let (info, consumed) = parse(&data).unwrap().unwrap();
+        assert_eq!(consumed, data.len());
+        assert_eq!(info.source_addr, Some("10.0.0.1:56324".parse().unwrap()));
+        let ssl = info.ssl.unwrap();
+        assert!(ssl.client_ssl && ssl.verified);
+        assert_eq!(ssl.cn.as_deref(), Some("client-1"));
+    }
+}
@@ -1,13 +1,15 @@
 use std::num::NonZeroU32;
 use std::pin::Pin;
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 use crossbeam::queue::SegQueue;
 use futures::channel::mpsc;
+use futures::task::AtomicWaker;
 use futures::SinkExt;
 use futures::Stream;
 use governor::{
@@ -149,6 +151,150 @@ impl Limiter {
     }
 }
 
+///Inbound publish limiter of a client, messages/second and bytes/second.
+///A message is only taken from the limits when both of them allow it.
+pub struct PublishLimiter {
+    messages_rate: Option<NonZeroU32>,
+    bytes_rate: Option<NonZeroU32>,
+    state: Mutex<PublishLimiterState>,
+}
+
+struct PublishLimiterState {
+    messages: f64,
+    bytes: f64,
+    refilled_at: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublishLimitExceeded {
+    MessageRate,
+    ByteRate,
+}
+
+impl PublishLimiter {
+    ///Returns None if neither limit is set
+    #[inline]
+    pub fn new(messages_rate: u32, bytes_rate: u32) -> Option<Self> {
+        let messages_rate = NonZeroU32::new(messages_rate);
+        let bytes_rate = NonZeroU32::new(bytes_rate);
+        if messages_rate.is_none() && bytes_rate.is_none() {
+            None
+        } else {
+            let state = PublishLimiterState {
+                messages: messages_rate.map(|n| n.get() as f64).unwrap_or_default(),
+                bytes: bytes_rate.map(|n| n.get() as f64).unwrap_or_default(),
+                refilled_at: Instant::now(),
+            };
+            Some(Self { messages_rate, bytes_rate, state: Mutex::new(state) })
+        }
+    }
+
+    ///Check whether a message of the given size is allowed now
+    #[inline]
+    pub fn check(&self, size: usize) -> std::result::Result<(), PublishLimitExceeded> {
+        self.acquire(size).map_err(|(exceeded, _)| exceeded)
+    }
+
+    ///Wait until a message of the given size is allowed
+    #[inline]
+    pub async fn until_ready(&self, size: usize) {
+        while let Err((_, wait)) = self.acquire(size) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    //Returns the exceeded limit and how long until it allows the message, the burst of each
+    //limit is one second, a message larger than the byte burst takes the whole burst
+    fn acquire(&self, size: usize) -> std::result::Result<(), (PublishLimitExceeded, Duration)> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+        state.refilled_at = now;
+
+        if let Some(rate) = self.messages_rate {
+            let rate = rate.get() as f64;
+            state.messages = (state.messages + elapsed * rate).min(rate);
+        }
+        let bytes_needed = if let Some(rate) = self.bytes_rate {
+            let rate = rate.get() as f64;
+            state.bytes = (state.bytes + elapsed * rate).min(rate);
+            (size.max(1) as f64).min(rate)
+        } else {
+            0.0
+        };
+
+        if let Some(rate) = self.messages_rate {
+            if state.messages < 1.0 {
+                let wait = (1.0 - state.messages) / rate.get() as f64;
+                return Err((PublishLimitExceeded::MessageRate, Duration::from_secs_f64(wait)));
+            }
+        }
+        if let Some(rate) = self.bytes_rate {
+            if state.bytes < bytes_needed {
+                let wait = (bytes_needed - state.bytes) / rate.get() as f64;
+                return Err((PublishLimitExceeded::ByteRate, Duration::from_secs_f64(wait)));
+            }
+        }
+
+        if self.messages_rate.is_some() {
+            state.messages -= 1.0;
+        }
+        state.bytes -= bytes_needed;
+        Ok(())
+    }
+}
+
+///Connection attribute key of the ReadPause
+pub const READ_PAUSE_KEY: &str = "read_pause";
+
+///Pauses the socket reads of a connection while its publishes are throttled,
+///the reads are resumed when all the guards are dropped
+#[derive(Clone, Default)]
+pub struct ReadPause {
+    inner: Arc<ReadPauseInner>,
+}
+
+#[derive(Default)]
+struct ReadPauseInner {
+    pauses: AtomicUsize,
+    waker: AtomicWaker,
+}
+
+impl ReadPause {
+    #[inline]
+    pub fn pause(&self) -> ReadPauseGuard {
+        self.inner.pauses.fetch_add(1, Ordering::SeqCst);
+        ReadPauseGuard { inner: self.inner.clone() }
+    }
+
+    ///Called by the stream before reading, returns Pending while the reads are paused
+    #[inline]
+    pub fn poll_resumed(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.inner.pauses.load(Ordering::SeqCst) == 0 {
+            return Poll::Ready(());
+        }
+        self.inner.waker.register(cx.waker());
+        if self.inner.pauses.load(Ordering::SeqCst) == 0 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+pub struct ReadPauseGuard {
+    inner: Arc<ReadPauseInner>,
+}
+
+impl Drop for ReadPauseGuard {
+    #[inline]
+    fn drop(&mut self) {
+        if self.inner.pauses.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.waker.wake();
+        }
+    }
+}
+
 pub struct Queue<T> {
     cap: usize,
     inner: SegQueue<T>,
@@ -257,4 +403,28 @@ mod test {
             println!("{} queue recv: {:?}", Local::now().format("%Y-%m-%d %H:%M:%S%.3f %z"), v);
         }
     }
+
+    #[test]
+    fn publish_limiter() {
+        use super::{PublishLimitExceeded, PublishLimiter};
+
+        //a message rejected by the byte rate does not take from the message rate
+        let limiter = PublishLimiter::new(2, 100).unwrap();
+        assert_eq!(limiter.check(100), Ok(()));
+        assert_eq!(limiter.check(50), Err(PublishLimitExceeded::ByteRate));
+        assert!(limiter.state.lock().unwrap().messages >= 1.0);
+
+        //a message rejected by the message rate does not take from the byte rate
+        let limiter = PublishLimiter::new(1, 1000).unwrap();
+        assert_eq!(limiter.check(10), Ok(()));
+        assert_eq!(limiter.check(10), Err(PublishLimitExceeded::MessageRate));
+        assert!(limiter.state.lock().unwrap().bytes < 991.0);
+        assert!(limiter.state.lock().unwrap().bytes >= 990.0);
+
+        //a message larger than the burst takes the whole burst
+        let limiter = PublishLimiter::new(0, 100).unwrap();
+        assert_eq!(limiter.check(1000), Ok(()));
+        assert_eq!(limiter.check(1), Err(PublishLimitExceeded::ByteRate));
+        assert!(PublishLimiter::new(0, 0).is_none());
+    }
 }
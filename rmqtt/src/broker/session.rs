@@ -17,11 +17,13 @@ use ntex_mqtt::v5::codec::RetainHandling;
 
 use crate::broker::hook::Hook;
 use crate::broker::inflight::{Inflight, InflightMessage, MomentStatus};
-use crate::broker::queue::{self, Limiter, Policy};
+use crate::broker::queue::{
+    self, Limiter, Policy, PublishLimitExceeded, PublishLimiter, ReadPause, READ_PAUSE_KEY,
+};
 use crate::broker::types::*;
 use crate::metrics::Metrics;
-use crate::settings::acl::AuthInfo;
-use crate::settings::listener::Listener;
+use crate::settings::acl::{self, AuthInfo};
+use crate::settings::listener::{Listener, PublishLimitAction};
 use crate::{MqttError, Result, Runtime};
 
 #[derive(Clone)]
@@ -33,6 +35,7 @@ pub struct SessionState {
     pub deliver_queue_tx: Option<MessageSender>,
     pub server_topic_aliases: Option<Rc<ServerTopicAliases>>,
     pub client_topic_aliases: Option<Rc<ClientTopicAliases>>,
+    pub publish_limiter: Option<Rc<PublishLimiter>>,
 }
 
 impl fmt::Debug for SessionState {
@@ -68,6 +71,14 @@ impl SessionState {
         };
         log::debug!("server_topic_aliases: {:?}", server_topic_aliases);
         log::debug!("client_topic_aliases: {:?}", client_topic_aliases);
+        let publish_limit = session
+            .auth_info
+            .as_ref()
+            .and_then(|auth_info| auth_info.publish_limit)
+            .unwrap_or_else(|| session.fitter.publish_limit());
+        log::debug!("publish_limit: {:?}", publish_limit);
+        let publish_limiter =
+            PublishLimiter::new(publish_limit.messages_rate, publish_limit.bytes_rate).map(Rc::new);
         Self {
             tx: None,
             session,
@@ -76,6 +87,7 @@ impl SessionState {
             deliver_queue_tx: None,
             server_topic_aliases,
             client_topic_aliases,
+            publish_limiter,
         }
     }
 
@@ -103,6 +115,7 @@ impl SessionState {
         };
 
         let mut flags = StateFlags::empty();
+        let mut drained_tx = None;
 
         log::debug!("{:?} there are {} offline messages ...", state.id, state.deliver_queue().len());
 
@@ -170,6 +183,15 @@ impl SessionState {
                                         log::warn!("{:?} Message::Kick, kick sender is closed, to {:?}, is_admin: {}", state.id, by_id, is_admin);
                                     }
                                 },
+                                Message::Drain(done_tx) => {
+                                    log::debug!("{:?} Message::Drain, the node is draining", state.id);
+                                    flags.insert(StateFlags::Drained);
+                                    drained_tx = Some(done_tx);
+                                    if let Err(e) = state.disconnected_reason_add(Reason::ConnectDrained).await {
+                                        log::error!("{:?} disconnected reason add error: {:?}", state.id, e);
+                                    }
+                                    break
+                                },
                                 Message::Disconnect(d) => {
                                     flags.insert(StateFlags::DisconnectReceived);
                                     //state.set_mqtt_disconnect(d).await;
@@ -297,7 +319,15 @@ impl SessionState {
             };
 
             if let Some(sink) = state.sink.as_ref() {
-                sink.close()
+                if flags.contains(StateFlags::Drained) {
+                    let drain_cfg = &Runtime::instance().settings.node.drain;
+                    sink.close_with_redirect(
+                        drain_cfg.server_moved,
+                        drain_cfg.server_reference.as_deref().map(ByteString::from),
+                    )
+                } else {
+                    sink.close()
+                }
             }
 
             //hook, client_disconnected
@@ -311,6 +341,13 @@ impl SessionState {
             };
             state.hook.client_disconnected(reason).await;
 
+            //Redispatch shared subscription messages to other members of the group
+            if !flags.contains(StateFlags::Kicked)
+                && Runtime::instance().settings.mqtt.shared_subscription_redispatch
+            {
+                state.redispatch_shared_messages().await;
+            }
+
             if flags.contains(StateFlags::Kicked) {
                 if flags.contains(StateFlags::ByAdminKick) {
                     state.clean(state.disconnected_reason_take().await.unwrap_or_default()).await;
@@ -325,6 +362,11 @@ impl SessionState {
                     state.hook.offline_inflight_messages(inflight_messages).await;
                 }
 
+                //The session has been taken offline, notify the drainer
+                if let Some(done_tx) = drained_tx.take() {
+                    let _ = done_tx.send(());
+                }
+
                 //Start offline event loop
                 Self::offline_start(
                     state.clone(),
@@ -456,6 +498,7 @@ impl SessionState {
             deliver_queue_tx: None,
             server_topic_aliases: None,
             client_topic_aliases: None,
+            publish_limiter: None,
         };
 
         let limiter = {
@@ -523,6 +566,132 @@ impl SessionState {
         (self, deliver_queue_tx, deliver_queue_rx)
     }
 
+    ///Redispatch queued and in-flight shared subscription messages to other online members of the same group
+    #[inline]
+    async fn redispatch_shared_messages(&self) {
+        let subs = match self.subscriptions().await {
+            Ok(subs) => subs
+                .read()
+                .await
+                .iter()
+                .filter_map(|(topic_filter, opts)| {
+                    Topic::from_str(topic_filter)
+                        .ok()
+                        .map(|t| (topic_filter.clone(), opts.shared_group().cloned(), t))
+                })
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                log::warn!("{:?} redispatch shared messages, get subscriptions error, {:?}", self.id, e);
+                return;
+            }
+        };
+        if !subs.iter().any(|(_, group, _)| group.is_some()) {
+            return;
+        }
+
+        //Only messages that match shared subscriptions alone are redispatched
+        let shared_of = |topic: &str| -> Option<(TopicFilter, SharedGroup)> {
+            let mut shared = None;
+            for (topic_filter, group, _) in subs.iter().filter(|(_, _, t)| t.matches_str(topic)) {
+                match group {
+                    Some(group) => {
+                        if shared.is_none() {
+                            shared = Some((topic_filter.clone(), group.clone()))
+                        }
+                    }
+                    None => return None,
+                }
+            }
+            shared
+        };
+
+        let mut queued = Vec::new();
+        while let Some(item) = self.deliver_queue().pop() {
+            queued.push(item);
+        }
+        for (from, publish) in queued {
+            if let Some((topic_filter, group)) = shared_of(&publish.topic) {
+                if self.redispatch_shared(&topic_filter, &group, &from, &publish).await {
+                    continue;
+                }
+            }
+            if let Err((from, publish)) = self.deliver_queue().push((from, publish)) {
+                //hook, message_dropped
+                Runtime::instance()
+                    .extends
+                    .hook_mgr()
+                    .await
+                    .message_dropped(Some(self.id.clone()), from, publish, Reason::MessageQueueFull)
+                    .await;
+            }
+        }
+
+        let inflight_messages = self.inflight_win().write().await.to_inflight_messages();
+        for iflt_msg in inflight_messages {
+            //QoS2 messages that have been received by the client are not redispatched
+            if !matches!(iflt_msg.status, MomentStatus::UnComplete) {
+                if let Some((topic_filter, group)) = shared_of(&iflt_msg.publish.topic) {
+                    if self.redispatch_shared(&topic_filter, &group, &iflt_msg.from, &iflt_msg.publish).await
+                    {
+                        continue;
+                    }
+                }
+            }
+            self.inflight_win().write().await.push_back(iflt_msg);
+        }
+    }
+
+    #[inline]
+    async fn redispatch_shared(
+        &self,
+        topic_filter: &TopicFilter,
+        group: &SharedGroup,
+        from: &From,
+        publish: &Publish,
+    ) -> bool {
+        //this session is excluded before the member is chosen, it is going offline
+        let mut relations_map = match Runtime::instance()
+            .extends
+            .router()
+            .await
+            .matches_excluded(from.id.clone(), &publish.topic, &self.id)
+            .await
+        {
+            Ok(relations_map) => relations_map,
+            Err(e) => {
+                log::warn!("{:?} redispatch shared message, matches error, {:?}", self.id, e);
+                return false;
+            }
+        };
+        relations_map.retain(|node_id, relations| {
+            relations.retain(|(tf, client_id, _, _, shared)| {
+                tf == topic_filter
+                    && matches!(shared, Some((g, true, _)) if g == group)
+                    && !(*node_id == self.id.node_id && client_id == &self.id.client_id)
+            });
+            !relations.is_empty()
+        });
+        if relations_map.is_empty() {
+            return false;
+        }
+
+        log::debug!("{:?} redispatch shared message to {:?}, {:?}", self.id, relations_map, publish);
+        //the message is kept by this session if it is not forwarded
+        if let Err(errs) = Runtime::instance()
+            .extends
+            .shared()
+            .await
+            .forwards_to_nodes(from.clone(), publish, relations_map)
+            .await
+        {
+            for (to, _, _, reason) in errs {
+                log::warn!("{:?} redispatch shared message to {:?} error, {}", self.id, to, reason);
+            }
+            return false;
+        }
+        true
+    }
+
     #[inline]
     pub(crate) async fn forward(&self, from: From, p: Publish) {
         let res = if let Some(ref tx) = self.tx {
@@ -580,7 +749,8 @@ impl SessionState {
                 let p = Publish::try_from(lw)?;
                 let from = From::from_lastwill(self.id.clone());
                 //hook, message_publish
-                let p = self.hook.message_publish(from.clone(), &p).await.unwrap_or(p);
+                let mut p = self.hook.message_publish(from.clone(), &p).await.unwrap_or(p);
+                p.topic = self.mount(p.topic);
                 log::debug!("process_last_will, publish: {:?}", p);
 
                 let listen_cfg = self.listen_cfg();
@@ -748,9 +918,22 @@ impl SessionState {
         //hook, message_delivered
         let publish = self.hook.message_delivered(from.clone(), &publish).await.unwrap_or(publish);
 
-        //send message
+        //send message, the mountpoint is stripped from the topic, the message out of the mountpoint
+        //is not delivered to the session
+        let unmounted = match (self.mountpoint.is_some(), self.unmount(&publish)) {
+            (true, None) => {
+                Runtime::instance()
+                    .extends
+                    .hook_mgr()
+                    .await
+                    .message_dropped(Some(self.id.clone()), from, publish, Reason::MessageNotMounted)
+                    .await;
+                return Ok(());
+            }
+            (_, unmounted) => unmounted,
+        };
         sink.publish(
-            &publish,
+            unmounted.as_ref().unwrap_or(&publish),
             expiry_check_res.message_expiry_interval(),
             self.server_topic_aliases.as_ref(),
         )
@@ -810,7 +993,7 @@ impl SessionState {
 
                 //rerelease
                 let release_packet = match sink {
-                    Sink::V3(_) => iflt_msg.release_packet_v3(),
+                    Sink::V3(_) | Sink::Gateway(_) => iflt_msg.release_packet_v3(),
                     Sink::V5(_) => iflt_msg.release_packet_v5(),
                 };
                 if let Some(release_packet) = release_packet {
@@ -872,7 +1055,7 @@ impl SessionState {
                 .router()
                 .await
                 .relations()
-                .get(&sub.topic_filter)
+                .get(&self.mount(sub.topic_filter.clone()))
                 .map(|rels| {
                     if rels.value().contains_key(&self.id.client_id) {
                         (true, rels.value().len() - 1)
@@ -911,6 +1094,8 @@ impl SessionState {
             }
         }
 
+        sub.topic_filter = self.mount(sub.topic_filter);
+
         //subscribe
         let sub_ret =
             Runtime::instance().extends.shared().await.entry(self.id.clone()).subscribe(&sub).await?;
@@ -971,6 +1156,7 @@ impl SessionState {
             unsub.topic_filter = topic_filter;
             log::debug!("{:?} adjust topic_filter: {:?}", self.id, unsub.topic_filter);
         }
+        unsub.topic_filter = self.mount(unsub.topic_filter);
         let ok =
             Runtime::instance().extends.shared().await.entry(self.id.clone()).unsubscribe(&unsub).await?;
         if ok {
@@ -1020,6 +1206,27 @@ impl SessionState {
         }
     }
 
+    ///Publish of the gateway client, see [`crate::broker::gateway`]
+    #[inline]
+    pub async fn publish_gateway(&self, publish: Publish) -> Result<bool> {
+        match self.publish(publish).await {
+            Err(e) => {
+                Metrics::instance().client_publish_error_inc();
+                if let Err(e) =
+                    self.disconnected_reason_add(Reason::PublishFailed(ByteString::from(e.to_string()))).await
+                {
+                    log::error!("{:?} disconnected reason add error: {:?}", self.id, e);
+                }
+                Err(e)
+            }
+            Ok(false) => {
+                Metrics::instance().client_publish_error_inc();
+                Ok(false)
+            }
+            Ok(true) => Ok(true),
+        }
+    }
+
     #[inline]
     async fn _publish_v5(&self, publish: &v5::Publish) -> Result<bool> {
         log::debug!("{:?} publish: {:?}", self.id, publish);
@@ -1035,12 +1242,48 @@ impl SessionState {
         let from = From::from_custom(self.id.clone());
 
         let listen_cfg = self.listen_cfg();
+
+        //publish rate limit
+        if let Some(limiter) = &self.publish_limiter {
+            let size = publish.topic.len() + publish.payload.len();
+            if matches!(listen_cfg.publish_limit_action, PublishLimitAction::Throttle) {
+                //the socket reads of the connection are paused until the message is allowed
+                let read_pause = self.extra_attrs.read().await.get::<ReadPause>(READ_PAUSE_KEY).cloned();
+                let _guard = read_pause.as_ref().map(|p| p.pause());
+                limiter.until_ready(size).await;
+            } else if let Err(exceeded) = limiter.check(size) {
+                if matches!(listen_cfg.publish_limit_action, PublishLimitAction::Disconnect) {
+                    log::warn!("{:?} publish limit exceeded, {:?}, disconnect", self.id, exceeded);
+                    self.disconnected_reason_add(Reason::PublishFailed(ByteString::from(format!(
+                        "publish limit exceeded, {:?}",
+                        exceeded
+                    ))))
+                    .await?;
+                    if let Some(sink) = &self.sink {
+                        sink.close_with_reason_code(match exceeded {
+                            PublishLimitExceeded::MessageRate => DisconnectReasonCode::MessageRateTooHigh,
+                            PublishLimitExceeded::ByteRate => DisconnectReasonCode::QuotaExceeded,
+                        });
+                    }
+                } else {
+                    //hook, Message dropped
+                    Runtime::instance()
+                        .extends
+                        .hook_mgr()
+                        .await
+                        .message_dropped(None, from, publish, Reason::PublishRateLimited)
+                        .await;
+                }
+                return Ok(false);
+            }
+        }
+
         if self.listen_cfg().delayed_publish {
             publish = Runtime::instance().extends.delayed_sender().await.parse(publish)?;
         }
 
         //hook, message_publish
-        let publish = self.hook.message_publish(from.clone(), &publish).await.unwrap_or(publish);
+        let mut publish = self.hook.message_publish(from.clone(), &publish).await.unwrap_or(publish);
 
         //hook, message_publish_check_acl
         let acl_result = self.hook.message_publish_check_acl(&publish).await;
@@ -1063,6 +1306,8 @@ impl SessionState {
             };
         }
 
+        publish.topic = self.mount(publish.topic);
+
         let message_storage_available = Runtime::instance().extends.message_mgr().await.enable();
 
         let message_expiry_interval =
@@ -1363,6 +1608,8 @@ pub struct _Session {
     pub fitter: FitterType,
     pub auth_info: Option<AuthInfo>,
     pub extra_attrs: RwLock<ExtraAttrs>,
+    ///Prefix of the topics of the client, the placeholders are replaced
+    pub mountpoint: Option<TopicName>,
 }
 
 impl Deref for _Session {
@@ -1393,6 +1640,40 @@ impl std::fmt::Debug for Session {
     }
 }
 
+#[inline]
+fn replace_mountpoint(mountpoint: &str, connect_info: &ConnectInfo) -> Result<TopicName> {
+    let invalid = |value: &str| value.is_empty() || value.contains(['+', '#', '/']);
+    if mountpoint.contains(acl::PLACEHOLDER_USERNAME) && connect_info.username().is_some_and(|u| invalid(u)) {
+        return Err(MqttError::from(format!(
+            "the username {:?} can not be used in the mountpoint",
+            connect_info.username()
+        )));
+    }
+    if mountpoint.contains(acl::PLACEHOLDER_CLIENTID) && invalid(connect_info.client_id()) {
+        return Err(MqttError::from(format!(
+            "the client id {:?} can not be used in the mountpoint",
+            connect_info.client_id()
+        )));
+    }
+    Ok(TopicName::from(acl::replaces(mountpoint, connect_info)?.as_ref()))
+}
+
+#[inline]
+fn mount(mountpoint: Option<&str>, topic: ByteString) -> ByteString {
+    match mountpoint {
+        Some(mountpoint) => ByteString::from(format!("{}{}", mountpoint, topic)),
+        None => topic,
+    }
+}
+
+#[inline]
+fn unmount(mountpoint: &str, publish: &Publish) -> Option<Publish> {
+    let topic = publish.topic.strip_prefix(mountpoint)?;
+    let mut publish = publish.clone();
+    publish.topic = ByteString::from(topic);
+    Some(publish)
+}
+
 impl Session {
     #[inline]
     #[allow(clippy::too_many_arguments)]
@@ -1416,6 +1697,7 @@ impl Session {
 
         last_id: Option<Id>,
     ) -> Result<Self> {
+        let mountpoint = Self::mountpoint(&listen_cfg, auth_info.as_ref(), &conn_info)?;
         let max_inflight = max_inflight.get() as usize;
         let message_retry_interval = listen_cfg.message_retry_interval.as_millis() as TimestampMillis;
         let message_expiry_interval = listen_cfg.message_expiry_interval.as_millis() as TimestampMillis;
@@ -1460,7 +1742,36 @@ impl Session {
                 last_id,
             )
             .await?;
-        Ok(Self(Arc::new(_Session { inner: session_like, id, fitter, auth_info, extra_attrs })))
+        Ok(Self(Arc::new(_Session { inner: session_like, id, fitter, auth_info, extra_attrs, mountpoint })))
+    }
+
+    ///The mountpoint of the client, the one of the auth plugins or the one of the listener. The connect
+    ///should be refused if it fails, such as a placeholder is replaced by an empty username or client id,
+    ///or one that contains "+", "#" or "/", which would mount the client out of its own topic tree
+    #[inline]
+    pub fn mountpoint(
+        listen_cfg: &Listener,
+        auth_info: Option<&AuthInfo>,
+        connect_info: &ConnectInfo,
+    ) -> Result<Option<TopicName>> {
+        auth_info
+            .and_then(|auth_info| auth_info.mountpoint.as_deref())
+            .or(listen_cfg.mountpoint.as_deref())
+            .filter(|mountpoint| !mountpoint.is_empty())
+            .map(|mountpoint| replace_mountpoint(mountpoint, connect_info))
+            .transpose()
+    }
+
+    ///Add the mountpoint to the topic or topic filter of the client
+    #[inline]
+    pub fn mount(&self, topic: ByteString) -> ByteString {
+        mount(self.mountpoint.as_deref(), topic)
+    }
+
+    ///Strip the mountpoint from the topic of the delivered message, None if it is not mounted
+    #[inline]
+    pub fn unmount(&self, publish: &Publish) -> Option<Publish> {
+        unmount(self.mountpoint.as_deref()?, publish)
     }
 
     #[inline]
@@ -1588,3 +1899,65 @@ pub trait SessionLike: Sync + Send {
     #[inline]
     async fn keepalive(&self, _ping: IsPing) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use ntex::util::Bytes;
+
+    use super::*;
+
+    fn connect_info(client_id: &str, username: Option<&str>) -> ConnectInfo {
+        let id = Id::new(1, None, None, ClientId::from(client_id), username.map(UserName::from));
+        let connect = ConnectV3 {
+            client_id: ClientId::from(client_id),
+            username: username.map(UserName::from),
+            ..Default::default()
+        };
+        ConnectInfo::V3(id, connect)
+    }
+
+    fn publish(topic: &str) -> Publish {
+        Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: TopicName::from(topic),
+            packet_id: None,
+            payload: Bytes::from_static(b"22.5"),
+            properties: Default::default(),
+            delay_interval: None,
+            create_time: 0,
+        }
+    }
+
+    #[test]
+    fn mountpoint() {
+        let conn_info = connect_info("dev1", Some("user1"));
+        assert_eq!(replace_mountpoint("tenant/${username}/", &conn_info).unwrap(), "tenant/user1/");
+        assert_eq!(replace_mountpoint("${clientid}/", &conn_info).unwrap(), "dev1/");
+        assert_eq!(replace_mountpoint("tenant/", &connect_info("a/b", Some("#"))).unwrap(), "tenant/");
+
+        //The placeholder values that would escape or widen the mountpoint are refused
+        for username in [None, Some(""), Some("a/b"), Some("+"), Some("#")] {
+            assert!(replace_mountpoint("${username}/", &connect_info("dev1", username)).is_err());
+        }
+        for client_id in ["", "a/b", "dev+", "dev#"] {
+            assert!(replace_mountpoint("${clientid}/", &connect_info(client_id, Some("user1"))).is_err());
+        }
+    }
+
+    #[test]
+    fn mount_unmount() {
+        let topic = ByteString::from("sensors/1");
+        assert_eq!(mount(None, topic.clone()), "sensors/1");
+        let mounted = mount(Some("tenant/user1/"), topic);
+        assert_eq!(mounted, "tenant/user1/sensors/1");
+
+        let p = unmount("tenant/user1/", &publish(&mounted)).unwrap();
+        assert_eq!(p.topic, "sensors/1");
+        assert_eq!(p.payload, Bytes::from_static(b"22.5"));
+        //The topics out of the mountpoint are not delivered
+        assert!(unmount("tenant/user1/", &publish("tenant/user2/sensors/1")).is_none());
+        assert!(unmount("tenant/user1/", &publish("sensors/1")).is_none());
+    }
+}
@@ -5,7 +5,7 @@ use std::fmt;
 use std::fmt::Display;
 use std::hash::Hash;
 use std::mem::{size_of, size_of_val};
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::num::{NonZeroU16, NonZeroU32};
 use std::ops::Deref;
 use std::rc::Rc;
@@ -46,6 +46,7 @@ pub use ntex_mqtt::v5::{
 use ntex_mqtt::TopicLevel;
 
 use crate::broker::fitter::Fitter;
+use crate::broker::gateway::GatewaySink;
 use crate::broker::inflight::Inflight;
 use crate::broker::queue::{Queue, Sender};
 use crate::broker::session::OfflineInfo;
@@ -367,6 +368,47 @@ pub enum AuthResult {
     NotAuthorized,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthStage {
+    ///CONNECT packet with Authentication Method
+    Connect,
+    ///AUTH packet with Re-authenticate reason code
+    Reauth,
+    ///AUTH packet with Continue Authentication reason code
+    Continue,
+}
+
+///Id of the network connection, it is kept in the connection attributes under [`CONN_ID_KEY`]
+pub type ConnId = u64;
+
+pub const CONN_ID_KEY: &str = "conn_id";
+
+#[inline]
+pub fn next_conn_id() -> ConnId {
+    static CONN_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+    CONN_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone)]
+pub struct ExtendedAuth {
+    ///The state of a multi-step exchange must be kept per connection, not per client id
+    pub conn_id: ConnId,
+    pub stage: AuthStage,
+    pub method: ByteString,
+    pub data: Option<Bytes>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExtendedAuthResult {
+    ///Authentication is not finished, the data is sent to the client in AUTH(Continue Authentication)
+    Continue(Option<Bytes>),
+    ///Authentication succeeded, the data is sent to the client in CONNACK or AUTH(Success)
+    Success(Superuser, Option<AuthInfo>, Option<Bytes>),
+    ///Authentication method is not supported
+    BadAuthenticationMethod,
+    NotAuthorized,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MessageExpiryCheckResult {
     Expiry,
@@ -1149,6 +1191,7 @@ impl Serialize for LastWill<'_> {
 pub enum Sink {
     V3(MqttSinkV3),
     V5(MqttSinkV5),
+    Gateway(GatewaySink),
 }
 
 impl Sink {
@@ -1159,6 +1202,36 @@ impl Sink {
                 s.close();
             }
             Sink::V5(s) => s.close(),
+            Sink::Gateway(s) => s.close(),
+        }
+    }
+
+    ///Close the connection, MQTT 5 clients receive a DISCONNECT with the reason code
+    #[inline]
+    pub(crate) fn close_with_reason_code(&self, reason_code: DisconnectReasonCode) {
+        match self {
+            Sink::V3(s) => s.close(),
+            Sink::V5(s) => s.close_with_reason(DisconnectV5::new(reason_code)),
+            Sink::Gateway(s) => s.close(),
+        }
+    }
+
+    ///Close the connection, MQTT 5 clients are asked to connect to another server
+    #[inline]
+    pub(crate) fn close_with_redirect(&self, server_moved: bool, server_reference: Option<ByteString>) {
+        match self {
+            Sink::V3(s) => s.close(),
+            Sink::Gateway(s) => s.close(),
+            Sink::V5(s) => {
+                let reason_code = if server_moved {
+                    DisconnectReasonCode::ServerMoved
+                } else {
+                    DisconnectReasonCode::UseAnotherServer
+                };
+                let mut d = DisconnectV5::new(reason_code);
+                d.server_reference = server_reference;
+                s.close_with_reason(d)
+            }
         }
     }
 
@@ -1170,7 +1243,7 @@ impl Sink {
         server_topic_aliases: Option<&Rc<ServerTopicAliases>>,
     ) -> Result<()> {
         let pkt = match self {
-            Sink::V3(_) => p.into_v3(),
+            Sink::V3(_) | Sink::Gateway(_) => p.into_v3(),
             Sink::V5(_) => p.into_v5(message_expiry_interval, server_topic_aliases).await,
         };
         self.send(pkt)
@@ -1193,6 +1266,11 @@ impl Sink {
                     return Err(MqttError::from(SendPacketError::Disconnected));
                 }
             }
+            Sink::Gateway(s) => {
+                if let Packet::V3(p) = p {
+                    s.send(p)?;
+                }
+            }
         }
         Ok(())
     }
@@ -1861,6 +1939,7 @@ impl StoredMessage {
 pub enum Message {
     Forward(From, Publish),
     Kick(oneshot::Sender<()>, Id, CleanStart, IsAdmin),
+    Drain(oneshot::Sender<()>),
     Disconnect(Disconnect),
     Closed(Reason),
     Keepalive(IsPing),
@@ -2142,6 +2221,12 @@ pub struct ExtraAttrs {
     attrs: HashMap<String, Box<dyn Any + Sync + Send>>,
 }
 
+impl fmt::Debug for ExtraAttrs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtraAttrs").field("keys", &self.attrs.keys().collect::<Vec<_>>()).finish()
+    }
+}
+
 impl Default for ExtraAttrs {
     fn default() -> Self {
         Self::new()
@@ -2193,6 +2278,12 @@ impl ExtraAttrs {
         self.attrs.entry(key).or_insert_with(|| Box::new(def_fn())).downcast_mut::<T>()
     }
 
+    ///Moves the attributes of other into self, the existing ones with the same key are replaced
+    #[inline]
+    pub fn extend(&mut self, other: ExtraAttrs) {
+        self.attrs.extend(other.attrs);
+    }
+
     #[inline]
     pub fn serialize_key<S, T>(&self, key: &str, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -2247,6 +2338,7 @@ bitflags! {
         const DisconnectReceived = 0b00000100;
         const CleanStart = 0b00001000;
         const Ping = 0b00010000;
+        const Drained = 0b00100000;
     }
 }
 
@@ -2289,6 +2381,7 @@ pub enum Reason {
     ConnectRemoteClose,
     ConnectKeepaliveTimeout,
     ConnectKicked(IsAdmin),
+    ConnectDrained,
     SessionExpiration,
     SubscribeFailed(Option<ByteString>),
     UnsubscribeFailed(Option<ByteString>),
@@ -2297,6 +2390,9 @@ pub enum Reason {
     DelayedPublishRefused,
     MessageExpiration,
     MessageQueueFull,
+    PublishRateLimited,
+    ///The topic of the delivered message is not under the mountpoint of the session
+    MessageNotMounted,
     PublishFailed(ByteString),
     ProtocolError(ByteString),
     Error(ByteString),
@@ -2382,6 +2478,9 @@ impl Display for Reason {
                     "Kicked" //kicked
                 }
             }
+            Reason::ConnectDrained => {
+                "Drained" //the node is draining
+            }
             Reason::SessionExpiration => {
                 "SessionExpiration" //session expiration
             }
@@ -2414,6 +2513,12 @@ impl Display for Reason {
             Reason::MessageQueueFull => {
                 "MessageQueueFull" //message deliver queue is full
             }
+            Reason::PublishRateLimited => {
+                "PublishRateLimited" //publish rate limit exceeded
+            }
+            Reason::MessageNotMounted => {
+                "MessageNotMounted" //message topic is not under the mountpoint
+            }
             Reason::PublishFailed(r) => return write!(f, "PublishFailed({})", r),
             Reason::Error(r) => r,
             Reason::ProtocolError(r) => return write!(f, "ProtocolError({})", r),
@@ -2540,6 +2645,165 @@ impl DisconnectInfo {
     }
 }
 
+///Banned client id, username, ip address or CIDR range
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BanKey {
+    ClientId(ClientId),
+    Username(UserName),
+    Ip(IpAddr),
+    Cidr(Cidr),
+}
+
+impl BanKey {
+    ///kind - clientid, username, ip or cidr
+    #[inline]
+    pub fn parse(kind: &str, who: &str) -> Result<Self> {
+        Ok(match kind {
+            "clientid" => BanKey::ClientId(ClientId::from(who)),
+            "username" => BanKey::Username(UserName::from(who)),
+            "ip" => BanKey::Ip(who.parse().map_err(|e| MqttError::from(format!("{}, {}", who, e)))?),
+            "cidr" => BanKey::Cidr(who.parse()?),
+            _ => return Err(MqttError::from(format!("unsupported ban type, {}", kind))),
+        })
+    }
+
+    #[inline]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BanKey::ClientId(_) => "clientid",
+            BanKey::Username(_) => "username",
+            BanKey::Ip(_) => "ip",
+            BanKey::Cidr(_) => "cidr",
+        }
+    }
+
+    #[inline]
+    pub fn who(&self) -> String {
+        match self {
+            BanKey::ClientId(client_id) => client_id.to_string(),
+            BanKey::Username(username) => username.to_string(),
+            BanKey::Ip(ip) => ip.to_string(),
+            BanKey::Cidr(cidr) => cidr.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub key: BanKey,
+    pub reason: Option<String>,
+    pub by: Option<String>,
+    pub at: Timestamp,
+    ///Expiry time, unit: seconds, None means never expires
+    pub until: Option<Timestamp>,
+}
+
+impl Ban {
+    #[inline]
+    pub fn new(key: BanKey, reason: Option<String>, by: Option<String>, until: Option<Timestamp>) -> Self {
+        Self { key, reason, by, at: timestamp_secs(), until }
+    }
+
+    #[inline]
+    pub fn is_expired(&self) -> bool {
+        self.until.map(|until| until <= timestamp_secs()).unwrap_or_default()
+    }
+
+    ///Whether the client is banned by this entry
+    #[inline]
+    pub fn is_match(&self, id: &Id) -> bool {
+        match &self.key {
+            BanKey::ClientId(client_id) => id.client_id == *client_id,
+            BanKey::Username(username) => id.username.as_ref() == Some(username),
+            BanKey::Ip(ip) => id.remote_addr.map(|addr| addr.ip() == *ip).unwrap_or_default(),
+            BanKey::Cidr(cidr) => id.remote_addr.map(|addr| cidr.contains(&addr.ip())).unwrap_or_default(),
+        }
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "as": self.key.kind(),
+            "who": self.key.who(),
+            "reason": self.reason,
+            "by": self.by,
+            "at": format_timestamp(self.at),
+            "until": self.until.map(format_timestamp),
+        })
+    }
+}
+
+///IP network, such as "192.168.1.0/24" or "fd00::/8"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    #[inline]
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => Self::v4_network(*ip, self.prefix) == net,
+            (IpAddr::V6(net), IpAddr::V6(ip)) => Self::v6_network(*ip, self.prefix) == net,
+            (IpAddr::V4(net), IpAddr::V6(ip)) => {
+                ip.to_ipv4_mapped().map(|ip| Self::v4_network(ip, self.prefix) == net).unwrap_or_default()
+            }
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+
+    #[inline]
+    fn v4_network(ip: Ipv4Addr, prefix: u8) -> Ipv4Addr {
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        Ipv4Addr::from(u32::from(ip) & mask)
+    }
+
+    #[inline]
+    fn v6_network(ip: Ipv6Addr, prefix: u8) -> Ipv6Addr {
+        let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+        Ipv6Addr::from(u128::from(ip) & mask)
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = MqttError;
+
+    #[inline]
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|e| MqttError::from(format!("invalid CIDR {}, {}", s, e)))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| MqttError::from(format!("invalid CIDR {}, bad prefix length", s)))?,
+            None => max_prefix,
+        };
+        let addr = match addr {
+            IpAddr::V4(ip) => IpAddr::V4(Self::v4_network(ip, prefix)),
+            IpAddr::V6(ip) => IpAddr::V6(Self::v6_network(ip, prefix)),
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
 #[inline]
 pub fn topic_size(topic: &Topic) -> usize {
     topic
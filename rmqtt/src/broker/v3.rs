@@ -7,18 +7,20 @@ use rust_box::task_exec_queue::LocalSpawnExt;
 use uuid::Uuid;
 
 use crate::broker::executor::{get_handshake_exec, is_too_many_unavailable, unavailable_stats};
+use crate::broker::flapping::FlappingDetector;
+use crate::broker::peer_cert::{self, PeerCertStatus, PEER_CERT_STATUS_KEY};
 use crate::broker::{inflight::MomentStatus, types::*};
 use crate::runtime::Runtime;
 use crate::settings::listener::Listener;
 use crate::{MqttError, Result, Session, SessionState};
 
+///Refuses the connect, the ack code can be changed by the client_connack hook
 #[inline]
-async fn refused_ack<Io>(
-    handshake: v3::Handshake<Io>,
+pub(crate) async fn refused(
     connect_info: &ConnectInfo,
     ack_code: ConnectAckReasonV3,
     reason: String,
-) -> v3::HandshakeAck<Io, SessionState> {
+) -> ConnectAckReasonV3 {
     if matches!(ack_code, ConnectAckReasonV3::ServiceUnavailable) {
         unavailable_stats().inc();
     }
@@ -35,7 +37,12 @@ async fn refused_ack<Io>(
         new_ack_code,
         reason
     );
-    new_ack_code.v3_error_ack(handshake)
+    match new_ack_code {
+        ConnectAckReason::V3(ack_code) if !matches!(ack_code, ConnectAckReasonV3::ConnectionAccepted) => {
+            ack_code
+        }
+        _ => ack_code,
+    }
 }
 
 #[inline]
@@ -44,6 +51,7 @@ pub async fn handshake<Io: 'static>(
     mut handshake: v3::Handshake<Io>,
     remote_addr: SocketAddr,
     local_addr: SocketAddr,
+    conn_attrs: ExtraAttrs,
 ) -> Result<v3::HandshakeAck<Io, SessionState>, MqttError> {
     log::debug!(
         "new Connection: local_addr: {:?}, remote: {:?}, {:?}, listen_cfg: {:?}",
@@ -69,6 +77,31 @@ pub async fn handshake<Io: 'static>(
         return Ok(ConnectAckReason::V3(ConnectAckReasonV3::ServiceUnavailable).v3_error_ack(handshake));
     }
 
+    //Reject the service if the node is draining.
+    if Runtime::instance().node.is_draining() {
+        log::info!(
+            "Connection Refused, handshake fail, reason: the node is draining, remote: {:?}",
+            remote_addr
+        );
+        return Ok(ConnectAckReason::V3(ConnectAckReasonV3::ServiceUnavailable).v3_error_ack(handshake));
+    }
+
+    //Use the fields of the TLS client certificate as the username or client id
+    let applied = {
+        let packet = handshake.packet_mut();
+        peer_cert::apply(
+            &conn_attrs,
+            listen_cfg.peer_cert_as_username,
+            listen_cfg.peer_cert_as_clientid,
+            &mut packet.username,
+            &mut packet.client_id,
+        )
+    };
+    if let Err(e) = applied {
+        log::warn!("Connection Refused, handshake fail, reason: {}, remote: {:?}", e, remote_addr);
+        return Ok(ConnectAckReason::V3(ConnectAckReasonV3::NotAuthorized).v3_error_ack(handshake));
+    }
+
     if handshake.packet().client_id.is_empty() {
         if handshake.packet().clean_session {
             handshake.packet_mut().client_id =
@@ -99,7 +132,7 @@ pub async fn handshake<Io: 'static>(
     Runtime::instance().stats.handshakings.max_max(handshake.handshakings());
 
     let exec = get_handshake_exec(local_addr.port(), listen_cfg.clone());
-    match _handshake(id.clone(), listen_cfg, handshake).spawn(&exec).result().await {
+    match _handshake(id.clone(), listen_cfg, handshake, conn_attrs).spawn(&exec).result().await {
         Ok(Ok(res)) => Ok(res),
         Ok(Err(e)) => {
             unavailable_stats().inc();
@@ -126,31 +159,83 @@ async fn _handshake<Io: 'static>(
     id: Id,
     listen_cfg: Listener,
     mut handshake: v3::Handshake<Io>,
+    conn_attrs: ExtraAttrs,
 ) -> Result<v3::HandshakeAck<Io, SessionState>, MqttError> {
-    let connect_info = Arc::new(ConnectInfo::V3(id.clone(), handshake.packet().clone()));
+    let sink = Sink::V3(handshake.sink());
+    match connect_session(id, listen_cfg, handshake.packet_mut(), conn_attrs, sink).await? {
+        Ok((state, session_present, keep_alive)) => {
+            Ok(handshake.ack(state, session_present).idle_timeout(keep_alive))
+        }
+        Err(ack_code) => Ok(ConnectAckReason::V3(ack_code).v3_error_ack(handshake)),
+    }
+}
+
+///Creates the session of the MQTT 3.1.1 connect, it is shared by the handshake of the MQTT clients and
+///the gateways. Returns the session, whether the session is present and the keepalive, or the ack code
+///of the refusal
+#[inline]
+pub(crate) async fn connect_session(
+    id: Id,
+    listen_cfg: Listener,
+    packet: &mut ConnectV3,
+    conn_attrs: ExtraAttrs,
+    sink: Sink,
+) -> Result<std::result::Result<(SessionState, bool, u16), ConnectAckReasonV3>> {
+    let connect_info = Arc::new(ConnectInfo::V3(id.clone(), packet.clone()));
 
     //hook, client connect
     let _ = Runtime::instance().extends.hook_mgr().await.client_connect(&connect_info).await;
 
     if listen_cfg.max_clientid_len > 0 && id.client_id.len() > listen_cfg.max_clientid_len {
-        return Ok(refused_ack(
-            handshake,
+        return Ok(Err(refused(
             &connect_info,
             ConnectAckReasonV3::IdentifierRejected,
             "client_id is too long".into(),
         )
-        .await);
+        .await));
     }
 
     let entry = Runtime::instance().extends.shared().await.entry(id.clone());
     let max_sessions = Runtime::instance().settings.mqtt.max_sessions;
     if max_sessions > 0 && Runtime::instance().stats.sessions.count() >= max_sessions && !entry.exist() {
-        return Ok(refused_ack(
-                    handshake,
+        return Ok(Err(refused(
                     &connect_info,
                     ConnectAckReasonV3::ServiceUnavailable,
                     format!("the number of sessions on the current node exceeds the limit, with a maximum of {} sessions allowed", max_sessions),
-                ).await);
+                ).await));
+    }
+
+    //Reject the banned client
+    if let Some(ban) = Runtime::instance().extends.ban_mgr().await.check(&id).await {
+        return Ok(Err(refused(
+            &connect_info,
+            ConnectAckReasonV3::NotAuthorized,
+            format!("banned, {}: {}, reason: {:?}", ban.key.kind(), ban.key.who(), ban.reason),
+        )
+        .await));
+    }
+
+    //Reject the client whose certificate is revoked
+    match conn_attrs.get::<PeerCertStatus>(PEER_CERT_STATUS_KEY) {
+        Some(PeerCertStatus::Revoked) => {
+            Runtime::instance().metrics.client_cert_revoked_inc();
+            return Ok(Err(refused(
+                &connect_info,
+                ConnectAckReasonV3::NotAuthorized,
+                peer_cert::revoked_reason(&conn_attrs),
+            )
+            .await));
+        }
+        Some(PeerCertStatus::CheckFailed(e)) => {
+            Runtime::instance().metrics.client_cert_check_error_inc();
+            return Ok(Err(refused(
+                &connect_info,
+                ConnectAckReasonV3::ServiceUnavailable,
+                format!("client certificate revocation check failed, {}", e),
+            )
+            .await));
+        }
+        None => {}
     }
 
     //hook, client authenticate
@@ -158,28 +243,37 @@ async fn _handshake<Io: 'static>(
         .extends
         .hook_mgr()
         .await
-        .client_authenticate(&connect_info, listen_cfg.allow_anonymous)
+        .client_authenticate(&connect_info, &conn_attrs, listen_cfg.allow_anonymous)
         .await;
     if !ack.success() {
-        if let ConnectAckReason::V3(ack) = ack {
-            return Ok(refused_ack(handshake, &connect_info, ack, "Authentication failed".into()).await);
-        } else {
-            unreachable!()
+        let ack = if let ConnectAckReason::V3(ack) = ack { ack } else { ConnectAckReasonV3::NotAuthorized };
+        return Ok(Err(refused(&connect_info, ack, "Authentication failed".into()).await));
+    }
+
+    //Flapping detection, only the authenticated connects are counted
+    match FlappingDetector::check(&connect_info).await {
+        Ok(Some(ban)) => {
+            return Ok(Err(refused(
+                &connect_info,
+                ConnectAckReasonV3::NotAuthorized,
+                format!("banned, {}: {}, reason: {:?}", ban.key.kind(), ban.key.who(), ban.reason),
+            )
+            .await));
         }
+        Ok(None) => {}
+        Err(e) => log::warn!("{:?} flapping detection error, {:?}", id, e),
     }
 
-    let sink = handshake.sink();
-    let packet = handshake.packet_mut();
+    //Reject the client whose username or client id can not be used in the mountpoint
+    if let Err(e) = Session::mountpoint(&listen_cfg, auth_info.as_ref(), &connect_info) {
+        return Ok(Err(refused(&connect_info, ConnectAckReasonV3::NotAuthorized, format!("{}", e)).await));
+    }
 
     let mut entry = match { Runtime::instance().extends.shared().await.entry(id.clone()) }.try_lock().await {
         Err(e) => {
-            return Ok(refused_ack(
-                handshake,
-                &connect_info,
-                ConnectAckReasonV3::ServiceUnavailable,
-                format!("{}", e),
-            )
-            .await);
+            return Ok(Err(
+                refused(&connect_info, ConnectAckReasonV3::ServiceUnavailable, format!("{}", e)).await
+            ));
         }
         Ok(entry) => entry,
     };
@@ -190,13 +284,9 @@ async fn _handshake<Io: 'static>(
         .await
     {
         Err(e) => {
-            return Ok(refused_ack(
-                handshake,
-                &connect_info,
-                ConnectAckReasonV3::ServiceUnavailable,
-                format!("{}", e),
-            )
-            .await);
+            return Ok(Err(
+                refused(&connect_info, ConnectAckReasonV3::ServiceUnavailable, format!("{}", e)).await
+            ));
         }
         Ok(OfflineSession::NotExist) => (false, false, None),
         Ok(OfflineSession::Exist(Some(offline_info))) => (!packet.clean_session, true, Some(offline_info)),
@@ -240,26 +330,28 @@ async fn _handshake<Io: 'static>(
     {
         Ok(s) => s,
         Err(e) => {
-            return Ok(refused_ack(
-                handshake,
+            return Ok(Err(refused(
                 connect_info.as_ref(),
                 ConnectAckReasonV3::ServiceUnavailable,
                 format!("{}", e),
             )
-            .await);
+            .await));
         }
     };
 
+    if !conn_attrs.is_empty() {
+        session.extra_attrs.write().await.extend(conn_attrs);
+    }
+
     let keep_alive = match session.fitter.keep_alive(&mut packet.keep_alive) {
         Ok(keep_alive) => keep_alive,
         Err(e) => {
-            return Ok(refused_ack(
-                handshake,
+            return Ok(Err(refused(
                 connect_info.as_ref(),
                 ConnectAckReasonV3::ServiceUnavailable,
                 format!("{:?}", e),
             )
-            .await);
+            .await));
         }
     };
 
@@ -270,15 +362,14 @@ async fn _handshake<Io: 'static>(
         hook.session_created().await;
     }
 
-    let (state, tx) = SessionState::new(session, Sink::V3(sink), hook, 0, 0).start(keep_alive).await?;
+    let (state, tx) = SessionState::new(session, sink, hook, 0, 0).start(keep_alive).await?;
     if let Err(e) = entry.set(state.session.clone(), tx).await {
-        return Ok(refused_ack(
-            handshake,
+        return Ok(Err(refused(
             connect_info.as_ref(),
             ConnectAckReasonV3::ServiceUnavailable,
             format!("{}", e),
         )
-        .await);
+        .await));
     }
 
     //hook, client connack
@@ -311,7 +402,7 @@ async fn _handshake<Io: 'static>(
         }
     }
 
-    Ok(handshake.ack(state, session_present).idle_timeout(keep_alive))
+    Ok(Ok((state, session_present, keep_alive)))
 }
 
 async fn subscribes(
@@ -3,14 +3,18 @@ use std::convert::From as _f;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
-use ntex_mqtt::v5::codec::{Auth, PublishAckReason};
+use ntex::util::Bytes;
+use ntex_mqtt::v5::codec::{Auth, AuthReasonCode, PublishAckReason};
 use ntex_mqtt::v5::PublishAck;
 use ntex_mqtt::v5::PublishResult;
 use rust_box::task_exec_queue::LocalSpawnExt;
 use uuid::Uuid;
 
 use crate::broker::executor::{get_handshake_exec, is_too_many_unavailable, unavailable_stats};
+use crate::broker::flapping::FlappingDetector;
+use crate::broker::peer_cert::{self, PeerCertStatus, PEER_CERT_STATUS_KEY};
 use crate::broker::{inflight::MomentStatus, types::*};
+use crate::settings::acl::AuthInfo;
 use crate::settings::listener::Listener;
 use crate::{MqttError, Result, Runtime, Session, SessionState};
 
@@ -46,6 +50,7 @@ pub async fn handshake<Io: 'static>(
     mut handshake: v5::Handshake<Io>,
     remote_addr: SocketAddr,
     local_addr: SocketAddr,
+    conn_attrs: ExtraAttrs,
 ) -> Result<v5::HandshakeAck<Io, SessionState>, MqttError> {
     log::debug!(
         "new Connection: local_addr: {:?}, remote: {:?}, {:?}, listen_cfg: {:?}",
@@ -71,6 +76,36 @@ pub async fn handshake<Io: 'static>(
         return Ok(ConnectAckReason::V5(ConnectAckReasonV5::ServerUnavailable).v5_error_ack(handshake));
     }
 
+    //Reject the service if the node is draining.
+    if Runtime::instance().node.is_draining() {
+        log::info!(
+            "Connection Refused, handshake fail, reason: the node is draining, remote: {:?}",
+            remote_addr
+        );
+        let ack_code = if Runtime::instance().settings.node.drain.server_moved {
+            ConnectAckReasonV5::ServerMoved
+        } else {
+            ConnectAckReasonV5::UseAnotherServer
+        };
+        return Ok(ConnectAckReason::V5(ack_code).v5_error_ack(handshake));
+    }
+
+    //Use the fields of the TLS client certificate as the username or client id
+    let applied = {
+        let packet = handshake.packet_mut();
+        peer_cert::apply(
+            &conn_attrs,
+            listen_cfg.peer_cert_as_username,
+            listen_cfg.peer_cert_as_clientid,
+            &mut packet.username,
+            &mut packet.client_id,
+        )
+    };
+    if let Err(e) = applied {
+        log::warn!("Connection Refused, handshake fail, reason: {}, remote: {:?}", e, remote_addr);
+        return Ok(ConnectAckReason::V5(ConnectAckReasonV5::NotAuthorized).v5_error_ack(handshake));
+    }
+
     let assigned_client_id = if handshake.packet().client_id.is_empty() {
         handshake.packet_mut().client_id =
             ClientId::from(Uuid::new_v4().as_simple().encode_lower(&mut Uuid::encode_buffer()).to_owned());
@@ -90,7 +125,11 @@ pub async fn handshake<Io: 'static>(
     Runtime::instance().stats.handshakings.max_max(handshake.handshakings());
 
     let exec = get_handshake_exec(local_addr.port(), listen_cfg.clone());
-    match _handshake(id.clone(), listen_cfg, handshake, assigned_client_id).spawn(&exec).result().await {
+    match _handshake(id.clone(), listen_cfg, handshake, assigned_client_id, conn_attrs)
+        .spawn(&exec)
+        .result()
+        .await
+    {
         Ok(Ok(res)) => Ok(res),
         Ok(Err(e)) => {
             unavailable_stats().inc();
@@ -113,6 +152,7 @@ pub async fn _handshake<Io: 'static>(
     listen_cfg: Listener,
     mut handshake: v5::Handshake<Io>,
     is_assigned_client_id: bool,
+    mut conn_attrs: ExtraAttrs,
 ) -> Result<v5::HandshakeAck<Io, SessionState>, MqttError> {
     let connect_info = Arc::new(ConnectInfo::V5(id.clone(), Box::new(handshake.packet().clone())));
     log::debug!("handshake.packet(): {:?}", handshake.packet());
@@ -129,17 +169,6 @@ pub async fn _handshake<Io: 'static>(
         .await);
     }
 
-    //Extended Auth is not supported
-    if handshake.packet().auth_method.is_some() {
-        return Ok(refused_ack(
-            handshake,
-            &connect_info,
-            ConnectAckReasonV5::BadAuthenticationMethod,
-            "extended Auth is not supported".into(),
-        )
-        .await);
-    }
-
     let entry = Runtime::instance().extends.shared().await.entry(id.clone());
     let max_sessions = Runtime::instance().settings.mqtt.max_sessions;
     if max_sessions > 0 && Runtime::instance().stats.sessions.count() >= max_sessions && !entry.exist() {
@@ -151,13 +180,64 @@ pub async fn _handshake<Io: 'static>(
                 ).await);
     }
 
+    //Reject the banned client
+    if let Some(ban) = Runtime::instance().extends.ban_mgr().await.check(&id).await {
+        return Ok(refused_ack(
+            handshake,
+            &connect_info,
+            ConnectAckReasonV5::Banned,
+            format!("banned, {}: {}, reason: {:?}", ban.key.kind(), ban.key.who(), ban.reason),
+        )
+        .await);
+    }
+
+    //Reject the client whose certificate is revoked, it is reported as Banned so that the client can
+    //tell it apart from a bad username or password
+    match conn_attrs.get::<PeerCertStatus>(PEER_CERT_STATUS_KEY) {
+        Some(PeerCertStatus::Revoked) => {
+            Runtime::instance().metrics.client_cert_revoked_inc();
+            return Ok(refused_ack(
+                handshake,
+                &connect_info,
+                ConnectAckReasonV5::Banned,
+                peer_cert::revoked_reason(&conn_attrs),
+            )
+            .await);
+        }
+        Some(PeerCertStatus::CheckFailed(e)) => {
+            Runtime::instance().metrics.client_cert_check_error_inc();
+            return Ok(refused_ack(
+                handshake,
+                &connect_info,
+                ConnectAckReasonV5::ServerUnavailable,
+                format!("client certificate revocation check failed, {}", e),
+            )
+            .await);
+        }
+        None => {}
+    }
+
     //hook, client authenticate
-    let (ack, superuser, auth_info) = Runtime::instance()
-        .extends
-        .hook_mgr()
-        .await
-        .client_authenticate(&connect_info, listen_cfg.allow_anonymous)
-        .await;
+    let mut auth_response = None;
+    let (ack, superuser, auth_info) = if let Some(auth_method) = handshake.packet().auth_method.clone() {
+        //extended authenticate
+        let conn_id = next_conn_id();
+        conn_attrs.insert(CONN_ID_KEY.into(), conn_id);
+        match extended_authenticate(conn_id, &handshake, &connect_info, auth_method.clone()).await {
+            Ok((superuser, auth_info, auth_data)) => {
+                auth_response = Some((auth_method, auth_data));
+                (ConnectAckReason::V5(ConnectAckReasonV5::Success), superuser, auth_info)
+            }
+            Err(ack) => (ConnectAckReason::V5(ack), false, None),
+        }
+    } else {
+        Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .client_authenticate(&connect_info, &conn_attrs, listen_cfg.allow_anonymous)
+            .await
+    };
     if !ack.success() {
         if let ConnectAckReason::V5(ack) = ack {
             return Ok(refused_ack(handshake, &connect_info, ack, "Authentication failed".into()).await);
@@ -166,6 +246,32 @@ pub async fn _handshake<Io: 'static>(
         }
     }
 
+    //Flapping detection, only the authenticated connects are counted
+    match FlappingDetector::check(&connect_info).await {
+        Ok(Some(ban)) => {
+            return Ok(refused_ack(
+                handshake,
+                &connect_info,
+                ConnectAckReasonV5::Banned,
+                format!("banned, {}: {}, reason: {:?}", ban.key.kind(), ban.key.who(), ban.reason),
+            )
+            .await);
+        }
+        Ok(None) => {}
+        Err(e) => log::warn!("{:?} flapping detection error, {:?}", id, e),
+    }
+
+    //Reject the client whose username or client id can not be used in the mountpoint
+    if let Err(e) = Session::mountpoint(&listen_cfg, auth_info.as_ref(), &connect_info) {
+        return Ok(refused_ack(
+            handshake,
+            &connect_info,
+            ConnectAckReasonV5::NotAuthorized,
+            format!("{}", e),
+        )
+        .await);
+    }
+
     let sink = handshake.sink();
     let packet = handshake.packet_mut();
 
@@ -246,6 +352,10 @@ pub async fn _handshake<Io: 'static>(
         }
     };
 
+    if !conn_attrs.is_empty() {
+        session.extra_attrs.write().await.extend(conn_attrs);
+    }
+
     let keep_alive = match session.fitter.keep_alive(&mut packet.keep_alive) {
         Ok(keep_alive) => keep_alive,
         Err(e) => {
@@ -342,10 +452,100 @@ pub async fn _handshake<Io: 'static>(
         ack.wildcard_subscription_available = Some(true);
         ack.subscription_identifiers_available = Some(true);
         ack.shared_subscription_available = Some(shared_subscription_available);
+        if let Some((auth_method, auth_data)) = auth_response {
+            ack.auth_method = Some(auth_method);
+            ack.auth_data = auth_data;
+        }
         log::debug!("{:?} handshake.ack: {:?}", id, ack);
     }))
 }
 
+#[inline]
+async fn extended_authenticate<Io>(
+    conn_id: ConnId,
+    handshake: &v5::Handshake<Io>,
+    connect_info: &ConnectInfo,
+    auth_method: ByteString,
+) -> std::result::Result<(Superuser, Option<AuthInfo>, Option<Bytes>), ConnectAckReasonV5> {
+    let auth = ExtendedAuth {
+        conn_id,
+        stage: AuthStage::Connect,
+        method: auth_method,
+        data: handshake.packet().auth_data.clone(),
+    };
+    let result =
+        Runtime::instance().extends.hook_mgr().await.client_authenticate_extended(connect_info, &auth).await;
+    match result {
+        ExtendedAuthResult::Success(superuser, auth_info, auth_data) => Ok((superuser, auth_info, auth_data)),
+        ExtendedAuthResult::Continue(_) => {
+            //the handshake cannot send AUTH packets before CONNACK, CONNECT must complete it
+            log::warn!(
+                "{:?} multi-step authentication is not supported in CONNECT, method: {:?}",
+                connect_info.id(),
+                auth.method
+            );
+            Err(ConnectAckReasonV5::BadAuthenticationMethod)
+        }
+        ExtendedAuthResult::BadAuthenticationMethod => Err(ConnectAckReasonV5::BadAuthenticationMethod),
+        ExtendedAuthResult::NotAuthorized => Err(ConnectAckReasonV5::NotAuthorized),
+    }
+}
+
+#[inline]
+async fn reauthenticate(state: &v5::Session<SessionState>, auth: &Auth) -> Result<Auth> {
+    let connect_info = state.connect_info().await?;
+    let auth_method = if let ConnectInfo::V5(_, connect) = connect_info.as_ref() {
+        connect.auth_method.clone()
+    } else {
+        None
+    };
+    //Re-authentication must use the same Authentication Method as in the CONNECT packet
+    let auth_method = match auth_method {
+        Some(auth_method) if auth.auth_method.as_ref() == Some(&auth_method) => auth_method,
+        _ => return Err(MqttError::from("Re-authentication failed, bad authentication method")),
+    };
+    let stage = match auth.reason_code {
+        AuthReasonCode::ReAuth => AuthStage::Reauth,
+        AuthReasonCode::ContinueAuth => AuthStage::Continue,
+        _ => return Err(MqttError::from("Re-authentication failed, invalid reason code")),
+    };
+    let conn_id = match state.extra_attrs.read().await.get::<ConnId>(CONN_ID_KEY) {
+        Some(conn_id) => *conn_id,
+        None => return Err(MqttError::from("Re-authentication failed, connection id does not exist")),
+    };
+    let auth = ExtendedAuth { conn_id, stage, method: auth_method.clone(), data: auth.auth_data.clone() };
+    let result = Runtime::instance()
+        .extends
+        .hook_mgr()
+        .await
+        .client_authenticate_extended(connect_info.as_ref(), &auth)
+        .await;
+    let (reason_code, auth_data) = match result {
+        ExtendedAuthResult::Continue(auth_data) => (AuthReasonCode::ContinueAuth, auth_data),
+        ExtendedAuthResult::Success(superuser, auth_info, auth_data) => {
+            //the privileges of the session are fixed when it connects, reconnect to change them
+            if superuser != state.superuser().await?
+                || auth_info.as_ref().map(|a| a.superuser) != state.auth_info.as_ref().map(|a| a.superuser)
+                || auth_info.as_ref().map(|a| &a.mountpoint)
+                    != state.auth_info.as_ref().map(|a| &a.mountpoint)
+            {
+                return Err(MqttError::from("Re-authentication failed, the privileges are changed"));
+            }
+            if auth_info.as_ref().map(|a| a.is_expired()).unwrap_or_default() {
+                return Err(MqttError::from("Re-authentication failed, the authentication info is expired"));
+            }
+            (AuthReasonCode::Success, auth_data)
+        }
+        ExtendedAuthResult::BadAuthenticationMethod => {
+            return Err(MqttError::from("Re-authentication failed, bad authentication method"))
+        }
+        ExtendedAuthResult::NotAuthorized => {
+            return Err(MqttError::from("Re-authentication failed, not authorized"))
+        }
+    };
+    Ok(Auth { reason_code, auth_method: Some(auth_method), auth_data, ..Default::default() })
+}
+
 async fn subscribes(
     state: &v5::Session<SessionState>,
     mut subs: v5::control::Subscribe,
@@ -390,7 +590,14 @@ pub async fn control_message<E: std::fmt::Debug>(
     let crs = match ctrl_msg {
         v5::ControlMessage::Auth(auth) => {
             let _ = state.send(Message::Keepalive(false));
-            auth.ack(Auth::default())
+            match reauthenticate(&state, auth.packet()).await {
+                Err(e) => {
+                    log::warn!("{:?} Re-authentication failed, reason: {}", state.id, e);
+                    state.disconnected_reason_add(Reason::from(e.to_string())).await?;
+                    return Err(e);
+                }
+                Ok(r) => auth.ack(r),
+            }
         }
         v5::ControlMessage::Ping(ping) => {
             let _ = state.send(Message::Keepalive(true));
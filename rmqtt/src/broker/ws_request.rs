@@ -0,0 +1,75 @@
+//! The HTTP upgrade request of a WebSocket connection, it is kept in the connection attributes
+//! under [`WS_REQUEST_KEY`], so the auth hooks can use its headers, such as cookies or X-Forwarded-For
+
+use serde::{Deserialize, Serialize};
+
+pub const WS_REQUEST_KEY: &str = "ws_request";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WsRequest {
+    pub path: String,
+    ///(name, value), the names are in lowercase
+    pub headers: Vec<(String, String)>,
+}
+
+impl WsRequest {
+    ///The first value of the header
+    #[inline]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    ///The value of the cookie, looked up in all Cookie headers
+    #[inline]
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, v)| v.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.trim_matches('"'))
+    }
+
+    ///The addresses of the X-Forwarded-For headers, the first one is the original client
+    #[inline]
+    pub fn forwarded_for(&self) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("x-forwarded-for"))
+            .flat_map(|(_, v)| v.split(','))
+            .map(|addr| addr.trim())
+            .filter(|addr| !addr.is_empty())
+            .collect()
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "path": self.path,
+            "headers": self.headers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers() {
+        let req = WsRequest {
+            path: "/mqtt".into(),
+            headers: vec![
+                ("cookie".into(), "a=1; session=\"abc\"".into()),
+                ("x-forwarded-for".into(), "10.0.0.1, 10.0.0.2".into()),
+                ("cookie".into(), "b=2".into()),
+            ],
+        };
+        assert_eq!(req.header("Cookie"), Some("a=1; session=\"abc\""));
+        assert_eq!(req.cookie("session"), Some("abc"));
+        assert_eq!(req.cookie("b"), Some("2"));
+        assert_eq!(req.cookie("c"), None);
+        assert_eq!(req.forwarded_for(), vec!["10.0.0.1", "10.0.0.2"]);
+    }
+}
@@ -2,14 +2,15 @@ use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use crate::broker::{
     default::{
-        DefaultAutoSubscription, DefaultDelayedSender, DefaultFitterManager, DefaultHookManager,
-        DefaultRetainStorage, DefaultRouter, DefaultSessionManager, DefaultShared, DefaultSharedSubscription,
+        DefaultAutoSubscription, DefaultBanManager, DefaultDelayedSender, DefaultFitterManager,
+        DefaultHookManager, DefaultRetainStorage, DefaultRouter, DefaultSessionManager, DefaultShared,
+        DefaultSharedSubscription,
     },
     fitter::FitterManager,
     hook::HookManager,
     session::SessionManager,
-    AutoSubscription, DefaultMessageManager, DelayedSender, MessageManager, RetainStorage, Router, Shared,
-    SharedSubscription,
+    AutoSubscription, BanManager, DefaultMessageManager, DelayedSender, MessageManager, RetainStorage,
+    Router, Shared, SharedSubscription,
 };
 
 // Defines a struct that manages a number of lock objects to different components that are
@@ -25,6 +26,7 @@ pub struct Manager {
     message_mgr: RwLock<Box<dyn MessageManager>>,
     delayed_sender: RwLock<Box<dyn DelayedSender>>,
     auto_subscription: RwLock<Box<dyn AutoSubscription>>,
+    ban_mgr: RwLock<Box<dyn BanManager>>,
 }
 
 impl Manager {
@@ -41,6 +43,7 @@ impl Manager {
             message_mgr: RwLock::new(Box::new(DefaultMessageManager::instance())),
             delayed_sender: RwLock::new(Box::new(DefaultDelayedSender::instance())),
             auto_subscription: RwLock::new(Box::new(DefaultAutoSubscription::instance())),
+            ban_mgr: RwLock::new(Box::new(DefaultBanManager::instance())),
         }
     }
 
@@ -138,4 +141,14 @@ impl Manager {
     pub async fn auto_subscription_mut(&self) -> RwLockWriteGuard<'_, Box<dyn AutoSubscription>> {
         self.auto_subscription.write().await
     }
+
+    #[inline]
+    pub async fn ban_mgr(&self) -> RwLockReadGuard<'_, Box<dyn BanManager>> {
+        self.ban_mgr.read().await
+    }
+
+    #[inline]
+    pub async fn ban_mgr_mut(&self) -> RwLockWriteGuard<'_, Box<dyn BanManager>> {
+        self.ban_mgr.write().await
+    }
 }
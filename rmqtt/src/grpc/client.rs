@@ -8,14 +8,34 @@ use std::time::Duration;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::sync::oneshot::Sender as OneshotSender;
 use tokio::sync::RwLock;
-use tonic::transport::{Channel, Endpoint};
+use tonic::metadata::{AsciiMetadataValue, MetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
 
 use crate::{MqttError, Result, Runtime};
 
 use super::pb::{self, node_service_client::NodeServiceClient};
 use super::{Message, MessageReply, MessageType};
 
-type NodeServiceClientType = NodeServiceClient<Channel>;
+type NodeServiceClientType = NodeServiceClient<InterceptedService<Channel, AuthInterceptor>>;
+
+///Attach the node cookie to every request
+#[derive(Clone)]
+struct AuthInterceptor {
+    cookie: AsciiMetadataValue,
+}
+
+impl Interceptor for AuthInterceptor {
+    #[inline]
+    fn call(
+        &mut self,
+        mut req: tonic::Request<()>,
+    ) -> std::result::Result<tonic::Request<()>, tonic::Status> {
+        req.metadata_mut().insert(super::AUTHORIZATION, self.cookie.clone());
+        Ok(req)
+    }
+}
 
 #[derive(Clone)]
 pub struct NodeGrpcClient {
@@ -23,6 +43,7 @@ pub struct NodeGrpcClient {
     active_tasks: Arc<AtomicUsize>,
     channel_tasks: Arc<AtomicUsize>,
     endpoint: Endpoint,
+    auth: AuthInterceptor,
     tx: Sender<(MessageType, Message, OneshotSender<Result<MessageReply>>)>,
 }
 
@@ -31,19 +52,29 @@ impl NodeGrpcClient {
     #[inline]
     pub async fn new(server_addr: &str) -> Result<Self> {
         log::debug!("rpc.client_timeout: {:?}", Runtime::instance().settings.rpc.client_timeout);
-        let concurrency_limit = Runtime::instance().settings.rpc.client_concurrency_limit + 1;
-        let endpoint = Channel::from_shared(format!("http://{}", server_addr))
-            .map(|endpoint| {
-                endpoint
-                    .concurrency_limit(concurrency_limit)
-                    .timeout(Runtime::instance().settings.rpc.client_timeout)
-            })
+        let rpccfg = &Runtime::instance().settings.rpc;
+        let concurrency_limit = rpccfg.client_concurrency_limit + 1;
+        let scheme = if rpccfg.tls_enable() { "https" } else { "http" };
+        let mut endpoint = Channel::from_shared(format!("{}://{}", scheme, server_addr))
+            .map(|endpoint| endpoint.concurrency_limit(concurrency_limit).timeout(rpccfg.client_timeout))
             .map_err(anyhow::Error::new)?;
+        if let (Some(ca), Some(cert), Some(key)) = (&rpccfg.tls_ca, &rpccfg.tls_cert, &rpccfg.tls_key) {
+            let mut tls_config = ClientTlsConfig::new()
+                .ca_certificate(Certificate::from_pem(tokio::fs::read(ca).await?))
+                .identity(Identity::from_pem(tokio::fs::read(cert).await?, tokio::fs::read(key).await?));
+            if let Some(domain_name) = &rpccfg.tls_domain_name {
+                tls_config = tls_config.domain_name(domain_name);
+            }
+            endpoint = endpoint.tls_config(tls_config)?;
+        }
+        let cookie = MetadataValue::try_from(Runtime::instance().settings.node.cookie.as_str())
+            .map_err(|e| MqttError::from(format!("invalid node.cookie, {}", e)))?;
+        let auth = AuthInterceptor { cookie };
         let active_tasks = Arc::new(AtomicUsize::new(0));
         let channel_tasks = Arc::new(AtomicUsize::new(0));
         let grpc_client = Arc::new(RwLock::new(None));
         let (tx, rx) = channel(100_000);
-        let c = Self { grpc_client, active_tasks, channel_tasks, endpoint, tx };
+        let c = Self { grpc_client, active_tasks, channel_tasks, endpoint, auth, tx };
         c.start(rx);
         Ok(c)
     }
@@ -59,13 +90,13 @@ impl NodeGrpcClient {
     }
 
     #[inline]
-    async fn _connect(endpoint: &Endpoint) -> Result<NodeServiceClientType> {
+    async fn _connect(endpoint: &Endpoint, auth: AuthInterceptor) -> Result<NodeServiceClientType> {
         let channel =
             tokio::time::timeout(Runtime::instance().settings.rpc.client_timeout, endpoint.connect())
                 .await
                 .map_err(anyhow::Error::new)?
                 .map_err(anyhow::Error::new)?;
-        let client = NodeServiceClient::new(channel);
+        let client = NodeServiceClient::with_interceptor(channel, auth);
         Ok(client)
     }
 
@@ -74,7 +105,7 @@ impl NodeGrpcClient {
         if let Some(c) = self.grpc_client.read().await.as_ref() {
             return Ok(c.clone());
         }
-        let c = Self::_connect(&self.endpoint).await?;
+        let c = Self::_connect(&self.endpoint, self.auth.clone()).await?;
         self.grpc_client.write().await.replace(c.clone());
         Ok(c)
     }
@@ -7,8 +7,8 @@ use futures::FutureExt;
 use client::NodeGrpcClient;
 
 use crate::broker::types::{
-    CleanStart, ClearSubscriptions, From, Id, IsAdmin, NodeId, Publish, Retain, Route, SessionStatus,
-    SubsSearchParams, SubsSearchResult, TopicFilter, TopicName,
+    Ban, BanKey, CleanStart, ClearSubscriptions, From, Id, IsAdmin, NodeId, Publish, Retain, Route,
+    SessionStatus, SubsSearchParams, SubsSearchResult, TopicFilter, TopicName,
 };
 use crate::{
     Addr, ClientId, MsgID, OfflineSession, Result, SharedGroup, SubRelations, SubRelationsMap,
@@ -27,6 +27,10 @@ pub(crate) mod pb {
 pub type MessageType = u64;
 
 pub const MESSAGE_TYPE_MESSAGE_GET: u64 = 22;
+pub const MESSAGE_TYPE_BAN: u64 = 23;
+
+///Metadata key of the node cookie, checked on every gRPC request
+pub(crate) const AUTHORIZATION: &str = "authorization";
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Message {
@@ -44,6 +48,9 @@ pub enum Message {
     SessionStatus(ClientId),
     MessageGet(ClientId, TopicFilter, Option<SharedGroup>),
     Data(Vec<u8>),
+    BanAdd(Ban),
+    BanRemove(BanKey),
+    BanList,
 }
 
 impl Message {
@@ -74,6 +81,7 @@ pub enum MessageReply {
     SessionStatus(Option<SessionStatus>),
     MessageGet(Vec<(MsgID, From, Publish)>),
     Data(Vec<u8>),
+    BanList(Vec<Ban>),
 }
 
 impl MessageReply {
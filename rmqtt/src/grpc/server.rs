@@ -1,16 +1,18 @@
-use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::atomic::{AtomicI64, AtomicIsize, Ordering};
 use std::sync::Arc;
 
 use once_cell::sync::Lazy;
-use tonic::{transport, Response};
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use tonic::{transport, Request, Response, Status};
 
-use crate::{Result, Runtime};
+use crate::broker::ban;
+use crate::{timestamp_secs, Result, Runtime};
 
 use super::pb::{
     self,
     node_service_server::{NodeService, NodeServiceServer},
 };
-use super::{Message, MessageReply, MessageType, MESSAGE_TYPE_MESSAGE_GET};
+use super::{Message, MessageReply, MessageType, MESSAGE_TYPE_BAN, MESSAGE_TYPE_MESSAGE_GET};
 
 pub struct Server {}
 
@@ -23,17 +25,34 @@ impl Server {
         //start grpc server
 
         let rpccfg = Runtime::instance().settings.rpc.clone();
-
-        //NodeServiceServer::with_interceptor(RmqttNodeService::default(), Self::check_auth)
+        let node = &Runtime::instance().settings.node;
+        if node.is_default_cookie() {
+            log::warn!(
+                "node.cookie is the default value, any host that can reach the gRPC server can act as \
+                 a node, set a secret node.cookie for the cluster"
+            );
+        }
+        if node.cookie_optional {
+            log::warn!("node.cookie_optional is enabled, the gRPC requests without cookie are accepted");
+        }
 
         log::info!(
-            "gRPC server is listening on tcp://{:?}, reuseaddr: {}, reuseport: {}",
+            "gRPC server is listening on {}://{:?}, reuseaddr: {}, reuseport: {}",
+            if rpccfg.tls_enable() { "tls" } else { "tcp" },
             rpccfg.server_addr,
             rpccfg.reuseaddr,
             rpccfg.reuseport
         );
-        let server =
-            transport::Server::builder().add_service(NodeServiceServer::new(NodeGrpcService::default()));
+        let mut builder = transport::Server::builder();
+        if let (Some(ca), Some(cert), Some(key)) = (&rpccfg.tls_ca, &rpccfg.tls_cert, &rpccfg.tls_key) {
+            //the client certificate is required and verified by the CA
+            let tls_config = ServerTlsConfig::new()
+                .identity(Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?))
+                .client_ca_root(Certificate::from_pem(std::fs::read(ca)?));
+            builder = builder.tls_config(tls_config)?;
+        }
+        let server = builder
+            .add_service(NodeServiceServer::with_interceptor(NodeGrpcService::default(), Self::check_auth));
 
         if rpccfg.reuseaddr || rpccfg.reuseport {
             let listener = tokio_stream::wrappers::TcpListenerStream::new(tokio::net::TcpListener::from_std(
@@ -46,16 +65,47 @@ impl Server {
         Ok(())
     }
 
-    // fn check_auth(req: Request<()>) -> std::result::Result<Request<()>, Status> {
-    //     log::debug!("check_auth, req: {:?}", req);
-    //
-    //     let token = MetadataValue::from_str(Runtime::instance().settings.node.cookie.as_str())
-    //         .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))?;
-    //     match req.metadata().get("authorization") {
-    //         Some(t) if token == t => Ok(req),
-    //         _ => Err(Status::unauthenticated("No valid auth token")),
-    //     }
-    // }
+    fn check_auth(req: Request<()>) -> std::result::Result<Request<()>, Status> {
+        let node = &Runtime::instance().settings.node;
+        let authorized = req
+            .metadata()
+            .get(super::AUTHORIZATION)
+            .map(|t| constant_time_eq(t.as_bytes(), node.cookie.as_bytes()));
+        match authorized {
+            Some(true) => Ok(req),
+            //Transition mode, the nodes without the cookie are not upgraded yet
+            None if node.cookie_optional => {
+                Runtime::instance().metrics.grpc_auth_missing_inc();
+                Self::warn_missing_cookie(&req);
+                Ok(req)
+            }
+            t => {
+                Runtime::instance().metrics.grpc_auth_failed_inc();
+                log::warn!(
+                    "gRPC authentication failed, remote addr: {:?}, {}",
+                    req.remote_addr(),
+                    if t.is_some() { "invalid cookie" } else { "no cookie" }
+                );
+                Err(Status::unauthenticated("No valid auth token"))
+            }
+        }
+    }
+
+    ///Warn at most once a minute, the requests between the nodes are frequent
+    fn warn_missing_cookie(req: &Request<()>) {
+        static LAST_WARNED: AtomicI64 = AtomicI64::new(0);
+        let now = timestamp_secs();
+        let last = LAST_WARNED.load(Ordering::Relaxed);
+        if now - last >= 60
+            && LAST_WARNED.compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+        {
+            log::warn!(
+                "gRPC request without cookie is accepted, remote addr: {:?}, disable node.cookie_optional \
+                 when all the nodes are upgraded",
+                req.remote_addr()
+            );
+        }
+    }
 
     #[inline]
     pub fn bind(
@@ -95,6 +145,22 @@ impl NodeGrpcService {
                     Ok(msgs) => Ok(MessageReply::MessageGet(msgs)),
                 }
             }
+            (MESSAGE_TYPE_BAN, Message::BanAdd(ban)) => match ban::apply(ban).await {
+                Err(e) => Ok(MessageReply::Error(e.to_string())),
+                Ok(()) => Ok(MessageReply::Success),
+            },
+            (MESSAGE_TYPE_BAN, Message::BanRemove(key)) => {
+                match Runtime::instance().extends.ban_mgr().await.remove(&key).await {
+                    Err(e) => Ok(MessageReply::Error(e.to_string())),
+                    Ok(_) => Ok(MessageReply::Success),
+                }
+            }
+            (MESSAGE_TYPE_BAN, Message::BanList) => {
+                match Runtime::instance().extends.ban_mgr().await.list().await {
+                    Err(e) => Ok(MessageReply::Error(e.to_string())),
+                    Ok(bans) => Ok(MessageReply::BanList(bans)),
+                }
+            }
             (_, msg) => Runtime::instance().extends.hook_mgr().await.grpc_message_received(typ, msg).await,
         }
     }
@@ -146,6 +212,14 @@ impl NodeService for NodeGrpcService {
     }
 }
 
+#[inline]
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 pub static ACTIVE_REQUEST_COUNT: Lazy<Arc<AtomicIsize>> = Lazy::new(|| Arc::new(AtomicIsize::new(0)));
 
 pub fn active_grpc_requests() -> isize {
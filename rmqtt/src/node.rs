@@ -5,7 +5,9 @@ use std::time::{Duration, Instant};
 use once_cell::sync::Lazy;
 use rust_box::std_ext::RwLock;
 use systemstat::Platform;
+use tokio::sync::{oneshot, watch};
 
+use crate::broker::types::Message;
 use crate::grpc::client::NodeGrpcClient;
 use crate::grpc::server::Server;
 use crate::{NodeId, Result, Runtime};
@@ -18,11 +20,16 @@ mod version {
 pub struct Node {
     pub start_time: chrono::DateTime<chrono::Local>,
     cpuload: AtomicI64,
+    draining: watch::Sender<bool>,
 }
 
 impl Node {
     pub(crate) fn new() -> Self {
-        Self { start_time: chrono::Local::now(), cpuload: AtomicI64::new(0) }
+        Self {
+            start_time: chrono::Local::now(),
+            cpuload: AtomicI64::new(0),
+            draining: watch::channel(false).0,
+        }
     }
 
     #[inline]
@@ -62,6 +69,67 @@ impl Node {
         });
     }
 
+    ///Start draining the node, returns false if it is already draining
+    #[inline]
+    pub fn drain(&self) -> bool {
+        self.draining.send_if_modified(|draining| !std::mem::replace(draining, true))
+    }
+
+    #[inline]
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    ///Wait until the node starts draining
+    #[inline]
+    pub async fn draining(&self) {
+        let mut rx = self.draining.subscribe();
+        while !*rx.borrow_and_update() {
+            if rx.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+
+    ///Disconnect all local online clients in batches and wait until their sessions are taken offline,
+    ///MQTT 5 clients receive a DISCONNECT with the UseAnotherServer or ServerMoved reason code.
+    pub async fn drain_sessions(&self) {
+        let cfg = &Runtime::instance().settings.node.drain;
+        let now = Instant::now();
+        let entries = Runtime::instance().extends.shared().await.iter().collect::<Vec<_>>();
+        let mut txs = Vec::new();
+        for entry in entries {
+            if entry.is_connected().await {
+                if let Some(tx) = entry.tx() {
+                    txs.push((entry.id(), tx));
+                }
+            }
+        }
+        log::info!("draining, {} clients will be disconnected", txs.len());
+
+        let mut dones = Vec::with_capacity(txs.len());
+        for (i, batch) in txs.chunks(cfg.batch_size.max(1)).enumerate() {
+            if i > 0 {
+                tokio::time::sleep(cfg.batch_interval).await;
+            }
+            for (id, tx) in batch {
+                let (done_tx, done_rx) = oneshot::channel();
+                if let Err(e) = tx.unbounded_send(Message::Drain(done_tx)) {
+                    log::debug!("{:?} send drain message error, {:?}", id, e);
+                } else {
+                    dones.push(done_rx);
+                }
+            }
+            log::info!("draining, {} clients have been disconnected", dones.len());
+        }
+
+        let remaining = cfg.timeout.saturating_sub(now.elapsed());
+        if tokio::time::timeout(remaining, futures::future::join_all(dones)).await.is_err() {
+            log::warn!("draining timeout, some sessions may not have been taken offline");
+        }
+        log::info!("draining is complete, cost time: {:?}", now.elapsed());
+    }
+
     #[inline]
     pub async fn status(&self) -> NodeStatus {
         NodeStatus::Running(1)
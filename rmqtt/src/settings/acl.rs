@@ -5,6 +5,7 @@ use std::time::Duration;
 use ntex_mqtt::v5::codec::SubscribeAckReason;
 
 use crate::broker::hook::{HookResult, ReturnType};
+use crate::settings::listener::PublishLimit;
 use crate::{anyhow::anyhow, serde_json, PublishAclResult, SubscribeAclResult};
 use crate::{timestamp, ConnectInfo, MqttError, Publish, QoS, Result, Subscribe};
 
@@ -18,6 +19,10 @@ pub struct AuthInfo {
     pub superuser: bool,
     pub expire_at: Option<Duration>,
     pub rules: Vec<Rule>,
+    ///Overrides the listener publish limits for this client
+    pub publish_limit: Option<PublishLimit>,
+    ///Overrides the listener mountpoint for this client
+    pub mountpoint: Option<String>,
 }
 
 impl AuthInfo {
@@ -274,7 +279,7 @@ impl Topic {
 }
 
 #[inline]
-fn replaces<'a>(topic_cfg: &'a str, connect_info: &ConnectInfo) -> Result<Cow<'a, str>> {
+pub(crate) fn replaces<'a>(topic_cfg: &'a str, connect_info: &ConnectInfo) -> Result<Cow<'a, str>> {
     let topic = match (topic_cfg.contains(PLACEHOLDER_USERNAME), topic_cfg.contains(PLACEHOLDER_CLIENTID)) {
         (true, true) => {
             if let Some(username) = connect_info.username() {
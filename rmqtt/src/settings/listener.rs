@@ -1,3 +1,4 @@
+use std::fmt;
 use std::net::SocketAddr;
 use std::num::{NonZeroU16, NonZeroU32};
 use std::ops::Deref;
@@ -5,11 +6,17 @@ use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
+use futures::channel::mpsc;
+use rust_box::std_ext::RwLock;
 use serde::de::{self, Deserialize, Deserializer};
+use serde::Serialize;
 
-use crate::broker::types::QoS;
+use crate::broker::ip_filter::{IpFilterRules, IpFilters};
+use crate::broker::peer_cert::PeerCertField;
+use crate::broker::types::{Cidr, DashMap, QoS};
+use crate::{MqttError, Result};
 
-use super::{deserialize_addr, deserialize_duration, to_duration, Bytesize};
+use super::{deserialize_addr, deserialize_cidrs, deserialize_duration, to_duration, Bytesize};
 
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 
@@ -33,19 +40,46 @@ pub struct Listeners {
     #[serde(default)]
     _wsss: HashMap<String, ListenerInner>,
 
+    #[serde(rename = "quic")]
+    #[serde(default)]
+    _quics: HashMap<String, ListenerInner>,
+
+    #[serde(rename = "unix")]
+    #[serde(default)]
+    _unixs: HashMap<String, ListenerInner>,
+
+    #[serde(rename = "mqttsn")]
+    #[serde(default)]
+    _mqttsns: HashMap<String, ListenerInner>,
+
+    #[serde(rename = "coap")]
+    #[serde(default)]
+    _coaps: HashMap<String, ListenerInner>,
+
     #[serde(default, skip)]
-    pub tcps: HashMap<Port, Listener>,
+    pub tcps: DashMap<Port, Listener>,
     #[serde(default, skip)]
-    pub tlss: HashMap<Port, Listener>,
+    pub tlss: DashMap<Port, Listener>,
     #[serde(default, skip)]
-    pub wss: HashMap<Port, Listener>,
+    pub wss: DashMap<Port, Listener>,
     #[serde(default, skip)]
-    pub wsss: HashMap<Port, Listener>,
+    pub wsss: DashMap<Port, Listener>,
+    #[serde(default, skip)]
+    pub quics: DashMap<Port, Listener>,
+    #[serde(default, skip)]
+    pub unixs: DashMap<String, Listener>,
+    #[serde(default, skip)]
+    pub mqttsns: DashMap<Port, Listener>,
+    #[serde(default, skip)]
+    pub coaps: DashMap<Port, Listener>,
+
+    #[serde(default, skip)]
+    watchers: Watchers,
 }
 
 impl Listeners {
     #[inline]
-    pub(crate) fn init(&mut self) {
+    pub(crate) fn init(&mut self) -> Result<()> {
         for (name, mut inner) in self._tcps.drain() {
             if inner.enable {
                 inner.name = name;
@@ -73,26 +107,284 @@ impl Listeners {
                 self.wsss.insert(inner.addr.port(), Listener::new(inner));
             }
         }
+
+        //Listeners are identified by the port, the udp port of the quic listener can not be
+        //shared with the other listeners
+        let quics = self._quics.drain().collect::<Vec<_>>();
+        for (name, mut inner) in quics {
+            if inner.enable {
+                if self.get(inner.addr.port()).is_some() {
+                    return Err(MqttError::from(format!(
+                        "quic listener {}, the port of addr {} is already used by another listener",
+                        name, inner.addr
+                    )));
+                }
+                inner.name = name;
+                self.quics.insert(inner.addr.port(), Listener::new(inner));
+            }
+        }
+
+        //The unix listeners are identified by the socket path, the addr is not bound
+        for (name, mut inner) in self._unixs.drain() {
+            if inner.enable {
+                let path = inner
+                    .path
+                    .clone()
+                    .ok_or_else(|| MqttError::from(format!("unix listener {}, path is not set", name)))?;
+                if self.unixs.contains_key(&path) {
+                    return Err(MqttError::from(format!(
+                        "unix listener {}, the path {} is already used by another listener",
+                        name, path
+                    )));
+                }
+                inner.name = name;
+                self.unixs.insert(path, Listener::new(inner));
+            }
+        }
+
+        let mqttsns = self._mqttsns.drain().collect::<Vec<_>>();
+        for (name, mut inner) in mqttsns {
+            if inner.enable {
+                if self.get(inner.addr.port()).is_some() {
+                    return Err(MqttError::from(format!(
+                        "mqttsn listener {}, the port of addr {} is already used by another listener",
+                        name, inner.addr
+                    )));
+                }
+                inner.name = name;
+                self.mqttsns.insert(inner.addr.port(), Listener::new(inner));
+            }
+        }
+
+        let coaps = self._coaps.drain().collect::<Vec<_>>();
+        for (name, mut inner) in coaps {
+            if inner.enable {
+                if self.get(inner.addr.port()).is_some() {
+                    return Err(MqttError::from(format!(
+                        "coap listener {}, the port of addr {} is already used by another listener",
+                        name, inner.addr
+                    )));
+                }
+                inner.name = name;
+                self.coaps.insert(inner.addr.port(), Listener::new(inner));
+            }
+        }
+        Ok(())
+    }
+
+    ///The listeners identified by the port, None for the unix listeners
+    #[inline]
+    fn listeners(&self, kind: ListenerKind) -> Option<&DashMap<Port, Listener>> {
+        match kind {
+            ListenerKind::Tcp => Some(&self.tcps),
+            ListenerKind::Tls => Some(&self.tlss),
+            ListenerKind::Ws => Some(&self.wss),
+            ListenerKind::Wss => Some(&self.wsss),
+            ListenerKind::Quic => Some(&self.quics),
+            ListenerKind::Unix => None,
+            ListenerKind::Mqttsn => Some(&self.mqttsns),
+            ListenerKind::Coap => Some(&self.coaps),
+        }
+    }
+
+    ///All listeners, sorted by the port, the unix listeners are at the end
+    #[inline]
+    pub fn all(&self) -> Vec<(ListenerKind, Listener)> {
+        let mut all = ListenerKind::ALL
+            .iter()
+            .filter_map(|kind| self.listeners(*kind).map(|listeners| (*kind, listeners)))
+            .flat_map(|(kind, listeners)| {
+                listeners.iter().map(|l| (kind, l.value().clone())).collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        all.sort_by_key(|(_, l)| l.addr.port());
+        let mut unixs =
+            self.unixs.iter().map(|l| (ListenerKind::Unix, l.value().clone())).collect::<Vec<_>>();
+        unixs.sort_by(|(_, l1), (_, l2)| l1.path.cmp(&l2.path));
+        all.extend(unixs);
+        all
+    }
+
+    #[inline]
+    pub fn kind(&self, id: &ListenerId) -> Option<ListenerKind> {
+        match id {
+            ListenerId::Port(port) => ListenerKind::ALL
+                .iter()
+                .find(|kind| self.listeners(**kind).map(|l| l.contains_key(port)).unwrap_or_default())
+                .copied(),
+            ListenerId::Path(path) => self.unixs.contains_key(path).then_some(ListenerKind::Unix),
+        }
+    }
+
+    #[inline]
+    fn insert(&self, kind: ListenerKind, id: ListenerId, listener: Listener) {
+        match (id, self.listeners(kind)) {
+            (ListenerId::Port(port), Some(listeners)) => {
+                listeners.insert(port, listener);
+            }
+            (ListenerId::Path(path), None) => {
+                self.unixs.insert(path, listener);
+            }
+            (id, _) => log::warn!("{} listener {} is not identified by {}", kind, listener.name, id),
+        }
+    }
+
+    ///Receives the listeners added, removed or updated at runtime, the server starts or stops them
+    #[inline]
+    pub fn watch(&self) -> mpsc::UnboundedReceiver<ListenerChange> {
+        let (tx, rx) = mpsc::unbounded();
+        self.watchers.0.write().push(tx);
+        rx
+    }
+
+    #[inline]
+    fn notify(&self, change: ListenerChange) {
+        self.watchers.0.write().retain(|tx| tx.unbounded_send(change.clone()).is_ok());
+    }
+
+    ///Add and start a listener at runtime
+    pub fn add(&self, kind: ListenerKind, name: String, mut inner: ListenerInner) -> Result<Listener> {
+        if kind == ListenerKind::Unix && inner.path.is_none() {
+            return Err(MqttError::from(format!("unix listener {}, path is not set", name)));
+        }
+        let id = ListenerId::new(kind, &inner);
+        if self.kind(&id).is_some() || (kind != ListenerKind::Unix && self.get(inner.addr.port()).is_some()) {
+            return Err(MqttError::from(format!(
+                "{} listener {}, {} is already used by another listener",
+                kind, name, id
+            )));
+        }
+        inner.name = name;
+        inner.enable = true;
+        let listener = Listener::new(inner);
+        self.insert(kind, id, listener.clone());
+        log::info!("{} listener {} is added, addr: {:?}", kind, listener.name, listener.addr);
+        self.notify(ListenerChange::Added(kind, listener.clone()));
+        Ok(listener)
+    }
+
+    ///Stop a listener at runtime
+    pub fn remove(&self, id: &ListenerId) -> Option<(ListenerKind, Listener)> {
+        let kind = self.kind(id)?;
+        let listener = match id {
+            ListenerId::Port(port) => self.listeners(kind)?.remove(port)?.1,
+            ListenerId::Path(path) => self.unixs.remove(path)?.1,
+        };
+        log::info!("{} listener {} is removed, addr: {:?}", kind, listener.name, listener.addr);
+        self.notify(ListenerChange::Removed(kind, listener.clone()));
+        Some((kind, listener))
+    }
+
+    ///Reconfigure a listener at runtime, the options bound to the running server can not be changed.
+    ///The new configuration applies to the subsequent connections, the existing sessions keep the old one.
+    ///If the options of [`ListenerInner::rebuild_required`] are changed, the server of the listener is
+    ///rebuilt, the connections of the old server are closed and the clients reconnect
+    pub fn update(&self, id: &ListenerId, mut inner: ListenerInner) -> Result<Listener> {
+        let (kind, old) = self
+            .kind(id)
+            .and_then(|kind| self.by_id(id).map(|l| (kind, l)))
+            .ok_or_else(|| MqttError::from(format!("listener {} is not found", id)))?;
+        let fields = old.restart_required(&inner);
+        if !fields.is_empty() {
+            return Err(MqttError::from(format!(
+                "{} listener {}, {} can not be changed at runtime, remove the listener and add it again",
+                kind,
+                old.name,
+                fields.join(", ")
+            )));
+        }
+        if let ListenerId::Port(port) = id {
+            if old.allow != inner.allow || old.deny != inner.deny {
+                let rules = IpFilterRules { allow: inner.allow.clone(), deny: inner.deny.clone() };
+                if let Err(e) = IpFilters::instance().set_rules(*port, rules) {
+                    log::warn!("{} listener {}, {:?}", kind, old.name, e);
+                }
+            }
+        }
+        inner.name.clone_from(&old.name);
+        inner.enable = true;
+        let listener = Listener::new(inner);
+        self.insert(kind, id.clone(), listener.clone());
+        log::info!("{} listener {} is updated, addr: {:?}", kind, listener.name, listener.addr);
+        self.notify(ListenerChange::Updated(kind, listener.clone()));
+        Ok(listener)
+    }
+
+    ///Apply the listeners of the reloaded configuration, only the added, removed or changed listeners
+    ///are applied. Returns the listeners that can not be applied, such as the options bound to the
+    ///running server are changed
+    pub fn apply(&self, listeners: &Listeners) -> Result<()> {
+        for (kind, l) in self.all() {
+            let id = ListenerId::new(kind, &l);
+            if listeners.kind(&id) != Some(kind) {
+                self.remove(&id);
+            }
+        }
+        let mut errs = Vec::new();
+        for (kind, l) in listeners.all() {
+            let id = ListenerId::new(kind, &l);
+            let res = match self.by_id(&id) {
+                Some(old) => {
+                    //The listener keeps its name when it is updated
+                    let mut inner = (*l).clone();
+                    inner.name.clone_from(&old.name);
+                    if *old == inner {
+                        continue;
+                    }
+                    self.update(&id, inner)
+                }
+                None => self.add(kind, l.name.clone(), (*l).clone()),
+            };
+            if let Err(e) = res {
+                log::warn!("apply {} listener {} failed, {}", kind, l.name, e);
+                errs.push(e.to_string());
+            }
+        }
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(MqttError::from(errs.join("; ")))
+        }
     }
 
     #[inline]
     pub fn tcp(&self, port: u16) -> Option<Listener> {
-        self.tcps.get(&port).cloned()
+        self.tcps.get(&port).map(|l| l.value().clone())
     }
 
     #[inline]
     pub fn tls(&self, port: u16) -> Option<Listener> {
-        self.tlss.get(&port).cloned()
+        self.tlss.get(&port).map(|l| l.value().clone())
     }
 
     #[inline]
     pub fn ws(&self, port: u16) -> Option<Listener> {
-        self.wss.get(&port).cloned()
+        self.wss.get(&port).map(|l| l.value().clone())
     }
 
     #[inline]
     pub fn wss(&self, port: u16) -> Option<Listener> {
-        self.wsss.get(&port).cloned()
+        self.wsss.get(&port).map(|l| l.value().clone())
+    }
+
+    #[inline]
+    pub fn quic(&self, port: u16) -> Option<Listener> {
+        self.quics.get(&port).map(|l| l.value().clone())
+    }
+
+    #[inline]
+    pub fn unix(&self, path: &str) -> Option<Listener> {
+        self.unixs.get(path).map(|l| l.value().clone())
+    }
+
+    #[inline]
+    pub fn mqttsn(&self, port: u16) -> Option<Listener> {
+        self.mqttsns.get(&port).map(|l| l.value().clone())
+    }
+
+    #[inline]
+    pub fn coap(&self, port: u16) -> Option<Listener> {
+        self.coaps.get(&port).map(|l| l.value().clone())
     }
 
     #[inline]
@@ -109,16 +401,156 @@ impl Listeners {
         if let Some(l) = self.wss(port) {
             return Some(l);
         }
+        if let Some(l) = self.quic(port) {
+            return Some(l);
+        }
+        if let Some(l) = self.mqttsn(port) {
+            return Some(l);
+        }
+        if let Some(l) = self.coap(port) {
+            return Some(l);
+        }
         None
     }
 
     #[inline]
-    pub(crate) fn set_default(&mut self) {
+    pub fn by_id(&self, id: &ListenerId) -> Option<Listener> {
+        match id {
+            ListenerId::Port(port) => self.get(*port),
+            ListenerId::Path(path) => self.unix(path),
+        }
+    }
+
+    ///The listener of the connections with the local addr, the connections of the unix listeners
+    ///report the addr of the listener
+    #[inline]
+    pub fn by_local_addr(&self, local_addr: &SocketAddr) -> Option<Listener> {
+        self.unixs
+            .iter()
+            .find(|l| l.addr == *local_addr)
+            .map(|l| l.value().clone())
+            .or_else(|| self.get(local_addr.port()))
+    }
+
+    #[inline]
+    pub(crate) fn set_default(&self) {
         let inner = Listener::default();
         self.tcps.insert(inner.addr.port(), inner);
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerKind {
+    Tcp,
+    Tls,
+    Ws,
+    Wss,
+    Quic,
+    Unix,
+    Mqttsn,
+    Coap,
+}
+
+impl ListenerKind {
+    pub const ALL: [ListenerKind; 8] = [
+        ListenerKind::Tcp,
+        ListenerKind::Tls,
+        ListenerKind::Ws,
+        ListenerKind::Wss,
+        ListenerKind::Quic,
+        ListenerKind::Unix,
+        ListenerKind::Mqttsn,
+        ListenerKind::Coap,
+    ];
+
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListenerKind::Tcp => "tcp",
+            ListenerKind::Tls => "tls",
+            ListenerKind::Ws => "ws",
+            ListenerKind::Wss => "wss",
+            ListenerKind::Quic => "quic",
+            ListenerKind::Unix => "unix",
+            ListenerKind::Mqttsn => "mqttsn",
+            ListenerKind::Coap => "coap",
+        }
+    }
+}
+
+impl FromStr for ListenerKind {
+    type Err = MqttError;
+
+    #[inline]
+    fn from_str(s: &str) -> Result<Self> {
+        ListenerKind::ALL
+            .iter()
+            .find(|kind| kind.as_str() == s)
+            .copied()
+            .ok_or_else(|| MqttError::from(format!("unknown listener kind, {}", s)))
+    }
+}
+
+impl fmt::Display for ListenerKind {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+///Identifies a listener, the unix listeners are identified by the socket path, the others by the port
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ListenerId {
+    Port(Port),
+    Path(String),
+}
+
+impl ListenerId {
+    #[inline]
+    pub fn new(kind: ListenerKind, listen_cfg: &ListenerInner) -> Self {
+        match (kind, &listen_cfg.path) {
+            (ListenerKind::Unix, Some(path)) => ListenerId::Path(path.clone()),
+            _ => ListenerId::Port(listen_cfg.addr.port()),
+        }
+    }
+}
+
+impl fmt::Display for ListenerId {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenerId::Port(port) => write!(f, "port {}", port),
+            ListenerId::Path(path) => write!(f, "path {}", path),
+        }
+    }
+}
+
+///Listener changed at runtime
+#[derive(Debug, Clone)]
+pub enum ListenerChange {
+    Added(ListenerKind, Listener),
+    Removed(ListenerKind, Listener),
+    Updated(ListenerKind, Listener),
+}
+
+#[derive(Clone)]
+struct Watchers(Arc<RwLock<Vec<mpsc::UnboundedSender<ListenerChange>>>>);
+
+impl Default for Watchers {
+    #[inline]
+    fn default() -> Self {
+        Watchers(Arc::new(RwLock::new(Vec::new())))
+    }
+}
+
+impl fmt::Debug for Watchers {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Watchers({})", self.0.read().len())
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Listener {
     inner: Arc<ListenerInner>,
@@ -138,7 +570,95 @@ impl Deref for Listener {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl Listener {
+    #[inline]
+    pub fn to_json(&self, kind: ListenerKind) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "kind": kind,
+            "addr": self.addr.to_string(),
+            "port": self.addr.port(),
+            "path": self.path,
+            "workers": self.workers,
+            "max_connections": self.max_connections,
+            "max_handshaking_limit": self.max_handshaking_limit,
+            "max_packet_size": self.max_packet_size.as_u32(),
+            "max_inflight": self.max_inflight.get(),
+            "max_mqueue_len": self.max_mqueue_len,
+            "allow_anonymous": self.allow_anonymous,
+            "min_keepalive": self.min_keepalive,
+            "max_keepalive": self.max_keepalive,
+            "max_clientid_len": self.max_clientid_len,
+            "max_subscriptions": self.max_subscriptions,
+            "mountpoint": self.mountpoint,
+            "session_expiry_interval": self.session_expiry_interval.as_secs(),
+            "message_expiry_interval": self.message_expiry_interval.as_secs(),
+        })
+    }
+}
+
+macro_rules! changed_fields {
+    ($a:expr, $b:expr, $($field:ident),*) => {{
+        let mut fields = Vec::new();
+        $(
+            if $a.$field != $b.$field {
+                fields.push(stringify!($field));
+            }
+        )*
+        fields
+    }};
+}
+
+impl ListenerInner {
+    ///The options that are bound to the running server, they can only be changed by restarting the listener
+    fn restart_required(&self, other: &ListenerInner) -> Vec<&'static str> {
+        changed_fields!(
+            self,
+            other,
+            addr,
+            path,
+            path_mode,
+            path_uid,
+            path_gid,
+            workers,
+            backlog,
+            reuseaddr,
+            reuseport,
+            max_conn_rate,
+            max_conn_rate_per_ip,
+            max_connections_per_ip,
+            proxy_protocol,
+            proxy_protocol_timeout,
+            cross_certificate,
+            cert,
+            key,
+            sni,
+            tls_reload_interval,
+            crl_file,
+            ocsp,
+            ocsp_responder_url,
+            ocsp_timeout,
+            ocsp_refresh_interval,
+            ocsp_fail_open,
+            quic_0rtt,
+            quic_idle_timeout,
+            mqttsn_gateway_id,
+            mqttsn_predefined_topics,
+            ws_path,
+            ws_subprotocols,
+            ws_allowed_origins,
+            ws_deflate
+        )
+    }
+
+    ///The options that are passed to the MQTT server of the TCP, TLS, WebSocket and Unix listeners when
+    ///it is built, the server of the listener is rebuilt to apply them
+    pub fn rebuild_required(&self, other: &ListenerInner) -> Vec<&'static str> {
+        changed_fields!(self, other, max_packet_size, max_inflight, handshake_timeout)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct ListenerInner {
     #[serde(default)]
     pub name: String,
@@ -152,6 +672,16 @@ pub struct ListenerInner {
     pub max_connections: usize,
     #[serde(default = "ListenerInner::max_handshaking_limit_default")]
     pub max_handshaking_limit: usize,
+    #[serde(default)]
+    pub max_conn_rate: u32,
+    #[serde(default)]
+    pub max_conn_rate_per_ip: u32,
+    #[serde(default)]
+    pub max_connections_per_ip: usize,
+    #[serde(default, deserialize_with = "deserialize_cidrs")]
+    pub allow: Vec<Cidr>,
+    #[serde(default, deserialize_with = "deserialize_cidrs")]
+    pub deny: Vec<Cidr>,
     #[serde(default = "ListenerInner::max_packet_size_default")]
     pub max_packet_size: Bytesize,
     #[serde(default = "ListenerInner::backlog_default")]
@@ -182,6 +712,13 @@ pub struct ListenerInner {
     )]
     pub mqueue_rate_limit: (NonZeroU32, Duration),
 
+    #[serde(default)]
+    pub max_publish_rate: u32,
+    #[serde(default)]
+    pub max_publish_bytes_rate: Bytesize,
+    #[serde(default)]
+    pub publish_limit_action: PublishLimitAction,
+
     #[serde(default = "ListenerInner::max_clientid_len_default")]
     pub max_clientid_len: usize,
 
@@ -228,11 +765,100 @@ pub struct ListenerInner {
     pub cross_certificate: bool,
     pub cert: Option<String>,
     pub key: Option<String>,
+    #[serde(default)]
+    pub peer_cert_as_username: Option<PeerCertField>,
+    #[serde(default)]
+    pub peer_cert_as_clientid: Option<PeerCertField>,
+    #[serde(default)]
+    pub sni: Vec<SniCert>,
+    #[serde(
+        default = "ListenerInner::tls_reload_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub tls_reload_interval: Duration,
+    #[serde(default)]
+    pub crl_file: Option<String>,
+    #[serde(default)]
+    pub ocsp: bool,
+    #[serde(default)]
+    pub ocsp_responder_url: Option<String>,
+    #[serde(default = "ListenerInner::ocsp_timeout_default", deserialize_with = "deserialize_duration")]
+    pub ocsp_timeout: Duration,
+    #[serde(
+        default = "ListenerInner::ocsp_refresh_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub ocsp_refresh_interval: Duration,
+    #[serde(default)]
+    pub ocsp_fail_open: bool,
+
+    ///Accept 0-RTT data of the resumed quic connections, it is only processed after the handshake completes
+    #[serde(default)]
+    pub quic_0rtt: bool,
+    ///Idle timeout of the quic connections, the server pings the idle connections at a third of it
+    #[serde(default = "ListenerInner::quic_idle_timeout_default", deserialize_with = "deserialize_duration")]
+    pub quic_idle_timeout: Duration,
+
+    ///Gateway id of the MQTT-SN listener, in the GWINFO packets
+    #[serde(default = "ListenerInner::mqttsn_gateway_id_default")]
+    pub mqttsn_gateway_id: u8,
+    ///Topic ids known by the MQTT-SN clients and the gateway in advance, such as { 1 = "sensors/temperature" }
+    #[serde(default, deserialize_with = "ListenerInner::deserialize_predefined_topics")]
+    pub mqttsn_predefined_topics: HashMap<u16, String>,
+
+    ///Interval of the CoAP ping to the idle observers, the observation is cancelled if the ping is not answered
+    #[serde(default = "ListenerInner::coap_keepalive_default", deserialize_with = "deserialize_duration")]
+    pub coap_keepalive: Duration,
+
+    ///Path of the WebSocket upgrade request, such as "/mqtt", any path is accepted if not set
+    #[serde(default)]
+    pub ws_path: Option<String>,
+    ///Subprotocols that can be negotiated, the client must offer one of them, not checked if empty
+    #[serde(default = "ListenerInner::ws_subprotocols_default")]
+    pub ws_subprotocols: Vec<String>,
+    ///Allowed values of the Origin header, any origin is accepted if empty.
+    ///The requests without Origin header (non-browser clients) are always accepted
+    #[serde(default)]
+    pub ws_allowed_origins: Vec<String>,
+    ///Compress the messages with permessage-deflate if the client offers it
+    #[serde(default)]
+    pub ws_deflate: bool,
 
     #[serde(default)]
     pub limit_subscription: bool,
     #[serde(default)]
     pub delayed_publish: bool,
+    ///Prefix of the topics of the clients, such as "tenants/${username}/", AuthInfo::mountpoint overrides it
+    #[serde(default)]
+    pub mountpoint: Option<String>,
+
+    #[serde(default = "ListenerInner::proxy_protocol_default")]
+    pub proxy_protocol: bool,
+    #[serde(
+        default = "ListenerInner::proxy_protocol_timeout_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub proxy_protocol_timeout: Duration,
+
+    ///Socket file of the unix listener
+    #[serde(default)]
+    pub path: Option<String>,
+    ///Permission of the socket file, such as "0660"
+    #[serde(default, deserialize_with = "ListenerInner::deserialize_mode")]
+    pub path_mode: Option<u32>,
+    #[serde(default)]
+    pub path_uid: Option<u32>,
+    #[serde(default)]
+    pub path_gid: Option<u32>,
+}
+
+///Certificate served to the clients that request the hostname by SNI
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct SniCert {
+    ///Such as "mqtt.example.com" or "*.example.com"
+    pub hostname: String,
+    pub cert: String,
+    pub key: String,
 }
 
 impl Default for ListenerInner {
@@ -244,6 +870,11 @@ impl Default for ListenerInner {
             workers: ListenerInner::workers_default(),
             max_connections: ListenerInner::max_connections_default(),
             max_handshaking_limit: ListenerInner::max_handshaking_limit_default(),
+            max_conn_rate: 0,
+            max_conn_rate_per_ip: 0,
+            max_connections_per_ip: 0,
+            allow: Vec::new(),
+            deny: Vec::new(),
             max_packet_size: ListenerInner::max_packet_size_default(),
             reuseaddr: ListenerInner::reuseaddr_default(),
             reuseport: ListenerInner::reuseport_default(),
@@ -257,6 +888,9 @@ impl Default for ListenerInner {
             handshake_timeout: ListenerInner::handshake_timeout_default(),
             max_mqueue_len: ListenerInner::max_mqueue_len_default(),
             mqueue_rate_limit: ListenerInner::mqueue_rate_limit_default(),
+            max_publish_rate: 0,
+            max_publish_bytes_rate: Bytesize::default(),
+            publish_limit_action: PublishLimitAction::default(),
             max_clientid_len: ListenerInner::max_clientid_len_default(),
             max_qos_allowed: ListenerInner::max_qos_allowed_default(),
             max_topic_levels: ListenerInner::max_topic_levels_default(),
@@ -270,8 +904,34 @@ impl Default for ListenerInner {
             cross_certificate: ListenerInner::cross_certificate_default(),
             cert: None,
             key: None,
+            peer_cert_as_username: None,
+            peer_cert_as_clientid: None,
+            sni: Vec::new(),
+            tls_reload_interval: ListenerInner::tls_reload_interval_default(),
+            crl_file: None,
+            ocsp: false,
+            ocsp_responder_url: None,
+            ocsp_timeout: ListenerInner::ocsp_timeout_default(),
+            ocsp_refresh_interval: ListenerInner::ocsp_refresh_interval_default(),
+            ocsp_fail_open: false,
+            quic_0rtt: false,
+            quic_idle_timeout: ListenerInner::quic_idle_timeout_default(),
+            mqttsn_gateway_id: ListenerInner::mqttsn_gateway_id_default(),
+            mqttsn_predefined_topics: HashMap::default(),
+            coap_keepalive: ListenerInner::coap_keepalive_default(),
+            ws_path: None,
+            ws_subprotocols: ListenerInner::ws_subprotocols_default(),
+            ws_allowed_origins: Vec::new(),
+            ws_deflate: false,
             limit_subscription: false,
             delayed_publish: false,
+            mountpoint: None,
+            proxy_protocol: ListenerInner::proxy_protocol_default(),
+            proxy_protocol_timeout: ListenerInner::proxy_protocol_timeout_default(),
+            path: None,
+            path_mode: None,
+            path_uid: None,
+            path_gid: None,
         }
     }
 }
@@ -380,6 +1040,50 @@ impl ListenerInner {
     fn message_expiry_interval_default() -> Duration {
         Duration::from_secs(300)
     }
+
+    #[inline]
+    fn mqttsn_gateway_id_default() -> u8 {
+        1
+    }
+
+    #[inline]
+    fn deserialize_predefined_topics<'de, D>(deserializer: D) -> Result<HashMap<u16, String>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let topics = std::collections::HashMap::<String, String>::deserialize(deserializer)?;
+        let mut predefined = HashMap::default();
+        for (id, topic) in topics {
+            let id = match u16::from_str(&id) {
+                Ok(id) if id > 0 && id < u16::MAX => id,
+                _ => {
+                    return Err(de::Error::custom(format!(
+                        "mqttsn_predefined_topics, invalid topic id, {}",
+                        id
+                    )))
+                }
+            };
+            if topic.is_empty() || topic.contains(['+', '#']) {
+                return Err(de::Error::custom(format!("mqttsn_predefined_topics, invalid topic, {}", topic)));
+            }
+            predefined.insert(id, topic);
+        }
+        Ok(predefined)
+    }
+
+    #[inline]
+    fn quic_idle_timeout_default() -> Duration {
+        Duration::from_secs(60)
+    }
+    #[inline]
+    fn coap_keepalive_default() -> Duration {
+        Duration::from_secs(120)
+    }
+
+    #[inline]
+    fn ws_subprotocols_default() -> Vec<String> {
+        vec!["mqtt".into(), "mqttv3.1".into()]
+    }
     #[inline]
     fn max_subscriptions_default() -> usize {
         0
@@ -388,6 +1092,14 @@ impl ListenerInner {
     fn shared_subscription_default() -> bool {
         true
     }
+    #[inline]
+    fn proxy_protocol_default() -> bool {
+        false
+    }
+    #[inline]
+    fn proxy_protocol_timeout_default() -> Duration {
+        Duration::from_secs(5)
+    }
 
     #[inline]
     pub fn handshake_timeout(&self) -> u16 {
@@ -399,6 +1111,17 @@ impl ListenerInner {
         }
     }
 
+    #[inline]
+    fn deserialize_mode<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = String::deserialize(deserializer)?;
+        u32::from_str_radix(v.trim_start_matches("0o"), 8)
+            .map(Some)
+            .map_err(|e| de::Error::custom(format!("path_mode, octal format error, {}, {:?}", v, e)))
+    }
+
     #[inline]
     fn deserialize_mqueue_rate_limit<'de, D>(deserializer: D) -> Result<(NonZeroU32, Duration), D::Error>
     where
@@ -438,4 +1161,51 @@ impl ListenerInner {
     fn cross_certificate_default() -> bool {
         false
     }
+
+    #[inline]
+    fn tls_reload_interval_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    #[inline]
+    fn ocsp_timeout_default() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    #[inline]
+    fn ocsp_refresh_interval_default() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    #[inline]
+    pub fn publish_limit(&self) -> PublishLimit {
+        PublishLimit {
+            messages_rate: self.max_publish_rate,
+            bytes_rate: self.max_publish_bytes_rate.as_u32(),
+        }
+    }
+}
+
+///Inbound publish limits of a client, 0 means no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PublishLimit {
+    ///Messages per second
+    #[serde(default)]
+    pub messages_rate: u32,
+    ///Bytes per second
+    #[serde(default)]
+    pub bytes_rate: u32,
+}
+
+///What to do when a client publishes faster than its limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PublishLimitAction {
+    ///Wait until the limit allows, which slows down reading from the client
+    #[default]
+    Throttle,
+    ///Drop the message, the message_dropped hook is triggered
+    Drop,
+    ///Disconnect the client, MQTT 5 clients receive MessageRateTooHigh or QuotaExceeded
+    Disconnect,
 }
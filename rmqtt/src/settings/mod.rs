@@ -61,28 +61,17 @@ impl Deref for Settings {
 
 impl Settings {
     fn new(opts: Options) -> Result<Self> {
-        let mut builder = Config::builder()
-            .add_source(File::with_name("/etc/rmqtt/rmqtt").required(false))
-            .add_source(File::with_name("/etc/rmqtt").required(false))
-            .add_source(File::with_name("rmqtt").required(false))
-            .add_source(
-                config::Environment::with_prefix("rmqtt")
-                    .try_parsing(true)
-                    .list_separator(" ")
-                    .with_list_parse_key("plugins.default_startups"),
-            );
-
-        if let Some(cfg) = opts.cfg_name.as_ref() {
-            builder = builder.add_source(File::with_name(cfg).required(false));
-        }
-
-        let mut inner: Inner = builder.build()?.try_deserialize()?;
+        let mut inner: Inner = Self::builder(&opts).build()?.try_deserialize()?;
 
-        inner.listeners.init();
+        inner.listeners.init()?;
         if inner.listeners.tcps.is_empty() && inner.listeners.tlss.is_empty() {
             //set default
             inner.listeners.set_default();
         }
+        inner.rpc.check()?;
+        if inner.node.id_from_hostname {
+            inner.node.id = crate::broker::discovery::hostname_id()?;
+        }
 
         //Command line configuration overriding file configuration
         if let Some(id) = opts.node_id {
@@ -98,6 +87,37 @@ impl Settings {
         Ok(Self(Arc::new(inner)))
     }
 
+    #[inline]
+    fn builder(opts: &Options) -> config::ConfigBuilder<config::builder::DefaultState> {
+        let mut builder = Config::builder()
+            .add_source(File::with_name("/etc/rmqtt/rmqtt").required(false))
+            .add_source(File::with_name("/etc/rmqtt").required(false))
+            .add_source(File::with_name("rmqtt").required(false))
+            .add_source(
+                config::Environment::with_prefix("rmqtt")
+                    .try_parsing(true)
+                    .list_separator(" ")
+                    .with_list_parse_key("plugins.default_startups"),
+            );
+
+        if let Some(cfg) = opts.cfg_name.as_ref() {
+            builder = builder.add_source(File::with_name(cfg).required(false));
+        }
+        builder
+    }
+
+    ///Reload the listeners from the configuration files, the added listeners are started, the removed
+    ///ones are stopped, and the changed ones are reconfigured. Returns the listeners that can not be applied
+    pub fn reload_listeners(&self) -> Result<()> {
+        let inner: Inner = Self::builder(&self.opts).build()?.try_deserialize()?;
+        let mut listeners = inner.listeners;
+        listeners.init()?;
+        if listeners.tcps.is_empty() && listeners.tlss.is_empty() {
+            listeners.set_default();
+        }
+        self.listeners.apply(&listeners)
+    }
+
     #[inline]
     pub fn instance() -> Result<&'static Self> {
         Ok(SETTINGS.get().ok_or_else(|| anyhow!("Settings not initialized"))?)
@@ -130,6 +150,9 @@ impl Settings {
         if cfg.opts.raft_leader_id.is_some() {
             crate::log::info!("raft_leader_id is {:?}", cfg.opts.raft_leader_id);
         }
+        if cfg.opts.raft_seed_addr.is_some() {
+            crate::log::info!("raft_seed_addr is {:?}", cfg.opts.raft_seed_addr);
+        }
         Ok(())
     }
 }
@@ -226,18 +249,32 @@ impl Task {
 pub struct Node {
     #[serde(default)]
     pub id: NodeId,
+    //Derive the node id from the ordinal of the hostname, such as rmqtt-2 => 3
+    #[serde(default)]
+    pub id_from_hostname: bool,
     #[serde(default = "Node::cookie_default")]
     pub cookie: String,
+    //Accept the gRPC requests without the cookie, so that the nodes can be upgraded one by one
+    #[serde(default)]
+    pub cookie_optional: bool,
     // #[serde(default = "Node::crash_dump_default")]
     // pub crash_dump: String,
     #[serde(default)]
     pub busy: Busy,
+    #[serde(default)]
+    pub drain: Drain,
 }
 
 impl Node {
     fn cookie_default() -> String {
         "rmqttsecretcookie".into()
     }
+
+    ///The cookie is not configured, it is known to everyone
+    #[inline]
+    pub fn is_default_cookie(&self) -> bool {
+        self.cookie == Self::cookie_default()
+    }
     // fn crash_dump_default() -> String {
     //     "/var/log/rmqtt/crash.dump".into()
     // }
@@ -291,6 +328,50 @@ impl Busy {
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct Drain {
+    //The number of clients disconnected per batch when the node is draining
+    #[serde(default = "Drain::batch_size_default")]
+    pub batch_size: usize,
+    //Interval between two batches
+    #[serde(default = "Drain::batch_interval_default", deserialize_with = "deserialize_duration")]
+    pub batch_interval: Duration,
+    //Maximum time to wait for all clients to go offline before the process exits
+    #[serde(default = "Drain::timeout_default", deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+    //Server Reference sent to MQTT 5 clients in the DISCONNECT packet
+    #[serde(default)]
+    pub server_reference: Option<String>,
+    //Use the ServerMoved(permanent) reason code instead of UseAnotherServer(temporary)
+    #[serde(default)]
+    pub server_moved: bool,
+}
+
+impl Default for Drain {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            batch_size: Self::batch_size_default(),
+            batch_interval: Self::batch_interval_default(),
+            timeout: Self::timeout_default(),
+            server_reference: None,
+            server_moved: false,
+        }
+    }
+}
+
+impl Drain {
+    fn batch_size_default() -> usize {
+        500
+    }
+    fn batch_interval_default() -> Duration {
+        Duration::from_secs(1)
+    }
+    fn timeout_default() -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Rpc {
     #[serde(default = "Rpc::server_addr_default", deserialize_with = "deserialize_addr")]
@@ -314,6 +395,17 @@ pub struct Rpc {
     //#Maximum number of messages sent in batch
     #[serde(default = "Rpc::batch_size_default")]
     pub batch_size: usize,
+
+    //#CA certificate of the nodes, with tls_cert and tls_key the gRPC connections use mutual TLS
+    #[serde(default)]
+    pub tls_ca: Option<String>,
+    #[serde(default)]
+    pub tls_cert: Option<String>,
+    #[serde(default)]
+    pub tls_key: Option<String>,
+    //#Name verified against the certificates of the other nodes, the host of the node address by default
+    #[serde(default)]
+    pub tls_domain_name: Option<String>,
 }
 
 impl Default for Rpc {
@@ -327,6 +419,10 @@ impl Default for Rpc {
             server_workers: Self::server_workers_default(),
             client_concurrency_limit: Self::client_concurrency_limit_default(),
             client_timeout: Self::client_timeout_default(),
+            tls_ca: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_domain_name: None,
         }
     }
 }
@@ -353,6 +449,18 @@ impl Rpc {
     fn client_timeout_default() -> Duration {
         Duration::from_secs(5)
     }
+
+    #[inline]
+    pub fn tls_enable(&self) -> bool {
+        self.tls_ca.is_some()
+    }
+
+    fn check(&self) -> Result<()> {
+        match (&self.tls_ca, &self.tls_cert, &self.tls_key) {
+            (Some(_), Some(_), Some(_)) | (None, None, None) => Ok(()),
+            _ => Err(MqttError::from("rpc.tls_ca, rpc.tls_cert and rpc.tls_key must be set together")),
+        }
+    }
 }
 
 #[derive(Default, Debug, Clone, Deserialize)]
@@ -441,9 +549,28 @@ pub struct Mqtt {
     pub delayed_publish_immediate: bool,
     #[serde(default = "Mqtt::max_sessions_default")]
     pub max_sessions: isize,
+    #[serde(default)]
+    pub shared_subscription_strategy: SharedSubscriptionStrategy,
+    //Strategy by share group name, overrides shared_subscription_strategy
+    #[serde(default)]
+    pub shared_subscription_group_strategies: std::collections::HashMap<String, SharedSubscriptionStrategy>,
+    //Redispatch queued and in-flight shared subscription messages to other group members when a member goes offline
+    #[serde(default)]
+    pub shared_subscription_redispatch: bool,
+    //Ban the client that reconnects too frequently
+    #[serde(default)]
+    pub flapping_detect: FlappingDetect,
 }
 
 impl Mqtt {
+    #[inline]
+    pub fn shared_subscription_strategy(&self, group: &str) -> SharedSubscriptionStrategy {
+        self.shared_subscription_group_strategies
+            .get(group)
+            .copied()
+            .unwrap_or(self.shared_subscription_strategy)
+    }
+
     fn delayed_publish_max_default() -> usize {
         100_000
     }
@@ -457,11 +584,74 @@ impl Mqtt {
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct FlappingDetect {
+    #[serde(default)]
+    pub enable: bool,
+    //Maximum number of connects allowed for a client id within the window_time
+    #[serde(default = "FlappingDetect::max_count_default")]
+    pub max_count: usize,
+    #[serde(default = "FlappingDetect::window_time_default", deserialize_with = "deserialize_duration")]
+    pub window_time: Duration,
+    //How long the flapping client is banned
+    #[serde(default = "FlappingDetect::ban_time_default", deserialize_with = "deserialize_duration")]
+    pub ban_time: Duration,
+}
+
+impl Default for FlappingDetect {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: false,
+            max_count: Self::max_count_default(),
+            window_time: Self::window_time_default(),
+            ban_time: Self::ban_time_default(),
+        }
+    }
+}
+
+impl FlappingDetect {
+    fn max_count_default() -> usize {
+        15
+    }
+    fn window_time_default() -> Duration {
+        Duration::from_secs(60)
+    }
+    fn ban_time_default() -> Duration {
+        Duration::from_secs(300)
+    }
+}
+
+///Shared subscription load balancing strategy, select a subscriber from a share group
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SharedSubscriptionStrategy {
+    ///Random online member
+    #[default]
+    Random,
+    ///Online members in turn
+    RoundRobin,
+    ///Keep sending to the same member until it goes offline
+    Sticky,
+    ///Hash by the publisher client id, messages from the same client go to the same member
+    HashClientid,
+    ///Hash by the topic, messages with the same topic go to the same member
+    HashTopic,
+    ///Prefer online members on this node
+    Local,
+    ///Member with the fewest in-flight messages on this node, the members on other nodes are
+    ///only chosen when no member on this node is online
+    LeastInflight,
+    ///Member with the fewest queued messages on this node, the members on other nodes are
+    ///only chosen when no member on this node is online
+    LeastQueued,
+}
+
 const BYTESIZE_K: usize = 1024;
 const BYTESIZE_M: usize = 1048576;
 const BYTESIZE_G: usize = 1073741824;
 
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
 pub struct Bytesize(usize);
 
 impl Bytesize {
@@ -652,6 +842,30 @@ where
     Ok(addr)
 }
 
+///CIDR list, such as ["10.0.0.0/8", "192.168.1.1"] or "10.0.0.0/8,192.168.1.1"
+#[inline]
+pub fn deserialize_cidrs<'de, D>(deserializer: D) -> std::result::Result<Vec<crate::Cidr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Cidrs {
+        List(Vec<String>),
+        Str(String),
+    }
+    let cidrs = match Cidrs::deserialize(deserializer)? {
+        Cidrs::List(cidrs) => cidrs,
+        Cidrs::Str(cidrs) => cidrs.split(',').map(|c| c.to_owned()).collect(),
+    };
+    cidrs
+        .iter()
+        .map(|c| c.trim())
+        .filter(|c| !c.is_empty())
+        .map(|c| c.parse::<crate::Cidr>().map_err(de::Error::custom))
+        .collect()
+}
+
 #[inline]
 pub fn deserialize_addr_option<'de, D>(
     deserializer: D,
@@ -30,6 +30,11 @@ pub struct Options {
     ///will be designated as the Leader. Default value: 0
     #[structopt(name = "raft-leader-id", long)]
     pub raft_leader_id: Option<NodeId>,
+
+    ///Raft address of any node in a running cluster, this node joins the cluster through it,
+    ///--raft-seed-addr "127.0.0.1:6003"
+    #[structopt(name = "raft-seed-addr", long)]
+    pub raft_seed_addr: Option<String>,
     // ///Node cookie
     // #[structopt(name = "cookie", long)]
     // pub node_cookie: Option<String>,